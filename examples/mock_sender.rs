@@ -0,0 +1,62 @@
+//! Downloads a pack programmatically from a local mock sender, without any IRC network.
+
+use irc::client::{data::Config, Client};
+use irc_downloader::dcc::DownloadOptions;
+use irc_downloader::downloader::{DownloadEvent, Downloader};
+use std::net::Ipv4Addr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let content = vec![42u8; 1 << 20];
+
+    // The mock sender serves the file to whoever connects first, like a bot after a DCC SEND
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let port = listener.local_addr()?.port();
+    let served = content.clone();
+    tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await?;
+        socket.write_all(&served).await?;
+        socket.shutdown().await?;
+        // Drain acknowledgements until the receiver hangs up
+        let mut acks = Vec::new();
+        socket.read_to_end(&mut acks).await?;
+        Ok::<_, std::io::Error>(())
+    });
+
+    let config: Config =
+        toml::from_str("server = \"mock\"\nnickname = \"downloader\"\nuse_mock_connection = true")?;
+    let client = Client::from_config(config).await?;
+
+    let download_folder = std::env::temp_dir().join("irc-downloader-example");
+    let downloader = Downloader::new(DownloadOptions::new(
+        Ipv4Addr::LOCALHOST,
+        0,
+        download_folder.clone(),
+    ));
+    let mut events = downloader.subscribe();
+    tokio::spawn(async move {
+        while let Ok(event) = events.recv().await {
+            if !matches!(event, DownloadEvent::Progress { .. }) {
+                println!("{:?}", event);
+            }
+        }
+    });
+
+    downloader.request_pack(&client.sender(), "MockBot", "xdcc send #1")?;
+    let offer = format!(
+        "\u{1}DCC SEND example.bin {} {} {}\u{1}",
+        u32::from(Ipv4Addr::LOCALHOST),
+        port,
+        content.len()
+    );
+    downloader
+        .accept(client.sender(), "MockBot".to_string(), &offer)
+        .await?;
+
+    let downloaded = std::fs::read(download_folder.join("example.bin"))?;
+    assert_eq!(downloaded, content);
+    println!("Downloaded {} bytes", downloaded.len());
+    Ok(())
+}
@@ -1,59 +1,971 @@
-use anyhow::bail;
+use crate::hash::{sfv_checksum, FileDigest, HashAlgorithm, Hasher};
+use crate::{sanitize_file_name, CancelReason};
+use anyhow::{anyhow, bail, Context};
 use irc::client;
 use lazy_static::lazy_static;
 use regex::Regex;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::path::Path;
-use tokio::fs::File;
-use tokio::io::{AsyncWriteExt, BufWriter};
-use tokio::net::{TcpListener, TcpStream};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
 use tokio::sync::watch::{self, Receiver, Sender};
-use tokio::time::{timeout, Duration};
+use tokio::sync::{oneshot, Semaphore};
+use tokio::time::{timeout, Duration, Instant};
+use utoipa::ToSchema;
 
 lazy_static! {
-    pub static ref REX_DCC_SEND : Regex = Regex::new("(?i)\u{1}DCC SEND (?P<filename>\\S+) (?P<address>\\d+) (?P<port>\\d+)(?: (?P<filesize>\\d+))?(?: (?P<id>\\d+))?.*\u{1}")
+    pub static ref REX_DCC_SEND : Regex = Regex::new("(?i)\u{1}DCC SEND (?P<filename>\"[^\"]*\"|\\S+) (?P<address>\\d+|[0-9a-f.]*:[0-9a-f:.]*) (?P<port>\\d+)(?: (?P<filesize>\\d+))?(?: (?P<id>\\d+))?.*\u{1}")
         .expect("Valid regex");
+    pub static ref REX_DCC_ACCEPT : Regex = Regex::new("(?i)\u{1}DCC ACCEPT (?P<filename>\"[^\"]*\"|\\S+) (?P<port>\\d+) (?P<position>\\d+)(?: (?P<id>\\d+))?.*\u{1}")
+        .expect("Valid regex");
+}
+
+/// Makes sure `path` names a file directly inside `folder`, even after resolving symlinks, so a
+/// crafted name or a planted link cannot make a download write elsewhere.
+fn ensure_inside(folder: &Path, path: &Path) -> anyhow::Result<()> {
+    let folder = folder.canonicalize()?;
+    let parent = path
+        .parent()
+        .ok_or_else(|| anyhow!("{} has no parent", path.display()))?
+        .canonicalize()?;
+    if parent != folder {
+        bail!("{} is outside of {}", path.display(), folder.display());
+    }
+    if std::fs::symlink_metadata(path).map_or(false, |meta| meta.file_type().is_symlink()) {
+        bail!("{} is a symlink", path.display());
+    }
+    Ok(())
+}
+
+/// Time a bot has to answer a DCC RESUME, after that the file is transferred from the start.
+const RESUME_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Settings shared by all transfers.
+#[derive(Clone)]
+pub struct DownloadOptions {
+    /// Our public IPv4 address, needed for passive transfers. Unknown while it could not be
+    /// looked up.
+    pub myip: Option<Ipv4Addr>,
+    pub port: u16,
+    pub download_folder: PathBuf,
+    /// Written into lock files, so other instances sharing the download folder know who holds it.
+    pub instance_id: String,
+    /// Locks without activity on the target file for this long may be broken.
+    pub stale_lock_after: Duration,
+    /// Fixed limit for the duration of a transfer, used when the file size is unknown.
+    pub transfer_timeout: Option<Duration>,
+    /// Slowest throughput in bytes per second we accept, scales the transfer timeout.
+    pub min_throughput: Option<u64>,
+    /// A running transfer receiving nothing for this long is cancelled.
+    pub stall_timeout: Option<Duration>,
+    /// A running transfer slower than this many bytes per second over the rate window is cancelled.
+    pub min_speed: Option<u64>,
+    /// Permissions of downloaded files, Unix only
+    pub file_mode: Option<u32>,
+    pub file_owner: Option<u32>,
+    pub file_group: Option<u32>,
+    /// Digest computed while transferring
+    pub hash: Option<HashAlgorithm>,
+    /// Sync the file and its directory to stable storage before declaring it complete
+    pub fsync_on_complete: bool,
+    /// Read the file back after the transfer, checking its length and digest
+    pub verify_readback: bool,
+    /// Check the CRC32 against a .sfv file listing it in the download folder
+    pub check_sfv: bool,
+    /// Second place completed files are put, as a backup
+    pub mirror: Option<Mirror>,
+    /// Local address to connect from, and to listen on for passive transfers
+    pub source_address: Option<Ipv4Addr>,
+    /// Our IPv6 address, if we have one
+    pub myip_v6: Option<Ipv6Addr>,
+    /// Refuse offers from IPv6 addresses, e.g. behind a NAT only forwarding IPv4
+    pub ipv4_only: bool,
+    /// Transfers waiting for a bot to accept continuing a partial file
+    pub resumes: PendingResumes,
+    /// Limits the sockets open at once, shared with the IRC connections. Transfers wait for one
+    /// to be free.
+    pub socket_limit: Option<Arc<Semaphore>>,
+    /// Limits the transfers running at once. Further offers wait for one to end.
+    pub transfer_limit: Option<Arc<Semaphore>>,
+    /// Size of the acknowledgements telling the sender how much we received
+    pub ack_width: AckWidth,
+    /// Appended to the file name until the download is complete
+    pub part_suffix: String,
+    /// What to do when requesting a file that is in the download folder already
+    pub existing_files: ExistingFiles,
+    /// Limits the bytes per second of all transfers together
+    pub bandwidth: Option<Arc<Throttle>>,
+    /// Bytes per second each transfer is limited to
+    pub max_bytes_per_sec: Option<u64>,
+    /// Bytes to keep free on the volume of the download folder, files that would not leave them
+    /// are not transferred
+    pub disk_reserve: u64,
+}
+
+/// Token bucket limiting the throughput of the transfers sharing it. Up to a second worth of
+/// bytes may be received at once, after that transfers wait until they are within the rate.
+pub struct Throttle {
+    bytes_per_sec: u64,
+    /// Bytes that may be received right away as of when it was last updated, negative while
+    /// transfers are ahead of the rate
+    budget: Mutex<(f64, Instant)>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec.max(1),
+            budget: Mutex::new((bytes_per_sec as f64, Instant::now())),
+        }
+    }
+
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec
+    }
+
+    /// Takes received bytes from the budget, waiting as long as that puts us ahead of the rate.
+    pub async fn consume(&self, bytes: usize) {
+        let rate = self.bytes_per_sec as f64;
+        let wait = {
+            let mut budget = self.budget.lock().unwrap();
+            let now = Instant::now();
+            let refilled = budget.0 + now.duration_since(budget.1).as_secs_f64() * rate;
+            *budget = (refilled.min(rate) - bytes as f64, now);
+            (budget.0 < 0.0).then(|| Duration::from_secs_f64(-budget.0 / rate))
+        };
+        if let Some(wait) = wait {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Mirror {
+    pub folder: PathBuf,
+    #[serde(default)]
+    pub mode: MirrorMode,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MirrorMode {
+    #[default]
+    Copy,
+    /// Hard link, copying when the mirror is on another file system
+    Hardlink,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExistingFiles {
+    /// Download again, keeping the new file under a free name
+    #[default]
+    Rename,
+    /// Don't request files present already, if they match the size and digest given, and don't
+    /// download offers of a file present already
+    Skip,
+    /// Download again, replacing the file
+    Overwrite,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AckWidth {
+    /// 32 bits as the DCC protocol has it, wrapping around for files beyond 4GiB
+    #[default]
+    Bits32,
+    /// 64 bits, as some senders expect for large files
+    Bits64,
+}
+
+/// Acknowledgement of having received `transferred` bytes in total, in network byte order.
+fn ack(transferred: u64, width: AckWidth) -> Vec<u8> {
+    match width {
+        AckWidth::Bits32 => (transferred as u32).to_be_bytes().to_vec(),
+        AckWidth::Bits64 => transferred.to_be_bytes().to_vec(),
+    }
+}
+
+impl DownloadOptions {
+    /// Options with defaults for everything besides what's needed for passive transfers.
+    pub fn new(myip: Ipv4Addr, port: u16, download_folder: PathBuf) -> Self {
+        Self {
+            myip: Some(myip),
+            port,
+            download_folder,
+            instance_id: format!("pid-{}", std::process::id()),
+            stale_lock_after: Duration::from_secs(600),
+            transfer_timeout: None,
+            min_throughput: None,
+            stall_timeout: None,
+            min_speed: None,
+            file_mode: None,
+            file_owner: None,
+            file_group: None,
+            hash: None,
+            fsync_on_complete: false,
+            verify_readback: false,
+            check_sfv: false,
+            mirror: None,
+            source_address: None,
+            myip_v6: None,
+            ipv4_only: false,
+            resumes: PendingResumes::default(),
+            socket_limit: None,
+            transfer_limit: None,
+            ack_width: AckWidth::default(),
+            part_suffix: ".part".to_string(),
+            existing_files: ExistingFiles::default(),
+            bandwidth: None,
+            max_bytes_per_sec: None,
+            disk_reserve: 0,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn apply_permissions(path: &Path, options: &DownloadOptions) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Some(mode) = options.file_mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    if options.file_owner.is_some() || options.file_group.is_some() {
+        std::os::unix::fs::chown(path, options.file_owner, options.file_group)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn apply_permissions(_path: &Path, options: &DownloadOptions) -> std::io::Result<()> {
+    if options.file_mode.is_some() || options.file_owner.is_some() || options.file_group.is_some() {
+        log::warn!("File permissions are only supported on Unix, ignoring them");
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn sync_directory(path: &Path) -> std::io::Result<()> {
+    std::fs::File::open(path)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_directory(_path: &Path) -> std::io::Result<()> {
+    // Directories cannot be opened for syncing here, the entry is persisted with the file
+    Ok(())
+}
+
+/// Checks that an address can be used as source address, i.e. is assigned to an interface.
+pub fn check_source_address(address: Ipv4Addr) -> anyhow::Result<()> {
+    std::net::TcpListener::bind((address, 0)).with_context(|| {
+        format!(
+            "DCC source address {} is not usable, is it assigned to an interface?",
+            address
+        )
+    })?;
+    Ok(())
+}
+
+/// Whether a bot elsewhere on the internet could reach this address. Private, loopback and
+/// carrier-grade NAT (100.64.0.0/10) addresses usually mean `myip` is misconfigured.
+fn is_public(ip: IpAddr) -> bool {
+    match canonical(ip) {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            let shared = a == 100 && (b & 0xc0) == 64;
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || shared)
+        }
+        IpAddr::V6(ip) => {
+            let [first, ..] = ip.segments();
+            // Unique local fc00::/7 and link local fe80::/10
+            let local = (first & 0xfe00) == 0xfc00 || (first & 0xffc0) == 0xfe80;
+            !(ip.is_loopback() || ip.is_unspecified() || local)
+        }
+    }
+}
+
+/// The IPv4 address an IPv4-mapped IPv6 address stands for, so addresses of dual-stack sockets
+/// compare equal to the IPv4 ones bots announce.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        v4 => v4,
+    }
+}
+
+/// Parses the address of a DCC SEND: IPv4 as a decimal 32 bit number, IPv6 as a decimal 128 bit
+/// number or in colon notation.
+fn parse_address(address: &str) -> Option<IpAddr> {
+    if address.contains(':') {
+        return address.parse::<Ipv6Addr>().ok().map(IpAddr::V6);
+    }
+    let number = address.parse::<u128>().ok()?;
+    Some(match u32::try_from(number) {
+        Ok(v4) => IpAddr::V4(Ipv4Addr::from(v4)),
+        Err(_) => IpAddr::V6(Ipv6Addr::from(number)),
+    })
+}
+
+/// Reads address and port of an offer. A few clients send them the other way around, recognized
+/// by the address being no usable IPv4 address (within 0.0.0.0/8) while the port is one.
+fn address_and_port(address: &str, port: &str) -> anyhow::Result<SocketAddr> {
+    let plausible = |ip: &IpAddr| match ip {
+        IpAddr::V4(v4) => v4.octets()[0] != 0,
+        IpAddr::V6(v6) => !v6.is_unspecified(),
+    };
+    match (parse_address(address), port.parse::<u16>()) {
+        // Passive offers may leave the address zero
+        (Some(ip), Ok(port)) if port == 0 || plausible(&ip) => Ok(SocketAddr::new(ip, port)),
+        _ => match (address.parse::<u16>(), parse_address(port)) {
+            (Ok(swapped_port), Some(ip)) if plausible(&ip) => {
+                log::warn!(
+                    "Address and port of DCC SEND are transposed, connecting to {} port {}",
+                    ip,
+                    swapped_port
+                );
+                Ok(SocketAddr::new(ip, swapped_port))
+            }
+            _ => bail!("Implausible address {} with port {}", address, port),
+        },
+    }
+}
+
+/// Connects to a sender, from the source address if there is one.
+async fn connect(
+    address: SocketAddr,
+    source_address: Option<Ipv4Addr>,
+) -> anyhow::Result<TcpStream> {
+    // The source address is IPv4, IPv6 connections are made from wherever the system routes them
+    let Some(source_address) = source_address.filter(|_| address.is_ipv4()) else {
+        return Ok(TcpStream::connect(address).await?);
+    };
+    let socket = TcpSocket::new_v4()?;
+    socket
+        .bind(SocketAddrV4::new(source_address, 0).into())
+        .with_context(|| format!("Could not connect from {}", source_address))?;
+    Ok(socket.connect(address.into()).await?)
+}
+
+/// Removes leftovers of failed transfers from the download folder: empty files and, with a
+/// maximum age, partial files (ending in `part_suffix`) not written to for that long. Files named
+/// in `keep`, those of live downloads, and files locked by a transfer are left alone. Returns the
+/// removed files.
+pub fn sweep_download_folder(
+    folder: &Path,
+    keep: &HashSet<String>,
+    part_suffix: &str,
+    part_max_age: Option<Duration>,
+) -> std::io::Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    for entry in std::fs::read_dir(folder)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !metadata.is_file() || name.ends_with(".lock") {
+            continue;
+        }
+        let download_name = name.strip_suffix(part_suffix).unwrap_or(&name);
+        let mut lock_name = folder.join(download_name).into_os_string();
+        lock_name.push(".lock");
+        if keep.contains(download_name) || Path::new(&lock_name).exists() {
+            continue;
+        }
+        let stale_part = name.ends_with(part_suffix)
+            && part_max_age.map_or(false, |max_age| {
+                metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| modified.elapsed().ok())
+                    .map_or(false, |age| age >= max_age)
+            });
+        if metadata.len() == 0 || stale_part {
+            std::fs::remove_file(entry.path())?;
+            removed.push(entry.path());
+        }
+    }
+    Ok(removed)
+}
+
+/// Names of the downloads with a partial file (ending in `part_suffix`) in the download folder.
+pub fn partial_downloads(folder: &Path, part_suffix: &str) -> std::io::Result<HashSet<String>> {
+    let mut downloads = HashSet::new();
+    for entry in std::fs::read_dir(folder)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if let Some(download_name) = name.strip_suffix(part_suffix) {
+            if entry.metadata()?.is_file() {
+                downloads.insert(download_name.to_string());
+            }
+        }
+    }
+    Ok(downloads)
+}
+
+/// Where a download is written to until it is complete.
+pub fn part_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(suffix);
+    PathBuf::from(part)
+}
+
+/// Checks the suffix of partial files. Without one, every file would pass for a partial one and
+/// be swept, and with a path separator partial files would end up in another folder.
+pub fn check_part_suffix(suffix: &str) -> anyhow::Result<()> {
+    if suffix.is_empty() {
+        bail!("part_suffix must not be empty");
+    }
+    if suffix.contains(['/', '\\']) {
+        bail!("part_suffix {:?} must not contain a path separator", suffix);
+    }
+    Ok(())
+}
+
+/// Fails unless the volume of `folder` has room for `needed` more bytes. When the free space
+/// can't be determined, that is only logged.
+fn check_free_space(folder: &Path, needed: u64) -> anyhow::Result<()> {
+    let available = match fs2::available_space(folder) {
+        Ok(available) => available,
+        Err(err) => {
+            log::warn!(
+                "Could not determine free space in {}: {}",
+                folder.display(),
+                err
+            );
+            return Ok(());
+        }
+    };
+    if available < needed {
+        bail!(
+            "Insufficient disk space: need {} bytes, have {}",
+            needed,
+            available
+        );
+    }
+    Ok(())
+}
+
+/// `path`, or if that is taken the first free one of `name (1).ext`, `name (2).ext`, ...
+fn free_path(path: &Path) -> PathBuf {
+    if !path.exists() {
+        return path.to_path_buf();
+    }
+    let stem = path
+        .file_stem()
+        .map_or_else(String::new, |stem| stem.to_string_lossy().into_owned());
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    (1..)
+        .map(|n| path.with_file_name(format!("{} ({}){}", stem, n, extension)))
+        .find(|candidate| !candidate.exists())
+        .expect("Some name is free")
+}
+
+/// Puts a completed file into the mirror folder.
+async fn mirror_file(path: &Path, mirror: &Mirror) -> std::io::Result<PathBuf> {
+    tokio::fs::create_dir_all(&mirror.folder).await?;
+    let target = mirror
+        .folder
+        .join(path.file_name().expect("Downloads are files"));
+    if mirror.mode == MirrorMode::Hardlink {
+        match tokio::fs::hard_link(path, &target).await {
+            Ok(()) => return Ok(target),
+            Err(err) => log::debug!("Hard linking {} failed, copying: {}", target.display(), err),
+        }
+    }
+    tokio::fs::copy(path, &target).await?;
+    Ok(target)
+}
+
+/// Reads a completed file back, checking it has the length written and the digest computed.
+async fn verify_readback(
+    path: &Path,
+    length: u64,
+    digest: Option<&FileDigest>,
+) -> anyhow::Result<()> {
+    let mut file = File::open(path).await?;
+    let mut hasher = digest.map(|digest| Hasher::new(digest.algorithm));
+    let mut read = 0;
+    let mut buf = [0; 16384];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        read += n as u64;
+        if let Some(hasher) = &mut hasher {
+            hasher.update(&buf[..n]);
+        }
+    }
+    if read != length {
+        bail!("Read back {} bytes, but wrote {}", read, length);
+    }
+    if let (Some(hasher), Some(digest)) = (hasher, digest) {
+        let read_digest = hasher.finalize();
+        if read_digest != *digest {
+            bail!(
+                "Read back {:?} {}, but computed {}",
+                digest.algorithm,
+                read_digest.value,
+                digest.value
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Whether the complete file is at `path` already, with the size and digest expected if known.
+pub async fn is_present(
+    path: &Path,
+    size: Option<u64>,
+    digest: Option<&FileDigest>,
+) -> std::io::Result<bool> {
+    let metadata = match tokio::fs::symlink_metadata(path).await {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    if !metadata.is_file() || size.map_or(false, |size| size != metadata.len()) {
+        return Ok(false);
+    }
+    let Some(digest) = digest else {
+        return Ok(true);
+    };
+    let mut file = File::open(path).await?;
+    let mut hasher = Hasher::new(digest.algorithm);
+    let mut buf = [0; 16384];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().value.eq_ignore_ascii_case(&digest.value))
+}
+
+/// A successful transfer, with the cost of the integrity checks done.
+#[derive(Clone, Debug, Default)]
+pub struct CompletedTransfer {
+    /// Where the file ended up, another name if the offered one was taken
+    pub path: PathBuf,
+    pub digest: Option<FileDigest>,
+    pub fsync: Option<Duration>,
+    pub verify: Option<Duration>,
+    /// Nothing was transferred, the file was present already
+    pub skipped: bool,
+}
+
+/// What went wrong with a failed transfer, to tell patterns apart in the stats.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureKind {
+    /// No connection within the time limit, for passive transfers often a port forwarding problem
+    ConnectTimeout,
+    ConnectFailed,
+    /// Someone else than the bot connected to our passive port
+    IpMismatch,
+    ConnectionLost,
+    /// The transfer did not finish within the time limit
+    Timeout,
+    /// Aborted for making no or too little progress
+    Stalled,
+    /// The file did not match its size, digest or .sfv entry
+    Integrity,
+    Disk,
+    Other,
+}
+
+impl FailureKind {
+    pub fn of(err: &anyhow::Error) -> Self {
+        err.downcast_ref::<TransferError>()
+            .map_or(FailureKind::Other, |err| err.kind)
+    }
+
+    /// Whether asking the bot again may help. A full disk stays full, a corrupt partial file is
+    /// not to be resumed, and a stranger connecting to our port is no accident.
+    pub fn is_transient(self) -> bool {
+        !matches!(
+            self,
+            FailureKind::Disk | FailureKind::Integrity | FailureKind::IpMismatch
+        )
+    }
+}
+
+/// An error of a transfer tagged with its [`FailureKind`], displayed as the error itself.
+#[derive(Debug)]
+pub struct TransferError {
+    pub kind: FailureKind,
+    error: anyhow::Error,
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl std::error::Error for TransferError {}
+
+impl TransferError {
+    pub fn new(kind: FailureKind, error: anyhow::Error) -> Self {
+        Self { kind, error }
+    }
+}
+
+trait Categorize<T> {
+    fn kind(self, kind: FailureKind) -> anyhow::Result<T>;
+}
+
+impl<T, E: Into<anyhow::Error>> Categorize<T> for Result<T, E> {
+    fn kind(self, kind: FailureKind) -> anyhow::Result<T> {
+        self.map_err(|error| TransferError::new(kind, error.into()).into())
+    }
+}
+
+/// Another instance is already transferring to the same target path.
+#[derive(Debug)]
+pub struct LockConflict {
+    pub holder: String,
+}
+
+impl fmt::Display for LockConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "File is locked by instance {}", self.holder)
+    }
+}
+
+impl std::error::Error for LockConflict {}
+
+/// Cooperative lock on a target path, released on drop.
+struct TargetLock {
+    path: PathBuf,
+}
+
+impl TargetLock {
+    fn acquire(target: &Path, instance_id: &str, stale_after: Duration) -> anyhow::Result<Self> {
+        let mut lock_name = target.as_os_str().to_owned();
+        lock_name.push(".lock");
+        let path = PathBuf::from(lock_name);
+        loop {
+            match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(mut file) => {
+                    file.write_all(instance_id.as_bytes())?;
+                    return Ok(Self { path });
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                    let holder = std::fs::read_to_string(&path).unwrap_or_default();
+                    if !Self::is_stale(&path, target, stale_after) {
+                        bail!(LockConflict { holder });
+                    }
+                    log::warn!(
+                        "Breaking stale lock of instance {} on {}",
+                        holder,
+                        target.display()
+                    );
+                    std::fs::remove_file(&path)?;
+                }
+                Err(err) => bail!(err),
+            }
+        }
+    }
+
+    fn is_stale(lock: &Path, target: &Path, stale_after: Duration) -> bool {
+        let age = |path: &Path| {
+            std::fs::metadata(path)
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|t| t.elapsed().ok())
+        };
+        // The target not being modified means its size did not change either
+        matches!(age(lock), Some(age) if age > stale_after)
+            && age(target).map_or(true, |age| age > stale_after)
+    }
+}
+
+impl Drop for TargetLock {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            log::warn!("Could not remove lock {}: {}", self.path.display(), err);
+        }
+    }
 }
 
 #[derive(Default)]
 pub struct DownloadProgress {
-    pub transferred_bytes: usize,
+    pub transferred_bytes: u64,
+    /// When `transferred_bytes` was last updated
+    pub updated_at: Option<Instant>,
+    /// Address we told the bot to connect to, once a passive reply was sent
+    pub advertised: Option<SocketAddr>,
+    /// Waiting for one of the other transfers to end
+    pub waiting: bool,
+}
+
+/// Span over which the throughput of a transfer is measured
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// Progress of a transfer over the last seconds. Unlike the average since the start, the
+/// throughput it gives drops right after a stall and recovers soon after.
+#[derive(Default)]
+pub struct RateWindow {
+    samples: VecDeque<(Instant, u64)>,
+    /// When the transferred bytes last grew
+    progressed_at: Option<Instant>,
+}
+
+impl RateWindow {
+    pub fn record(&mut self, at: Instant, transferred_bytes: u64) {
+        if self
+            .samples
+            .back()
+            .map_or(true, |(_, last)| transferred_bytes > *last)
+        {
+            self.progressed_at = Some(at);
+        }
+        self.samples.push_back((at, transferred_bytes));
+        // The latest sample before the window is kept as the start
+        while self.samples.len() > 2 && at.duration_since(self.samples[1].0) >= RATE_WINDOW {
+            self.samples.pop_front();
+        }
+    }
+
+    pub fn bytes_per_sec(&self) -> u64 {
+        let (Some((first_at, first)), Some((last_at, last))) =
+            (self.samples.front(), self.samples.back())
+        else {
+            return 0;
+        };
+        let elapsed = last_at.duration_since(*first_at).as_secs_f64();
+        if elapsed == 0.0 {
+            return 0;
+        }
+        ((last - first) as f64 / elapsed) as u64
+    }
+
+    /// Seconds until `file_size` bytes are transferred at the current throughput.
+    pub fn eta_secs(&self, file_size: Option<u64>) -> Option<u64> {
+        let (_, transferred_bytes) = self.samples.back()?;
+        let bytes_per_sec = self.bytes_per_sec();
+        if bytes_per_sec == 0 {
+            return None;
+        }
+        let left = file_size?.saturating_sub(*transferred_bytes);
+        Some(left.div_ceil(bytes_per_sec))
+    }
+
+    /// Why a transfer with this progress is to be cancelled at `now`, if it stalled or is too
+    /// slow. Nothing is judged before the transfer has started.
+    pub fn cancel_reason(&self, now: Instant, options: &DownloadOptions) -> Option<CancelReason> {
+        let progressed_at = self.progressed_at?;
+        if options
+            .stall_timeout
+            .map_or(false, |limit| now.duration_since(progressed_at) >= limit)
+        {
+            return Some(CancelReason::Stall);
+        }
+        let min_speed = options.min_speed?;
+        let (first_at, _) = self.samples.front()?;
+        // Only once the window is full, a transfer is slow to get going
+        (now.duration_since(*first_at) >= RATE_WINDOW && self.bytes_per_sec() < min_speed)
+            .then_some(CancelReason::MinSpeed)
+    }
+}
+
+/// A bot agreeing to continue a transfer at `position`, answering our DCC RESUME.
+#[derive(Debug, PartialEq, Eq)]
+pub struct DccAccept {
+    pub file_name: String,
+    pub port: u16,
+    pub position: u64,
+    pub id: Option<usize>,
+}
+
+impl DccAccept {
+    pub fn parse(message: &str) -> Option<Self> {
+        let capture = REX_DCC_ACCEPT.captures(message)?;
+        Some(Self {
+            file_name: capture["filename"].trim_matches('"').to_string(),
+            port: capture["port"].parse().ok()?,
+            position: capture["position"].parse().ok()?,
+            id: capture.name("id").and_then(|id| id.as_str().parse().ok()),
+        })
+    }
+}
+
+/// Transfers waiting for their DCC ACCEPT, by port and token of the offer. Clones share them.
+#[derive(Clone, Default)]
+pub struct PendingResumes(Arc<Mutex<HashMap<(u16, Option<usize>), oneshot::Sender<u64>>>>);
+
+impl PendingResumes {
+    fn expect(&self, port: u16, id: Option<usize>) -> oneshot::Receiver<u64> {
+        let (sender, receiver) = oneshot::channel();
+        self.0.lock().unwrap().insert((port, id), sender);
+        receiver
+    }
+
+    fn forget(&self, port: u16, id: Option<usize>) {
+        self.0.lock().unwrap().remove(&(port, id));
+    }
+
+    /// Hands the position to the transfer waiting for it. Returns whether there was one; file
+    /// names are not compared, as bots tend to rewrite them.
+    pub fn accept(&self, accept: &DccAccept) -> bool {
+        let waiting = self.0.lock().unwrap().remove(&(accept.port, accept.id));
+        waiting.map_or(false, |sender| sender.send(accept.position).is_ok())
+    }
 }
 
 pub struct DccSend {
+    /// Name the file is stored under, sanitized.
     pub file_name: String,
-    pub address: SocketAddrV4,
-    pub file_size: Option<usize>,
+    /// Name as the bot sent it, which replies have to repeat.
+    pub offered_name: String,
+    pub address: SocketAddr,
+    pub file_size: Option<u64>,
     pub id: Option<usize>,
     progress_sender: Sender<DownloadProgress>,
 }
 
+/// Assigns the numbers following the port to file size and token, in the standard order unless
+/// the size the pack was announced with is the second number and not the first. Some bots put
+/// the token first in passive offers, which can't be told from the numbers alone.
+fn size_and_token(fields: &[u64], announced_size: Option<u64>) -> (Option<u64>, Option<u64>) {
+    match *fields {
+        [] => (None, None),
+        [size] => (Some(size), None),
+        [first, second, ..] => {
+            if matches!(announced_size, Some(size) if first != size && second == size) {
+                (Some(second), Some(first))
+            } else {
+                (Some(first), Some(second))
+            }
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PassiveDiagnostics {
+    #[schema(value_type = String)]
+    pub ip: Ipv4Addr,
+    pub port: u16,
+    pub listening: bool,
+    pub reachable: bool,
+    pub message: String,
+}
+
+/// Binds the passive DCC listener and tries to connect to it via the public ip, the same way a
+/// bot would when we ask for a passive transfer.
+pub async fn diagnose_passive(myip: Ipv4Addr, port: u16) -> PassiveDiagnostics {
+    let listener = match TcpListener::bind(SocketAddrV4::new(Ipv4Addr::from(0), port)).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            return PassiveDiagnostics {
+                ip: myip,
+                port,
+                listening: false,
+                reachable: false,
+                message: format!("Could not listen on port {}: {}", port, err),
+            }
+        }
+    };
+    let port = listener.local_addr().map(|a| a.port()).unwrap_or(port);
+    let accept = timeout(Duration::from_secs(5), listener.accept());
+    let connect = timeout(
+        Duration::from_secs(5),
+        TcpStream::connect(SocketAddrV4::new(myip, port)),
+    );
+    let (accepted, _) = tokio::join!(accept, connect);
+    let reachable = matches!(accepted, Ok(Ok(_)));
+    let message = if reachable {
+        format!("Port {} is reachable on {}", port, myip)
+    } else {
+        format!(
+            "Port {} is not reachable on {}. Make sure it is forwarded to this machine. \
+            Some routers don't allow connecting to their own public address, \
+            verify from outside with e.g. `nc -vz {} {}`.",
+            port, myip, myip, port
+        )
+    };
+    PassiveDiagnostics {
+        ip: myip,
+        port,
+        listening: true,
+        reachable,
+        message,
+    }
+}
+
 impl DccSend {
     pub fn from_str(message: &str) -> Option<(Self, Receiver<DownloadProgress>)> {
+        Self::parse(message, None)
+    }
+
+    /// Like `from_str`, with the size the pack was announced with to tell size and token apart.
+    pub fn parse(
+        message: &str,
+        announced_size: Option<u64>,
+    ) -> Option<(Self, Receiver<DownloadProgress>)> {
         if let Some(capture) = REX_DCC_SEND.captures(message) {
-            if let (Some(file_name), Some(address), Some(port), file_size, id) = (
+            if let (Some(offered_name), Some(address), Some(port)) = (
                 capture.name("filename"),
                 capture.name("address"),
                 capture.name("port"),
-                capture.name("filesize"),
-                capture.name("id"),
             ) {
-                let Ok(address) = address.as_str()
-                    .parse::<u32>()
-                    .map(Ipv4Addr::from) else { return None; };
-                let Ok(port) = port.as_str().parse::<u16>() else { return None };
-                let file_size = file_size
-                    .map(|fs| fs.as_str().parse::<usize>())
-                    .transpose()
-                    .ok()
-                    .flatten();
+                let address = match address_and_port(address.as_str(), port.as_str()) {
+                    Ok(address) => address,
+                    Err(err) => {
+                        log::warn!("Refusing DCC SEND of {}: {}", offered_name.as_str(), err);
+                        return None;
+                    }
+                };
+                let fields: Vec<_> = [capture.name("filesize"), capture.name("id")]
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|field| field.as_str().parse::<u64>().ok())
+                    .collect();
+                let (file_size, id) = size_and_token(&fields, announced_size);
+                let id = id.and_then(|id| usize::try_from(id).ok());
+                // Some bots send 0 when they do not know the size
+                let file_size = file_size.filter(|&file_size| file_size > 0);
+                if fields.len() > 1 && file_size != fields.first().copied() {
+                    log::info!(
+                        "Interpreting DCC SEND of {} as token {:?} before size {:?}",
+                        offered_name.as_str(),
+                        id,
+                        file_size
+                    );
+                }
+                let file_name = match sanitize_file_name(offered_name.as_str()) {
+                    Ok(file_name) => file_name,
+                    Err(err) => {
+                        log::warn!("Refusing DCC SEND: {}", err);
+                        return None;
+                    }
+                };
                 let (progress_sender, receiver) = watch::channel(DownloadProgress::default());
                 Some((
                     Self {
-                        file_name: file_name.as_str().to_string(),
-                        address: SocketAddrV4::new(address, port),
+                        file_name,
+                        offered_name: offered_name.as_str().to_string(),
+                        address,
                         file_size,
-                        id: id.and_then(|id| id.as_str().parse::<usize>().ok()),
+                        id,
                         progress_sender,
                     },
                     receiver,
@@ -66,28 +978,239 @@ impl DccSend {
         }
     }
 
+    /// Time the transfer may take at most. With a known file size, this grows with the size based
+    /// on the minimum acceptable throughput, so huge files are not aborted prematurely.
+    pub fn transfer_timeout(&self, options: &DownloadOptions) -> Option<Duration> {
+        let scaled = match (self.file_size, options.min_throughput) {
+            (Some(file_size), Some(throughput)) if throughput > 0 => {
+                Some(Duration::from_secs(file_size / throughput))
+            }
+            _ => None,
+        };
+        match (options.transfer_timeout, scaled) {
+            (Some(fixed), Some(scaled)) => Some(fixed.max(scaled)),
+            (fixed, scaled) => fixed.or(scaled),
+        }
+    }
+
     pub fn is_passive(&self) -> bool {
         self.address.port() == 0
     }
 
+    /// The address to listen on for a passive transfer, and ours to tell the bot. The bot
+    /// connects from the address family it offered from, which is checked, so IPv6 is only
+    /// advertised to bots offering from IPv6.
+    fn passive_addresses(
+        &self,
+        myip: Option<Ipv4Addr>,
+        options: &DownloadOptions,
+    ) -> anyhow::Result<(IpAddr, IpAddr)> {
+        match (options.myip_v6, options.ipv4_only) {
+            // Dual-stack, so bots may connect over either protocol
+            (Some(myip_v6), false) if self.address.is_ipv6() => {
+                Ok((IpAddr::V6(Ipv6Addr::UNSPECIFIED), IpAddr::V6(myip_v6)))
+            }
+            _ => {
+                let myip = myip
+                    .ok_or_else(|| anyhow!("Our IP address is unknown, cannot offer to receive"))?;
+                Ok((
+                    IpAddr::V4(options.source_address.unwrap_or(Ipv4Addr::UNSPECIFIED)),
+                    IpAddr::V4(myip),
+                ))
+            }
+        }
+    }
+
+    /// Asks the bot to continue where an earlier transfer to `path` stopped. Returns the position
+    /// the bot agreed to, 0 to transfer the whole file, the file size if nothing is left. For
+    /// passive offers this happens before we tell the bot where to connect, the token telling the
+    /// transfers apart.
+    async fn negotiate_resume(
+        &self,
+        path: &Path,
+        sender: &client::Sender,
+        nick: &str,
+        options: &DownloadOptions,
+    ) -> anyhow::Result<u64> {
+        let Some(file_size) = self.file_size else {
+            return Ok(0);
+        };
+        let existing = match tokio::fs::metadata(path).await {
+            Ok(metadata) => metadata.len(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err).kind(FailureKind::Disk),
+        };
+        if existing > file_size {
+            return Err(anyhow!(
+                "{} exists with {} bytes, more than the {} offered",
+                self.file_name,
+                existing,
+                file_size
+            ))
+            .kind(FailureKind::Integrity);
+        }
+        if existing == 0 {
+            return Ok(0);
+        }
+        if existing == file_size {
+            log::info!(
+                "{} is complete already, not transferring it again",
+                self.file_name
+            );
+            return Ok(file_size);
+        }
+        let port = self.address.port();
+        let accepted = options.resumes.expect(port, self.id);
+        log::info!(
+            "Asking {} to resume {} at {}",
+            nick,
+            self.file_name,
+            existing
+        );
+        let token = self.id.map(|id| format!(" {}", id)).unwrap_or_default();
+        sender.send_privmsg(
+            nick,
+            format!(
+                "\u{1}DCC RESUME {} {} {}{}\u{1}",
+                self.offered_name, port, existing, token
+            ),
+        )?;
+        match timeout(RESUME_TIMEOUT, accepted).await {
+            Ok(Ok(position)) if position <= existing => Ok(position),
+            Ok(Ok(position)) => {
+                log::warn!(
+                    "{} wants to resume {} at {}, but we only have {} bytes",
+                    nick,
+                    self.file_name,
+                    position,
+                    existing
+                );
+                Ok(0)
+            }
+            _ => {
+                options.resumes.forget(port, self.id);
+                log::info!(
+                    "{} did not accept resuming {}, transferring all of it",
+                    nick,
+                    self.file_name
+                );
+                Ok(0)
+            }
+        }
+    }
+
     pub async fn download(
         &self,
         sender: client::Sender,
         nick: String,
-        myip: Ipv4Addr,
-        port: u16,
-        download_folder: &Path,
-    ) -> anyhow::Result<()> {
+        options: &DownloadOptions,
+    ) -> anyhow::Result<CompletedTransfer> {
         log::info!("Starting to download {}", self.file_name);
-        let mut stream = if self.is_passive() {
+        let DownloadOptions {
+            myip,
+            port,
+            ref download_folder,
+            ..
+        } = *options;
+        std::fs::create_dir_all(download_folder).kind(FailureKind::Disk)?;
+        let path = download_folder.join(&self.file_name);
+        ensure_inside(download_folder, &path).kind(FailureKind::Disk)?;
+        let _lock = TargetLock::acquire(&path, &options.instance_id, options.stale_lock_after)?;
+        if options.existing_files == ExistingFiles::Skip && path.is_file() {
+            log::info!("{} exists already, not downloading it", self.file_name);
+            return Ok(CompletedTransfer {
+                path,
+                skipped: true,
+                ..Default::default()
+            });
+        }
+        // Written under another name until complete, so nobody takes a partial file for the real
+        // thing. A failed transfer leaves it behind to be resumed.
+        let part = part_path(&path, &options.part_suffix);
+        let _transfer = match &options.transfer_limit {
+            Some(limit) => {
+                let waiting = limit.available_permits() == 0;
+                if waiting {
+                    log::info!(
+                        "Waiting for other transfers before downloading {}",
+                        self.file_name
+                    );
+                    self.progress_sender
+                        .send_modify(|progress| progress.waiting = true);
+                }
+                let permit = limit.acquire().await?;
+                if waiting {
+                    self.progress_sender
+                        .send_modify(|progress| progress.waiting = false);
+                }
+                Some(permit)
+            }
+            None => None,
+        };
+        // Rather than running out of space halfway through, or asking the bot to resume a file
+        // there is no room for. Not resumed, the partial file is overwritten, so either way only
+        // the rest takes space.
+        match self.file_size {
+            Some(file_size) => {
+                let existing = match tokio::fs::metadata(&part).await {
+                    Ok(metadata) => metadata.len(),
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => 0,
+                    Err(err) => return Err(err).kind(FailureKind::Disk),
+                };
+                let needed = file_size
+                    .saturating_sub(existing)
+                    .saturating_add(options.disk_reserve);
+                check_free_space(download_folder, needed).kind(FailureKind::Disk)?;
+            }
+            None => log::debug!(
+                "Size of {} unknown, not checking free space",
+                self.file_name
+            ),
+        }
+        let offset = self
+            .negotiate_resume(&part, &sender, &nick, options)
+            .await?;
+        // A partial file as large as offered only needs to be finished
+        let complete = offset > 0 && Some(offset) == self.file_size;
+        if complete {
+            self.progress_sender
+                .send_modify(|progress| progress.transferred_bytes = offset);
+        }
+        let _socket = match &options.socket_limit {
+            Some(limit) if !complete => {
+                if limit.available_permits() == 0 {
+                    log::info!("Waiting for a free socket to download {}", self.file_name);
+                }
+                Some(limit.acquire().await?)
+            }
+            _ => None,
+        };
+        if !complete && options.ipv4_only && self.address.is_ipv6() {
+            return Err(anyhow!(
+                "Refusing offer from IPv6 address {}",
+                self.address.ip()
+            ))
+            .kind(FailureKind::ConnectFailed);
+        }
+        let stream = if complete {
+            None
+        } else if self.is_passive() {
             log::info!("Initiating passive download");
-            let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::from(0), port)).await?;
-            let std::net::SocketAddr::V4(addr) = listener.local_addr()? else { bail!("Failed to retrieve port") };
-            let port = addr.port();
+            let (bind_address, myip) = self
+                .passive_addresses(myip, options)
+                .kind(FailureKind::ConnectFailed)?;
+            let listener = TcpListener::bind(SocketAddr::new(bind_address, port))
+                .await
+                .with_context(|| format!("Could not listen on {}:{}", bind_address, port))
+                .kind(FailureKind::ConnectFailed)?;
+            let port = listener.local_addr()?.port();
             let msg = format!(
                 "\u{1}DCC SEND {} {} {} {} {}\u{1}",
-                self.file_name,
-                u32::from(myip),
+                self.offered_name,
+                match myip {
+                    IpAddr::V4(v4) => u32::from(v4).to_string(),
+                    IpAddr::V6(v6) => v6.to_string(),
+                },
                 port,
                 self.file_size
                     .map(|file_size| file_size.to_string())
@@ -96,50 +1219,242 @@ impl DccSend {
                     .map(|id| id.to_string())
                     .unwrap_or_else(|| "".to_string())
             );
+            let advertised = SocketAddr::new(myip, port);
+            if !is_public(myip) {
+                log::warn!(
+                    "Advertising non-public address {} to {}, the bot will likely fail to connect",
+                    advertised,
+                    nick
+                );
+            }
+            log::info!("Asking {} to connect to {}", nick, advertised);
             log::debug!("Sending to {}: {:?}", nick, msg);
             sender.send_privmsg(nick, msg)?;
-            let (stream, other) = timeout(Duration::from_secs(30), listener.accept()).await??;
-            let SocketAddr::V4(addr) = other else { unreachable!("Opened IPv4 port, but got some connection that is not IPv4?!") };
-            if addr.ip() != self.address.ip() {
-                bail!("IP mismatch on connected client");
+            self.progress_sender
+                .send_modify(|progress| progress.advertised = Some(advertised));
+            let (stream, other) = timeout(Duration::from_secs(30), listener.accept())
+                .await
+                .kind(FailureKind::ConnectTimeout)?
+                .kind(FailureKind::ConnectFailed)?;
+            if canonical(other.ip()) != canonical(self.address.ip()) {
+                return Err(anyhow!("IP mismatch on connected client"))
+                    .kind(FailureKind::IpMismatch);
             }
-            stream
+            Some(stream)
         } else {
             log::info!("Connecting to {:?} to download", self.address);
-            timeout(Duration::from_secs(30), TcpStream::connect(self.address)).await??
+            let stream = timeout(
+                Duration::from_secs(30),
+                connect(self.address, options.source_address),
+            )
+            .await
+            .kind(FailureKind::ConnectTimeout)?
+            .kind(FailureKind::ConnectFailed)?;
+            Some(stream)
         };
         log::debug!("Connected");
-        std::fs::create_dir_all(download_folder)?;
-        let path = download_folder.join(&self.file_name);
-        log::debug!("Trying to create file: {}", path.display());
-        let target_file = File::create(path).await?;
+        log::debug!("Trying to create file: {}", part.display());
+        let target_file = if offset > 0 {
+            let file = OpenOptions::new()
+                .append(true)
+                .open(&part)
+                .await
+                .kind(FailureKind::Disk)?;
+            file.set_len(offset).await.kind(FailureKind::Disk)?;
+            file
+        } else {
+            File::create(&part).await.kind(FailureKind::Disk)?
+        };
         let mut writer = BufWriter::new(target_file);
-        stream
-            .write_all(&self.file_size.unwrap().to_be_bytes())
-            .await?;
-        let mut transferred_bytes = 0;
-        loop {
-            stream.readable().await?;
-
+        let mut hasher = options.hash.map(Hasher::new);
+        let mut sfv_hasher = (options.check_sfv && options.hash != Some(HashAlgorithm::Crc32))
+            .then(crc32fast::Hasher::new);
+        if offset > 0 && (hasher.is_some() || sfv_hasher.is_some()) {
+            // The digests cover the whole file, including what we already had
+            let mut existing = File::open(&part)
+                .await
+                .kind(FailureKind::Disk)?
+                .take(offset);
             let mut buf = [0; 16384];
-            match stream.try_read(&mut buf) {
-                Ok(0) => break,
-                Ok(n) => {
-                    transferred_bytes += n;
-                    writer.write_all(&buf[0..n]).await?;
-                    self.progress_sender
-                        .send(DownloadProgress { transferred_bytes })
-                        .ok();
+            loop {
+                let n = existing.read(&mut buf).await.kind(FailureKind::Disk)?;
+                if n == 0 {
+                    break;
+                }
+                if let Some(hasher) = &mut hasher {
+                    hasher.update(&buf[..n]);
                 }
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    continue;
+                if let Some(hasher) = &mut sfv_hasher {
+                    hasher.update(&buf[..n]);
+                }
+            }
+        }
+        let mut transferred_bytes = offset;
+        // What is left of the latest acknowledgement, sent as the socket takes it
+        let mut unsent_ack = Vec::new();
+        let throttle = options.max_bytes_per_sec.map(Throttle::new);
+        let transfer = async {
+            let Some(stream) = &stream else {
+                return Ok(());
+            };
+            loop {
+                stream.readable().await.kind(FailureKind::ConnectionLost)?;
+
+                let mut buf = [0; 16384];
+                match stream.try_read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        transferred_bytes += n as u64;
+                        writer.write_all(&buf[0..n]).await.kind(FailureKind::Disk)?;
+                        if let Some(hasher) = &mut hasher {
+                            hasher.update(&buf[0..n]);
+                        }
+                        if let Some(hasher) = &mut sfv_hasher {
+                            hasher.update(&buf[0..n]);
+                        }
+                        self.progress_sender.send_modify(|progress| {
+                            progress.transferred_bytes = transferred_bytes;
+                            progress.updated_at = Some(Instant::now());
+                        });
+                        // Holding back reading and acknowledging slows the sender down as well
+                        for throttle in throttle.iter().chain(options.bandwidth.as_deref()) {
+                            throttle.consume(n).await;
+                        }
+                        // Never waiting for the sender to take acks, one not reading them would
+                        // stall the transfer once the socket buffer is full. Later acks supersede
+                        // earlier ones, but one started is finished not to garble the stream.
+                        if unsent_ack.is_empty() {
+                            unsent_ack = ack(transferred_bytes, options.ack_width);
+                        }
+                        match stream.try_write(&unsent_ack) {
+                            Ok(written) => {
+                                unsent_ack.drain(..written);
+                            }
+                            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {}
+                            // Senders may close the connection right after the last byte, a lost
+                            // ack is no reason to fail
+                            Err(err) => {
+                                log::debug!(
+                                    "Could not acknowledge {} bytes: {}",
+                                    transferred_bytes,
+                                    err
+                                );
+                                unsent_ack.clear();
+                            }
+                        }
+                    }
+                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                        continue;
+                    }
+                    Err(e) => return Err(e).kind(FailureKind::ConnectionLost),
                 }
-                Err(e) => bail!(e),
             }
+            Ok::<_, anyhow::Error>(())
+        };
+        match self.transfer_timeout(options) {
+            Some(limit) => timeout(limit, transfer)
+                .await
+                .map_err(|_| anyhow!("Transfer did not finish within {}s", limit.as_secs()))
+                .kind(FailureKind::Timeout)??,
+            None => transfer.await?,
+        }
+        writer.flush().await.kind(FailureKind::Disk)?;
+        match self.file_size {
+            Some(file_size) if transferred_bytes < file_size => {
+                return Err(anyhow!(
+                    "Transfer ended after {} of {} bytes",
+                    transferred_bytes,
+                    file_size
+                ))
+                .kind(FailureKind::ConnectionLost)
+            }
+            Some(file_size) if transferred_bytes > file_size => {
+                return Err(anyhow!(
+                    "Received {} bytes, but {} were offered",
+                    transferred_bytes,
+                    file_size
+                ))
+                .kind(FailureKind::Integrity)
+            }
+            Some(_) => {}
+            None => log::info!(
+                "{} was offered without size, could not verify all {} bytes arrived",
+                self.file_name,
+                transferred_bytes
+            ),
         }
-        writer.flush().await?;
+        let mut completed = CompletedTransfer::default();
+        let started = Instant::now();
+        if options.fsync_on_complete {
+            writer.get_ref().sync_all().await.kind(FailureKind::Disk)?;
+        }
+        let path = match options.existing_files {
+            ExistingFiles::Overwrite => path,
+            ExistingFiles::Rename | ExistingFiles::Skip => free_path(&path),
+        };
+        if path.file_name() != Some(self.file_name.as_ref()) {
+            log::info!(
+                "{} exists already, keeping the download as {}",
+                self.file_name,
+                path.display()
+            );
+        }
+        tokio::fs::rename(&part, &path)
+            .await
+            .kind(FailureKind::Disk)?;
+        if options.fsync_on_complete {
+            sync_directory(download_folder).kind(FailureKind::Disk)?;
+            completed.fsync = Some(started.elapsed());
+            log::info!("Synced {} in {:?}", self.file_name, completed.fsync);
+        }
+        apply_permissions(&path, options)?;
         log::info!("File successfully transferred: {}", self.file_name);
-        Ok(())
+        completed.path = path.clone();
+        completed.digest = hasher.map(Hasher::finalize);
+        if let Some(digest) = &completed.digest {
+            log::info!(
+                "{:?} of {}: {}",
+                digest.algorithm,
+                self.file_name,
+                digest.value
+            );
+        }
+        if options.check_sfv {
+            let crc = match (&completed.digest, sfv_hasher) {
+                (_, Some(hasher)) => hasher.finalize(),
+                (Some(digest), None) => u32::from_str_radix(&digest.value, 16)?,
+                (None, None) => unreachable!("CRC32 computed when checking .sfv"),
+            };
+            match sfv_checksum(download_folder, &self.file_name).await? {
+                Some(expected) if expected != crc => {
+                    return Err(anyhow!(
+                        "CRC32 of {} is {:08x}, .sfv lists {:08x}",
+                        self.file_name,
+                        crc,
+                        expected
+                    ))
+                    .kind(FailureKind::Integrity)
+                }
+                Some(_) => log::info!("{} matches its .sfv entry", self.file_name),
+                None => log::debug!("No .sfv lists {}", self.file_name),
+            }
+        }
+        if options.verify_readback {
+            let started = Instant::now();
+            verify_readback(&path, transferred_bytes, completed.digest.as_ref())
+                .await
+                .kind(FailureKind::Integrity)?;
+            completed.verify = Some(started.elapsed());
+            log::info!("Verified {} in {:?}", self.file_name, completed.verify);
+        }
+        if let Some(mirror) = &options.mirror {
+            // The download itself is fine, the backup is best effort
+            match mirror_file(&path, mirror).await {
+                Ok(target) => log::info!("Mirrored {} to {}", self.file_name, target.display()),
+                Err(err) => log::warn!("Could not mirror {}: {}", self.file_name, err),
+            }
+        }
+        Ok(completed)
     }
 }
 
@@ -165,12 +1480,802 @@ mod tests {
         );
 
         let (dcc_send, _) = DccSend::from_str(&input).unwrap();
-        assert_eq!(dcc_send.address.ip().octets(), [73, 25, 176, 14]);
+        assert_eq!(
+            dcc_send.address.ip(),
+            IpAddr::V4(Ipv4Addr::new(73, 25, 176, 14))
+        );
         assert_eq!(dcc_send.address.port(), 0);
         assert_eq!(dcc_send.file_size, Some(3498348389));
         assert_eq!(dcc_send.id, Some(22));
     }
 
+    #[test]
+    fn passive_offer_field_orders() {
+        // iroffer-dinoex
+        let (dcc_send, _) = DccSend::from_str(
+            "\u{1}DCC SEND \"[SubsPlease] Show - 01 (1080p) [A1B2C3D4].mkv\" 1311387915 0 1439553262 31\u{1}",
+        )
+        .unwrap();
+        assert!(dcc_send.is_passive());
+        assert_eq!(dcc_send.file_size, Some(1439553262));
+        assert_eq!(dcc_send.id, Some(31));
+
+        // A small file, its token the larger number
+        let offer = "\u{1}DCC SEND Show.S01E01.720p.nfo 1311387915 0 2781 140\u{1}";
+        let (dcc_send, _) = DccSend::from_str(offer).unwrap();
+        assert_eq!(dcc_send.file_size, Some(2781));
+        assert_eq!(dcc_send.id, Some(140));
+        let (dcc_send, _) = DccSend::parse(offer, Some(2781)).unwrap();
+        assert_eq!(dcc_send.file_size, Some(2781));
+
+        // Token first, only the announced size tells
+        let offer = "\u{1}DCC SEND Show.S01E02.720p.mkv 1311387915 0 4 734003200\u{1}";
+        let (dcc_send, _) = DccSend::parse(offer, Some(734003200)).unwrap();
+        assert_eq!(dcc_send.file_size, Some(734003200));
+        assert_eq!(dcc_send.id, Some(4));
+        let (dcc_send, _) = DccSend::from_str(offer).unwrap();
+        assert_eq!(dcc_send.file_size, Some(4));
+    }
+
+    #[test]
+    fn transposed_address_and_port() {
+        let (dcc_send, _) =
+            DccSend::from_str("\u{1}DCC SEND file.mkv 5000 1226420238 1024\u{1}").unwrap();
+        assert_eq!(
+            dcc_send.address,
+            "73.25.176.14:5000".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(dcc_send.file_size, Some(1024));
+
+        // Passive, the port is zero
+        let (dcc_send, _) =
+            DccSend::from_str("\u{1}DCC SEND file.mkv 0 1226420238 1024 7\u{1}").unwrap();
+        assert!(dcc_send.is_passive());
+        assert_eq!(dcc_send.id, Some(7));
+
+        // Both fields small, no telling which is which
+        assert!(DccSend::from_str("\u{1}DCC SEND file.mkv 5000 6000 1024\u{1}").is_none());
+        assert!(address_and_port("70000", "80000").is_err());
+        assert_eq!(
+            address_and_port("2130706433", "5000").unwrap(),
+            "127.0.0.1:5000".parse::<SocketAddr>().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn diagnose_passive_loopback() {
+        let diagnostics = diagnose_passive(Ipv4Addr::LOCALHOST, 0).await;
+        assert!(diagnostics.listening);
+        assert!(diagnostics.reachable);
+        assert_ne!(diagnostics.port, 0);
+    }
+
+    #[test]
+    fn target_lock_conflict() {
+        let folder = std::env::temp_dir().join(format!("irc-dl-lock-{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        let target = folder.join("file.mkv");
+
+        let lock = TargetLock::acquire(&target, "a", Duration::from_secs(60)).unwrap();
+        let err = TargetLock::acquire(&target, "b", Duration::from_secs(60))
+            .err()
+            .unwrap();
+        assert_eq!(err.downcast_ref::<LockConflict>().unwrap().holder, "a");
+
+        // Without any activity, the lock can be taken over
+        TargetLock::acquire(&target, "b", Duration::ZERO).unwrap();
+        std::mem::forget(lock);
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[tokio::test]
+    async fn readback_verification() {
+        let path = std::env::temp_dir().join(format!("irc-dl-readback-{}", std::process::id()));
+        std::fs::write(&path, b"hello world").unwrap();
+        let mut hasher = Hasher::new(HashAlgorithm::Crc32);
+        hasher.update(b"hello world");
+        let digest = hasher.finalize();
+
+        verify_readback(&path, 11, Some(&digest)).await.unwrap();
+        assert!(verify_readback(&path, 12, None).await.is_err());
+        let other = FileDigest {
+            value: "00000000".to_string(),
+            ..digest
+        };
+        assert!(verify_readback(&path, 11, Some(&other)).await.is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rate_over_recent_progress() {
+        let start = Instant::now();
+        let mut window = RateWindow::default();
+        assert_eq!(window.bytes_per_sec(), 0);
+        window.record(start, 0);
+        window.record(start + Duration::from_secs(1), 1000);
+        assert_eq!(window.bytes_per_sec(), 1000);
+        assert_eq!(window.eta_secs(Some(3000)), Some(2));
+        assert_eq!(window.eta_secs(None), None);
+
+        // Resuming after a stall, only the last seconds count
+        window.record(start + Duration::from_secs(11), 2000);
+        assert_eq!(window.bytes_per_sec(), 100);
+        window.record(start + Duration::from_secs(12), 3000);
+        window.record(start + Duration::from_secs(17), 8000);
+        assert_eq!(window.bytes_per_sec(), 1000);
+        assert_eq!(window.eta_secs(Some(8500)), Some(1));
+
+        // Ticks without progress bring it down
+        window.record(start + Duration::from_secs(20), 8000);
+        assert_eq!(window.bytes_per_sec(), 625);
+        window.record(start + Duration::from_secs(23), 8000);
+        assert_eq!(window.bytes_per_sec(), 0);
+        assert_eq!(window.eta_secs(Some(8500)), None);
+    }
+
+    #[test]
+    fn stalled_transfers_cancelled() {
+        let start = Instant::now();
+        let mut options = DownloadOptions::new(Ipv4Addr::LOCALHOST, 0, std::env::temp_dir());
+        options.stall_timeout = Some(Duration::from_secs(30));
+        let mut window = RateWindow::default();
+        // Not started yet, waiting for a slot is no stall
+        assert_eq!(
+            window.cancel_reason(start + Duration::from_secs(60), &options),
+            None
+        );
+
+        window.record(start, 1000);
+        window.record(start + Duration::from_secs(10), 2000);
+        // Samples without progress don't count as activity
+        window.record(start + Duration::from_secs(20), 2000);
+        let stalled = start + Duration::from_secs(40);
+        assert_eq!(
+            window.cancel_reason(stalled, &options),
+            Some(CancelReason::Stall)
+        );
+        options.stall_timeout = None;
+        assert_eq!(window.cancel_reason(stalled, &options), None);
+    }
+
+    #[test]
+    fn slow_transfers_cancelled() {
+        let start = Instant::now();
+        let mut options = DownloadOptions::new(Ipv4Addr::LOCALHOST, 0, std::env::temp_dir());
+        options.min_speed = Some(500);
+        let mut window = RateWindow::default();
+        window.record(start, 0);
+        window.record(start + Duration::from_secs(1), 100);
+        // Slow to get going, not judged before the window is full
+        assert_eq!(
+            window.cancel_reason(start + Duration::from_secs(1), &options),
+            None
+        );
+
+        window.record(start + Duration::from_secs(6), 1100);
+        assert_eq!(
+            window.cancel_reason(start + Duration::from_secs(6), &options),
+            Some(CancelReason::MinSpeed)
+        );
+        window.record(start + Duration::from_secs(7), 5000);
+        assert_eq!(
+            window.cancel_reason(start + Duration::from_secs(7), &options),
+            None
+        );
+    }
+
+    #[test]
+    fn free_space_checked() {
+        let folder = std::env::temp_dir();
+        check_free_space(&folder, 0).unwrap();
+        let err = check_free_space(&folder, u64::MAX).unwrap_err();
+        assert!(err.to_string().starts_with("Insufficient disk space"));
+    }
+
+    #[tokio::test]
+    async fn present_files() {
+        let path = std::env::temp_dir().join(format!("irc-dl-present-{}", std::process::id()));
+        assert!(!is_present(&path, None, None).await.unwrap());
+        std::fs::write(&path, b"hello world").unwrap();
+        let digest = FileDigest {
+            algorithm: HashAlgorithm::Crc32,
+            value: "0D4A1185".to_string(),
+        };
+
+        assert!(is_present(&path, None, None).await.unwrap());
+        assert!(is_present(&path, Some(11), Some(&digest)).await.unwrap());
+        assert!(!is_present(&path, Some(12), None).await.unwrap());
+        let other = FileDigest {
+            value: "00000000".to_string(),
+            ..digest
+        };
+        assert!(!is_present(&path, None, Some(&other)).await.unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn mirror_by_copy_or_link() {
+        let folder = std::env::temp_dir().join(format!("irc-dl-mirror-{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        let path = folder.join("file.mkv");
+        std::fs::write(&path, b"content").unwrap();
+
+        for mode in [MirrorMode::Copy, MirrorMode::Hardlink] {
+            let mirror = Mirror {
+                folder: folder.join(format!("{:?}", mode)),
+                mode,
+            };
+            let target = mirror_file(&path, &mirror).await.unwrap();
+            assert_eq!(std::fs::read(&target).unwrap(), b"content");
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            assert_eq!(std::fs::metadata(&path).unwrap().nlink(), 2);
+        }
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn dcc_accept() {
+        let accept =
+            DccAccept::parse("\u{1}DCC ACCEPT \"Some file.mkv\" 5000 1048576\u{1}").unwrap();
+        assert_eq!(
+            accept,
+            DccAccept {
+                file_name: "Some file.mkv".to_string(),
+                port: 5000,
+                position: 1048576,
+                id: None,
+            }
+        );
+        assert_eq!(
+            DccAccept::parse("\u{1}DCC ACCEPT file.mkv 0 1024 17\u{1}")
+                .unwrap()
+                .id,
+            Some(17)
+        );
+        assert!(DccAccept::parse("\u{1}DCC SEND file.mkv 1 2 3\u{1}").is_none());
+
+        let resumes = PendingResumes::default();
+        let mut accepted = resumes.expect(5000, None);
+        assert!(!resumes.accept(&DccAccept {
+            port: 5001,
+            ..accept
+        }));
+        assert!(accepted.try_recv().is_err());
+        let accept = DccAccept::parse("\u{1}DCC ACCEPT file.mkv 5000 1048576\u{1}").unwrap();
+        assert!(resumes.clone().accept(&accept));
+        assert_eq!(accepted.try_recv().unwrap(), 1048576);
+        assert!(!resumes.accept(&accept));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throttle_holds_transfers_to_rate() {
+        let throttle = Throttle::new(1_000_000);
+        let started = Instant::now();
+        // A second worth passes right away
+        throttle.consume(1_000_000).await;
+        assert_eq!(started.elapsed(), Duration::ZERO);
+        throttle.consume(100_000).await;
+        throttle.consume(100_000).await;
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(199) && elapsed <= Duration::from_millis(202));
+    }
+
+    #[tokio::test]
+    async fn transfers_held_to_their_limit() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            socket.write_all(&[7; 1500]).await.unwrap();
+            socket.shutdown().await.unwrap();
+            socket.read_to_end(&mut Vec::new()).await.unwrap();
+        });
+        let (dcc_send, _) = DccSend::from_str(&format!(
+            "\u{1}DCC SEND limited.bin {} {} 1500\u{1}",
+            u32::from(Ipv4Addr::LOCALHOST),
+            port
+        ))
+        .unwrap();
+        let config: client::data::Config =
+            toml::from_str("server = \"mock\"\nnickname = \"me\"\nuse_mock_connection = true")
+                .unwrap();
+        let client = client::Client::from_config(config).await.unwrap();
+        let folder = std::env::temp_dir().join(format!("irc-dl-limited-{}", std::process::id()));
+        let options = DownloadOptions {
+            max_bytes_per_sec: Some(1000),
+            ..DownloadOptions::new(Ipv4Addr::LOCALHOST, 0, folder.clone())
+        };
+
+        // Over real sockets, so on the wall clock. Only the lower bound is checked, a slow machine
+        // can't break that.
+        let started = std::time::Instant::now();
+        dcc_send
+            .download(client.sender(), "Bot".to_string(), &options)
+            .await
+            .unwrap();
+        // A second worth passes right away, the other 500 bytes take half a second
+        assert!(started.elapsed() >= std::time::Duration::from_millis(490));
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn sizes_beyond_4gib() {
+        for size in [4_294_967_295u64, 4_294_967_296, 12_884_901_888] {
+            let offer = format!("\u{1}DCC SEND file.mkv 1226420238 5000 {}\u{1}", size);
+            let (dcc_send, _) = DccSend::from_str(&offer).unwrap();
+            assert_eq!(dcc_send.file_size, Some(size));
+        }
+        let (dcc_send, _) =
+            DccSend::from_str("\u{1}DCC SEND file.mkv 1226420238 0 5000000000 7\u{1}").unwrap();
+        assert_eq!(dcc_send.file_size, Some(5_000_000_000));
+        assert_eq!(dcc_send.id, Some(7));
+        let options = DownloadOptions {
+            min_throughput: Some(1_000_000),
+            ..DownloadOptions::new(Ipv4Addr::LOCALHOST, 0, PathBuf::new())
+        };
+        assert!(dcc_send.transfer_timeout(&options).unwrap() >= Duration::from_secs(5000));
+    }
+
+    #[test]
+    fn offers_without_size() {
+        let (dcc_send, _) =
+            DccSend::from_str("\u{1}DCC SEND file.mkv 1226420238 5000\u{1}").unwrap();
+        assert_eq!(dcc_send.file_size, None);
+        let (dcc_send, _) =
+            DccSend::from_str("\u{1}DCC SEND file.mkv 1226420238 5000 0\u{1}").unwrap();
+        assert_eq!(dcc_send.file_size, None);
+        assert_eq!(
+            dcc_send.transfer_timeout(&DownloadOptions::new(
+                Ipv4Addr::LOCALHOST,
+                0,
+                PathBuf::new()
+            )),
+            None
+        );
+    }
+
+    #[test]
+    fn ack_widths() {
+        assert_eq!(ack(100_000, AckWidth::Bits32), 100_000u32.to_be_bytes());
+        // Wraps around beyond 4GiB
+        assert_eq!(
+            ack(5_000_000_000, AckWidth::Bits32),
+            705_032_704u32.to_be_bytes()
+        );
+        assert_eq!(
+            ack(5_000_000_000, AckWidth::Bits64),
+            5_000_000_000u64.to_be_bytes()
+        );
+    }
+
+    #[tokio::test]
+    async fn acknowledges_received_bytes() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let sender = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            for _ in 0..10 {
+                socket.write_all(&[7; 10_000]).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            socket.shutdown().await.unwrap();
+            let mut acks = Vec::new();
+            socket.read_to_end(&mut acks).await.unwrap();
+            acks
+        });
+        // Without size, as some bots send it
+        let (dcc_send, _) = DccSend::from_str(&format!(
+            "\u{1}DCC SEND acked.bin {} {}\u{1}",
+            u32::from(Ipv4Addr::LOCALHOST),
+            port
+        ))
+        .unwrap();
+        let config: client::data::Config =
+            toml::from_str("server = \"mock\"\nnickname = \"me\"\nuse_mock_connection = true")
+                .unwrap();
+        let client = client::Client::from_config(config).await.unwrap();
+        let folder = std::env::temp_dir().join(format!("irc-dl-acks-{}", std::process::id()));
+        let options = DownloadOptions::new(Ipv4Addr::LOCALHOST, 0, folder.clone());
+
+        dcc_send
+            .download(client.sender(), "Bot".to_string(), &options)
+            .await
+            .unwrap();
+        let acks: Vec<_> = sender
+            .await
+            .unwrap()
+            .chunks(4)
+            .map(|ack| u32::from_be_bytes(ack.try_into().unwrap()))
+            .collect();
+        assert!(acks.len() > 1);
+        assert!(acks.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(acks.last(), Some(&100_000));
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[tokio::test]
+    async fn existing_files_overwritten() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            socket.write_all(b"new").await.unwrap();
+            socket.shutdown().await.unwrap();
+            socket.read_to_end(&mut Vec::new()).await.unwrap();
+        });
+        let (dcc_send, _) = DccSend::from_str(&format!(
+            "\u{1}DCC SEND taken.bin {} {} 3\u{1}",
+            u32::from(Ipv4Addr::LOCALHOST),
+            port
+        ))
+        .unwrap();
+        let config: client::data::Config =
+            toml::from_str("server = \"mock\"\nnickname = \"me\"\nuse_mock_connection = true")
+                .unwrap();
+        let client = client::Client::from_config(config).await.unwrap();
+        let folder = std::env::temp_dir().join(format!("irc-dl-overwrite-{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        std::fs::write(folder.join("taken.bin"), b"old file").unwrap();
+        let options = DownloadOptions {
+            existing_files: ExistingFiles::Overwrite,
+            ..DownloadOptions::new(Ipv4Addr::LOCALHOST, 0, folder.clone())
+        };
+
+        let transfer = dcc_send
+            .download(client.sender(), "Bot".to_string(), &options)
+            .await
+            .unwrap();
+        assert_eq!(transfer.path, folder.join("taken.bin"));
+        assert_eq!(std::fs::read(&transfer.path).unwrap(), b"new");
+        assert!(!folder.join("taken (1).bin").exists());
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[tokio::test]
+    async fn existing_files_skipped() {
+        // Nobody listens, skipping must not connect
+        let (dcc_send, _) = DccSend::from_str(&format!(
+            "\u{1}DCC SEND taken.bin {} 1 3\u{1}",
+            u32::from(Ipv4Addr::LOCALHOST)
+        ))
+        .unwrap();
+        let config: client::data::Config =
+            toml::from_str("server = \"mock\"\nnickname = \"me\"\nuse_mock_connection = true")
+                .unwrap();
+        let client = client::Client::from_config(config).await.unwrap();
+        let folder = std::env::temp_dir().join(format!("irc-dl-skip-offer-{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        std::fs::write(folder.join("taken.bin"), b"old file").unwrap();
+        let options = DownloadOptions {
+            existing_files: ExistingFiles::Skip,
+            ..DownloadOptions::new(Ipv4Addr::LOCALHOST, 0, folder.clone())
+        };
+
+        let transfer = dcc_send
+            .download(client.sender(), "Bot".to_string(), &options)
+            .await
+            .unwrap();
+        assert!(transfer.skipped);
+        assert_eq!(transfer.path, folder.join("taken.bin"));
+        assert_eq!(std::fs::read(&transfer.path).unwrap(), b"old file");
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[tokio::test]
+    async fn complete_partial_files_finished() {
+        // Nobody listens, nothing is left to transfer
+        let (dcc_send, _) = DccSend::from_str(&format!(
+            "\u{1}DCC SEND done.bin {} 1 11\u{1}",
+            u32::from(Ipv4Addr::LOCALHOST)
+        ))
+        .unwrap();
+        let config: client::data::Config =
+            toml::from_str("server = \"mock\"\nnickname = \"me\"\nuse_mock_connection = true")
+                .unwrap();
+        let client = client::Client::from_config(config).await.unwrap();
+        let folder = std::env::temp_dir().join(format!("irc-dl-finished-{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        std::fs::write(folder.join("done.bin.part"), b"hello world").unwrap();
+        let options = DownloadOptions {
+            hash: Some(HashAlgorithm::Crc32),
+            ..DownloadOptions::new(Ipv4Addr::LOCALHOST, 0, folder.clone())
+        };
+
+        let transfer = dcc_send
+            .download(client.sender(), "Bot".to_string(), &options)
+            .await
+            .unwrap();
+        assert_eq!(transfer.path, folder.join("done.bin"));
+        assert_eq!(std::fs::read(&transfer.path).unwrap(), b"hello world");
+        assert_eq!(transfer.digest.unwrap().value, "0d4a1185");
+        assert!(!folder.join("done.bin.part").exists());
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[tokio::test]
+    async fn disk_reserve_kept() {
+        // Nobody listens, the transfer must fail before connecting
+        let (dcc_send, _) = DccSend::from_str(&format!(
+            "\u{1}DCC SEND reserved.bin {} 1 1024\u{1}",
+            u32::from(Ipv4Addr::LOCALHOST)
+        ))
+        .unwrap();
+        let config: client::data::Config =
+            toml::from_str("server = \"mock\"\nnickname = \"me\"\nuse_mock_connection = true")
+                .unwrap();
+        let client = client::Client::from_config(config).await.unwrap();
+        let folder = std::env::temp_dir().join(format!("irc-dl-reserve-{}", std::process::id()));
+        let options = DownloadOptions {
+            disk_reserve: u64::MAX,
+            ..DownloadOptions::new(Ipv4Addr::LOCALHOST, 0, folder.clone())
+        };
+
+        let err = dcc_send
+            .download(client.sender(), "Bot".to_string(), &options)
+            .await
+            .unwrap_err();
+        assert_eq!(FailureKind::of(&err), FailureKind::Disk);
+        assert!(err.to_string().starts_with("Insufficient disk space"));
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn failure_kinds() {
+        let err = Err::<(), _>(std::io::Error::from(std::io::ErrorKind::WriteZero))
+            .kind(FailureKind::Disk)
+            .context("Writing")
+            .unwrap_err();
+        assert_eq!(FailureKind::of(&err), FailureKind::Disk);
+        assert_eq!(format!("{:#}", err), "Writing: write zero");
+        assert_eq!(FailureKind::of(&anyhow!("Unknown")), FailureKind::Other);
+    }
+
+    #[test]
+    fn public_addresses() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        assert!(is_public(ip("203.0.113.7")));
+        assert!(!is_public(ip("192.168.1.2")));
+        assert!(!is_public(ip("100.64.0.1")));
+        assert!(is_public(ip("100.128.0.1")));
+        assert!(!is_public(ip("127.0.0.1")));
+        assert!(is_public(ip("2001:db8::1")));
+        assert!(!is_public(ip("::1")));
+        assert!(!is_public(ip("fd12:3456::1")));
+        assert!(!is_public(ip("fe80::1")));
+        assert!(!is_public(ip("::ffff:192.168.1.2")));
+    }
+
+    #[test]
+    fn ipv6_advertised_to_ipv6_offers_only() {
+        let options = DownloadOptions {
+            myip_v6: Some("2001:db8::1".parse().unwrap()),
+            ..DownloadOptions::new(Ipv4Addr::LOCALHOST, 0, PathBuf::new())
+        };
+        let myip = Some(Ipv4Addr::new(203, 0, 113, 1));
+        let (v4, _) = DccSend::from_str("\u{1}DCC SEND file.mkv 3232235777 0 1024 3\u{1}").unwrap();
+        assert_eq!(
+            v4.passive_addresses(myip, &options).unwrap(),
+            (
+                Ipv4Addr::UNSPECIFIED.into(),
+                Ipv4Addr::new(203, 0, 113, 1).into()
+            )
+        );
+        assert!(v4.passive_addresses(None, &options).is_err());
+
+        let decimal = u128::from("2001:db8::7".parse::<Ipv6Addr>().unwrap());
+        let (v6, _) =
+            DccSend::from_str(&format!("\u{1}DCC SEND file.mkv {} 0 1024 3\u{1}", decimal))
+                .unwrap();
+        assert_eq!(
+            v6.passive_addresses(myip, &options).unwrap(),
+            (
+                Ipv6Addr::UNSPECIFIED.into(),
+                "2001:db8::1".parse::<IpAddr>().unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn ipv6_offers() {
+        let (dcc_send, _) =
+            DccSend::from_str("\u{1}DCC SEND file.mkv 2001:db8::7 5000 1024\u{1}").unwrap();
+        assert_eq!(
+            dcc_send.address,
+            "[2001:db8::7]:5000".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(dcc_send.file_size, Some(1024));
+
+        let decimal = u128::from("2001:db8::7".parse::<Ipv6Addr>().unwrap());
+        let (dcc_send, _) =
+            DccSend::from_str(&format!("\u{1}DCC SEND file.mkv {} 0 1024 3\u{1}", decimal))
+                .unwrap();
+        assert_eq!(
+            dcc_send.address.ip(),
+            "2001:db8::7".parse::<IpAddr>().unwrap()
+        );
+        assert!(dcc_send.is_passive());
+
+        assert_eq!(
+            parse_address("16777343"),
+            Some(IpAddr::V4(Ipv4Addr::new(1, 0, 0, 127)))
+        );
+        assert_eq!(parse_address("not:an:address"), None);
+        assert_eq!(
+            canonical("::ffff:10.0.0.1".parse().unwrap()),
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))
+        );
+    }
+
+    #[tokio::test]
+    async fn connect_from_source_address() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let _stream = connect(address, Some(Ipv4Addr::LOCALHOST)).await.unwrap();
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer.ip(), Ipv4Addr::LOCALHOST);
+
+        check_source_address(Ipv4Addr::LOCALHOST).unwrap();
+        // TEST-NET-1, not assigned to any interface here
+        assert!(check_source_address(Ipv4Addr::new(192, 0, 2, 1)).is_err());
+    }
+
+    #[test]
+    fn part_suffix_checked() {
+        check_part_suffix(".part").unwrap();
+        check_part_suffix("~").unwrap();
+        assert!(check_part_suffix("").is_err());
+        assert!(check_part_suffix("/x").is_err());
+        assert!(check_part_suffix(".part\\..").is_err());
+    }
+
+    #[test]
+    fn free_path_numbers_taken_names() {
+        let folder = std::env::temp_dir().join(format!("irc-dl-free-{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        let path = folder.join("show.mkv");
+        assert_eq!(free_path(&path), path);
+        std::fs::write(&path, "").unwrap();
+        assert_eq!(free_path(&path), folder.join("show (1).mkv"));
+        std::fs::write(folder.join("show (1).mkv"), "").unwrap();
+        assert_eq!(free_path(&path), folder.join("show (2).mkv"));
+        std::fs::write(folder.join("README"), "").unwrap();
+        assert_eq!(free_path(&folder.join("README")), folder.join("README (1)"));
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn sweep_leftovers() {
+        let folder = std::env::temp_dir().join(format!("irc-dl-sweep-{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        for (name, content) in [
+            ("empty.mkv", ""),
+            ("active.mkv", ""),
+            ("locked.mkv", ""),
+            ("locked.mkv.lock", "other"),
+            ("complete.mkv", "content"),
+            ("old.mkv.part", "partial"),
+            ("active.mkv.part", "partial"),
+            ("transferring.mkv.part", "partial"),
+            ("transferring.mkv.lock", "other"),
+        ] {
+            std::fs::write(folder.join(name), content).unwrap();
+        }
+        let keep = HashSet::from(["active.mkv".to_string()]);
+
+        let removed = sweep_download_folder(&folder, &keep, ".part", None).unwrap();
+        assert_eq!(removed, vec![folder.join("empty.mkv")]);
+        let mut removed =
+            sweep_download_folder(&folder, &keep, ".part", Some(Duration::ZERO)).unwrap();
+        removed.sort();
+        assert_eq!(removed, vec![folder.join("old.mkv.part")]);
+        assert!(folder.join("active.mkv.part").exists());
+        assert!(folder.join("transferring.mkv.part").exists());
+        assert!(folder.join("locked.mkv").exists());
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn partial_downloads_found() {
+        let folder = std::env::temp_dir().join(format!("irc-dl-parts-{}", std::process::id()));
+        std::fs::create_dir_all(folder.join("folder.mkv.part")).unwrap();
+        for name in [
+            "complete.mkv",
+            "first.mkv.part",
+            "second.mkv.part",
+            "second.mkv.lock",
+        ] {
+            std::fs::write(folder.join(name), "partial").unwrap();
+        }
+
+        let parts = partial_downloads(&folder, ".part").unwrap();
+        std::fs::remove_dir_all(&folder).unwrap();
+        assert_eq!(
+            parts,
+            HashSet::from(["first.mkv".to_string(), "second.mkv".to_string()])
+        );
+    }
+
+    #[test]
+    fn transfer_timeout_scales_with_size() {
+        let mut options = DownloadOptions {
+            transfer_timeout: Some(Duration::from_secs(600)),
+            min_throughput: Some(1_000_000),
+            ..DownloadOptions::new(Ipv4Addr::LOCALHOST, 0, PathBuf::new())
+        };
+        let (small, _) =
+            DccSend::from_str("\u{1}DCC SEND small.mkv 1226420238 0 50000000 1\u{1}").unwrap();
+        let (huge, _) =
+            DccSend::from_str("\u{1}DCC SEND huge.mkv 1226420238 0 50000000000 1\u{1}").unwrap();
+        let (unknown, _) =
+            DccSend::from_str("\u{1}DCC SEND unknown.mkv 1226420238 0\u{1}").unwrap();
+        assert_eq!(
+            small.transfer_timeout(&options),
+            Some(Duration::from_secs(600))
+        );
+        assert_eq!(
+            huge.transfer_timeout(&options),
+            Some(Duration::from_secs(50000))
+        );
+        assert_eq!(
+            unknown.transfer_timeout(&options),
+            Some(Duration::from_secs(600))
+        );
+
+        options.transfer_timeout = None;
+        assert_eq!(unknown.transfer_timeout(&options), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_mode_applied() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("irc-dl-mode-{}", std::process::id()));
+        std::fs::write(&path, b"").unwrap();
+        let options = DownloadOptions {
+            file_mode: Some(0o640),
+            ..DownloadOptions::new(Ipv4Addr::LOCALHOST, 0, PathBuf::new())
+        };
+        apply_permissions(&path, &options).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o777, 0o640);
+    }
+
+    #[test]
+    fn offered_names_sanitized() {
+        let offer = |name: &str| {
+            DccSend::from_str(&format!("\u{1}DCC SEND {} 2130706433 5000 100\u{1}", name))
+                .map(|(send, _)| send.file_name)
+        };
+        assert_eq!(offer("../../.bashrc").as_deref(), Some(".bashrc"));
+        assert_eq!(offer("/etc/passwd").as_deref(), Some("passwd"));
+        assert_eq!(
+            offer("\"file with spaces.mkv\"").as_deref(),
+            Some("file with spaces.mkv")
+        );
+        assert_eq!(offer(".."), None);
+        assert_eq!(offer("NUL"), None);
+    }
+
+    #[test]
+    fn targets_stay_inside_download_folder() {
+        let folder = std::env::temp_dir().join(format!("ircdl-inside-{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        assert!(ensure_inside(&folder, &folder.join("file.bin")).is_ok());
+        assert!(ensure_inside(&folder, &folder.join("../file.bin")).is_err());
+        #[cfg(unix)]
+        {
+            let link = folder.join("link.bin");
+            std::os::unix::fs::symlink("/tmp/elsewhere.bin", &link).unwrap();
+            assert!(ensure_inside(&folder, &link).is_err());
+        }
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
     #[test]
     fn dcc_send_passive2() {
         let input = "\u{1}DCC SEND Well_this-could-be.something.mkv 1226420238 0\u{1}";
@@ -0,0 +1,133 @@
+use crate::dcc::{CompletedTransfer, DccSend, DownloadOptions};
+use crate::handler::{self, TransferObserver};
+use crate::{check_irc_text, check_nick, DEFAULT_MAX_NICK_LEN};
+use anyhow::{anyhow, bail};
+use irc::client::Sender;
+use tokio::sync::broadcast;
+
+#[derive(Clone, Debug)]
+pub enum DownloadEvent {
+    Started {
+        file_name: String,
+    },
+    Progress {
+        file_name: String,
+        transferred: u64,
+        file_size: Option<u64>,
+    },
+    Completed {
+        file_name: String,
+        transfer: CompletedTransfer,
+    },
+    Failed {
+        file_name: String,
+        error: String,
+    },
+}
+
+/// Transfers packs from XDCC bots, for embedding into other tools.
+///
+/// The IRC connection stays with the caller: requests are sent through its [`Sender`], and the
+/// `DCC SEND` offers bots answer with are handed to [`Downloader::accept`]. Partial files are
+/// continued if the bot agrees, its `DCC ACCEPT` is to be handed to [`Downloader::handle_accept`].
+pub struct Downloader {
+    options: DownloadOptions,
+    events: broadcast::Sender<DownloadEvent>,
+}
+
+/// Events kept for subscribers falling behind, by default.
+const EVENT_CAPACITY: usize = 64;
+
+impl Downloader {
+    pub fn new(options: DownloadOptions) -> Self {
+        Self::with_event_capacity(options, EVENT_CAPACITY)
+    }
+
+    /// Keeps `capacity` events, at least one, for subscribers falling behind. Those missing some
+    /// more learn how many from [`broadcast::error::RecvError::Lagged`].
+    pub fn with_event_capacity(options: DownloadOptions, capacity: usize) -> Self {
+        let (events, _) = broadcast::channel(capacity.max(1));
+        Self { options, events }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<DownloadEvent> {
+        self.events.subscribe()
+    }
+
+    /// Asks a bot for a pack, `command` being something like `xdcc send #12`.
+    pub fn request_pack(&self, sender: &Sender, nick: &str, command: &str) -> anyhow::Result<()> {
+        check_nick(nick, DEFAULT_MAX_NICK_LEN)?;
+        check_irc_text("Command", command)?;
+        sender.send_privmsg(nick, command)?;
+        Ok(())
+    }
+
+    /// Passes on a `DCC ACCEPT` to the transfer that asked to resume. Returns whether the message
+    /// was one a transfer waited for.
+    pub fn handle_accept(&self, message: &str) -> bool {
+        handler::pass_dcc_accept(&self.options, message).unwrap_or(false)
+    }
+
+    /// Transfers the file of a `DCC SEND` offer of `nick`, resolving once it is complete. Like in
+    /// the web interface, transfers stalling or too slow for the options are cancelled.
+    pub async fn accept(
+        &self,
+        sender: Sender,
+        nick: String,
+        offer: &str,
+    ) -> anyhow::Result<CompletedTransfer> {
+        let Some((dcc_send, progress)) = DccSend::from_str(offer) else {
+            bail!("Not a DCC SEND offer: {:?}", offer)
+        };
+        let file_name = dcc_send.file_name.clone();
+        self.emit(DownloadEvent::Started {
+            file_name: file_name.clone(),
+        });
+        let download = dcc_send.download(sender, nick, &self.options);
+        let mut observer = ProgressEvents {
+            downloader: self,
+            dcc_send: &dcc_send,
+        };
+        let (outcome, _) = handler::supervise_transfer(
+            &dcc_send,
+            download,
+            progress,
+            &self.options,
+            &mut observer,
+        )
+        .await;
+        let result = outcome.unwrap_or_else(|reason| Err(anyhow!("Cancelled: {:?}", reason)));
+        self.emit(match &result {
+            Ok(transfer) => DownloadEvent::Completed {
+                file_name,
+                transfer: transfer.clone(),
+            },
+            Err(err) => DownloadEvent::Failed {
+                file_name,
+                error: format!("{:#}", err),
+            },
+        });
+        result
+    }
+
+    fn emit(&self, event: DownloadEvent) {
+        // Nobody listening is fine
+        self.events.send(event).ok();
+    }
+}
+
+/// Turns the progress of a transfer into events.
+struct ProgressEvents<'a> {
+    downloader: &'a Downloader,
+    dcc_send: &'a DccSend,
+}
+
+impl TransferObserver for ProgressEvents<'_> {
+    fn progressed(&mut self, transferred: u64) {
+        self.downloader.emit(DownloadEvent::Progress {
+            file_name: self.dcc_send.file_name.clone(),
+            transferred,
+            file_size: self.dcc_send.file_size,
+        });
+    }
+}
@@ -0,0 +1,774 @@
+//! Handling of what IRC servers send us: `DCC SEND` offers become transfers, notices of bots and
+//! numerics of the server update the downloads waiting for them.
+//!
+//! The application embedding it implements [`Host`], giving access to its connections and hearing
+//! about searches and ended downloads. [`supervise_transfer`] drives a single transfer, for those
+//! keeping the IRC connection to themselves like [`crate::downloader::Downloader`].
+
+use crate::dcc::{self, CompletedTransfer, DccAccept, DccSend, DownloadOptions, FailureKind};
+use crate::server::{self, ConnectionState, ServerConnection, ServerId};
+use crate::stats::StatsStore;
+use crate::{
+    is_search_end, CancelReason, Cancellation, DownloadId, DownloadProgress, DownloadStatus,
+    SearchResult,
+};
+use dashmap::DashMap;
+use futures_util::stream::{Abortable, Aborted};
+use irc::client::prelude::*;
+use irc::proto::FormattedStringExt;
+use irc::proto::Response::*;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::num::NonZeroU64;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::time::{Duration, Instant};
+
+/// How long a server may take to tell whether a nick is online.
+pub const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What handling messages needs of the application. Only the connections, the options for
+/// transfers and ids for unsolicited downloads are required, the rest is up to the application.
+pub trait Host: Send + Sync + 'static {
+    fn servers(&self) -> &DashMap<ServerId, ServerConnection>;
+
+    fn download_options(&self) -> DownloadOptions;
+
+    /// Id for a download a bot offered without being asked.
+    fn next_download_id(&self) -> DownloadId;
+
+    /// Moves a server to another state.
+    fn set_server_state(&self, server_id: &ServerId, state: ConnectionState) {
+        if let Some(server) = self.servers().get(server_id) {
+            server.set_state(state);
+        }
+    }
+
+    /// Where transfers of bots are counted, if anywhere.
+    fn stats(&self) -> Option<&StatsStore> {
+        None
+    }
+
+    /// How failed transfers are requested again, by default they are not.
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy::default()
+    }
+
+    /// Whether the partial files of aborted transfers are kept, to resume them later.
+    fn keep_aborted_parts(&self) -> bool {
+        true
+    }
+
+    /// Partial file of a download of `file_name`.
+    fn part_path(&self, file_name: &str) -> PathBuf {
+        let options = self.download_options();
+        dcc::part_path(
+            &options.download_folder.join(file_name),
+            &options.part_suffix,
+        )
+    }
+
+    /// Called with every message before it is handled.
+    fn message_received(&self, _server_id: &ServerId, _message: &Message) {}
+
+    /// A result of a search came in.
+    fn search_result(&self, _server_id: &ServerId, _result: SearchResult) {}
+
+    /// A bot said it sent all its results of a search.
+    fn search_ended(&self, _server_id: &ServerId, _bot: &str) {}
+
+    /// A download ended, completed, failed or aborted, after `transferred` bytes of its last
+    /// transfer.
+    fn download_ended(&self, _server_id: &ServerId, _download_id: DownloadId, _transferred: u64) {}
+}
+
+/// How failed downloads are requested again.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    /// Delay of the first retry, doubled for each further one
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Delay before the given retry, `None` once there are no retries left.
+    pub fn delay(&self, retry: u32) -> Option<Duration> {
+        (1..=self.max_retries)
+            .contains(&retry)
+            .then(|| self.backoff.saturating_mul(2u32.saturating_pow(retry - 1)))
+    }
+}
+
+/// Handles a message of a server. Fails only if the server could not be told to join the
+/// configured channels once we registered.
+pub fn handle_message<H: Host>(
+    host: &Arc<H>,
+    server_id: ServerId,
+    message: Message,
+) -> anyhow::Result<()> {
+    host.message_received(&server_id, &message);
+    match message.command {
+        Command::PRIVMSG(channel, msg) => {
+            if !channel.starts_with('#') {
+                eprintln!("GOT {:?}: {:?} - {:?}", message.prefix, channel, msg);
+            }
+            if let Some(Prefix::Nickname(nick, _, _)) = message.prefix {
+                if let Some(expected) = pass_dcc_accept(&host.download_options(), &msg) {
+                    if !expected {
+                        log::warn!("Unexpected DCC ACCEPT from {}: {}", nick, msg);
+                    }
+                    return Ok(());
+                }
+                if let Some((dcc_send, receiver)) = DccSend::from_str(&msg) {
+                    let is_new = host
+                        .servers()
+                        .get(&server_id)
+                        .map_or(true, |server| server.is_new_offer(&dcc_send));
+                    if is_new {
+                        tokio::spawn(accept_offer(
+                            host.clone(),
+                            server_id,
+                            nick,
+                            dcc_send,
+                            receiver,
+                        ));
+                    }
+                }
+            }
+        }
+        Command::PONG(server, token) => {
+            if let Some(connection) = host.servers().get(&server_id) {
+                connection.pong(token.as_deref().unwrap_or(&server));
+            }
+        }
+        Command::NICK(new_nick) => {
+            if let (Some(Prefix::Nickname(old_nick, _, _)), Some(server)) =
+                (&message.prefix, host.servers().get(&server_id))
+            {
+                server.nick_changed(old_nick, &new_nick);
+            }
+        }
+        Command::JOIN(channel, _, _) => {
+            if let (Some(Prefix::Nickname(nick, _, _)), Some(server)) =
+                (&message.prefix, host.servers().get(&server_id))
+            {
+                if nick.eq_ignore_ascii_case(&server.nick()) {
+                    let delay = server.channel_joined(&channel);
+                    if server.joined_all_channels() {
+                        host.set_server_state(&server_id, ConnectionState::JoinedChannels);
+                    }
+                    if let Some(delay) = delay {
+                        let (host, server_id) = (host.clone(), server_id.clone());
+                        tokio::spawn(async move {
+                            tokio::time::sleep(delay).await;
+                            if let Some(server) = host.servers().get(&server_id) {
+                                if let Err(err) = server.greet(&channel) {
+                                    log::warn!("Could not greet {}: {}", channel, err);
+                                }
+                            }
+                        });
+                    }
+                }
+            }
+        }
+        Command::Response(RPL_WELCOME, args) => {
+            eprintln!(
+                "Known servers: {:?}",
+                host.servers()
+                    .iter()
+                    .map(|m| m.key().clone())
+                    .collect::<Vec<_>>()
+            );
+            eprintln!("Tried server: {}", server_id);
+            let Some(server) = host.servers().get(&server_id) else {
+                return Ok(());
+            };
+            if let Some(nick) = args.first() {
+                server.registered_as(nick);
+            }
+            host.set_server_state(&server_id, ConnectionState::Connected);
+            if let (false, Some(interval)) = (server.has_primary_nick(), server.nick_recovery) {
+                log::info!("Registered as {} on {}", server.nick(), server_id);
+                let (host, server_id, connected_at) =
+                    (host.clone(), server_id.clone(), server.connected_at);
+                tokio::spawn(async move {
+                    if let Err(err) =
+                        recover_nick(host, server_id.clone(), interval, connected_at).await
+                    {
+                        log::warn!("Could not get the nick back on {}: {}", server_id, err);
+                    }
+                });
+            }
+            server.join_channels()?;
+            // Downloads taken over from before a reconnect
+            if let Err(err) = server.dispatch_all_queued() {
+                log::warn!("Could not request queued downloads: {}", err);
+            }
+        }
+        Command::NOTICE(target, notice) => {
+            let notice = notice.strip_formatting();
+            // Only the server restricts us, bots may use the same words
+            let from_server = matches!(message.prefix, None | Some(Prefix::ServerName(_)));
+            let retry_at = host
+                .servers()
+                .get(&server_id)
+                .filter(|_| from_server)
+                .and_then(|server| server.detect_restriction(None, &notice));
+            if let Some(retry_at) = retry_at {
+                retry_when_unrestricted(host.clone(), server_id.clone(), retry_at);
+            }
+            if let (Some(Prefix::Nickname(nick, _, _)), Some(limits)) =
+                (&message.prefix, server::BotLimits::from_notice(&notice))
+            {
+                if let Some(server) = host.servers().get(&server_id) {
+                    server.update_bot_limits(nick, limits);
+                }
+            }
+            if let Some(Prefix::Nickname(nick, _, _)) = &message.prefix {
+                if let Some(server) = host.servers().get(&server_id) {
+                    server.handle_services_notice(nick);
+                    let delayed = server
+                        .handle_already_sending(nick, &notice)
+                        .or_else(|| server.handle_slots_full(nick, &notice));
+                    if let Some((id, retry_at)) = delayed {
+                        retry_delayed(host.clone(), server_id.clone(), id, retry_at);
+                    }
+                    server.handle_no_such_pack(nick, &notice);
+                    server.handle_queue_position(nick, &notice);
+                    if let Err(err) = server.handle_channel_required(nick, &notice) {
+                        log::warn!("Could not join the channel {} requires: {}", nick, err);
+                    }
+                }
+            }
+            let sender = match &message.prefix {
+                Some(Prefix::Nickname(nick, _, _)) => Some(nick.as_str()),
+                _ => None,
+            };
+            let result = host
+                .servers()
+                .get(&server_id)
+                .and_then(|server| server.search_result(&target, sender, &notice));
+            if let Some(result) = result {
+                host.search_result(&server_id, result);
+            } else if let Some(sender) = sender.filter(|_| is_search_end(&notice)) {
+                log::debug!("Search of {} on {} ended: {}", sender, server_id, notice);
+                host.search_ended(&server_id, sender);
+            }
+        }
+        Command::Response(response, args) => {
+            if response == Response::ERR_NOSUCHNICK {
+                let grace = host
+                    .servers()
+                    .get_mut(&server_id)
+                    .and_then(|mut server| server.sender_missing(&args[1]));
+                if let Some(grace) = grace {
+                    let (host, server_id, nick) =
+                        (host.clone(), server_id.clone(), args[1].clone());
+                    tokio::spawn(async move {
+                        tokio::time::sleep(grace).await;
+                        if let Some(server) = host.servers().get(&server_id) {
+                            if let Err(err) = server.check_sender_presence(&nick) {
+                                log::warn!("Could not check for {}: {}", nick, err);
+                            }
+                        }
+                    });
+                }
+            } else if response == Response::RPL_ISUPPORT {
+                if let Some(server) = host.servers().get(&server_id) {
+                    server.update_isupport(&args);
+                }
+            } else if response == Response::RPL_ISON {
+                if let (Some(present), Some(mut server)) =
+                    (args.last(), host.servers().get_mut(&server_id))
+                {
+                    server.presence_reply(present);
+                }
+            }
+            let retry_at = host.servers().get(&server_id).and_then(|server| {
+                server.detect_restriction(
+                    Some(&format!("{:03}", response as u16)),
+                    args.last().map_or("", String::as_str),
+                )
+            });
+            if let Some(retry_at) = retry_at {
+                retry_when_unrestricted(host.clone(), server_id, retry_at);
+            }
+        }
+        // Not yet allowed to send messages to other users
+        Command::Raw(code, args)
+            if host
+                .servers()
+                .get(&server_id)
+                .map_or(false, |server| server.is_restriction_numeric(&code)) =>
+        {
+            let retry_at = host.servers().get(&server_id).and_then(|server| {
+                server.detect_restriction(Some(&code), args.last().map_or("", String::as_str))
+            });
+            if let Some(retry_at) = retry_at {
+                retry_when_unrestricted(host.clone(), server_id, retry_at);
+            }
+        }
+        _ => eprintln!("{:?}", message),
+    }
+    Ok(())
+}
+
+/// Passes a `DCC ACCEPT` on to the transfer that asked to resume. Returns `None` if the message
+/// is none, otherwise whether a transfer waited for it.
+pub fn pass_dcc_accept(options: &DownloadOptions, message: &str) -> Option<bool> {
+    DccAccept::parse(message).map(|accept| options.resumes.accept(&accept))
+}
+
+/// Hears how a transfer driven by [`supervise_transfer`] is going.
+pub trait TransferObserver {
+    /// Nothing arrived yet: we wait for a free slot, or for the bot to connect to the address
+    /// told it.
+    fn waiting(&mut self, _waiting: bool, _advertised: Option<SocketAddr>) {}
+
+    /// The first bytes arrived. The counters of `progress` are kept up to date from then on,
+    /// without calling the observer.
+    fn started(&mut self, _progress: &DownloadProgress) {}
+
+    /// More bytes arrived, `transferred` in all.
+    fn progressed(&mut self, _transferred: u64) {}
+
+    /// Asked every second whether the transfer is to be cancelled, and why.
+    fn abandoned(&mut self) -> Option<CancelReason> {
+        None
+    }
+}
+
+/// Runs a transfer to its end, measuring its throughput and cancelling it if it stalls, is too
+/// slow for the options or the observer abandons it. Returns how it ended, the reason if it was
+/// cancelled, and the bytes received.
+pub async fn supervise_transfer(
+    dcc_send: &DccSend,
+    download: impl Future<Output = anyhow::Result<CompletedTransfer>>,
+    mut receiver: watch::Receiver<dcc::DownloadProgress>,
+    options: &DownloadOptions,
+    observer: &mut impl TransferObserver,
+) -> (Result<anyhow::Result<CompletedTransfer>, CancelReason>, u64) {
+    let (cancellation, abort_registration) = Cancellation::new_pair();
+    let progress = DownloadProgress {
+        transferred: Arc::new(AtomicU64::new(0)),
+        file_size: dcc_send.file_size.and_then(NonZeroU64::new),
+        bytes_per_sec: Arc::new(AtomicU64::new(0)),
+        eta_seconds: Arc::new(AtomicU64::new(0)),
+        cancellation: cancellation.clone(),
+    };
+    let mut rate = dcc::RateWindow::default();
+    let mut progress_reported = false;
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    let download = Abortable::new(download, abort_registration);
+    tokio::pin!(download);
+    let outcome = loop {
+        tokio::select! {
+            outcome = &mut download => break outcome,
+            _ = tick.tick() => {
+                // Without progress updates during a stall, the throughput would stay as it was
+                if progress_reported {
+                    rate.record(Instant::now(), progress.transferred.load(Ordering::Relaxed));
+                    progress.bytes_per_sec.store(rate.bytes_per_sec(), Ordering::Relaxed);
+                    progress.eta_seconds.store(rate.eta_secs(dcc_send.file_size).unwrap_or(0), Ordering::Relaxed);
+                }
+                if let Some(reason) = rate.cancel_reason(Instant::now(), options) {
+                    log::info!("Cancelling {}: {:?}", dcc_send.file_name, reason);
+                    cancellation.cancel(reason);
+                }
+                if let Some(reason) = observer.abandoned() {
+                    cancellation.cancel(reason);
+                }
+            }
+            Ok(()) = receiver.changed() => {
+                let (transferred, updated_at, advertised, waiting) = {
+                    let progress = receiver.borrow();
+                    (progress.transferred_bytes, progress.updated_at, progress.advertised, progress.waiting)
+                };
+                progress.transferred.store(transferred, Ordering::Relaxed);
+                if transferred == 0 {
+                    observer.waiting(waiting, advertised);
+                    continue;
+                }
+                if let Some(updated_at) = updated_at {
+                    rate.record(updated_at, transferred);
+                    progress.bytes_per_sec.store(rate.bytes_per_sec(), Ordering::Relaxed);
+                    progress.eta_seconds.store(rate.eta_secs(dcc_send.file_size).unwrap_or(0), Ordering::Relaxed);
+                }
+                if !progress_reported {
+                    progress_reported = true;
+                    observer.started(&progress);
+                }
+                observer.progressed(transferred);
+            }
+        }
+    };
+    let transferred = receiver.borrow().transferred_bytes;
+    let outcome =
+        outcome.map_err(|Aborted| cancellation.reason().unwrap_or(CancelReason::UserRequest));
+    (outcome, transferred)
+}
+
+/// Keeps the download of a transfer up to date.
+pub struct DownloadObserver<'a, H: Host> {
+    host: &'a H,
+    server_id: &'a ServerId,
+    download_id: DownloadId,
+}
+
+impl<'a, H: Host> DownloadObserver<'a, H> {
+    pub fn new(host: &'a H, server_id: &'a ServerId, download_id: DownloadId) -> Self {
+        Self {
+            host,
+            server_id,
+            download_id,
+        }
+    }
+}
+
+impl<H: Host> TransferObserver for DownloadObserver<'_, H> {
+    fn waiting(&mut self, waiting: bool, advertised: Option<SocketAddr>) {
+        if let Some(server) = self.host.servers().get(self.server_id) {
+            if let Some(mut download) = server.downloads.get_mut(&self.download_id) {
+                download.advertised_address = advertised;
+                if waiting {
+                    download.status = DownloadStatus::Waiting;
+                } else if matches!(download.status, DownloadStatus::Waiting) {
+                    download.status = DownloadStatus::Connecting;
+                }
+            }
+            server.download_updated();
+        }
+    }
+
+    fn started(&mut self, progress: &DownloadProgress) {
+        let Some(server) = self.host.servers().get(self.server_id) else {
+            progress.cancellation.cancel(CancelReason::UserRequest);
+            return;
+        };
+        let Some(mut download) = server.downloads.get_mut(&self.download_id) else {
+            return;
+        };
+        // Aborted while still connecting
+        if let DownloadStatus::Aborted { reason } = download.status {
+            progress.cancellation.cancel(reason);
+        }
+        // Don't let a late progress update revive a finished download
+        if !download.status.is_terminal() {
+            download.status = DownloadStatus::Progress(progress.clone());
+            drop(download);
+            server.download_updated();
+        }
+    }
+
+    fn abandoned(&mut self) -> Option<CancelReason> {
+        // Gone with its server or aborted while still connecting or waiting, without progress
+        // the transfer would not notice
+        let Some(server) = self.host.servers().get(self.server_id) else {
+            return Some(CancelReason::UserRequest);
+        };
+        let download = server.downloads.get(&self.download_id);
+        match download.as_deref().map(|d| &d.status) {
+            None => Some(CancelReason::UserRequest),
+            Some(DownloadStatus::Aborted { reason }) => Some(*reason),
+            Some(_) => None,
+        }
+    }
+}
+
+/// Transfers the file a bot offered, if a download is waiting for it or unsolicited offers of
+/// the bot are accepted, and settles the download once the transfer ended.
+async fn accept_offer<H: Host>(
+    host: Arc<H>,
+    server_id: ServerId,
+    nick: String,
+    dcc_send: DccSend,
+    receiver: watch::Receiver<dcc::DownloadProgress>,
+) {
+    let mut options = match host.servers().get(&server_id) {
+        Some(server) => {
+            let options = host.download_options();
+            DownloadOptions {
+                source_address: server.dcc_source_address.or(options.source_address),
+                ack_width: server.ack_width(&nick).unwrap_or(options.ack_width),
+                ..options
+            }
+        }
+        None => host.download_options(),
+    };
+    let (download_id, download) = {
+        // Removed meanwhile
+        let Some(server) = host.servers().get(&server_id) else {
+            return;
+        };
+        let client = &server.client;
+        let Some(mut download) = server
+            .offered_download(&nick, &dcc_send)
+            .or_else(|| server.unsolicited_download(&nick, &dcc_send, || host.next_download_id()))
+            .and_then(|id| server.downloads.get_mut(&id))
+        else {
+            log::warn!(
+                "Dropping offer of {} from {}, no download is waiting for it",
+                dcc_send.file_name,
+                nick
+            );
+            return;
+        };
+        if matches!(
+            download.status,
+            DownloadStatus::Waiting | DownloadStatus::Connecting | DownloadStatus::Progress(_)
+        ) {
+            log::warn!("Download in progress already");
+            return;
+        }
+        download.status = DownloadStatus::Connecting;
+        download.dcc_token = dcc_send.id;
+        // The file is saved under the name offered
+        download.file_name = dcc_send.file_name.clone();
+        options.max_bytes_per_sec = download.max_bytes_per_sec.or(options.max_bytes_per_sec);
+        let download_id = download.id;
+        drop(download);
+        server.download_updated();
+        (
+            download_id,
+            dcc_send.download(client.sender(), nick.clone(), &options),
+        )
+    };
+    let started = Instant::now();
+    let mut observer = DownloadObserver::new(&*host, &server_id, download_id);
+    let (outcome, transferred) =
+        supervise_transfer(&dcc_send, download, receiver, &options, &mut observer).await;
+    match outcome {
+        Err(reason) => {
+            eprintln!("Aborted: {:?}", reason);
+            transfer_aborted(
+                &*host,
+                &server_id,
+                download_id,
+                &nick,
+                &dcc_send.file_name,
+                reason,
+            );
+        }
+        Ok(Err(err)) => {
+            eprintln!("Download error: {}", err);
+            transfer_failed(&host, &server_id, download_id, &nick, &err);
+        }
+        Ok(Ok(transfer)) if transfer.skipped => {
+            // Done already, the file on disk stands for the download
+            let bytes = std::fs::metadata(&transfer.path).map_or(0, |metadata| metadata.len());
+            if let Some(server) = host.servers().get(&server_id) {
+                server.completed(&download_id, bytes, transfer.path);
+            }
+        }
+        Ok(Ok(transfer)) => {
+            eprintln!("Download completed");
+            if let Some(stats) = host.stats() {
+                stats.record_transfer(&server_id, &nick, Ok((transferred, started.elapsed())));
+                stats.record_finishing(&server_id, &nick, transfer.fsync, transfer.verify);
+            }
+            let Some(server) = host.servers().get(&server_id) else {
+                return;
+            };
+            if let Some(mut download) = server.downloads.get_mut(&download_id) {
+                download.digest = transfer.digest;
+            }
+            server.completed(&download_id, transferred, transfer.path);
+        }
+    }
+    host.download_ended(&server_id, download_id, transferred);
+    if let Some(server) = host.servers().get(&server_id) {
+        if let Err(err) = server.dispatch_queued(&nick) {
+            log::warn!("Could not request next download of {}: {}", nick, err);
+        }
+    }
+}
+
+/// Settles a download whose transfer was cancelled for `reason`.
+pub fn transfer_aborted(
+    host: &impl Host,
+    server_id: &str,
+    download_id: DownloadId,
+    bot_nick: &str,
+    file_name: &str,
+    reason: CancelReason,
+) {
+    // Paused for lack of disk space, requested again to resume once there is, or by the user
+    let paused = matches!(reason, CancelReason::Disk | CancelReason::Pause);
+    if matches!(reason, CancelReason::Stall | CancelReason::MinSpeed) {
+        if let Some(stats) = host.stats() {
+            stats.record_transfer(server_id, bot_nick, Err(FailureKind::Stalled));
+        }
+    }
+    if !host.keep_aborted_parts() && !paused {
+        let part = host.part_path(file_name);
+        match std::fs::remove_file(&part) {
+            Ok(()) => log::info!("Removed partial file {}", part.display()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => log::warn!("Could not remove {}: {}", part.display(), err),
+        }
+    }
+    if let Some(server) = host.servers().get(server_id) {
+        if let Some(mut download) = server.downloads.get_mut(&download_id) {
+            download.status = match reason {
+                CancelReason::Disk => DownloadStatus::Queued,
+                CancelReason::Pause => DownloadStatus::Paused,
+                reason => DownloadStatus::Aborted { reason },
+            };
+        }
+        server.download_updated();
+    }
+}
+
+/// Settles a download whose transfer failed, retrying it as the policy says unless another
+/// attempt would fail the same.
+pub fn transfer_failed<H: Host>(
+    host: &Arc<H>,
+    server_id: &ServerId,
+    download_id: DownloadId,
+    bot_nick: &str,
+    err: &anyhow::Error,
+) {
+    let conflict = err.downcast_ref::<dcc::LockConflict>();
+    let kind = FailureKind::of(err);
+    if let (None, Some(stats)) = (conflict, host.stats()) {
+        stats.record_transfer(server_id, bot_nick, Err(kind));
+    }
+    let policy = host.retry_policy();
+    let Some(server) = host.servers().get(server_id) else {
+        return;
+    };
+    // Removed or trashed by the user meanwhile
+    let Some(mut download) = server.downloads.get_mut(&download_id) else {
+        return;
+    };
+    if let Some(conflict) = conflict {
+        download.status = DownloadStatus::Conflict(conflict.holder.clone());
+    } else if let Some(delay) = policy
+        .delay(download.retries + 1)
+        .filter(|_| kind.is_transient())
+    {
+        // Transient failures like a lost connection are common, the bot is asked again and the
+        // transfer resumes the partial file
+        let retry_at = Instant::now() + delay;
+        download.retries += 1;
+        download.notice = Some(format!("{}", err));
+        download.status = DownloadStatus::Retrying(retry_at);
+        log::info!("Retrying {} in {}s", download.file_name, delay.as_secs());
+        retry_delayed(host.clone(), server_id.clone(), download_id, retry_at);
+    } else {
+        download.status = DownloadStatus::Failed(format!("{}", err));
+    }
+    drop(download);
+    server.download_updated();
+}
+
+/// Requests a delayed download again at `retry_at`.
+pub fn retry_delayed<H: Host>(
+    host: Arc<H>,
+    server_id: ServerId,
+    id: DownloadId,
+    retry_at: Instant,
+) {
+    tokio::spawn(async move {
+        tokio::time::sleep_until(retry_at).await;
+        let Some(server) = host.servers().get(&server_id) else {
+            return;
+        };
+        if let Err(err) = server.retry_delayed(&id) {
+            log::warn!("Could not retry download {}: {}", id, err);
+        }
+    });
+}
+
+/// Requests the downloads of a server again once its restriction is expected to be over.
+fn retry_when_unrestricted<H: Host>(host: Arc<H>, server_id: ServerId, mut retry_at: Instant) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep_until(retry_at).await;
+            let Some(server) = host.servers().get(&server_id) else {
+                return;
+            };
+            match server.retry_restricted() {
+                Some(later) => retry_at = later,
+                None => return,
+            }
+        }
+    });
+}
+
+/// Periodically tries to get the configured nick back, until we have it or the connection it
+/// was started for is replaced.
+async fn recover_nick<H: Host>(
+    host: Arc<H>,
+    server_id: ServerId,
+    interval: Duration,
+    connected_at: Instant,
+) -> anyhow::Result<()> {
+    loop {
+        tokio::time::sleep(interval).await;
+        let released = match host.servers().get(&server_id) {
+            Some(server) => server.nick_released.clone(),
+            None => return Ok(()),
+        };
+        // Waiting from before the ghost, not to miss a quick answer
+        let notified = released.notified();
+        let ghosted = {
+            let Some(server) = host.servers().get(&server_id) else {
+                return Ok(());
+            };
+            // A new connection has a task of its own
+            if server.connected_at != connected_at {
+                return Ok(());
+            }
+            if server.has_primary_nick() {
+                log::info!("Got nick {} back on {}", server.primary_nick(), server_id);
+                return Ok(());
+            }
+            log::info!(
+                "Trying to get nick {} back on {}",
+                server.primary_nick(),
+                server_id
+            );
+            server.ghost_primary_nick()?
+        };
+        // NickServ answering, or the ghost giving up the nick
+        if ghosted
+            && tokio::time::timeout(LOOKUP_TIMEOUT, notified)
+                .await
+                .is_err()
+        {
+            log::info!("No answer of NickServ on {}", server_id);
+        }
+        let lookup = match host.servers().get(&server_id) {
+            Some(server) => server.lookup_nick(server.primary_nick())?,
+            None => return Ok(()),
+        };
+        match tokio::time::timeout(LOOKUP_TIMEOUT, lookup).await {
+            Ok(Ok(false)) => {
+                if let Some(server) = host.servers().get(&server_id) {
+                    server.claim_primary_nick()?;
+                }
+            }
+            _ => log::info!("Nick still in use on {}", server_id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_backoff_doubles() {
+        let policy = RetryPolicy {
+            max_retries: 3,
+            backoff: Duration::from_secs(30),
+        };
+        assert_eq!(policy.delay(0), None);
+        assert_eq!(policy.delay(1), Some(Duration::from_secs(30)));
+        assert_eq!(policy.delay(3), Some(Duration::from_secs(120)));
+        assert_eq!(policy.delay(4), None);
+    }
+}
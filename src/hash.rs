@@ -0,0 +1,151 @@
+use serde::{Deserialize, Serialize};
+use sha1::Digest;
+use std::fmt::Write;
+use std::path::Path;
+use utoipa::ToSchema;
+
+/// Hash computed over downloaded files while they are transferred.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    Crc32,
+    Md5,
+    Sha1,
+    Sha256,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, Hash, ToSchema)]
+pub struct FileDigest {
+    pub algorithm: HashAlgorithm,
+    /// Lower case hex
+    pub value: String,
+}
+
+/// Incremental hashing, fed with each chunk as it arrives.
+pub enum Hasher {
+    Crc32(crc32fast::Hasher),
+    Md5(md5::Md5),
+    Sha1(sha1::Sha1),
+    Sha256(sha2::Sha256),
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Crc32 => Hasher::Crc32(crc32fast::Hasher::new()),
+            HashAlgorithm::Md5 => Hasher::Md5(md5::Md5::new()),
+            HashAlgorithm::Sha1 => Hasher::Sha1(sha1::Sha1::new()),
+            HashAlgorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Crc32(hasher) => hasher.update(data),
+            Hasher::Md5(hasher) => hasher.update(data),
+            Hasher::Sha1(hasher) => hasher.update(data),
+            Hasher::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> FileDigest {
+        let (algorithm, bytes) = match self {
+            Hasher::Crc32(hasher) => (
+                HashAlgorithm::Crc32,
+                hasher.finalize().to_be_bytes().to_vec(),
+            ),
+            Hasher::Md5(hasher) => (HashAlgorithm::Md5, hasher.finalize().to_vec()),
+            Hasher::Sha1(hasher) => (HashAlgorithm::Sha1, hasher.finalize().to_vec()),
+            Hasher::Sha256(hasher) => (HashAlgorithm::Sha256, hasher.finalize().to_vec()),
+        };
+        let value = bytes.iter().fold(String::new(), |mut hex, byte| {
+            write!(hex, "{:02x}", byte).expect("Writing to a String");
+            hex
+        });
+        FileDigest { algorithm, value }
+    }
+}
+
+/// Parses a simple file verification (.sfv) listing into (file name, CRC32) pairs.
+/// Comment lines start with `;`, file names may contain spaces.
+pub fn parse_sfv(content: &str) -> Vec<(String, u32)> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with(';'))
+        .filter_map(|line| {
+            let (name, crc) = line.rsplit_once(char::is_whitespace)?;
+            let crc = u32::from_str_radix(crc, 16).ok()?;
+            Some((name.trim_end().to_string(), crc))
+        })
+        .collect()
+}
+
+/// Looks through the .sfv files in `folder` for the CRC32 listed for `file_name`.
+/// Names are compared case-insensitively, like the Windows tools producing them.
+pub async fn sfv_checksum(folder: &Path, file_name: &str) -> anyhow::Result<Option<u32>> {
+    let mut entries = tokio::fs::read_dir(folder).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let is_sfv = path
+            .extension()
+            .map_or(false, |ext| ext.eq_ignore_ascii_case("sfv"));
+        if !is_sfv {
+            continue;
+        }
+        let content = tokio::fs::read(&path).await?;
+        if let Some((_, crc)) = parse_sfv(&String::from_utf8_lossy(&content))
+            .into_iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(file_name))
+        {
+            log::debug!("{} listed in {}", file_name, path.display());
+            return Ok(Some(crc));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(algorithm: HashAlgorithm) -> String {
+        let mut hasher = Hasher::new(algorithm);
+        hasher.update(b"hello ");
+        hasher.update(b"world");
+        hasher.finalize().value
+    }
+
+    #[test]
+    fn incremental_digests() {
+        assert_eq!(digest(HashAlgorithm::Crc32), "0d4a1185");
+        assert_eq!(
+            digest(HashAlgorithm::Md5),
+            "5eb63bbbe01eeed093cb22bb8f5acdc3"
+        );
+        assert_eq!(
+            digest(HashAlgorithm::Sha1),
+            "2aae6c35c94fcfb415dbe95f408b9ce91ee846ed"
+        );
+        assert_eq!(
+            digest(HashAlgorithm::Sha256),
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
+    }
+
+    #[test]
+    fn sfv_entries() {
+        let sfv = "; Generated by some tool\r\n\
+                   release.r00 0D4A1185\r\n\
+                   with space.bin deadbeef\r\n\
+                   \r\n\
+                   broken line\r\n";
+        assert_eq!(
+            parse_sfv(sfv),
+            vec![
+                ("release.r00".to_string(), 0x0d4a1185),
+                ("with space.bin".to_string(), 0xdeadbeef),
+            ]
+        );
+    }
+}
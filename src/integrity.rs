@@ -0,0 +1,144 @@
+//! Checks of the downloads we know about against the download folder, for files deleted or
+//! changed behind our back.
+
+use crate::queue_store::QueueStore;
+use crate::server::ServerId;
+use crate::{dcc, DownloadId, DownloadItem, DownloadStatus};
+use serde::Serialize;
+use std::path::Path;
+use utoipa::ToSchema;
+
+/// How a download does not match the download folder.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum Inconsistency {
+    /// The file of a completed download is gone
+    MissingFile,
+    /// The file of a completed download is smaller than what was received
+    SizeMismatch,
+    /// A transfer interrupted by a restart has no partial file to continue, it starts over
+    MissingPart,
+}
+
+/// A download not matching the download folder.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq, ToSchema)]
+pub struct Finding {
+    pub server: ServerId,
+    pub id: DownloadId,
+    pub file_name: String,
+    pub inconsistency: Inconsistency,
+}
+
+impl Finding {
+    pub fn of(item: &DownloadItem, inconsistency: Inconsistency) -> Self {
+        Self {
+            server: item.server.clone(),
+            id: item.id,
+            file_name: item.file_name.clone(),
+            inconsistency,
+        }
+    }
+}
+
+/// What is wrong with the file of a completed download, if anything. Files that cannot be read
+/// are not judged.
+pub fn check_completed(item: &DownloadItem) -> Option<Inconsistency> {
+    let DownloadStatus::Completed { bytes, path, .. } = &item.status else {
+        return None;
+    };
+    match std::fs::metadata(path) {
+        Ok(metadata) => (metadata.len() < *bytes).then_some(Inconsistency::SizeMismatch),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Some(Inconsistency::MissingFile),
+        Err(err) => {
+            log::warn!("Could not check {}: {}", path.display(), err);
+            None
+        }
+    }
+}
+
+/// Saved transfers whose partial file is gone start over, they are saved as requested. Safe to
+/// do without asking, there is nothing left to continue. Returns them.
+pub fn check_saved(queue: &QueueStore, folder: &Path, part_suffix: &str) -> Vec<Finding> {
+    queue
+        .restart_interrupted(|download| {
+            !dcc::part_path(&folder.join(&download.file_name), part_suffix).exists()
+        })
+        .into_iter()
+        .map(|download| Finding {
+            server: download.server,
+            id: download.id,
+            file_name: download.file_name,
+            inconsistency: Inconsistency::MissingPart,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn completed(folder: &Path, id: DownloadId, bytes: u64) -> DownloadItem {
+        let file_name = format!("file{}.mkv", id);
+        DownloadItem {
+            status: DownloadStatus::Completed {
+                finished_at: chrono::Utc::now(),
+                bytes,
+                path: folder.join(&file_name),
+            },
+            ..DownloadItem::new(
+                id,
+                "irc.example.org".to_string(),
+                file_name,
+                "Bot".to_string(),
+                format!("xdcc send #{}", id),
+            )
+        }
+    }
+
+    fn temp_folder(name: &str) -> PathBuf {
+        let folder =
+            std::env::temp_dir().join(format!("irc-dl-integrity-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        folder
+    }
+
+    #[test]
+    fn completed_files_checked() {
+        let folder = temp_folder("completed");
+        std::fs::write(folder.join("file1.mkv"), "complete").unwrap();
+        std::fs::write(folder.join("file2.mkv"), "cut").unwrap();
+
+        let intact = check_completed(&completed(&folder, 1, 8));
+        let truncated = check_completed(&completed(&folder, 2, 8));
+        let deleted = check_completed(&completed(&folder, 3, 8));
+        std::fs::remove_dir_all(&folder).unwrap();
+        assert_eq!(intact, None);
+        assert_eq!(truncated, Some(Inconsistency::SizeMismatch));
+        assert_eq!(deleted, Some(Inconsistency::MissingFile));
+    }
+
+    #[test]
+    fn transfers_without_part_start_over() {
+        let folder = temp_folder("saved");
+        std::fs::write(folder.join("file1.mkv.part"), "partial").unwrap();
+        let queue = QueueStore::load(folder.join("downloads.json")).unwrap();
+        let transfer = |id| DownloadItem {
+            status: DownloadStatus::Connecting,
+            ..completed(&folder, id, 0)
+        };
+        queue.update("irc.example.org", &[transfer(1), transfer(2)]);
+
+        let findings = check_saved(&queue, &folder, ".part");
+        let again = check_saved(&queue, &folder, ".part");
+        std::fs::remove_dir_all(&folder).unwrap();
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].id, 2);
+        assert_eq!(findings[0].inconsistency, Inconsistency::MissingPart);
+        let restored = queue.downloads("irc.example.org");
+        // Continued, with a notice telling of the restart
+        assert!(restored[0].notice.is_some());
+        assert!(restored[1].notice.is_none());
+        assert!(again.is_empty());
+    }
+}
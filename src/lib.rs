@@ -0,0 +1,645 @@
+//! XDCC downloads over IRC.
+//!
+//! [`downloader::Downloader`] transfers packs without any of the web interface, [`server`] manages
+//! connections to IRC servers, [`handler`] acts on the messages they send and [`dcc`] implements
+//! the DCC protocol itself.
+
+pub mod dcc;
+pub mod downloader;
+pub mod handler;
+pub mod hash;
+pub mod integrity;
+pub mod queue_store;
+pub mod schedule;
+pub mod server;
+pub mod stats;
+
+use crate::hash::FileDigest;
+use crate::server::ServerId;
+use anyhow::bail;
+use futures_util::stream::{AbortHandle, AbortRegistration};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::num::NonZeroU64;
+use std::path::PathBuf;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use tokio::time::Instant;
+use utoipa::ToSchema;
+
+lazy_static! {
+    pub static ref REX_SEARCH: Regex = Regex::new(
+        r"(?P<filename>[[:word:][:punct:]]+)\s+(?:.\s+)+(?i)/msg\s+(?P<nick>[^\s]+)\s+(?P<command>xdcc\s+send\s+#?\d+)"
+    )
+    .expect("Valid regex");
+    /// Announcement formats of search results, tried in order. The anchored pack-first format
+    /// comes before the default one, which would match its lines as well.
+    pub static ref SEARCH_PATTERNS: Vec<(&'static str, Regex)> = vec![
+        (
+            "pack-first",
+            Regex::new(
+                r"(?i)^\s*\(?#(?P<pack>\d+)\)?\s+(?:\d+x\s+)?\[?\s*[\d.]+\s*[KMGT]i?B?\]?\s+(?P<filename>\S+)\s+-\s+/msg\s+(?P<nick>\S+)\s+(?P<command>xdcc\s+send\s+#?\d+)"
+            )
+            .expect("Valid regex"),
+        ),
+        ("default", REX_SEARCH.clone()),
+        (
+            "command-first",
+            Regex::new(
+                r"(?i)/msg\s+(?P<nick>\S+)\s+(?P<command>xdcc\s+send\s+#?\d+)\W+(?:\d+x\W+)?(?:[\d.]+\s*[KMGT]i?B?\W+)?(?P<filename>[[:word:]][[:word:][:punct:]]*\.[[:alnum:]]{2,4})"
+            )
+            .expect("Valid regex"),
+        ),
+    ];
+    /// Notices of search bots telling all results were sent, e.g. "42 results found" or
+    /// "No matches found".
+    static ref REX_SEARCH_END: Regex = Regex::new(
+        r"(?i)^\W*(?:(?:\d+|no)\s+(?:results?|matches|packs?|files?)\s+(?:were\s+)?(?:found|returned|shown)|(?:found|returned)\s+\d+\s+(?:results?|matches|packs?|files?)|search\s+(?:complete|finished|done|ended))\b"
+    )
+    .expect("Valid regex");
+    static ref REX_NICK: Regex =
+        Regex::new(r"^[A-Za-z\[\]\\`_^{|}][A-Za-z0-9\[\]\\`_^{|}-]*$").expect("Valid regex");
+}
+
+/// Longer lines are cut before matching, no sane announcement is that long.
+const MAX_SEARCH_LINE: usize = 512;
+
+pub struct SearchMatch<'a> {
+    pub pattern: &'static str,
+    /// The line matched, cut to its maximum length
+    pub line: &'a str,
+    pub file_name: &'a str,
+    pub nick: &'a str,
+    pub command: &'a str,
+}
+
+/// Whether a notice ends the results of a search.
+pub fn is_search_end(notice: &str) -> bool {
+    REX_SEARCH_END.is_match(notice)
+}
+
+pub fn parse_search_line(line: &str) -> Option<SearchMatch<'_>> {
+    let line = match line.char_indices().nth(MAX_SEARCH_LINE) {
+        Some((end, _)) => &line[..end],
+        None => line,
+    };
+    SEARCH_PATTERNS.iter().find_map(|(pattern, regex)| {
+        let captures = regex.captures(line)?;
+        Some(SearchMatch {
+            pattern: *pattern,
+            line,
+            file_name: captures.name("filename")?.as_str(),
+            nick: captures.name("nick")?.as_str(),
+            command: captures.name("command")?.as_str(),
+        })
+    })
+}
+
+pub type DownloadId = usize;
+
+#[derive(Serialize, Clone, Debug, Hash, ToSchema)]
+pub struct DownloadItem {
+    pub id: DownloadId,
+    pub server: ServerId,
+    #[serde(rename = "fileName")]
+    pub file_name: String,
+    pub nick: String,
+    pub status: DownloadStatus,
+    #[serde(skip)]
+    pub request_command: String,
+    /// Last notice of the bot explaining the status
+    pub notice: Option<String>,
+    /// Digest of the completed file, if hashing is configured
+    pub digest: Option<FileDigest>,
+    /// Place in the queue of the bot, once it told us
+    pub queue: Option<QueuePosition>,
+    /// Place in our own queue of the bot while queued, 1 being requested next
+    pub position: Option<usize>,
+    /// Address we asked the bot to connect to, for passive transfers
+    #[schema(value_type = Option<String>)]
+    pub advertised_address: Option<SocketAddr>,
+    /// Token of the DCC SEND the download was offered with
+    #[serde(skip)]
+    pub dcc_token: Option<usize>,
+    /// Times the download was requested again after failing
+    pub retries: u32,
+    /// Further bots offering the file, tried in turn while the current one has no free slots
+    pub alternatives: Vec<DownloadSource>,
+    /// Limit of the transfer in bytes per second, instead of the configured one
+    pub max_bytes_per_sec: Option<u64>,
+}
+
+/// A bot to request a file from and how.
+#[derive(Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq, ToSchema)]
+pub struct DownloadSource {
+    pub nick: String,
+    pub command: String,
+}
+
+#[derive(Serialize, Clone, Debug, Hash, ToSchema)]
+pub struct QueuePosition {
+    pub position: usize,
+    /// Length of the whole queue, if the bot says
+    pub length: Option<usize>,
+    /// Estimated seconds until the transfer starts, once the queue was seen moving
+    pub eta_secs: Option<u64>,
+    /// Where and when we first learned our position
+    #[serde(skip)]
+    pub first_position: usize,
+    #[serde(skip)]
+    pub first_seen: Instant,
+}
+
+impl QueuePosition {
+    pub fn new(position: usize, length: Option<usize>) -> Self {
+        Self {
+            position,
+            length,
+            eta_secs: None,
+            first_position: position,
+            first_seen: Instant::now(),
+        }
+    }
+
+    /// Takes note of an updated position. The time the queue took per position so far gives
+    /// the estimate for the positions left.
+    pub fn update(&mut self, position: usize, length: Option<usize>) {
+        if position > self.first_position {
+            // Pushed back, e.g. by the bot prioritizing others. Start observing anew.
+            *self = Self::new(position, length);
+            return;
+        }
+        self.position = position;
+        self.length = length;
+        let advanced = self.first_position - position;
+        self.eta_secs = (advanced > 0).then(|| {
+            let per_position = self.first_seen.elapsed().as_secs_f64() / advanced as f64;
+            (per_position * position as f64).round() as u64
+        });
+    }
+}
+
+impl DownloadItem {
+    pub fn new(
+        id: DownloadId,
+        server: ServerId,
+        file_name: String,
+        nick: String,
+        request_command: String,
+    ) -> Self {
+        Self {
+            id,
+            server,
+            file_name,
+            nick,
+            status: DownloadStatus::Requested,
+            request_command,
+            notice: None,
+            digest: None,
+            queue: None,
+            position: None,
+            advertised_address: None,
+            dcc_token: None,
+            retries: 0,
+            alternatives: Vec::new(),
+            max_bytes_per_sec: None,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, ToSchema)]
+pub struct DownloadProgress {
+    /// Updated by the transfer directly, so progress doesn't need to touch the downloads map
+    #[serde(serialize_with = "serialize_counter")]
+    #[schema(value_type = u64)]
+    pub transferred: Arc<AtomicU64>,
+    #[schema(value_type = Option<u64>)]
+    pub file_size: Option<NonZeroU64>,
+    /// Throughput over the last seconds, updated like `transferred`
+    #[serde(serialize_with = "serialize_counter")]
+    #[schema(value_type = u64)]
+    pub bytes_per_sec: Arc<AtomicU64>,
+    /// Seconds left at that throughput, 0 while unknown as the file size or throughput is
+    #[serde(serialize_with = "serialize_eta")]
+    #[schema(value_type = Option<u64>)]
+    pub eta_seconds: Arc<AtomicU64>,
+    #[serde(skip)]
+    pub cancellation: Cancellation,
+}
+
+/// Hashes the values the counters hold, so a changed progress tells in the hash of the download
+impl Hash for DownloadProgress {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.transferred.load(Ordering::Relaxed).hash(state);
+        self.file_size.hash(state);
+        self.bytes_per_sec.load(Ordering::Relaxed).hash(state);
+        self.eta_seconds.load(Ordering::Relaxed).hash(state);
+    }
+}
+
+/// Why a download was cancelled.
+#[derive(Serialize, Clone, Copy, Debug, Hash, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum CancelReason {
+    UserRequest,
+    Stall,
+    Shutdown,
+    Disk,
+    MinSpeed,
+    /// Paused, to be continued later
+    Pause,
+}
+
+/// Cancels a running transfer, keeping the reason for it. The first reason given wins.
+#[derive(Clone, Debug)]
+pub struct Cancellation {
+    handle: AbortHandle,
+    reason: Arc<Mutex<Option<CancelReason>>>,
+}
+
+impl Cancellation {
+    pub fn new_pair() -> (Self, AbortRegistration) {
+        let (handle, registration) = AbortHandle::new_pair();
+        let cancellation = Self {
+            handle,
+            reason: Default::default(),
+        };
+        (cancellation, registration)
+    }
+
+    pub fn cancel(&self, reason: CancelReason) {
+        self.reason.lock().unwrap().get_or_insert(reason);
+        self.handle.abort();
+    }
+
+    pub fn reason(&self) -> Option<CancelReason> {
+        *self.reason.lock().unwrap()
+    }
+}
+
+#[derive(Serialize, Clone, Debug, Hash, ToSchema)]
+pub enum DownloadStatus {
+    Requested,
+    SenderAbsent,
+    Delayed(#[serde(skip)] Instant),
+    /// Failed, to be requested again at the instant as the retry policy says
+    Retrying(#[serde(skip)] Instant),
+    Progress(DownloadProgress),
+    Failed(String),
+    Connecting,
+    /// Held back locally, because the bot would not accept more requests
+    Queued,
+    /// Waiting in the queue of the bot
+    RemoteQueued {
+        position: usize,
+        /// Length of the queue, if the bot says
+        total: Option<usize>,
+        /// Seconds until the transfer starts, as the bot says or estimated from the queue moving
+        eta_secs: Option<u64>,
+    },
+    /// Offered by the bot, waiting for one of our other transfers to end
+    Waiting,
+    /// Another instance is transferring the same file
+    Conflict(String),
+    /// Transfer stopped on request, the partial file is kept to continue from
+    Paused,
+    Aborted {
+        reason: CancelReason,
+    },
+    Completed {
+        #[schema(value_type = String)]
+        finished_at: chrono::DateTime<chrono::Utc>,
+        /// Size of the file
+        bytes: u64,
+        /// Where the file was written, another name than offered if that was taken
+        #[schema(value_type = String)]
+        path: PathBuf,
+    },
+}
+
+fn serialize_counter<S: serde::Serializer>(
+    counter: &Arc<AtomicU64>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(counter.load(Ordering::Relaxed))
+}
+
+fn serialize_eta<S: serde::Serializer>(
+    eta_seconds: &Arc<AtomicU64>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match eta_seconds.load(Ordering::Relaxed) {
+        0 => serializer.serialize_none(),
+        eta_seconds => serializer.serialize_some(&eta_seconds),
+    }
+}
+
+impl DownloadStatus {
+    /// No further transfer happens for downloads in this status.
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            DownloadStatus::Failed(_)
+                | DownloadStatus::Conflict(_)
+                | DownloadStatus::Aborted { .. }
+                | DownloadStatus::Completed { .. }
+        )
+    }
+
+    /// The download ended without the file, or the bot was not there. It may be requested again.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            DownloadStatus::SenderAbsent => true,
+            DownloadStatus::Completed { .. } => false,
+            status => status.is_terminal(),
+        }
+    }
+}
+
+#[derive(Serialize, Default, Clone, Debug, ToSchema)]
+pub struct SearchResult {
+    pub server: ServerId,
+    #[serde(rename = "fileName")]
+    pub file_name: String,
+    pub nick: String,
+    pub command: String,
+    /// Announcement the result was parsed from, formatting stripped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw: Option<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use irc::proto::FormattedStringExt;
+
+    #[test]
+    fn sanitized_file_names() {
+        assert_eq!(sanitize_file_name("show.mkv").unwrap(), "show.mkv");
+        assert_eq!(
+            sanitize_file_name("\"file with spaces.mkv\"").unwrap(),
+            "file with spaces.mkv"
+        );
+        for traversal in [
+            "../../.ssh/authorized_keys",
+            "..\\..\\.ssh\\authorized_keys",
+            "/home/user/.ssh/authorized_keys",
+        ] {
+            assert_eq!(sanitize_file_name(traversal).unwrap(), "authorized_keys");
+        }
+        assert_eq!(sanitize_file_name("C:evil.exe").unwrap(), "C_evil.exe");
+        assert_eq!(
+            sanitize_file_name("C:\\Windows\\evil.exe").unwrap(),
+            "evil.exe"
+        );
+        for refused in [
+            "..", "...", "/", "\"\"", "dir/..", "a\nb.mkv", "con", "NUL.txt", "com1.mkv",
+        ] {
+            assert!(sanitize_file_name(refused).is_err(), "{:?}", refused);
+        }
+    }
+
+    #[test]
+    fn search_result1() {
+        let input = "\u{3}00,01\u{2}058\u{3}15\u{2})\u{3}10  10x\u{3}04\u{2} |\u{3}10\u{2} 7.5G\u{3}04\u{2} |\u{3}10\u{2} Something.Something-I-dont-really-know.2022.German.DTS.DL.720p.BluRay.x264-JJ.mkv\u{3}04\u{2} |\u{3}09\u{2} /MSG [AA]-DEMO|EU|S|DOESNOTEXIST XDCC SEND 90 \u{3}04\u{2}|\u{2}\u{3}00 Used: 11.53% 29/15 avg: 1.71TiB/s (113328s ago)\u{3}04 ".strip_formatting();
+
+        let capture = REX_SEARCH.captures(&input).unwrap();
+        itertools::assert_equal(
+            capture.iter().skip(1).flatten().map(|i| i.as_str()),
+            [
+                "Something.Something-I-dont-really-know.2022.German.DTS.DL.720p.BluRay.x264-JJ.mkv",
+                "[AA]-DEMO|EU|S|DOESNOTEXIST",
+                "XDCC SEND 90",
+            ],
+        );
+        assert!(capture.name("filename").is_some());
+        assert!(capture.name("nick").is_some());
+        assert!(capture.name("command").is_some());
+        assert_eq!(parse_search_line(&input).unwrap().pattern, "default");
+    }
+
+    #[test]
+    fn search_result_pack_first() {
+        let found =
+            parse_search_line("(#1234) 4.3G SomeFile.mkv - /msg Bot xdcc send 1234").unwrap();
+        assert_eq!(found.pattern, "pack-first");
+        assert_eq!(found.file_name, "SomeFile.mkv");
+        assert_eq!(found.nick, "Bot");
+        assert_eq!(found.command, "xdcc send 1234");
+    }
+
+    #[test]
+    fn search_result_command_first() {
+        for input in [
+            "/msg Bot xdcc send #5 [1.2G] Some.File.S01E01.mkv",
+            "/MSG Bot XDCC SEND 5 | 1.2G | Some.File.S01E01.mkv",
+        ] {
+            let found = parse_search_line(input).unwrap();
+            assert_eq!(found.pattern, "command-first");
+            assert_eq!(found.file_name, "Some.File.S01E01.mkv");
+            assert_eq!(found.nick, "Bot");
+        }
+    }
+
+    #[test]
+    fn search_end() {
+        for notice in [
+            "42 results found",
+            "** 3 packs found, showing all",
+            "No matches found for \"foo\"",
+            "Found 7 results",
+            "Search complete.",
+        ] {
+            assert!(is_search_end(notice), "{}", notice);
+        }
+        for notice in [
+            "#12 4x [1.2G] Found.10.Results.mkv",
+            "Searching for foo...",
+            "Some.File.mkv - /msg Bot xdcc send #5 - 12 files found in total",
+        ] {
+            assert!(!is_search_end(notice), "{}", notice);
+        }
+    }
+
+    #[test]
+    fn search_result_pathological_line() {
+        let input = format!("{} /msg", "a ".repeat(100_000));
+        assert!(parse_search_line(&input).is_none());
+    }
+
+    #[test]
+    fn search_result2() {
+        let input = "\u{3}03(\u{3} 0x \u{3}03[\u{3}001.7G\u{3}03]\u{2} I-cant-believe-this.S01E07.1080p.HEVC.x265-noooaa.mkv \u{2}) (\u{3} /msg IDONOTCAREWHATYOURNAMEIS xdcc send #13384 \u{3}03) (\u{3} Used:\u{3}03 1/10 \u{3}Avg: \u{3}991034.62MB/s )".strip_formatting();
+        eprintln!("{}", input);
+
+        let capture = REX_SEARCH.captures(&input).unwrap();
+        itertools::assert_equal(
+            capture.iter().skip(1).flatten().map(|i| i.as_str()),
+            [
+                "I-cant-believe-this.S01E07.1080p.HEVC.x265-noooaa.mkv",
+                "IDONOTCAREWHATYOURNAMEIS",
+                "xdcc send #13384",
+            ],
+        );
+        assert!(capture.name("filename").is_some());
+        assert!(capture.name("nick").is_some());
+        assert!(capture.name("command").is_some());
+        assert_eq!(parse_search_line(&input).unwrap().pattern, "default");
+    }
+
+    #[test]
+    fn file_names_as_rewritten_by_bots() {
+        let advertised = "Some Show S01E01 1080p.mkv";
+        assert!(same_file_name(advertised, "Some.Show.S01E01.1080p.mkv"));
+        assert!(same_file_name(advertised, "Some_Show_S01E01_1080p.mkv"));
+        assert!(same_file_name(advertised, "some.show.s01e01.1080P.MKV"));
+        assert!(same_file_name(
+            "[Group] Anime - 01 [720p].mkv",
+            "[Group]_Anime_-_01_[720p].mkv"
+        ));
+        assert!(same_file_name("Ünïcode Fïle.epub", "ünïcode_fïle.EPUB"));
+        assert!(!same_file_name(advertised, "Some Show S01E02 1080p.mkv"));
+        assert!(!same_file_name(advertised, "SomeShow S01E01 1080p.mkv"));
+        assert!(!same_file_name("a-b.mkv", "a.b.mkv"));
+    }
+
+    #[test]
+    fn irc_injection_rejected() {
+        assert!(check_irc_text("command", "xdcc send #1").is_ok());
+        assert!(check_irc_text("command", "xdcc send #1\r\nQUIT :bye").is_err());
+        assert!(check_irc_text("command", "xdcc send #1\nQUIT").is_err());
+        assert!(check_irc_text("command", "xdcc\0send").is_err());
+
+        assert!(check_nick("[Bot]-01|x", DEFAULT_MAX_NICK_LEN).is_ok());
+        assert!(check_nick("Bot\r\nQUIT", DEFAULT_MAX_NICK_LEN).is_err());
+        assert!(check_nick("Bot QUIT", DEFAULT_MAX_NICK_LEN).is_err());
+        assert!(check_nick("#channel", DEFAULT_MAX_NICK_LEN).is_err());
+        assert!(check_nick("1bot", DEFAULT_MAX_NICK_LEN).is_err());
+        assert!(check_nick("LongBotName", 9).is_err());
+    }
+}
+
+/// Comparison using the IRC casemapping (rfc1459), where `[]\` are the upper case of `{}|`.
+pub trait IrcCase {
+    type Other: ?Sized;
+
+    fn eq_ignore_irc_case(&self, other: &Self::Other) -> bool;
+}
+
+impl IrcCase for [u8] {
+    type Other = [u8];
+
+    fn eq_ignore_irc_case(&self, other: &Self::Other) -> bool {
+        self.len() == other.len()
+            && std::iter::zip(self, other).all(|(a, b)| {
+                a.eq_ignore_ascii_case(b)
+                    || matches!(
+                        (a, b),
+                        (b'{', b'[')
+                            | (b'[', b'{')
+                            | (b'}', b']')
+                            | (b']', b'}')
+                            | (b'\\', b'|')
+                            | (b'|', b'\\')
+                    )
+            })
+    }
+}
+
+impl IrcCase for String {
+    type Other = str;
+    fn eq_ignore_irc_case(&self, other: &Self::Other) -> bool {
+        self.as_bytes().eq_ignore_irc_case(other.as_bytes())
+    }
+}
+
+impl IrcCase for str {
+    type Other = str;
+    fn eq_ignore_irc_case(&self, other: &Self::Other) -> bool {
+        self.as_bytes().eq_ignore_irc_case(other.as_bytes())
+    }
+}
+
+/// Whether two names are of the same file, as bots rewrite them: ignoring case and taking ` `,
+/// `.` and `_` as the same, so `Some Show S01E01.mkv` is `Some_Show_S01E01.mkv`.
+pub fn same_file_name(a: &str, b: &str) -> bool {
+    let normalized = |name: &str| {
+        name.chars()
+            .map(|c| match c {
+                ' ' | '_' => '.',
+                c => c,
+            })
+            .flat_map(char::to_lowercase)
+            .collect::<String>()
+    };
+    normalized(a) == normalized(b)
+}
+
+/// Nick length allowed when the server did not announce its NICKLEN.
+pub const DEFAULT_MAX_NICK_LEN: usize = 32;
+
+/// Checks user supplied text before it ends up in an IRC message. Line breaks and other control
+/// characters would allow sending arbitrary commands over our connection.
+pub fn check_irc_text(field: &str, value: &str) -> anyhow::Result<()> {
+    if value.chars().any(char::is_control) {
+        bail!("{} must not contain control characters", field);
+    }
+    Ok(())
+}
+
+/// Names Windows reserves for devices, with any extension.
+const RESERVED_FILE_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Turns a file name a remote user sent into one that can only end up inside the download
+/// folder: quotes and directory components are stripped, characters Windows does not allow in
+/// names are replaced. Names that are empty, `..`, contain control characters or are reserved
+/// are refused.
+pub fn sanitize_file_name(name: &str) -> anyhow::Result<String> {
+    if name.chars().any(char::is_control) {
+        bail!("File name {:?} contains control characters", name);
+    }
+    let unquoted = name.trim().trim_matches('"');
+    let base = unquoted.rsplit(['/', '\\']).next().unwrap_or_default();
+    let sanitized: String = base
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c => c,
+        })
+        .collect();
+    // Windows drops trailing dots and spaces, which would turn `...` into `..`
+    let sanitized = sanitized.trim_end_matches(['.', ' ']).to_string();
+    if sanitized.is_empty() || sanitized == "." || sanitized == ".." {
+        bail!("{:?} is not a usable file name", name);
+    }
+    let stem = sanitized.split('.').next().unwrap_or_default();
+    if RESERVED_FILE_NAMES
+        .iter()
+        .any(|reserved| stem.trim_end().eq_ignore_ascii_case(reserved))
+    {
+        bail!("{:?} is a reserved file name", name);
+    }
+    if sanitized != unquoted {
+        log::warn!("Sanitized file name {:?} to {:?}", name, sanitized);
+    }
+    Ok(sanitized)
+}
+
+/// Checks a nick against the grammar of RFC 2812, with the length the server allows.
+pub fn check_nick(nick: &str, max_len: usize) -> anyhow::Result<()> {
+    if !REX_NICK.is_match(nick) {
+        bail!("{:?} is not a valid nick", nick);
+    }
+    if nick.len() > max_len {
+        bail!("Nick {:?} is longer than {} characters", nick, max_len);
+    }
+    Ok(())
+}
@@ -1,522 +1,3724 @@
-mod dcc;
-mod server;
-
-use crate::dcc::DccSend;
-use crate::server::{ServerConfig, ServerConnection, ServerId};
 use axum::{
     extract::{Path, Query, State},
-    http::StatusCode,
-    response::sse::{Event, KeepAlive, Sse},
+    http::{header, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
     routing::{delete, get, post},
     Json, Router,
 };
 use dashmap::DashMap;
-use futures_util::stream::{AbortHandle, Abortable, Aborted, FuturesUnordered};
+use futures_util::stream::FuturesUnordered;
 use irc::client::prelude::*;
-use irc::proto::FormattedStringExt;
-use irc::proto::Response::*;
-use lazy_static::lazy_static;
-use regex::Regex;
+use irc::client::ClientStream;
+use irc_downloader::dcc::{self, DownloadOptions, FailureKind};
+use irc_downloader::handler::{self, Host, RetryPolicy};
+use irc_downloader::hash::{FileDigest, HashAlgorithm};
+use irc_downloader::integrity::{self, Finding, Inconsistency};
+use irc_downloader::queue_store::QueueStore;
+use irc_downloader::schedule::{self, QuietHours};
+use irc_downloader::server::{self, ConnectionState, ServerConfig, ServerConnection, ServerId};
+use irc_downloader::stats::{BotStats, StatsStore};
+use irc_downloader::{
+    check_irc_text, check_nick, sanitize_file_name, CancelReason, DownloadId, DownloadItem,
+    DownloadProgress, DownloadSource, DownloadStatus, SearchResult, DEFAULT_MAX_NICK_LEN,
+};
 use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
 use std::convert::Infallible;
-use std::net::Ipv4Addr;
-use std::num::NonZeroUsize;
+use std::hash::{Hash, Hasher};
 use std::path::PathBuf;
 use std::sync::{
-    atomic::{AtomicUsize, Ordering},
-    Arc, Mutex,
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    Arc, Mutex, RwLock,
 };
-use tokio::sync::watch;
+use tokio::sync::{broadcast, mpsc, watch, Semaphore};
 use tokio::time::{Duration, Instant};
-use tokio_stream::{wrappers::WatchStream, StreamExt, StreamMap};
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream, WatchStream};
+use tokio_stream::{StreamExt, StreamMap};
+use tower_http::compression::CompressionLayer;
 use tower_http::services::ServeDir;
-
-lazy_static! {
-    pub static ref REX_SEARCH: Regex = Regex::new(
-        r"(?P<filename>[[:word:][:punct:]]+)\s+(?:.\s+)+(?i)/msg\s+(?P<nick>[^\s]+)\s+(?P<command>xdcc\s+send\s+#?\d+)"
-    )
-    .expect("Valid regex");
-}
+use utoipa::{OpenApi, ToSchema};
 
 #[derive(Deserialize, Serialize)]
 pub struct Configuration {
     servers: Vec<ServerConfig>,
     download_folder: PathBuf,
     port: u16,
+    /// Identifies this instance in lock files when sharing the download folder
+    instance_id: Option<String>,
+    /// Seconds after which a lock of an instance without progress is considered stale
+    #[serde(default = "default_stale_lock_secs")]
+    stale_lock_secs: u64,
+    /// Seconds a transfer may take, used as is for files of unknown size
+    transfer_timeout_secs: Option<u64>,
+    /// Minimum throughput in bytes per second, extends the transfer timeout of large files
+    min_throughput: Option<u64>,
+    /// Seconds a running transfer may receive nothing before it is aborted
+    stall_timeout_secs: Option<u64>,
+    /// Bytes per second below which a running transfer is aborted, over the last seconds
+    min_speed: Option<u64>,
+    /// Permissions of downloaded files (e.g. `0o664`), Unix only
+    file_mode: Option<u32>,
+    /// Uid of the owner of downloaded files, Unix only
+    file_owner: Option<u32>,
+    /// Gid of the group of downloaded files, Unix only
+    file_group: Option<u32>,
+    /// Local times in which requests are queued instead of sent
+    #[serde(default)]
+    quiet_hours: Vec<QuietHours>,
+    /// Also pause running transfers during quiet hours. They are resumed once they are over.
+    #[serde(default)]
+    pause_in_quiet_hours: bool,
+    /// Digest to compute over downloaded files
+    hash: Option<HashAlgorithm>,
+    /// Sync completed files to stable storage before declaring them complete
+    #[serde(default)]
+    fsync_on_complete: bool,
+    /// Read completed files back to check their length and digest
+    #[serde(default)]
+    verify_readback: bool,
+    /// Check completed files against .sfv files in the download folder
+    #[serde(default)]
+    check_sfv: bool,
+    /// Second folder completed files are copied or hard linked to
+    mirror: Option<dcc::Mirror>,
+    /// Local address DCC connections are made from, and passive ones are accepted on
+    dcc_source_address: Option<std::net::Ipv4Addr>,
+    /// Our public IPv4 address, told bots for passive transfers. Looked up if not given.
+    external_ip: Option<std::net::Ipv4Addr>,
+    /// Our public IPv6 address, told bots for passive transfers over IPv6. Looked up if not given.
+    external_ip_v6: Option<std::net::Ipv6Addr>,
+    /// Services answering with the IPv4 address asking, tried in order to look up ours
+    #[serde(default = "default_ip_lookup_services")]
+    ip_lookup_services: Vec<String>,
+    /// Seconds after which our address is looked up again, as residential ones change
+    #[serde(default = "default_ip_refresh_secs")]
+    ip_refresh_secs: u64,
+    /// Only use IPv4 for DCC, e.g. behind a NAT not forwarding IPv6
+    #[serde(default)]
+    ipv4_only: bool,
+    /// What to do when a requested or offered file is in the download folder already
+    #[serde(default, alias = "on_conflict")]
+    existing_files: dcc::ExistingFiles,
+    /// Regular removal of leftovers of failed transfers from the download folder
+    cleanup: Option<Cleanup>,
+    /// Seconds removed downloads can be restored, their partial files are kept until then
+    #[serde(default = "default_trash_window_secs")]
+    trash_window_secs: u64,
+    /// Times a failed download is requested again before it stays failed
+    #[serde(default)]
+    max_retries: u32,
+    /// Seconds before the first retry of a failed download, doubled for each further one
+    #[serde(default = "default_retry_backoff_secs")]
+    retry_backoff_secs: u64,
+    /// When a bot is on several servers, pick the one with the healthiest connection rather than
+    /// the first configured
+    #[serde(default)]
+    prefer_healthy: bool,
+    /// Where search channel and bot statistics are kept
+    #[serde(default = "default_stats_file")]
+    stats_file: PathBuf,
+    /// Where outstanding downloads are kept, to request them again after a restart
+    #[serde(default = "default_queue_file")]
+    queue_file: PathBuf,
+    /// Concurrent clients of /events, more are turned away
+    #[serde(default = "default_max_event_clients")]
+    max_event_clients: usize,
+    /// Events kept for /events clients falling behind before they miss some
+    #[serde(default = "default_event_buffer")]
+    event_buffer: usize,
+    /// Sockets open at once, IRC connections and DCC transfers together. Transfers wait for a
+    /// free one.
+    max_sockets: Option<usize>,
+    /// Transfers running at once, further offers wait for one to end
+    max_concurrent_downloads: Option<usize>,
+    /// Bytes per second of all transfers together
+    max_bytes_per_sec: Option<u64>,
+    /// Bytes per second of each transfer, unless requested otherwise
+    max_bytes_per_sec_per_download: Option<u64>,
+    /// `bits64` for senders expecting 64 bit acknowledgements. Servers can set it per bot with
+    /// `ack_widths`.
+    #[serde(default)]
+    ack_width: dcc::AckWidth,
+    /// Appended to the names of files being downloaded
+    #[serde(default = "default_part_suffix")]
+    part_suffix: String,
+    /// Keep the partial file of aborted downloads, to resume them later
+    #[serde(default = "default_keep_aborted_parts")]
+    keep_aborted_parts: bool,
+    /// Continue downloads a restart interrupted from their partial file, requesting them again
+    /// once connected. Otherwise they stay paused until resumed.
+    #[serde(default = "default_resume_parts")]
+    resume_parts: bool,
+    /// Megabytes to keep free on the volume of the download folder. Below that, requests are
+    /// queued until space is freed.
+    disk_reserve_mb: Option<u64>,
+    /// Also pause running transfers when free space drops below the reserve. They are resumed
+    /// once there is space again.
+    #[serde(default)]
+    pause_on_low_disk: bool,
+    /// URL a summary of each download is POSTed to as JSON when it completes, fails or is aborted
+    completion_webhook: Option<String>,
 }
 
-pub type DownloadId = usize;
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Cleanup {
+    /// Seconds between sweeps, the first one runs at startup
+    #[serde(default = "default_cleanup_interval_secs")]
+    interval_secs: u64,
+    /// Age in seconds after which `.part` files of no known download are removed
+    part_max_age_secs: Option<u64>,
+}
 
-#[derive(Serialize, Clone, Debug)]
-pub struct DownloadItem {
-    pub id: DownloadId,
-    pub server: ServerId,
-    #[serde(rename = "fileName")]
-    pub file_name: String,
-    pub nick: String,
-    pub status: DownloadStatus,
-    #[serde(skip)]
-    pub request_command: String,
+const CONFIG_FILE: &str = "config.toml";
+
+/// Settings that take effect when reloading the configuration. Changes of others need a restart,
+/// those of servers may reconnect them, see [`server::RECONNECT_SETTINGS`].
+const LIVE_SETTINGS: &[&str] = &[
+    "download_folder",
+    "port",
+    "instance_id",
+    "stale_lock_secs",
+    "transfer_timeout_secs",
+    "min_throughput",
+    "stall_timeout_secs",
+    "min_speed",
+    "file_mode",
+    "file_owner",
+    "file_group",
+    "hash",
+    "fsync_on_complete",
+    "verify_readback",
+    "check_sfv",
+    "mirror",
+    "dcc_source_address",
+    "trash_window_secs",
+    "max_retries",
+    "retry_backoff_secs",
+    "prefer_healthy",
+    "max_event_clients",
+    "ack_width",
+    "part_suffix",
+    "keep_aborted_parts",
+    "max_concurrent_downloads",
+    "max_bytes_per_sec",
+    "max_bytes_per_sec_per_download",
+    "existing_files",
+    "completion_webhook",
+];
+
+impl Configuration {
+    fn load(path: &std::path::Path) -> anyhow::Result<Self> {
+        let configuration: Configuration =
+            toml::from_str(std::str::from_utf8(&std::fs::read(path)?)?)?;
+        server::check_unique_ids(&configuration.servers)?;
+        for source_address in configuration
+            .servers
+            .iter()
+            .filter_map(|s| s.dcc_source_address)
+            .chain(configuration.dcc_source_address)
+        {
+            dcc::check_source_address(source_address)?;
+        }
+        for server in &configuration.servers {
+            server.restriction_patterns()?;
+        }
+        dcc::check_part_suffix(&configuration.part_suffix)?;
+        if let Some(cleanup) = &configuration.cleanup {
+            if cleanup.interval_secs == 0 {
+                anyhow::bail!("cleanup.interval_secs must be greater than 0");
+            }
+        }
+        if configuration.event_buffer == 0 {
+            anyhow::bail!("event_buffer must be at least 1");
+        }
+        Ok(configuration)
+    }
+
+    /// Options for transfers, without limits on sockets and concurrent transfers.
+    fn download_options(
+        &self,
+        myip: Option<std::net::Ipv4Addr>,
+        myip_v6: Option<std::net::Ipv6Addr>,
+    ) -> DownloadOptions {
+        DownloadOptions {
+            myip,
+            port: self.port,
+            download_folder: self.download_folder.clone(),
+            instance_id: self
+                .instance_id
+                .clone()
+                .unwrap_or_else(|| format!("pid-{}", std::process::id())),
+            stale_lock_after: Duration::from_secs(self.stale_lock_secs),
+            transfer_timeout: self.transfer_timeout_secs.map(Duration::from_secs),
+            min_throughput: self.min_throughput,
+            stall_timeout: self.stall_timeout_secs.map(Duration::from_secs),
+            min_speed: self.min_speed,
+            file_mode: self.file_mode,
+            file_owner: self.file_owner,
+            file_group: self.file_group,
+            hash: self.hash,
+            fsync_on_complete: self.fsync_on_complete,
+            verify_readback: self.verify_readback,
+            check_sfv: self.check_sfv,
+            mirror: self.mirror.clone(),
+            source_address: self.dcc_source_address,
+            myip_v6,
+            ipv4_only: self.ipv4_only,
+            resumes: Default::default(),
+            socket_limit: None,
+            transfer_limit: None,
+            ack_width: self.ack_width,
+            part_suffix: self.part_suffix.clone(),
+            existing_files: self.existing_files,
+            bandwidth: self
+                .max_bytes_per_sec
+                .map(|rate| Arc::new(dcc::Throttle::new(rate))),
+            max_bytes_per_sec: self.max_bytes_per_sec_per_download,
+            disk_reserve: self.disk_reserve_mb.map_or(0, |mb| mb * 1024 * 1024),
+        }
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: self.max_retries,
+            backoff: Duration::from_secs(self.retry_backoff_secs),
+        }
+    }
+}
+
+fn default_cleanup_interval_secs() -> u64 {
+    3600
+}
+
+fn default_part_suffix() -> String {
+    ".part".to_string()
+}
+
+fn default_keep_aborted_parts() -> bool {
+    true
+}
+
+fn default_resume_parts() -> bool {
+    true
+}
+
+fn default_ip_lookup_services() -> Vec<String> {
+    vec![
+        "https://api.ipify.org/".to_string(),
+        "https://ifconfig.me/ip".to_string(),
+        "https://icanhazip.com/".to_string(),
+    ]
+}
+
+fn default_ip_refresh_secs() -> u64 {
+    3600
+}
+
+fn default_trash_window_secs() -> u64 {
+    600
+}
+
+fn default_retry_backoff_secs() -> u64 {
+    30
+}
+
+fn default_stale_lock_secs() -> u64 {
+    600
+}
+
+fn default_stats_file() -> PathBuf {
+    PathBuf::from("stats.json")
+}
+
+fn default_queue_file() -> PathBuf {
+    PathBuf::from("downloads.json")
 }
 
-#[derive(Serialize, Clone, Debug)]
-pub struct DownloadProgress {
-    pub transferred: usize,
-    pub file_size: Option<NonZeroUsize>,
-    #[serde(skip)]
-    pub abort_handle: AbortHandle,
+fn default_max_event_clients() -> usize {
+    32
 }
 
-#[derive(Serialize, Clone, Debug)]
-pub enum DownloadStatus {
-    Requested,
-    SenderAbsent,
-    Delayed(#[serde(skip)] Instant),
-    Progress(DownloadProgress),
-    Failed(String),
-    Connecting,
+fn default_event_buffer() -> usize {
+    256
 }
 
+/// Seconds a client turned away from /events should wait
+const EVENT_RETRY_AFTER: &str = "10";
+
 #[derive(Deserialize)]
 pub struct AbortDownloadRequest {
     pub id: DownloadId,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct DownloadRequest {
-    pub server: ServerId,
+    /// Label or URL of the server. Without server, the bot is looked for on all connected servers
+    pub server: Option<ServerId>,
     #[serde(rename = "fileName")]
     pub file_name: String,
     pub nick: String,
     pub command: String,
+    /// Size of the file, to tell whether one present already is complete
+    pub size: Option<u64>,
+    /// Digest of the file, to tell whether one present already is the same
+    pub digest: Option<FileDigest>,
+    /// Further bots on the server offering the file, asked when the bot has no free slots
+    #[serde(default)]
+    pub alternatives: Vec<DownloadSource>,
+    /// Bytes per second to limit the transfer to, instead of the configured limit
+    pub max_bytes_per_sec: Option<u64>,
 }
 
-#[derive(Serialize, Default, Clone)]
-pub struct SearchResult {
-    pub server: ServerId,
-    #[serde(rename = "fileName")]
-    pub file_name: String,
-    pub nick: String,
-    pub command: String,
+#[derive(Serialize, Debug, ToSchema)]
+pub struct RequestedDownload {
+    pub id: DownloadId,
+    /// The file was present already, so it was not requested from the bot
+    pub already_present: bool,
 }
 
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Clone, ToSchema)]
 pub struct MessageDto {
     pub prefix: String,
     pub message: String,
 }
 
-#[derive(Serialize, Default, Clone)]
+impl From<&Message> for MessageDto {
+    fn from(msg: &Message) -> Self {
+        MessageDto {
+            prefix: msg
+                .prefix
+                .as_ref()
+                .map(|p| format!("{:?}", p))
+                .unwrap_or_else(|| "".to_string()),
+            message: format!("{:?}", msg.command),
+        }
+    }
+}
+
+#[derive(Default)]
 pub struct Search {
     results: Vec<SearchResult>,
+    /// Command the latest search was sent with
+    command_template: String,
+    /// Progress of the latest search on each server it was sent to
+    servers: HashMap<ServerId, ServerSearch>,
+}
+
+struct ServerSearch {
+    started: Instant,
+    first_result: Option<Duration>,
+    /// When the search was sent or the latest result came in
+    last_activity: Instant,
+    results: usize,
+    /// Bots that sent results, in lower case
+    bots: HashSet<String>,
+    /// Bots that said all their results were sent, in lower case
+    ended: HashSet<String>,
+    /// Time without results after which the server is done
+    idle: Duration,
+    /// Time the server gets at most
+    timeout: Duration,
+}
+
+impl Search {
+    /// Adds a result unless the same pack was found before. Returns whether it was new.
+    fn add_result(&mut self, result: SearchResult) -> bool {
+        let known = self.results.iter().any(|known| {
+            known.server == result.server
+                && known.nick.eq_ignore_ascii_case(&result.nick)
+                && known.command.eq_ignore_ascii_case(&result.command)
+        });
+        if !known {
+            self.results.push(result);
+        }
+        !known
+    }
+}
+
+impl ServerSearch {
+    fn new(server: &ServerConnection) -> Self {
+        let now = Instant::now();
+        Self {
+            started: now,
+            first_result: None,
+            last_activity: now,
+            results: 0,
+            bots: HashSet::new(),
+            ended: HashSet::new(),
+            idle: server.search_idle,
+            timeout: server.search_timeout,
+        }
+    }
+
+    /// When the server would be done without further results.
+    fn quiet_at(&self) -> Instant {
+        let idle = if self.results > 0 {
+            self.idle
+        } else {
+            self.idle.max(SEARCH_FIRST_RESULT)
+        };
+        self.last_activity + idle
+    }
+
+    /// Whether every bot that sent results said it was done.
+    fn ended(&self) -> bool {
+        !self.ended.is_empty() && self.bots.is_subset(&self.ended)
+    }
+
+    /// When the server is done searching unless more results come in, `None` if it is done.
+    fn pending_until(&self) -> Option<Instant> {
+        let until = self.quiet_at().min(self.started + self.timeout);
+        (!self.ended() && until > Instant::now()).then_some(until)
+    }
+
+    /// Whether the server was still sending results when its time was up.
+    fn timed_out(&self) -> bool {
+        !self.ended() && self.quiet_at() > self.started + self.timeout
+    }
+}
+
+/// Time for the first result to come in, bots may take a moment to search
+const SEARCH_FIRST_RESULT: Duration = Duration::from_secs(3);
+
+/// An event for /events clients, with its data as JSON.
+#[derive(Clone)]
+struct SseEvent {
+    name: &'static str,
+    data: Arc<str>,
+}
+
+#[derive(Serialize, Clone, ToSchema)]
+pub struct ServerStateChange {
+    pub server: ServerId,
+    pub state: ConnectionState,
+}
+
+/// A download gone from the list, removed or cleared.
+#[derive(Serialize, Clone, ToSchema)]
+pub struct DownloadRemoved {
+    pub id: DownloadId,
+}
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Body of the completion webhook.
+#[derive(Serialize)]
+struct CompletionNotice {
+    id: DownloadId,
+    file_name: String,
+    nick: String,
+    server: ServerId,
+    status: DownloadStatus,
+    /// Bytes received, of this transfer
+    transferred: u64,
 }
 
 pub struct App {
     search: Mutex<Search>,
-    message_receiver: watch::Receiver<Message>,
-    myip: Ipv4Addr,
+    /// Notified of results and ends of searches
+    search_updates: watch::Sender<()>,
+    /// Events serialized once, shared by all /events clients
+    events: broadcast::Sender<SseEvent>,
+    max_event_clients: AtomicUsize,
+    event_clients: AtomicUsize,
+    /// Events skipped for clients that fell behind
+    dropped_events: AtomicU64,
+    event_buffer: usize,
+    download_options: RwLock<DownloadOptions>,
     servers: DashMap<String, ServerConnection>,
+    /// All servers of the configuration, connected or not
+    configured_servers: RwLock<Vec<ServerId>>,
+    /// Servers added without persisting them, kept on reloads of the configuration file
+    runtime_servers: Mutex<HashSet<ServerId>>,
+    /// Streams of new connections, for the main loop to take messages from
+    new_streams: mpsc::UnboundedSender<(ServerId, ClientStream)>,
     download_id: AtomicUsize,
+    stats: StatsStore,
+    queue: QueueStore,
+    /// Saved downloads fixed at startup, as they did not match the download folder
+    startup_findings: Vec<Finding>,
+    prefer_healthy: AtomicBool,
+    trash_window: Mutex<Duration>,
+    retry_policy: Mutex<RetryPolicy>,
+    keep_aborted_parts: AtomicBool,
+    completion_webhook: Mutex<Option<String>>,
+    /// Shared by the calls of the completion webhook
+    webhook_client: reqwest::Client,
+    /// Read again by /config/reload
+    config_file: PathBuf,
+    /// The configuration as last loaded, to tell what a reload changes
+    configuration: Mutex<serde_json::Value>,
+}
+
+impl App {
+    /// Sends an event to all /events clients.
+    fn publish(&self, name: &'static str, data: &impl Serialize) {
+        let data = match serde_json::to_string(data) {
+            Ok(data) => data.into(),
+            Err(err) => {
+                log::warn!("Could not serialize {} event: {}", name, err);
+                return;
+            }
+        };
+        // Fails only without any clients
+        self.events.send(SseEvent { name, data }).ok();
+    }
+
+    /// Tells the completion webhook about a download that ended, in the background. Failing to
+    /// reach it is only logged.
+    fn notify_completion(&self, server_id: &str, download_id: DownloadId, transferred: u64) {
+        let Some(url) = self.completion_webhook.lock().unwrap().clone() else {
+            return;
+        };
+        let Some(download) = self
+            .servers
+            .get(server_id)
+            .and_then(|server| server.ended_download(&download_id))
+        else {
+            return;
+        };
+        let notice = CompletionNotice {
+            id: download.id,
+            file_name: download.file_name,
+            nick: download.nick,
+            server: download.server,
+            status: download.status,
+            transferred,
+        };
+        let client = self.webhook_client.clone();
+        tokio::spawn(async move {
+            let body = match serde_json::to_vec(&notice) {
+                Ok(body) => body,
+                Err(err) => {
+                    log::warn!(
+                        "Could not serialize completion of {}: {}",
+                        notice.file_name,
+                        err
+                    );
+                    return;
+                }
+            };
+            let response = client
+                .post(&url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body)
+                .timeout(WEBHOOK_TIMEOUT)
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status);
+            if let Err(err) = response {
+                log::warn!("Could not tell {} about {}: {}", url, notice.file_name, err);
+            }
+        });
+    }
+
+    fn trash_window(&self) -> Duration {
+        *self.trash_window.lock().unwrap()
+    }
+
+    fn configured_servers(&self) -> Vec<ServerId> {
+        self.configured_servers.read().unwrap().clone()
+    }
+}
+
+impl Host for App {
+    fn servers(&self) -> &DashMap<ServerId, ServerConnection> {
+        &self.servers
+    }
+
+    fn download_options(&self) -> DownloadOptions {
+        self.download_options.read().unwrap().clone()
+    }
+
+    fn next_download_id(&self) -> DownloadId {
+        self.download_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Moves a server to another state, telling /events clients about the change.
+    fn set_server_state(&self, server_id: &ServerId, state: ConnectionState) {
+        let changed = self
+            .servers
+            .get(server_id)
+            .map_or(false, |server| server.set_state(state));
+        if changed {
+            self.publish(
+                "server-status",
+                &ServerStateChange {
+                    server: server_id.clone(),
+                    state,
+                },
+            );
+        }
+    }
+
+    fn stats(&self) -> Option<&StatsStore> {
+        Some(&self.stats)
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        *self.retry_policy.lock().unwrap()
+    }
+
+    fn keep_aborted_parts(&self) -> bool {
+        self.keep_aborted_parts.load(Ordering::Relaxed)
+    }
+
+    fn message_received(&self, _server_id: &ServerId, message: &Message) {
+        self.publish("irc-message", &MessageDto::from(message));
+    }
+
+    fn search_result(&self, server_id: &ServerId, result: SearchResult) {
+        let mut search = self.search.lock().unwrap();
+        let bot = result.nick.to_lowercase();
+        let new = search.add_result(result);
+        if let Some(server_search) = search.servers.get_mut(server_id) {
+            server_search
+                .first_result
+                .get_or_insert(server_search.started.elapsed());
+            server_search.last_activity = Instant::now();
+            server_search.bots.insert(bot);
+            if new {
+                server_search.results += 1;
+            }
+        }
+        drop(search);
+        self.search_updates.send_replace(());
+    }
+
+    fn search_ended(&self, server_id: &ServerId, bot: &str) {
+        let mut search = self.search.lock().unwrap();
+        if let Some(server_search) = search.servers.get_mut(server_id) {
+            server_search.ended.insert(bot.to_lowercase());
+        }
+        drop(search);
+        self.search_updates.send_replace(());
+    }
+
+    fn download_ended(&self, server_id: &ServerId, download_id: DownloadId, transferred: u64) {
+        self.notify_completion(server_id, download_id, transferred);
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     simple_logger::init_with_level(log::Level::Info).unwrap();
 
-    let mut configuration: Configuration =
-        toml::from_str(std::str::from_utf8(&std::fs::read("config.toml")?)?)?;
+    let config_file = PathBuf::from(CONFIG_FILE);
+    let mut configuration = Configuration::load(&config_file)?;
+    let loaded = serde_json::to_value(&configuration)?;
 
-    let (tx, message_receiver) = watch::channel(Message::new(None, "DIE", vec![])?);
-    let myip: std::net::Ipv4Addr = reqwest::get("https://api.ipify.org/")
-        .await?
-        .text()
-        .await?
-        .parse()
-        .expect("Could not retrieve own ip");
+    let (events, _) = broadcast::channel(configuration.event_buffer);
+    // Looked up in the background otherwise, not to hold up connecting
+    let myip_v6 = configuration
+        .external_ip_v6
+        .filter(|_| !configuration.ipv4_only);
     let servers = DashMap::new();
     let mut streams = StreamMap::new();
+    let configured_servers = configuration
+        .servers
+        .iter()
+        .filter_map(ServerConfig::id)
+        .collect();
+    let sockets = configuration
+        .max_sockets
+        .map(|max| Arc::new(Semaphore::new(max)));
     let mut connections: FuturesUnordered<_> = configuration
         .servers
         .drain(..)
-        .map(ServerConnection::new)
+        .map(|config| {
+            let sockets = sockets.clone();
+            async move {
+                let id = config.id().unwrap_or_default();
+                connect(config, sockets).await.map_err(|err| (id, err))
+            }
+        })
         .collect();
-    while let Some((server_connection, server_id, stream)) = connections.next().await.transpose()? {
-        log::info!("Connected to {}", server_id);
-        servers.insert(server_id.clone(), server_connection);
-        streams.insert(server_id, stream);
+    let mut unreachable = Vec::new();
+    while let Some(connection) = connections.next().await {
+        match connection {
+            Ok((server_connection, server_id, stream)) => {
+                log::info!("Connected to {}", server_id);
+                servers.insert(server_id.clone(), server_connection);
+                // Ends with None, so closed connections are noticed
+                streams.insert(server_id, stream.map(Some).chain(tokio_stream::once(None)));
+            }
+            Err((server_id, err)) => {
+                log::error!("{:#}", err);
+                unreachable.push(server_id);
+            }
+        }
+    }
+    let stats = StatsStore::load(configuration.stats_file.clone())?;
+    for server in servers.iter() {
+        for (channel, channel_stats) in stats.channels(&server.id) {
+            server.channel_stats.insert(channel, channel_stats);
+        }
+    }
+    let queue = QueueStore::load(configuration.queue_file.clone())?;
+    let startup_findings = integrity::check_saved(
+        &queue,
+        &configuration.download_folder,
+        &configuration.part_suffix,
+    );
+    for finding in &startup_findings {
+        log::warn!(
+            "Partial file of {} is gone, requesting it from the start",
+            finding.file_name
+        );
+    }
+    match dcc::partial_downloads(&configuration.download_folder, &configuration.part_suffix) {
+        Ok(parts) => {
+            let saved = queue.file_names();
+            for name in parts.iter().filter(|name| !saved.contains(*name)) {
+                log::info!(
+                    "Leaving partial file of {} alone, no saved download continues it",
+                    name
+                );
+            }
+            if !configuration.resume_parts {
+                for name in queue.pause_where(|download| parts.contains(&download.file_name)) {
+                    log::info!("Not continuing {} until resumed", name);
+                }
+            }
+        }
+        Err(err) => log::warn!("Could not look for partial files: {}", err),
+    }
+    for server in servers.iter() {
+        // Requested again once registered with the server
+        for download in queue.downloads(&server.id) {
+            log::info!(
+                "Restoring download of {} from {}",
+                download.file_name,
+                download.nick
+            );
+            server.downloads.insert(download.id, download);
+        }
     }
+    let (new_streams, mut connected) = mpsc::unbounded_channel();
     let app_state = Arc::new(App {
         search: Default::default(),
-        message_receiver,
-        myip,
+        search_updates: watch::channel(()).0,
+        events,
+        max_event_clients: AtomicUsize::new(configuration.max_event_clients),
+        event_clients: AtomicUsize::new(0),
+        dropped_events: AtomicU64::new(0),
+        event_buffer: configuration.event_buffer,
+        download_options: RwLock::new(DownloadOptions {
+            socket_limit: sockets,
+            transfer_limit: configuration
+                .max_concurrent_downloads
+                .map(|max| Arc::new(Semaphore::new(max))),
+            ..configuration.download_options(configuration.external_ip, myip_v6)
+        }),
         servers,
-        download_id: AtomicUsize::new(0),
+        configured_servers: RwLock::new(configured_servers),
+        runtime_servers: Default::default(),
+        new_streams,
+        download_id: AtomicUsize::new(queue.max_id().map_or(0, |id| id + 1)),
+        stats,
+        queue,
+        startup_findings,
+        prefer_healthy: AtomicBool::new(configuration.prefer_healthy),
+        trash_window: Mutex::new(Duration::from_secs(configuration.trash_window_secs)),
+        retry_policy: Mutex::new(configuration.retry_policy()),
+        keep_aborted_parts: AtomicBool::new(configuration.keep_aborted_parts),
+        completion_webhook: Mutex::new(configuration.completion_webhook.clone()),
+        webhook_client: reqwest::Client::new(),
+        config_file,
+        configuration: Mutex::new(loaded),
     });
     tokio::spawn(web_server(app_state.clone()));
+    tokio::spawn(persist_stats(app_state.clone()));
+    tokio::spawn(persist_downloads(app_state.clone()));
+    tokio::spawn(publish_download_updates(app_state.clone()));
+    tokio::spawn(measure_latency(app_state.clone()));
+    tokio::spawn(empty_trash(app_state.clone()));
+    if let Some(cleanup) = configuration.cleanup.clone() {
+        tokio::spawn(clean_download_folder(app_state.clone(), cleanup));
+    }
+    if myip_v6.is_none() && !configuration.ipv4_only {
+        tokio::spawn(lookup_own_ipv6(app_state.clone()));
+    }
+    if configuration.external_ip.is_none() {
+        tokio::spawn(refresh_external_ip(
+            app_state.clone(),
+            configuration.ip_lookup_services.clone(),
+            Duration::from_secs(configuration.ip_refresh_secs),
+        ));
+    }
+    if let Some(reserve_mb) = configuration.disk_reserve_mb {
+        tokio::spawn(enforce_disk_reserve(
+            app_state.clone(),
+            reserve_mb * 1024 * 1024,
+            configuration.pause_on_low_disk,
+        ));
+    }
+    if !configuration.quiet_hours.is_empty() {
+        tokio::spawn(enforce_quiet_hours(
+            app_state.clone(),
+            configuration.quiet_hours.clone(),
+            configuration.pause_in_quiet_hours,
+        ));
+    }
 
-    while let Some((server_id, message)) = streams.next().await {
-        let message = message?;
-        tx.send(message.clone())?;
-        match message.command {
-            Command::PRIVMSG(channel, msg) => {
-                if !channel.starts_with('#') {
-                    eprintln!("GOT {:?}: {:?} - {:?}", message.prefix, channel, msg);
-                }
-                if let Some(Prefix::Nickname(nick, _, _)) = message.prefix {
-                    if let Some((dcc_send, mut receiver)) = DccSend::from_str(&msg) {
-                        let app_state = app_state.clone();
-                        let download_folder = configuration.download_folder.clone();
-                        tokio::spawn(async move {
-                            let (download_id, download) = {
-                                let server = &app_state
-                                    .servers
-                                    .get(&server_id)
-                                    .expect("Server should be connected");
-                                let client = &server.client;
-                                let mut download = server.downloads.iter_mut()
-                                    .find(|d| d.file_name == dcc_send.file_name)
-                                    .expect("Associated download not found. TODO: This can happen if someone is 'trolling' us or the name is different.");
-                                if matches!(download.status, DownloadStatus::Connecting) {
-                                    log::warn!("Download in progress already");
-                                    return;
-                                }
-                                download.status = DownloadStatus::Connecting;
-                                (
-                                    download.id,
-                                    dcc_send.download(
-                                        client.sender(),
-                                        nick,
-                                        app_state.myip,
-                                        configuration.port,
-                                        &download_folder,
-                                    ),
-                                )
-                            };
-                            let (abort_handle, abort_registration) = AbortHandle::new_pair();
-                            let download = Abortable::new(download, abort_registration);
-                            tokio::pin!(download);
-                            loop {
-                                tokio::select! {
-                                    x = &mut download => {
-                                        match x {
-                                            Err(Aborted) => {
-                                                eprintln!("Aborted");
-                                            }
-                                            Ok(Err(y)) => {
-                                                eprintln!("Download error: {}", y);
-                                                app_state
-                                                    .servers
-                                                    .get(&server_id)
-                                                    .expect("Server should be connected")
-                                                    .downloads
-                                                    .get_mut(&download_id)
-                                                    .expect("File name mismatch")
-                                                    .status = DownloadStatus::Failed(format!("{}", y));
-                                            }
-                                            Ok(Ok(_)) => {
-                                                eprintln!("Download completed");
-                                                app_state
-                                                    .servers
-                                                    .get(&server_id)
-                                                    .expect("Server should be connected")
-                                                    .completed(&download_id);
-                                            }
-                                        }
-                                        break;
-                                    }
-                                    _ = receiver.changed() => {
-                                        // eprintln!("Progress : {:?}", receiver.borrow().transferred_bytes);
-                                        let transferred = receiver.borrow().transferred_bytes;
-                                        app_state
-                                            .servers
-                                            .get(&server_id)
-                                            .expect("Server should be connected")
-                                            .downloads
-                                            .get_mut(&download_id)
-                                            .expect("File name mismatch")
-                                            .status = DownloadStatus::Progress(DownloadProgress {
-                                            transferred,
-                                            file_size: dcc_send
-                                                .file_size
-                                                .map(|fs| NonZeroUsize::new(fs).unwrap()),
-                                            abort_handle: abort_handle.clone()
-                                        });
-                                    }
-                                }
-                            }
-                        });
-                    }
-                }
+    for server_id in unreachable {
+        tokio::spawn(reconnect(app_state.clone(), server_id));
+    }
+
+    let shutdown = tokio::signal::ctrl_c();
+    tokio::pin!(shutdown);
+    loop {
+        let (server_id, message) = tokio::select! {
+            _ = &mut shutdown => {
+                log::info!("Shutting down");
+                save_downloads(&app_state).await;
+                save_stats(&app_state).await;
+                return Ok(());
             }
-            Command::Response(RPL_WELCOME, _) => {
-                eprintln!(
-                    "Known servers: {:?}",
-                    app_state
-                        .servers
-                        .iter()
-                        .map(|m| m.key().clone())
-                        .collect::<Vec<_>>()
-                );
-                eprintln!("Tried server: {}", server_id);
-                app_state
-                    .servers
-                    .get(&server_id)
-                    .expect("Server should be connected")
-                    .join_channels()?;
-            }
-            Command::NOTICE(_, notice) => {
-                let notice = notice.strip_formatting();
-                if let Some(captures) = REX_SEARCH.captures(&notice) {
-                    if let (Some(file_name), Some(nick), Some(command)) = (
-                        captures.name("filename"),
-                        captures.name("nick"),
-                        captures.name("command"),
-                    ) {
-                        app_state.search.lock().unwrap().results.push(SearchResult {
-                            server: server_id,
-                            file_name: file_name.as_str().to_string(),
-                            nick: nick.as_str().to_string(),
-                            command: command.as_str().to_string(),
-                        });
-                    } else {
-                        eprintln!("capture error {:?} - {:?}", message.prefix, notice);
-                    }
-                }
+            Some(next) = streams.next() => next,
+            Some((server_id, stream)) = connected.recv() => {
+                streams.insert(server_id, stream.map(Some).chain(tokio_stream::once(None)));
+                continue;
             }
-            Command::Response(response, args) => {
-                if response == Response::ERR_NOSUCHNICK {
-                    app_state
-                        .servers
-                        .get_mut(&server_id)
-                        .expect("Server should be connected")
-                        .handle_sender_gone(&args[1]);
-                }
+        };
+        let message = match message {
+            Some(Ok(message)) => message,
+            Some(Err(err)) => {
+                log::warn!("Connection to {} failed: {}", server_id, err);
+                streams.remove(&server_id);
+                app_state.set_server_state(&server_id, ConnectionState::Disconnected);
+                tokio::spawn(reconnect(app_state.clone(), server_id));
+                continue;
             }
-            // Not yet allowed to send messages to other users
-            Command::Raw(code, _) if code == "531" => {
-                let retry_at = app_state
-                    .servers
-                    .get_mut(&server_id)
-                    .expect("Server should be connected")
-                    .mark_downloads_delayed();
-                let app_state = app_state.clone();
-                tokio::spawn(async move {
-                    tokio::time::sleep_until(retry_at).await;
-                    log::info!("Retrying downloads of {}", server_id);
-                    let server = &app_state
-                        .servers
-                        .get_mut(&server_id)
-                        .expect("Server should be connected");
-                    for download in server.downloads.iter() {
-                        server
-                            .client
-                            .send_privmsg(&download.nick, &download.request_command)?;
-                    }
-                    Ok::<_, anyhow::Error>(())
-                });
+            None if !app_state.servers.contains_key(&server_id) => {
+                log::info!("Connection to removed server {} closed", server_id);
+                continue;
             }
-            _ => eprintln!("{:?}", message),
-        }
+            None => {
+                log::warn!("Connection to {} closed", server_id);
+                app_state.set_server_state(&server_id, ConnectionState::Disconnected);
+                tokio::spawn(reconnect(app_state.clone(), server_id));
+                continue;
+            }
+        };
+        handler::handle_message(&app_state, server_id, message)?;
     }
-    Ok(())
 }
 
-async fn web_server(app_state: Arc<App>) -> anyhow::Result<()> {
-    let blub = Router::new()
-        .route("/downloads", get(downloads))
-        .route("/download", post(request_download))
-        .route("/download/:id", delete(abort_download))
-        .route("/search", get(search))
-        .route("/events", get(sse_handler))
-        .nest_service("/", ServeDir::new("frontend/dist"))
-        .with_state(app_state);
-    // .route("/downloads", get
-    axum::Server::bind(&"0.0.0.0:3000".parse().unwrap())
-        .serve(blub.into_make_service())
-        .await
-        .map_err(anyhow::Error::new)
+/// Writes the statistics collected by the servers every now and then.
+async fn persist_stats(app_state: Arc<App>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(30));
+    loop {
+        interval.tick().await;
+        save_stats(&app_state).await;
+    }
 }
 
-async fn abort_download(
-    State(state): State<Arc<App>>,
-    Path(id): Path<DownloadId>,
-) -> Result<(), StatusCode> {
-    log::info!("Aborting download {}", id);
-    for server in state.servers.iter_mut() {
-        server.abort_download(&id);
+async fn save_stats(app_state: &Arc<App>) {
+    for server in app_state.servers.iter() {
+        app_state.stats.update_channels(
+            &server.id,
+            server
+                .channel_stats
+                .iter()
+                .map(|c| (c.key().clone(), c.value().clone())),
+        );
+    }
+    let app = app_state.clone();
+    let saved = tokio::task::spawn_blocking(move || app.stats.save()).await;
+    if let Err(err) = saved.map_err(anyhow::Error::from).and_then(|saved| saved) {
+        log::warn!("Could not save statistics: {}", err);
     }
-    Ok(())
 }
 
-async fn request_download(
-    State(state): State<Arc<App>>,
-    request: Json<DownloadRequest>,
-) -> Result<(), StatusCode> {
-    let DownloadRequest {
-        server,
-        file_name,
-        nick,
-        command,
-    } = request.0;
-    let server_connection = &mut state
-        .servers
-        .get_mut(&server)
-        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
-    let id = state.download_id.fetch_add(1, Ordering::SeqCst);
+/// Writes the outstanding downloads on change. The last time is on shutdown, in `main`.
+async fn persist_downloads(app_state: Arc<App>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(5));
+    loop {
+        interval.tick().await;
+        save_downloads(&app_state).await;
+    }
+}
 
-    server_connection.downloads.insert(
-        id,
-        DownloadItem {
-            id,
-            server,
-            file_name,
-            nick: nick.clone(),
-            status: DownloadStatus::Requested,
-            request_command: command.clone(),
-        },
-    );
-    eprintln!("Requesting DL: {} {}", nick, command);
-    server_connection
-        .client
-        .send_privmsg(nick, command)
-        .map_err(|_err| StatusCode::INTERNAL_SERVER_ERROR)?;
-    Ok(())
+async fn save_downloads(app_state: &Arc<App>) {
+    for server in app_state.servers.iter() {
+        let downloads: Vec<_> = server.downloads.iter().map(|d| d.value().clone()).collect();
+        app_state.queue.update(&server.id, &downloads);
+    }
+    let app = app_state.clone();
+    let saved = tokio::task::spawn_blocking(move || app.queue.save()).await;
+    if let Err(err) = saved.map_err(anyhow::Error::from).and_then(|saved| saved) {
+        log::warn!("Could not save downloads: {}", err);
+    }
 }
 
-async fn downloads(State(state): State<Arc<App>>) -> Json<Vec<DownloadItem>> {
-    let servers = &state.servers;
-    let downloads: Vec<_> = servers
-        .iter()
-        .flat_map(|s| s.downloads.iter().map(|r| r.clone()).collect::<Vec<_>>())
-        .collect();
-    Json(downloads)
+/// Interval of `download-update` events with the progress of transfers. Other changes are
+/// published right away.
+const DOWNLOAD_EVENT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Publishes a `download-update` event for each download that changed since the last one.
+async fn publish_download_updates(app_state: Arc<App>) {
+    let mut updates = StreamMap::new();
+    let mut interval = tokio::time::interval(DOWNLOAD_EVENT_INTERVAL);
+    let mut published: HashMap<DownloadId, u64> = HashMap::new();
+    loop {
+        tokio::select! {
+            Some(_) = updates.next() => {}
+            _ = interval.tick() => {
+                // Servers added or connected again, the streams of those gone ended
+                for server in app_state.servers.iter() {
+                    if !updates.contains_key(server.key()) {
+                        let changes = WatchStream::new(server.subscribe_download_updates());
+                        updates.insert(server.key().clone(), changes);
+                    }
+                }
+            }
+        }
+        let downloads: Vec<_> = app_state
+            .servers
+            .iter()
+            .flat_map(|s| {
+                let mut downloads = s.download_list();
+                downloads.extend(s.completed_downloads());
+                downloads
+            })
+            .collect();
+        // Hashes tell changes cheaply, only the changed downloads are serialized
+        let mut current = HashMap::with_capacity(downloads.len());
+        for download in downloads {
+            let mut hasher = DefaultHasher::new();
+            download.hash(&mut hasher);
+            let fingerprint = hasher.finish();
+            if published.get(&download.id) != Some(&fingerprint) {
+                app_state.publish("download-update", &download);
+            }
+            current.insert(download.id, fingerprint);
+        }
+        for id in published.keys().filter(|id| !current.contains_key(id)) {
+            app_state.publish("download-removed", &DownloadRemoved { id: *id });
+        }
+        published = current;
+    }
 }
 
-#[derive(serde::Deserialize)]
-struct SearchQuery {
-    query: String,
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(300);
+
+/// Connects to a server. Servers do not wait for a socket, none might be freed before all are
+/// connected.
+async fn connect(
+    config: ServerConfig,
+    sockets: Option<Arc<Semaphore>>,
+) -> anyhow::Result<(ServerConnection, ServerId, ClientStream)> {
+    let permit = sockets
+        .map(Semaphore::try_acquire_owned)
+        .transpose()
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "Not connecting to {}, no socket left",
+                config.id().unwrap_or_default()
+            )
+        })?;
+    let (mut connection, server_id, stream) = ServerConnection::new(config).await?;
+    connection.socket_permit = permit;
+    Ok((connection, server_id, stream))
 }
 
-async fn search(
-    State(state): State<Arc<App>>,
-    Query(search_query): Query<SearchQuery>,
-) -> Result<Json<Vec<SearchResult>>, StatusCode> {
-    state.search.lock().unwrap().results.clear();
-    for server in state.servers.iter_mut() {
-        server
-            .search(&search_query.query)
-            .map_err(|_err| StatusCode::INTERNAL_SERVER_ERROR)?;
+/// Connects to a server again after losing the connection, waiting longer after each failed
+/// attempt. The new connection takes over from the previous one.
+async fn reconnect(app_state: Arc<App>, server_id: ServerId) {
+    let mut delay = RECONNECT_DELAY;
+    loop {
+        app_state.set_server_state(&server_id, ConnectionState::Reconnecting);
+        log::info!("Reconnecting to {} in {}s", server_id, delay.as_secs());
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        // As configured now, changes needing a reconnect take effect
+        let configuration = app_state.configuration.lock().unwrap().clone();
+        let Some(config) = servers_by_id(&configuration)
+            .remove(&server_id)
+            .and_then(|config| serde_json::from_value::<ServerConfig>(config).ok())
+        else {
+            log::warn!("{} is no longer configured, not reconnecting", server_id);
+            return;
+        };
+        // The previous connection hands over its socket
+        let sockets = if app_state.servers.contains_key(&server_id) {
+            None
+        } else {
+            app_state.download_options().socket_limit
+        };
+        let (connection, _, stream) = match connect(config, sockets).await {
+            Ok(connected) => connected,
+            Err(err) => {
+                log::warn!("Could not reconnect to {}: {:#}", server_id, err);
+                continue;
+            }
+        };
+        log::info!("Connected to {}", server_id);
+        // Replaced in place, transfers look their server up until they end
+        match app_state.servers.get_mut(&server_id) {
+            Some(mut server) => {
+                let previous = std::mem::replace(&mut *server, connection);
+                let adopted = server.take_over(previous);
+                log::info!("Took over {} downloads of {}", adopted, server_id);
+            }
+            None => {
+                // Never connected since the start, restore its saved downloads
+                for download in app_state.queue.downloads(&server_id) {
+                    connection.downloads.insert(download.id, download);
+                }
+                app_state.servers.insert(server_id.clone(), connection);
+            }
+        }
+        app_state.new_streams.send((server_id, stream)).ok();
+        return;
     }
-    // TODO find a better way to wait for results
-    tokio::time::sleep(Duration::from_millis(1000)).await;
-    Ok(Json(state.search.lock().unwrap().results.clone()))
 }
 
-async fn sse_handler(
-    State(app_state): State<Arc<App>>,
-) -> Sse<impl tokio_stream::Stream<Item = Result<Event, Infallible>>> {
-    // A `Stream` that repeats an event every second
-    // let stream = stream::repeat_with(|| Event::default().event("update").data("hi!"))
-    //     .map(Ok)
-    //     .throttle(Duration::from_secs(1));
-    let message_receiver = app_state.message_receiver.clone();
-    let stream = WatchStream::from_changes(message_receiver)
-        .map(|msg| {
-            Event::default()
-                .event("irc-message")
-                .json_data(MessageDto {
-                    prefix: msg
-                        .prefix
-                        .map(|p| format!("{:?}", p))
-                        .unwrap_or_else(|| "".to_string()),
-                    message: format!("{:?}", msg.command),
-                })
-                .expect("Could not serialize message")
+async fn clean_download_folder(app_state: Arc<App>, cleanup: Cleanup) {
+    let mut interval = tokio::time::interval(Duration::from_secs(cleanup.interval_secs));
+    loop {
+        interval.tick().await;
+        let keep = app_state
+            .servers
+            .iter()
+            .flat_map(|s| {
+                let trashed = s.trash.iter().map(|t| t.value().0.file_name.clone());
+                s.downloads
+                    .iter()
+                    .map(|d| d.file_name.clone())
+                    .chain(trashed)
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        let options = app_state.download_options();
+        let part_max_age = cleanup.part_max_age_secs.map(Duration::from_secs);
+        let swept = tokio::task::spawn_blocking(move || {
+            dcc::sweep_download_folder(
+                &options.download_folder,
+                &keep,
+                &options.part_suffix,
+                part_max_age,
+            )
         })
-        .map(Ok);
+        .await;
+        match swept
+            .map_err(anyhow::Error::from)
+            .and_then(|swept| Ok(swept?))
+        {
+            Ok(removed) => {
+                for path in removed {
+                    log::info!("Removed leftover {}", path.display());
+                }
+            }
+            Err(err) => log::warn!("Could not clean up the download folder: {}", err),
+        }
+    }
+}
 
-    Sse::new(stream).keep_alive(KeepAlive::default())
+/// Finally removes trashed downloads after the trash window, with their partial files.
+async fn empty_trash(app_state: Arc<App>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(30));
+    loop {
+        interval.tick().await;
+        for server in app_state.servers.iter() {
+            for item in server.expire_trash(app_state.trash_window()) {
+                let in_use = server
+                    .downloads
+                    .iter()
+                    .any(|d| d.file_name == item.file_name);
+                if in_use {
+                    continue;
+                }
+                let path = app_state.part_path(&item.file_name);
+                match std::fs::remove_file(&path) {
+                    Ok(()) => log::info!("Removed partial file {}", path.display()),
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(err) => log::warn!("Could not remove {}: {}", path.display(), err),
+                }
+            }
+        }
+    }
+}
+
+/// Regularly measures the round trip time to every server.
+async fn measure_latency(app_state: Arc<App>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        for server in app_state.servers.iter() {
+            if let Err(err) = server.ping() {
+                log::warn!("Could not ping {}: {}", server.key(), err);
+            }
+        }
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        downloads,
+        servers,
+        add_server,
+        remove_server,
+        stats,
+        server_stats,
+        request_download,
+        abort_download,
+        restore_download,
+        retry_download,
+        pause_download,
+        resume_download,
+        trash,
+        clear_completed,
+        wait_for_download,
+        abort_matching_downloads,
+        search,
+        sse_handler,
+        diagnose_dcc,
+        verify_downloads,
+        reload_config,
+        openapi_json
+    ),
+    components(schemas(
+        DownloadItem,
+        DownloadProgress,
+        DownloadStatus,
+        DownloadRequest,
+        DownloadSource,
+        RequestedDownload,
+        WaitResponse,
+        TrashedDownload,
+        irc_downloader::QueuePosition,
+        SearchResult,
+        SearchResponse,
+        ServerSearchStatus,
+        MessageDto,
+        FileDigest,
+        HashAlgorithm,
+        ServerStatus,
+        server::ChannelStatus,
+        StatsDto,
+        BotStats,
+        FailureKind,
+        server::Restriction,
+        NickLookup,
+        LookupResult,
+        CancelReason,
+        dcc::PassiveDiagnostics,
+        ServerStateChange,
+        DownloadRemoved,
+        ConnectionState,
+        ConfigReload,
+        Finding,
+        Inconsistency,
+        VerifyReport
+    ))
+)]
+struct ApiDoc;
+
+async fn enforce_quiet_hours(
+    app_state: Arc<App>,
+    quiet_hours: Vec<QuietHours>,
+    pause_active: bool,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(30));
+    let mut paused = Vec::new();
+    loop {
+        interval.tick().await;
+        let quiet = schedule::is_quiet(&quiet_hours, chrono::Local::now().naive_local());
+        apply_quiet_hours(&app_state, quiet, pause_active, &mut paused);
+    }
+}
+
+/// Holds back requests while `quiet`, pausing running transfers as well if `pause_active`. Those
+/// are kept in `paused`, to be resumed with the queued requests once it is no longer quiet.
+fn apply_quiet_hours(
+    app_state: &App,
+    quiet: bool,
+    pause_active: bool,
+    paused: &mut Vec<(ServerId, DownloadId)>,
+) {
+    for server in app_state.servers.iter() {
+        let was_quiet = server.quiet.swap(quiet, Ordering::Relaxed);
+        if quiet && !was_quiet && pause_active {
+            let running: Vec<_> = server
+                .downloads
+                .iter()
+                .filter(|d| matches!(d.status, DownloadStatus::Progress(_)))
+                .map(|d| d.id)
+                .collect();
+            for id in running {
+                if server.pause_download(&id) {
+                    paused.push((server.key().clone(), id));
+                }
+            }
+        }
+        if was_quiet && !quiet {
+            log::info!(
+                "Quiet hours ended, requesting queued downloads of {}",
+                server.key()
+            );
+            for (_, id) in paused
+                .iter()
+                .filter(|(server_id, _)| server_id == server.key())
+            {
+                // Resumed by the user meanwhile, or gone
+                if let Err(err) = server.resume_download(id) {
+                    log::warn!("Could not resume download {}: {}", id, err);
+                }
+            }
+            paused.retain(|(server_id, _)| server_id != server.key());
+            if let Err(err) = server.dispatch_all_queued() {
+                log::warn!("Could not request queued downloads: {}", err);
+            }
+        }
+    }
+}
+
+/// Our public IPv4 address, from the first of the services that answers.
+async fn lookup_external_ip(services: &[String]) -> anyhow::Result<std::net::Ipv4Addr> {
+    for service in services {
+        let lookup = async { reqwest::get(service).await?.text().await };
+        match tokio::time::timeout(Duration::from_secs(10), lookup).await {
+            Ok(Ok(answer)) => match answer.trim().parse() {
+                Ok(ip) => return Ok(ip),
+                Err(_) => log::warn!("{} answered with no IPv4 address: {:?}", service, answer),
+            },
+            Ok(Err(err)) => log::warn!("Could not look up our IP at {}: {}", service, err),
+            Err(_) => log::warn!("Looking up our IP at {} timed out", service),
+        }
+    }
+    anyhow::bail!("None of the IP lookup services answered")
+}
+
+/// Until our address is known, lookups are retried this often
+const IP_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Looks up our public IPv4 address, and again every `refresh`. Passive transfers fail while it
+/// is unknown.
+async fn refresh_external_ip(app_state: Arc<App>, services: Vec<String>, refresh: Duration) {
+    loop {
+        let next = match lookup_external_ip(&services).await {
+            Ok(ip) => {
+                let mut options = app_state.download_options.write().unwrap();
+                if options.myip != Some(ip) {
+                    log::info!("Our IP address is {}", ip);
+                    options.myip = Some(ip);
+                }
+                refresh
+            }
+            Err(err) => {
+                log::warn!(
+                    "{}, passive transfers are not possible until it is known",
+                    err
+                );
+                IP_RETRY_INTERVAL
+            }
+        };
+        tokio::time::sleep(next).await;
+    }
+}
+
+/// Looks up our IPv6 address, if we can reach the internet over IPv6 at all. Transfers use IPv4
+/// until it is known.
+async fn lookup_own_ipv6(app_state: Arc<App>) {
+    let lookup = async { reqwest::get("https://api6.ipify.org/").await?.text().await };
+    let ip = match tokio::time::timeout(Duration::from_secs(10), lookup).await {
+        Ok(Ok(ip)) => ip.trim().parse::<std::net::Ipv6Addr>().ok(),
+        _ => None,
+    };
+    match ip {
+        Some(ip) => {
+            log::info!("Our IPv6 address is {}", ip);
+            app_state.download_options.write().unwrap().myip_v6 = Some(ip);
+        }
+        None => log::info!("No IPv6 connectivity, DCC transfers use IPv4 only"),
+    }
+}
+
+/// How often free space in the download folder is checked against the reserve
+const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+async fn enforce_disk_reserve(app_state: Arc<App>, reserve: u64, pause_active: bool) {
+    let mut interval = tokio::time::interval(DISK_CHECK_INTERVAL);
+    let mut was_low = false;
+    loop {
+        interval.tick().await;
+        let folder = app_state.download_options().download_folder;
+        let available = match fs2::available_space(&folder) {
+            Ok(available) => available,
+            Err(err) => {
+                log::warn!(
+                    "Could not determine free space in {}: {}",
+                    folder.display(),
+                    err
+                );
+                continue;
+            }
+        };
+        let low = available < reserve;
+        for server in app_state.servers.iter() {
+            server.disk_low.store(low, Ordering::Relaxed);
+        }
+        if low && !was_low {
+            log::warn!(
+                "Only {} MB free in {}, holding back requests",
+                available / (1024 * 1024),
+                folder.display()
+            );
+        }
+        if low && pause_active {
+            for server in app_state.servers.iter() {
+                for download in server.downloads.iter() {
+                    if let DownloadStatus::Progress(progress) = &download.status {
+                        log::info!("Pausing {} for lack of disk space", download.file_name);
+                        progress.cancellation.cancel(CancelReason::Disk);
+                    }
+                }
+            }
+        }
+        if !low && was_low {
+            log::info!(
+                "Free space in {} restored, requesting queued downloads",
+                folder.display()
+            );
+            for server in app_state.servers.iter() {
+                if let Err(err) = server.dispatch_all_queued() {
+                    log::warn!("Could not request queued downloads: {}", err);
+                }
+            }
+        }
+        was_low = low;
+    }
+}
+
+async fn web_server(app_state: Arc<App>) -> anyhow::Result<()> {
+    let blub = router(app_state, "frontend/dist");
+    // .route("/downloads", get
+    axum::Server::bind(&"0.0.0.0:3000".parse().unwrap())
+        .serve(blub.into_make_service())
+        .await
+        .map_err(anyhow::Error::new)
+}
+
+fn router(app_state: Arc<App>, static_files: &str) -> Router {
+    let compressed = Router::new()
+        .route("/downloads", get(downloads))
+        .route("/servers", get(servers).post(add_server))
+        .route("/servers/:id", delete(remove_server))
+        .route("/stats", get(stats))
+        .route("/servers/:id/stats", get(server_stats))
+        .route(
+            "/download",
+            post(request_download).delete(abort_matching_downloads),
+        )
+        .route("/download/:id", delete(abort_download))
+        .route("/download/:id/restore", post(restore_download))
+        .route("/download/:id/retry", post(retry_download))
+        .route("/download/:id/pause", post(pause_download))
+        .route("/download/:id/resume", post(resume_download))
+        .route("/downloads/trash", get(trash))
+        .route("/downloads/completed", delete(clear_completed))
+        .route("/download/:id/wait", get(wait_for_download))
+        .route("/search", get(search))
+        .route("/diagnostics/dcc", post(diagnose_dcc))
+        .route("/maintenance/verify", post(verify_downloads))
+        .route("/config/reload", post(reload_config))
+        .route("/api-docs/openapi.json", get(openapi_json))
+        .nest_service(
+            "/",
+            ServeDir::new(static_files)
+                .precompressed_br()
+                .precompressed_gzip(),
+        )
+        .layer(CompressionLayer::new().gzip(true).br(true));
+    // Compressing would hold back events until enough data accumulated
+    Router::new()
+        .route("/events", get(sse_handler))
+        .merge(compressed)
+        .with_state(app_state)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api-docs/openapi.json",
+    responses((status = 200, description = "This OpenAPI description"))
+)]
+async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/download/{id}",
+    params(("id" = DownloadId, Path, description = "Id of the download to abort")),
+    responses((status = 200, description = "Download aborted and moved to the trash, dropped if it had ended, or unknown"))
+)]
+async fn abort_download(
+    State(state): State<Arc<App>>,
+    Path(id): Path<DownloadId>,
+) -> Result<(), StatusCode> {
+    log::info!("Aborting download {}", id);
+    for server in state.servers.iter_mut() {
+        server.remove_download(&id);
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+    post,
+    path = "/download/{id}/restore",
+    params(("id" = DownloadId, Path, description = "Id of the removed download")),
+    responses(
+        (status = 200, description = "Download requested or queued again"),
+        (status = 404, description = "Download not in the trash"),
+        (status = 500, description = "Request could not be sent")
+    )
+)]
+async fn restore_download(
+    State(state): State<Arc<App>>,
+    Path(id): Path<DownloadId>,
+) -> Result<(), StatusCode> {
+    for server in state.servers.iter() {
+        let restored = server
+            .restore_download(&id)
+            .map_err(|_err| StatusCode::INTERNAL_SERVER_ERROR)?;
+        if restored {
+            return Ok(());
+        }
+    }
+    Err(StatusCode::NOT_FOUND)
+}
+
+#[utoipa::path(
+    post,
+    path = "/download/{id}/retry",
+    params(("id" = DownloadId, Path, description = "Id of the failed download")),
+    responses(
+        (status = 200, description = "Download requested or queued again"),
+        (status = 404, description = "Download unknown"),
+        (status = 409, description = "Download still under way"),
+        (status = 500, description = "Request could not be sent")
+    )
+)]
+async fn retry_download(
+    State(state): State<Arc<App>>,
+    Path(id): Path<DownloadId>,
+) -> Result<(), StatusCode> {
+    for server in state.servers.iter() {
+        let Some(retryable) = server.downloads.get(&id).map(|d| d.status.is_retryable()) else {
+            continue;
+        };
+        if !retryable {
+            return Err(StatusCode::CONFLICT);
+        }
+        return match server.retry_download(&id) {
+            Ok(true) => Ok(()),
+            // Changed in between
+            Ok(false) => Err(StatusCode::CONFLICT),
+            Err(_err) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+        };
+    }
+    Err(StatusCode::NOT_FOUND)
+}
+
+#[utoipa::path(
+    post,
+    path = "/download/{id}/pause",
+    params(("id" = DownloadId, Path, description = "Id of the transferring download")),
+    responses(
+        (status = 200, description = "Transfer stopping, the download is paused once it stopped"),
+        (status = 404, description = "Download unknown"),
+        (status = 409, description = "Download not transferring, or offered unsolicited and could not be resumed")
+    )
+)]
+async fn pause_download(
+    State(state): State<Arc<App>>,
+    Path(id): Path<DownloadId>,
+) -> Result<(), StatusCode> {
+    for server in state.servers.iter() {
+        if !server.downloads.contains_key(&id) {
+            continue;
+        }
+        return match server.pause_download(&id) {
+            true => Ok(()),
+            false => Err(StatusCode::CONFLICT),
+        };
+    }
+    Err(StatusCode::NOT_FOUND)
+}
+
+#[utoipa::path(
+    post,
+    path = "/download/{id}/resume",
+    params(("id" = DownloadId, Path, description = "Id of the paused download")),
+    responses(
+        (status = 200, description = "Download requested or queued again, continuing the partial file"),
+        (status = 404, description = "Download unknown"),
+        (status = 409, description = "Download not paused, or offered unsolicited"),
+        (status = 500, description = "Request could not be sent")
+    )
+)]
+async fn resume_download(
+    State(state): State<Arc<App>>,
+    Path(id): Path<DownloadId>,
+) -> Result<(), StatusCode> {
+    for server in state.servers.iter() {
+        if !server.downloads.contains_key(&id) {
+            continue;
+        }
+        return match server.resume_download(&id) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(StatusCode::CONFLICT),
+            Err(_err) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+        };
+    }
+    Err(StatusCode::NOT_FOUND)
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TrashedDownload {
+    pub download: DownloadItem,
+    /// Seconds left to restore it
+    pub expires_in_secs: u64,
+}
+
+#[utoipa::path(
+    get,
+    path = "/downloads/trash",
+    responses((status = 200, body = [TrashedDownload]))
+)]
+async fn trash(State(state): State<Arc<App>>) -> Json<Vec<TrashedDownload>> {
+    let trash = state
+        .servers
+        .iter()
+        .flat_map(|s| {
+            s.trash
+                .iter()
+                .map(|entry| {
+                    let (download, removed_at) = entry.value();
+                    TrashedDownload {
+                        download: download.clone(),
+                        expires_in_secs: state
+                            .trash_window()
+                            .saturating_sub(removed_at.elapsed())
+                            .as_secs(),
+                    }
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    Json(trash)
+}
+
+/// Seconds a wait for a download lasts, unless the query says otherwise
+const DEFAULT_WAIT_SECS: u64 = 30;
+const MAX_WAIT_SECS: u64 = 300;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum WaitUntil {
+    /// The first bytes arrived
+    Progress,
+    Terminal,
+}
+
+#[derive(Deserialize)]
+struct WaitQuery {
+    timeout: Option<u64>,
+    until: Option<WaitUntil>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct WaitResponse {
+    pub download: DownloadItem,
+    /// The download completed, /downloads lists it with the recently completed ones until they
+    /// are cleared
+    pub completed: bool,
+    /// The download did not reach the state waited for in time
+    pub timed_out: bool,
+}
+
+#[utoipa::path(
+    get,
+    path = "/download/{id}/wait",
+    params(
+        ("id" = DownloadId, Path, description = "Id of the download to wait for"),
+        ("timeout" = Option<u64>, Query, description = "Seconds to wait at most, 30 by default"),
+        ("until" = Option<String>, Query, description = "`progress` to wait for the first bytes, `terminal` (default) for the download to end")
+    ),
+    responses(
+        (status = 200, body = WaitResponse),
+        (status = 404, description = "Download unknown")
+    )
+)]
+async fn wait_for_download(
+    State(state): State<Arc<App>>,
+    Path(id): Path<DownloadId>,
+    Query(query): Query<WaitQuery>,
+) -> Result<Json<WaitResponse>, StatusCode> {
+    let until = query.until.unwrap_or(WaitUntil::Terminal);
+    let timeout = query
+        .timeout
+        .unwrap_or(DEFAULT_WAIT_SECS)
+        .min(MAX_WAIT_SECS);
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    // Subscribed before looking at the download, so no change between slips through
+    let (server_id, mut updates) = state
+        .servers
+        .iter()
+        .find(|s| s.downloads.contains_key(&id) || s.completed_download(&id).is_some())
+        .map(|s| (s.key().clone(), s.subscribe_download_updates()))
+        .ok_or(StatusCode::NOT_FOUND)?;
+    loop {
+        let (download, completed) = {
+            let server = state.servers.get(&server_id).ok_or(StatusCode::NOT_FOUND)?;
+            let download = server.downloads.get(&id).map(|d| d.clone());
+            match download {
+                Some(download) => (download, false),
+                None => (
+                    server
+                        .completed_download(&id)
+                        .ok_or(StatusCode::NOT_FOUND)?,
+                    true,
+                ),
+            }
+        };
+        let reached = completed
+            || download.status.is_terminal()
+            || (until == WaitUntil::Progress
+                && matches!(download.status, DownloadStatus::Progress(_)));
+        if reached {
+            return Ok(Json(WaitResponse {
+                download,
+                completed,
+                timed_out: false,
+            }));
+        }
+        if tokio::time::timeout_at(deadline, updates.changed())
+            .await
+            .is_err()
+        {
+            return Ok(Json(WaitResponse {
+                download,
+                completed,
+                timed_out: true,
+            }));
+        }
+    }
+}
+
+fn bad_request(err: anyhow::Error) -> axum::response::Response {
+    (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+}
+
+#[derive(Deserialize)]
+struct AbortQuery {
+    filename: Option<String>,
+    nick: Option<String>,
+    command: Option<String>,
+    #[serde(default)]
+    all: bool,
+}
+
+#[utoipa::path(
+    delete,
+    path = "/download",
+    params(
+        ("filename" = Option<String>, Query, description = "File name of the downloads to abort"),
+        ("nick" = Option<String>, Query, description = "Nick of the bot sending the downloads"),
+        ("command" = Option<String>, Query, description = "Command the downloads were requested with"),
+        ("all" = Option<bool>, Query, description = "Abort all matches if there are several")
+    ),
+    responses(
+        (status = 200, body = [usize], description = "Ids of the aborted downloads"),
+        (status = 400, description = "Neither filename nor nick given, or invalid values"),
+        (status = 404, description = "No download matches"),
+        (status = 409, body = [DownloadItem], description = "Several downloads match and `all` is not set")
+    )
+)]
+async fn abort_matching_downloads(
+    State(state): State<Arc<App>>,
+    Query(query): Query<AbortQuery>,
+) -> Result<Json<Vec<DownloadId>>, axum::response::Response> {
+    if query.filename.is_none() && query.nick.is_none() {
+        return Err(bad_request(anyhow::anyhow!(
+            "Either filename or nick is required"
+        )));
+    }
+    for (field, value) in [
+        ("File name", &query.filename),
+        ("Nick", &query.nick),
+        ("Command", &query.command),
+    ] {
+        if let Some(value) = value {
+            check_irc_text(field, value).map_err(bad_request)?;
+        }
+    }
+    let ids: Vec<_> = state
+        .servers
+        .iter()
+        .flat_map(|server| {
+            server.find_downloads(
+                query.filename.as_deref(),
+                query.nick.as_deref(),
+                query.command.as_deref(),
+            )
+        })
+        .collect();
+    if ids.is_empty() {
+        return Err(StatusCode::NOT_FOUND.into_response());
+    }
+    if ids.len() > 1 && !query.all {
+        let ambiguous: Vec<_> = state
+            .servers
+            .iter()
+            .flat_map(|server| {
+                ids.iter()
+                    .filter_map(|id| server.downloads.get(id).map(|d| d.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        return Err((StatusCode::CONFLICT, Json(ambiguous)).into_response());
+    }
+    log::info!("Aborting downloads {:?}", ids);
+    for server in state.servers.iter() {
+        for id in &ids {
+            server.remove_download(id);
+        }
+    }
+    Ok(Json(ids))
+}
+
+#[derive(Serialize, ToSchema, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LookupResult {
+    Online,
+    Offline,
+    Timeout,
+    Failed,
+}
+
+/// Whether a nick is on one of the servers.
+#[derive(Serialize, ToSchema, Debug)]
+pub struct NickLookup {
+    pub server: ServerId,
+    pub result: LookupResult,
+}
+
+/// Looks for a bot on all connected servers at once, returning the first configured server it is
+/// on, or with `prefer_healthy` the healthiest of those. If it is on none, the results of all
+/// servers are returned.
+async fn locate_bot(state: &App, nick: &str) -> Result<ServerId, Vec<NickLookup>> {
+    let lookups: Vec<_> = state
+        .configured_servers()
+        .into_iter()
+        .filter_map(|id| {
+            let server = state.servers.get(&id)?;
+            let lookup = server.lookup_nick(nick);
+            Some((id, lookup))
+        })
+        .collect();
+    let results =
+        futures_util::future::join_all(lookups.into_iter().map(|(server, lookup)| async move {
+            let result = match lookup {
+                Ok(answer) => match tokio::time::timeout(handler::LOOKUP_TIMEOUT, answer).await {
+                    Ok(Ok(true)) => LookupResult::Online,
+                    Ok(Ok(false)) => LookupResult::Offline,
+                    Ok(Err(_)) => LookupResult::Failed,
+                    Err(_) => LookupResult::Timeout,
+                },
+                Err(err) => {
+                    log::warn!("Could not look for {} on {}: {}", nick, server, err);
+                    LookupResult::Failed
+                }
+            };
+            NickLookup { server, result }
+        }))
+        .await;
+    log::info!("Looked for {}: {:?}", nick, results);
+    let mut online = results.iter().filter(|r| r.result == LookupResult::Online);
+    let found = if state.prefer_healthy.load(Ordering::Relaxed) {
+        let health = |lookup: &&NickLookup| {
+            state
+                .servers
+                .get(&lookup.server)
+                .map_or(f64::INFINITY, |server| server.health())
+        };
+        online.min_by(|a, b| health(a).total_cmp(&health(b)))
+    } else {
+        online.next()
+    };
+    match found {
+        Some(found) => Ok(found.server.clone()),
+        None => Err(results),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/download",
+    request_body = DownloadRequest,
+    responses(
+        (status = 200, body = RequestedDownload, description = "Download requested, queued while the server is disconnected, or completed right away as the file is present already"),
+        (status = 400, description = "Invalid nick, command or file name"),
+        (status = 404, body = [NickLookup], description = "Server unknown, or without server the bot was found nowhere"),
+        (status = 503, description = "Server not connected yet since the start"),
+        (status = 500, description = "Request could not be sent")
+    )
+)]
+async fn request_download(
+    State(state): State<Arc<App>>,
+    request: Json<DownloadRequest>,
+) -> Result<Json<RequestedDownload>, axum::response::Response> {
+    let DownloadRequest {
+        server,
+        file_name,
+        nick,
+        command,
+        size,
+        digest,
+        alternatives,
+        max_bytes_per_sec,
+    } = request.0;
+    check_irc_text("File name", &file_name)
+        .and_then(|_| check_irc_text("Command", &command))
+        .and_then(|_| {
+            alternatives
+                .iter()
+                .try_for_each(|source| check_irc_text("Command", &source.command))
+        })
+        .map_err(bad_request)?;
+    // Stored like the offer will be, so the two can be matched
+    let file_name = sanitize_file_name(&file_name).map_err(bad_request)?;
+    let options = state.download_options();
+    let present = match options.existing_files {
+        dcc::ExistingFiles::Skip => {
+            let path = options.download_folder.join(&file_name);
+            match dcc::is_present(&path, size, digest.as_ref()).await {
+                Ok(true) => tokio::fs::metadata(&path)
+                    .await
+                    .ok()
+                    .map(|metadata| (path, metadata.len())),
+                Ok(false) => None,
+                Err(err) => {
+                    log::warn!("Could not check {}: {}", path.display(), err);
+                    None
+                }
+            }
+        }
+        dcc::ExistingFiles::Rename | dcc::ExistingFiles::Overwrite => None,
+    };
+    let server = match server {
+        Some(server) => server,
+        None => {
+            check_nick(&nick, DEFAULT_MAX_NICK_LEN).map_err(bad_request)?;
+            locate_bot(&state, &nick)
+                .await
+                .map_err(|lookups| (StatusCode::NOT_FOUND, Json(lookups)).into_response())?
+        }
+    };
+    let Some(server_connection) = state.servers.get(&server) else {
+        return Err(if state.configured_servers().contains(&server) {
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(header::RETRY_AFTER, "30")],
+            )
+                .into_response()
+        } else {
+            StatusCode::NOT_FOUND.into_response()
+        });
+    };
+    check_nick(&nick, server_connection.max_nick_len()).map_err(bad_request)?;
+    for source in &alternatives {
+        check_nick(&source.nick, server_connection.max_nick_len()).map_err(bad_request)?;
+    }
+    let id = state.next_download_id();
+    let mut item = DownloadItem::new(id, server, file_name, nick, command);
+    item.alternatives = alternatives;
+    item.max_bytes_per_sec = max_bytes_per_sec;
+
+    if let Some((path, bytes)) = present {
+        log::info!("{} is present already, not requesting it", item.file_name);
+        item.notice = Some("Present already, not requested".to_string());
+        item.digest = digest;
+        server_connection.downloads.insert(id, item);
+        server_connection.completed(&id, bytes, path);
+        return Ok(Json(RequestedDownload {
+            id,
+            already_present: true,
+        }));
+    }
+
+    if let Some(retry_at) = server_connection.pick_source(&mut item) {
+        log::info!(
+            "{} has no free slots, delaying {}",
+            item.nick,
+            item.file_name
+        );
+        item.status = DownloadStatus::Delayed(retry_at);
+        server_connection.downloads.insert(id, item);
+        server_connection.download_updated();
+        handler::retry_delayed(state.clone(), server_connection.id.clone(), id, retry_at);
+        return Ok(Json(RequestedDownload {
+            id,
+            already_present: false,
+        }));
+    }
+
+    eprintln!("Requesting DL: {} {}", item.nick, item.request_command);
+    server_connection
+        .request(item)
+        .map_err(|_err| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+    Ok(Json(RequestedDownload {
+        id,
+        already_present: false,
+    }))
+}
+
+#[derive(Serialize, Default, Debug, ToSchema)]
+pub struct ConfigReload {
+    /// Changed settings now in effect
+    pub applied: Vec<String>,
+    /// Changed settings of servers taking effect as they reconnect now, like credentials, and
+    /// servers connected or disconnected as they were added or removed
+    pub reconnecting: Vec<String>,
+    /// Changed settings taking effect after a restart
+    pub requires_restart: Vec<String>,
+}
+
+/// Names of the settings differing between two configurations, at the top level.
+fn changed_settings(old: &serde_json::Value, new: &serde_json::Value) -> Vec<String> {
+    let empty = serde_json::Map::new();
+    let old = old.as_object().unwrap_or(&empty);
+    let new = new.as_object().unwrap_or(&empty);
+    let mut changed: Vec<_> = old
+        .keys()
+        .chain(new.keys())
+        .filter(|key| old.get(*key) != new.get(*key))
+        .cloned()
+        .collect();
+    changed.sort();
+    changed.dedup();
+    changed
+}
+
+/// Servers of a configuration by id, as JSON.
+fn servers_by_id(configuration: &serde_json::Value) -> HashMap<ServerId, serde_json::Value> {
+    configuration["servers"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|server| {
+            let config: ServerConfig = serde_json::from_value(server.clone()).ok()?;
+            Some((config.id()?, server.clone()))
+        })
+        .collect()
+}
+
+#[utoipa::path(
+    post,
+    path = "/config/reload",
+    responses(
+        (status = 200, body = ConfigReload, description = "Configuration reloaded, listing what changed"),
+        (status = 400, description = "Configuration unreadable or invalid, nothing was changed")
+    )
+)]
+async fn reload_config(
+    State(state): State<Arc<App>>,
+) -> Result<Json<ConfigReload>, axum::response::Response> {
+    let mut configuration = Configuration::load(&state.config_file).map_err(bad_request)?;
+    let mut new = serde_json::to_value(&configuration)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+    let mut loaded = state.configuration.lock().unwrap();
+    // Servers added without persisting them stay, unless the file has them now
+    let in_file = servers_by_id(&new);
+    let runtime: Vec<_> = {
+        let runtime_servers = state.runtime_servers.lock().unwrap();
+        servers_by_id(&loaded)
+            .into_iter()
+            .filter(|(id, _)| runtime_servers.contains(id) && !in_file.contains_key(id))
+            .map(|(_, server)| server)
+            .collect()
+    };
+    if let Some(servers) = new["servers"].as_array_mut() {
+        servers.extend(runtime);
+    }
+    let mut reload = ConfigReload::default();
+    for setting in changed_settings(&loaded, &new) {
+        if setting == "servers" {
+            continue;
+        }
+        if LIVE_SETTINGS.contains(&setting.as_str()) {
+            reload.applied.push(setting);
+        } else {
+            reload.requires_restart.push(setting);
+        }
+    }
+
+    // Checked completely before changing anything, a bad reload leaves everything as it was
+    let (old_servers, new_servers) = (servers_by_id(&loaded), servers_by_id(&new));
+    let removed: Vec<_> = old_servers
+        .keys()
+        .filter(|id| !new_servers.contains_key(*id))
+        .cloned()
+        .collect();
+    let mut added = Vec::new();
+    let mut reconnecting = Vec::new();
+    let mut changed_servers = Vec::new();
+    for config in std::mem::take(&mut configuration.servers) {
+        let id = config.id().unwrap_or_default();
+        let Some(old) = old_servers.get(&id) else {
+            added.push(id);
+            continue;
+        };
+        let changed = changed_settings(old, &new_servers[&id]);
+        if changed.is_empty() {
+            continue;
+        }
+        config.restriction_patterns().map_err(bad_request)?;
+        let (reconnect, live): (Vec<_>, Vec<_>) = changed
+            .into_iter()
+            .partition(|setting| server::RECONNECT_SETTINGS.contains(&setting.as_str()));
+        let qualified = |setting| format!("servers.{}.{}", id, setting);
+        if !reconnect.is_empty() {
+            reload
+                .reconnecting
+                .extend(reconnect.into_iter().map(qualified));
+            reconnecting.push(id.clone());
+        }
+        changed_servers.push((id, config, live));
+    }
+    reload.reconnecting.extend(
+        removed
+            .iter()
+            .chain(&added)
+            .map(|id| format!("servers.{}", id)),
+    );
+
+    state
+        .runtime_servers
+        .lock()
+        .unwrap()
+        .retain(|id| !in_file.contains_key(id));
+    for (id, config, live) in changed_servers {
+        if let Some(mut server) = state.servers.get_mut(&id) {
+            if let Err(err) = server.apply_settings(config) {
+                log::warn!("Could not apply the settings of {}: {:#}", id, err);
+                continue;
+            }
+            reload.applied.extend(
+                live.into_iter()
+                    .map(|setting| format!("servers.{}.{}", id, setting)),
+            );
+        }
+    }
+
+    {
+        let mut options = state.download_options.write().unwrap();
+        let limit_changed = reload
+            .applied
+            .iter()
+            .any(|setting| setting == "max_concurrent_downloads");
+        let bandwidth_changed = reload
+            .applied
+            .iter()
+            .any(|setting| setting == "max_bytes_per_sec");
+        let configured = configuration.download_options(options.myip, options.myip_v6);
+        *options = DownloadOptions {
+            resumes: options.resumes.clone(),
+            socket_limit: options.socket_limit.clone(),
+            // Running transfers keep their permits of the previous limit
+            transfer_limit: if limit_changed {
+                configuration
+                    .max_concurrent_downloads
+                    .map(|max| Arc::new(Semaphore::new(max)))
+            } else {
+                options.transfer_limit.clone()
+            },
+            // Likewise for the bandwidth they share
+            bandwidth: if bandwidth_changed {
+                configured.bandwidth.clone()
+            } else {
+                options.bandwidth.clone()
+            },
+            ipv4_only: options.ipv4_only,
+            ..configured
+        };
+    }
+    state
+        .max_event_clients
+        .store(configuration.max_event_clients, Ordering::Relaxed);
+    state
+        .prefer_healthy
+        .store(configuration.prefer_healthy, Ordering::Relaxed);
+    *state.trash_window.lock().unwrap() = Duration::from_secs(configuration.trash_window_secs);
+    *state.retry_policy.lock().unwrap() = configuration.retry_policy();
+    state
+        .keep_aborted_parts
+        .store(configuration.keep_aborted_parts, Ordering::Relaxed);
+    *state.completion_webhook.lock().unwrap() = configuration.completion_webhook;
+    *loaded = new;
+    drop(loaded);
+    for id in &removed {
+        disconnect_server(&state, id);
+    }
+    for id in added {
+        let mut configured = state.configured_servers.write().unwrap();
+        if !configured.contains(&id) {
+            configured.push(id.clone());
+            tokio::spawn(reconnect(state.clone(), id));
+        }
+    }
+    // Their connection closing reconnects them, with the configuration as loaded now
+    for id in reconnecting {
+        if let Some(server) = state.servers.get(&id) {
+            if let Err(err) = server.client.send_quit("Reconnecting") {
+                log::warn!("Could not quit {}: {}", id, err);
+            }
+        }
+    }
+    log::info!("Reloaded configuration: {:?}", reload);
+    Ok(Json(reload))
+}
+
+#[utoipa::path(
+    post,
+    path = "/diagnostics/dcc",
+    responses(
+        (status = 200, body = dcc::PassiveDiagnostics),
+        (status = 503, description = "Our IP address is not known yet")
+    )
+)]
+async fn diagnose_dcc(
+    State(state): State<Arc<App>>,
+) -> Result<Json<dcc::PassiveDiagnostics>, StatusCode> {
+    let options = state.download_options();
+    let myip = options.myip.ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    Ok(Json(dcc::diagnose_passive(myip, options.port).await))
+}
+
+#[derive(Deserialize)]
+struct VerifyQuery {
+    #[serde(default)]
+    requeue: bool,
+    #[serde(default)]
+    forget: bool,
+}
+
+/// What checking the downloads against the download folder found, and what was done about it.
+#[derive(Serialize, Default, Debug, ToSchema)]
+pub struct VerifyReport {
+    /// Saved downloads fixed at startup
+    pub at_startup: Vec<Finding>,
+    /// Completed downloads not matching their file
+    pub findings: Vec<Finding>,
+    /// Downloads whose file is gone, requested again
+    pub requeued: Vec<DownloadId>,
+    /// Downloads whose file is gone, forgotten
+    pub forgotten: Vec<DownloadId>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/maintenance/verify",
+    params(
+        ("requeue" = Option<bool>, Query, description = "Request completed downloads whose file is gone again"),
+        ("forget" = Option<bool>, Query, description = "Forget completed downloads whose file is gone, unless requested again")
+    ),
+    responses((status = 200, body = VerifyReport))
+)]
+async fn verify_downloads(
+    State(state): State<Arc<App>>,
+    Query(query): Query<VerifyQuery>,
+) -> Json<VerifyReport> {
+    let mut report = VerifyReport {
+        at_startup: state.startup_findings.clone(),
+        ..Default::default()
+    };
+    for server in state.servers.iter() {
+        for item in server.completed_downloads() {
+            let Some(inconsistency) = integrity::check_completed(&item) else {
+                continue;
+            };
+            log::warn!("{} is inconsistent: {:?}", item.file_name, inconsistency);
+            report.findings.push(Finding::of(&item, inconsistency));
+            if inconsistency != Inconsistency::MissingFile {
+                continue;
+            }
+            let requeued = query.requeue
+                && server.request_again(&item.id).unwrap_or_else(|err| {
+                    log::warn!("Could not request {} again: {}", item.file_name, err);
+                    false
+                });
+            if requeued {
+                report.requeued.push(item.id);
+            } else if query.forget && server.forget_completed(&item.id) {
+                report.forgotten.push(item.id);
+            }
+        }
+    }
+    Json(report)
+}
+
+#[utoipa::path(
+    get,
+    path = "/downloads",
+    responses((status = 200, body = [DownloadItem], description = "Current downloads, followed by the ones completed lately"))
+)]
+async fn downloads(State(state): State<Arc<App>>) -> Json<Vec<DownloadItem>> {
+    let servers = &state.servers;
+    let mut downloads: Vec<_> = servers.iter().flat_map(|s| s.download_list()).collect();
+    downloads.extend(servers.iter().flat_map(|s| s.completed_downloads()));
+    Json(downloads)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/downloads/completed",
+    responses((status = 200, body = usize, description = "Completed downloads forgotten, how many there were"))
+)]
+async fn clear_completed(State(state): State<Arc<App>>) -> Json<usize> {
+    Json(state.servers.iter().map(|s| s.clear_completed()).sum())
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ServerStatus {
+    pub id: ServerId,
+    pub connected: bool,
+    pub state: ConnectionState,
+    /// Nick we are known by, which may be an alternate one
+    pub nick: Option<String>,
+    pub channels: Vec<server::ChannelStatus>,
+    /// Seconds since we connected
+    pub connected_secs: Option<u64>,
+    /// Downloads that did not end yet
+    pub active_downloads: usize,
+    /// Why the server currently refuses our messages
+    pub restriction: Option<server::Restriction>,
+    /// Latest round trip times in milliseconds, oldest first
+    pub latency_ms: Vec<u64>,
+    /// Lower is healthier, see `prefer_healthy`
+    pub health: Option<f64>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/servers",
+    responses((status = 200, body = [ServerStatus]))
+)]
+async fn servers(State(state): State<Arc<App>>) -> Json<Vec<ServerStatus>> {
+    let servers = state
+        .configured_servers()
+        .into_iter()
+        .map(|id| {
+            let connection = state.servers.get(&id);
+            // Reconnecting keeps the previous connection around until the new one takes over
+            let connection_state = connection
+                .as_deref()
+                .map_or(ConnectionState::Disconnected, ServerConnection::state);
+            let connected = matches!(
+                connection_state,
+                ConnectionState::Connected | ConnectionState::JoinedChannels
+            );
+            ServerStatus {
+                id,
+                connected,
+                state: connection_state,
+                nick: connection.as_deref().map(ServerConnection::nick),
+                channels: connection
+                    .as_deref()
+                    .map_or_else(Vec::new, ServerConnection::channel_statuses),
+                connected_secs: connection
+                    .as_deref()
+                    .filter(|_| connected)
+                    .map(|server| server.connected_at.elapsed().as_secs()),
+                active_downloads: connection
+                    .as_deref()
+                    .map_or(0, ServerConnection::active_downloads),
+                restriction: connection
+                    .as_deref()
+                    .and_then(|server| server.restriction.lock().unwrap().clone()),
+                latency_ms: connection.as_deref().map_or_else(Vec::new, |server| {
+                    server
+                        .latencies()
+                        .iter()
+                        .map(|latency| latency.as_millis() as u64)
+                        .collect()
+                }),
+                health: connection.as_deref().map(ServerConnection::health),
+            }
+        })
+        .collect();
+    Json(servers)
+}
+
+#[derive(Deserialize)]
+struct PersistQuery {
+    #[serde(default)]
+    persist: bool,
+}
+
+/// Changes the servers in the configuration file. Comments and formatting of the file are lost.
+fn persist_servers(
+    path: &std::path::Path,
+    change: impl FnOnce(&mut Vec<toml::Value>),
+) -> anyhow::Result<()> {
+    let mut file: toml::Value = toml::from_str(&std::fs::read_to_string(path)?)?;
+    let servers = file
+        .as_table_mut()
+        .ok_or_else(|| anyhow::anyhow!("Configuration is not a table"))?
+        .entry("servers")
+        .or_insert_with(|| toml::Value::Array(Vec::new()))
+        .as_array_mut()
+        .ok_or_else(|| anyhow::anyhow!("servers is not an array"))?;
+    change(servers);
+    std::fs::write(path, toml::to_string_pretty(&file)?)?;
+    Ok(())
+}
+
+#[utoipa::path(
+    post,
+    path = "/servers",
+    request_body(content = Object, description = "Server as configured in `servers` of config.toml"),
+    params(("persist" = Option<bool>, Query, description = "Also add the server to config.toml, otherwise it is kept on reloads but gone after a restart")),
+    responses(
+        (status = 201, body = String, description = "Connected, the id of the server"),
+        (status = 400, description = "Invalid configuration"),
+        (status = 409, description = "A server with the same id is configured already"),
+        (status = 502, description = "Could not connect to the server")
+    )
+)]
+async fn add_server(
+    State(state): State<Arc<App>>,
+    Query(query): Query<PersistQuery>,
+    Json(config): Json<serde_json::Value>,
+) -> Result<(StatusCode, Json<ServerId>), axum::response::Response> {
+    let config: ServerConfig =
+        serde_json::from_value(config).map_err(|err| bad_request(anyhow::Error::new(err)))?;
+    let Some(id) = config.id() else {
+        return Err(bad_request(anyhow::anyhow!("Server URL missing")));
+    };
+    config.restriction_patterns().map_err(bad_request)?;
+    if let Some(source_address) = config.dcc_source_address {
+        dcc::check_source_address(source_address).map_err(bad_request)?;
+    }
+    if state.configured_servers().contains(&id) {
+        return Err(StatusCode::CONFLICT.into_response());
+    }
+    let json = serde_json::to_value(&config)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+    let toml = toml::Value::try_from(&config)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+    let (connection, _, stream) = connect(config, state.download_options().socket_limit)
+        .await
+        .map_err(|err| (StatusCode::BAD_GATEWAY, format!("{:#}", err)).into_response())?;
+    {
+        let mut configured = state.configured_servers.write().unwrap();
+        // Added by another request while connecting
+        if configured.contains(&id) {
+            return Err(StatusCode::CONFLICT.into_response());
+        }
+        configured.push(id.clone());
+    }
+    log::info!("Connected to {}", id);
+    state.servers.insert(id.clone(), connection);
+    if let Some(servers) = state.configuration.lock().unwrap()["servers"].as_array_mut() {
+        servers.push(json);
+    }
+    state.new_streams.send((id.clone(), stream)).ok();
+    if !query.persist {
+        state.runtime_servers.lock().unwrap().insert(id.clone());
+    }
+    if query.persist {
+        persist_servers(&state.config_file, |servers| servers.push(toml)).map_err(|err| {
+            log::warn!("Could not add {} to the configuration: {:#}", id, err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?;
+    }
+    Ok((StatusCode::CREATED, Json(id)))
+}
+
+/// Disconnects from a server no longer configured, aborting its downloads. False if it was not
+/// configured.
+fn disconnect_server(state: &App, id: &ServerId) -> bool {
+    {
+        let mut configured = state.configured_servers.write().unwrap();
+        let Some(index) = configured.iter().position(|configured| configured == id) else {
+            return false;
+        };
+        configured.remove(index);
+    }
+    state.runtime_servers.lock().unwrap().remove(id);
+    if let Some((_, server)) = state.servers.remove(id) {
+        // Transfers still connecting or waiting for a slot stop once they notice the server gone
+        for download in server.downloads.iter() {
+            if let DownloadStatus::Progress(progress) = &download.status {
+                progress.cancellation.cancel(CancelReason::UserRequest);
+            }
+        }
+        if let Err(err) = server.client.send_quit("") {
+            log::warn!("Could not quit {}: {}", id, err);
+        }
+    }
+    state.queue.update(id, &[]);
+    log::info!("Removed server {}", id);
+    true
+}
+
+#[utoipa::path(
+    delete,
+    path = "/servers/{id}",
+    params(
+        ("id" = String, Path, description = "Label or URL of the server"),
+        ("persist" = Option<bool>, Query, description = "Also remove the server from config.toml")
+    ),
+    responses(
+        (status = 200, description = "Disconnected, its downloads aborted"),
+        (status = 404, description = "No such server")
+    )
+)]
+async fn remove_server(
+    State(state): State<Arc<App>>,
+    Path(id): Path<ServerId>,
+    Query(query): Query<PersistQuery>,
+) -> Result<(), StatusCode> {
+    // Without it in the configuration, a pending reconnect gives up
+    if let Some(servers) = state.configuration.lock().unwrap()["servers"].as_array_mut() {
+        servers.retain(|server| {
+            serde_json::from_value::<ServerConfig>(server.clone())
+                .map_or(true, |config| config.id().as_ref() != Some(&id))
+        });
+    }
+    if !disconnect_server(&state, &id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    if query.persist {
+        persist_servers(&state.config_file, |servers| {
+            servers.retain(|server| {
+                server
+                    .clone()
+                    .try_into::<ServerConfig>()
+                    .map_or(true, |config| config.id().as_ref() != Some(&id))
+            })
+        })
+        .map_err(|err| {
+            log::warn!("Could not remove {} from the configuration: {:#}", id, err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    }
+    Ok(())
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct StatsDto {
+    /// Clients currently following /events
+    pub event_clients: usize,
+    pub max_event_clients: usize,
+    /// Events skipped for clients that fell behind, since start
+    pub dropped_events: u64,
+    /// Events kept for clients falling behind
+    pub event_buffer: usize,
+    /// Events not yet seen by the slowest client
+    pub queued_events: usize,
+    /// Transfers on all servers, with the failures by kind
+    pub transfers: BotStats,
+}
+
+#[utoipa::path(
+    get,
+    path = "/stats",
+    responses((status = 200, body = StatsDto))
+)]
+async fn stats(State(state): State<Arc<App>>) -> Json<StatsDto> {
+    Json(StatsDto {
+        event_clients: state.event_clients.load(Ordering::Relaxed),
+        max_event_clients: state.max_event_clients.load(Ordering::Relaxed),
+        dropped_events: state.dropped_events.load(Ordering::Relaxed),
+        event_buffer: state.event_buffer,
+        queued_events: state.events.len(),
+        transfers: state.stats.transfer_totals(None),
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/servers/{id}/stats",
+    params(("id" = ServerId, Path, description = "Label or URL of the server")),
+    responses(
+        (status = 200, body = BotStats, description = "Transfers of all bots of the server, with the failures by kind"),
+        (status = 404, description = "No such server configured")
+    )
+)]
+async fn server_stats(
+    State(state): State<Arc<App>>,
+    Path(id): Path<ServerId>,
+) -> Result<Json<BotStats>, StatusCode> {
+    if !state.configured_servers().contains(&id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok(Json(state.stats.transfer_totals(Some(&id))))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SearchResponse {
+    /// Each result names the server it came from
+    pub results: Vec<SearchResult>,
+    pub servers: Vec<ServerSearchStatus>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ServerSearchStatus {
+    pub server: ServerId,
+    pub results: usize,
+    /// Milliseconds until the first result arrived
+    pub first_result_ms: Option<u64>,
+    /// Milliseconds until the latest result arrived
+    pub last_result_ms: Option<u64>,
+    /// Results were still coming in when the search gave up
+    pub timed_out: bool,
+}
+
+#[derive(serde::Deserialize)]
+struct SearchQuery {
+    query: String,
+    command_template: Option<String>,
+    #[serde(default)]
+    include_raw: bool,
+    /// Add to the results of the previous searches instead of replacing them
+    #[serde(default)]
+    append: bool,
+}
+
+#[utoipa::path(
+    get,
+    path = "/search",
+    params(
+        ("query" = String, Query, description = "Search term sent to the search channels"),
+        ("command_template" = Option<String>, Query, description = "Search command to use instead of `!s {}`, `{}` being replaced by the term"),
+        ("include_raw" = Option<bool>, Query, description = "Include the announcement each result was parsed from"),
+        ("append" = Option<bool>, Query, description = "Add to the results of previous searches, without duplicates, instead of replacing them")
+    ),
+    responses(
+        (status = 200, body = SearchResponse),
+        (status = 400, description = "Invalid query or command template"),
+        (status = 500, description = "Search could not be sent")
+    )
+)]
+async fn search(
+    State(state): State<Arc<App>>,
+    Query(search_query): Query<SearchQuery>,
+) -> Result<Json<SearchResponse>, axum::response::Response> {
+    check_irc_text("Query", &search_query.query).map_err(bad_request)?;
+    let template = search_query.command_template.as_deref();
+    if let Some(template) = template {
+        server::validate_search_template(template).map_err(bad_request)?;
+    }
+    {
+        let mut search = state.search.lock().unwrap();
+        if !search_query.append {
+            search.results.clear();
+        }
+        search.servers.clear();
+        search.command_template = template
+            .unwrap_or(server::DEFAULT_SEARCH_TEMPLATE)
+            .to_string();
+    }
+    // Subscribed before sending, so no result or end of the search slips through
+    let mut updates = state.search_updates.subscribe();
+    for server in state.servers.iter_mut() {
+        server
+            .search(&search_query.query, template)
+            .map_err(|_err| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+        state
+            .search
+            .lock()
+            .unwrap()
+            .servers
+            .insert(server.key().clone(), ServerSearch::new(&server));
+    }
+    // Until every server is done, the slowest one sets the pace
+    loop {
+        let pending_until = {
+            let search = state.search.lock().unwrap();
+            search
+                .servers
+                .values()
+                .filter_map(ServerSearch::pending_until)
+                .max()
+        };
+        let Some(until) = pending_until else { break };
+        tokio::time::timeout_at(until, updates.changed()).await.ok();
+    }
+    let search = state.search.lock().unwrap();
+    let servers = search
+        .servers
+        .iter()
+        .map(|(server, server_search)| ServerSearchStatus {
+            server: server.clone(),
+            results: server_search.results,
+            first_result_ms: server_search
+                .first_result
+                .map(|first| first.as_millis() as u64),
+            last_result_ms: (server_search.results > 0)
+                .then(|| (server_search.last_activity - server_search.started).as_millis() as u64),
+            timed_out: server_search.timed_out(),
+        })
+        .collect();
+    let mut results = search.results.clone();
+    if !search_query.include_raw {
+        for result in &mut results {
+            result.raw = None;
+        }
+    }
+    Ok(Json(SearchResponse { results, servers }))
+}
+
+/// Counts an /events client for as long as it is alive.
+struct EventClient(Arc<App>);
+
+impl EventClient {
+    fn register(app_state: Arc<App>) -> Option<Self> {
+        app_state
+            .event_clients
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |clients| {
+                (clients < app_state.max_event_clients.load(Ordering::Relaxed))
+                    .then_some(clients + 1)
+            })
+            .ok()?;
+        Some(EventClient(app_state))
+    }
+}
+
+impl Drop for EventClient {
+    fn drop(&mut self) {
+        self.0.event_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/events",
+    responses(
+        (
+            status = 200,
+            description = "Stream of `irc-message` events with a `MessageDto`, `server-status` events with a `ServerStateChange` whenever the connection to a server changes, `download-update` events with a `DownloadItem` whenever it changes, every second while transferring, and `download-removed` events with a `DownloadRemoved` once a download is gone",
+            body = MessageDto,
+            content_type = "text/event-stream"
+        ),
+        (status = 503, description = "Too many clients, retry after the given seconds")
+    )
+)]
+async fn sse_handler(
+    State(app_state): State<Arc<App>>,
+) -> Result<
+    Sse<impl tokio_stream::Stream<Item = Result<Event, Infallible>>>,
+    axum::response::Response,
+> {
+    let client = EventClient::register(app_state.clone()).ok_or_else(|| {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, EVENT_RETRY_AFTER)],
+            "Too many event clients",
+        )
+            .into_response()
+    })?;
+    let stream =
+        BroadcastStream::new(app_state.events.subscribe()).filter_map(move |event| match event {
+            Ok(event) => Some(Ok(Event::default().event(event.name).data(&*event.data))),
+            Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                client
+                    .0
+                    .dropped_events
+                    .fetch_add(skipped, Ordering::Relaxed);
+                None
+            }
+        });
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use irc::proto::FormattedStringExt;
+    use irc_downloader::Cancellation;
+    use regex::Regex;
 
-    #[test]
-    fn search_result1() {
-        let input = "\u{3}00,01\u{2}058\u{3}15\u{2})\u{3}10  10x\u{3}04\u{2} |\u{3}10\u{2} 7.5G\u{3}04\u{2} |\u{3}10\u{2} Something.Something-I-dont-really-know.2022.German.DTS.DL.720p.BluRay.x264-JJ.mkv\u{3}04\u{2} |\u{3}09\u{2} /MSG [AA]-DEMO|EU|S|DOESNOTEXIST XDCC SEND 90 \u{3}04\u{2}|\u{2}\u{3}00 Used: 11.53% 29/15 avg: 1.71TiB/s (113328s ago)\u{3}04 ".strip_formatting();
+    async fn app() -> Arc<App> {
+        app_with_event_clients(32).await
+    }
+
+    async fn app_with_event_clients(max_event_clients: usize) -> Arc<App> {
+        let config: ServerConfig = toml::from_str(
+            "channels = []\n[config]\nserver = \"mock\"\nnickname = \"me\"\nuse_mock_connection = true\n",
+        )
+        .unwrap();
+        let (connection, server_id, stream) = ServerConnection::new(config).await.unwrap();
+        // The stream owns the queue of outgoing messages, sending fails once it is dropped
+        tokio::spawn(async move {
+            let _stream = stream;
+            std::future::pending::<()>().await
+        });
+        Arc::new(App {
+            search: Default::default(),
+            search_updates: watch::channel(()).0,
+            events: broadcast::channel(default_event_buffer()).0,
+            max_event_clients: AtomicUsize::new(max_event_clients),
+            event_clients: AtomicUsize::new(0),
+            dropped_events: AtomicU64::new(0),
+            event_buffer: default_event_buffer(),
+            download_options: RwLock::new(DownloadOptions::new(
+                std::net::Ipv4Addr::LOCALHOST,
+                0,
+                std::env::temp_dir(),
+            )),
+            servers: DashMap::from_iter([(server_id.clone(), connection)]),
+            configured_servers: RwLock::new(vec![server_id]),
+            runtime_servers: Default::default(),
+            new_streams: mpsc::unbounded_channel().0,
+            download_id: AtomicUsize::new(0),
+            prefer_healthy: AtomicBool::new(false),
+            trash_window: Mutex::new(Duration::from_secs(600)),
+            retry_policy: Mutex::new(RetryPolicy {
+                max_retries: 0,
+                backoff: Duration::from_secs(30),
+            }),
+            keep_aborted_parts: AtomicBool::new(true),
+            completion_webhook: Mutex::new(None),
+            webhook_client: reqwest::Client::new(),
+            config_file: PathBuf::from(CONFIG_FILE),
+            configuration: Mutex::new(serde_json::Value::Null),
+            stats: StatsStore::load(std::env::temp_dir().join("irc-dl-test-stats.json")).unwrap(),
+            queue: QueueStore::load(std::env::temp_dir().join("irc-dl-test-downloads.json"))
+                .unwrap(),
+            startup_findings: Vec::new(),
+        })
+    }
+
+    /// A download of the mock server, of the pack numbered like its id.
+    fn item(id: DownloadId, nick: &str) -> DownloadItem {
+        DownloadItem::new(
+            id,
+            "mock".to_string(),
+            format!("file{}.mkv", id),
+            nick.to_string(),
+            format!("xdcc send #{}", id),
+        )
+    }
+
+    #[tokio::test]
+    async fn download_changes_published() {
+        let state = app().await;
+        let mut events = state.events.subscribe();
+        tokio::spawn(publish_download_updates(state.clone()));
+        state
+            .servers
+            .get("mock")
+            .unwrap()
+            .request(item(0, "Bot"))
+            .unwrap();
+        let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.name, "download-update");
+        assert!(event.data.contains("\"Requested\""));
+
+        assert!(state.servers.get("mock").unwrap().remove_download(&0));
+        let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.name, "download-removed");
+        assert_eq!(&*event.data, r#"{"id":0}"#);
+    }
+
+    /// Points the completion webhook of `state` at a listener and requests a download `0`.
+    async fn with_webhook(state: &App) -> tokio::net::TcpListener {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        *state.completion_webhook.lock().unwrap() =
+            Some(format!("http://{}/done", listener.local_addr().unwrap()));
+        state
+            .servers
+            .get("mock")
+            .unwrap()
+            .request(item(0, "Bot"))
+            .unwrap();
+        listener
+    }
+
+    /// The body of the first call of the webhook.
+    async fn webhook_body(listener: &tokio::net::TcpListener) -> serde_json::Value {
+        let (mut socket, _) = tokio::time::timeout(Duration::from_secs(5), listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut request = Vec::new();
+        while !request.ends_with(b"}") {
+            let mut buffer = [0; 1024];
+            let read = tokio::io::AsyncReadExt::read(&mut socket, &mut buffer)
+                .await
+                .unwrap();
+            assert_ne!(read, 0);
+            request.extend_from_slice(&buffer[..read]);
+        }
+        let request = String::from_utf8(request).unwrap();
+        assert!(request.starts_with("POST /done "));
+        serde_json::from_str(&request[request.find("\r\n\r\n").unwrap() + 4..]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn completion_webhook_called_on_failure() {
+        let state = app().await;
+        let listener = with_webhook(&state).await;
+        // Not over yet
+        state.notify_completion("mock", 0, 0);
+        state
+            .servers
+            .get("mock")
+            .unwrap()
+            .downloads
+            .get_mut(&0)
+            .unwrap()
+            .status = DownloadStatus::Failed("gone".to_string());
+        state.notify_completion("mock", 0, 42);
+
+        let body = webhook_body(&listener).await;
+        assert_eq!(body["file_name"], "file0.mkv");
+        assert_eq!(body["nick"], "Bot");
+        assert_eq!(body["server"], "mock");
+        assert_eq!(body["status"]["Failed"], "gone");
+        assert_eq!(body["transferred"], 42);
+    }
+
+    #[tokio::test]
+    async fn completion_webhook_called_on_completion() {
+        let state = app().await;
+        let listener = with_webhook(&state).await;
+        state
+            .servers
+            .get("mock")
+            .unwrap()
+            .completed(&0, 42, PathBuf::from("a (1).mkv"));
+        state.notify_completion("mock", 0, 42);
+
+        let body = webhook_body(&listener).await;
+        assert_eq!(body["file_name"], "a (1).mkv");
+        assert_eq!(body["status"]["Completed"]["bytes"], 42);
+        assert_eq!(body["transferred"], 42);
+    }
+
+    #[tokio::test]
+    async fn completion_webhook_called_when_removed() {
+        let state = app().await;
+        let listener = with_webhook(&state).await;
+        assert!(state.servers.get("mock").unwrap().remove_download(&0));
+        state.notify_completion("mock", 0, 7);
+
+        let body = webhook_body(&listener).await;
+        assert_eq!(body["id"], 0);
+        assert_eq!(body["status"]["Aborted"]["reason"], "user-request");
+        assert_eq!(body["transferred"], 7);
+    }
+
+    #[tokio::test]
+    async fn cancel_reasons_stored() {
+        let state = app().await;
+        let server = state.servers.get("mock").unwrap();
+        for (id, reason) in [
+            CancelReason::UserRequest,
+            CancelReason::Stall,
+            CancelReason::MinSpeed,
+            CancelReason::Shutdown,
+            CancelReason::Disk,
+            CancelReason::Pause,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let download = item(id, "CancelledBot");
+            let file_name = download.file_name.clone();
+            server.request(download).unwrap();
+            handler::transfer_aborted(&*state, "mock", id, "CancelledBot", &file_name, reason);
+            let status = server.downloads.get(&id).unwrap().status.clone();
+            match reason {
+                // Continued later
+                CancelReason::Disk => assert!(matches!(status, DownloadStatus::Queued)),
+                CancelReason::Pause => assert!(matches!(status, DownloadStatus::Paused)),
+                _ => assert!(matches!(
+                    status,
+                    DownloadStatus::Aborted { reason: stored } if stored == reason
+                )),
+            }
+        }
+        // Stalled and too slow transfers count as failures of the bot
+        let bots = state.stats.bots("mock");
+        let bot = &bots["CancelledBot"];
+        assert_eq!(bot.failures[&FailureKind::Stalled], 2);
+        assert_eq!(bot.failures.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn transfers_paused_in_quiet_hours() {
+        let state = app().await;
+        let server = state.servers.get("mock").unwrap();
+        server.request(item(0, "Bot")).unwrap();
+        let (cancellation, _) = Cancellation::new_pair();
+        server.downloads.get_mut(&0).unwrap().status = DownloadStatus::Progress(DownloadProgress {
+            transferred: Default::default(),
+            file_size: None,
+            bytes_per_sec: Default::default(),
+            eta_seconds: Default::default(),
+            cancellation: cancellation.clone(),
+        });
+        drop(server);
+        let mut paused = Vec::new();
+
+        apply_quiet_hours(&state, true, true, &mut paused);
+        assert_eq!(cancellation.reason(), Some(CancelReason::Pause));
+        assert_eq!(paused, [("mock".to_string(), 0)]);
+        handler::transfer_aborted(&*state, "mock", 0, "Bot", "file0.mkv", CancelReason::Pause);
+
+        apply_quiet_hours(&state, false, true, &mut paused);
+        assert!(paused.is_empty());
+        let server = state.servers.get("mock").unwrap();
+        assert!(matches!(
+            server.downloads.get(&0).unwrap().status,
+            DownloadStatus::Requested
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn download_list_follows_progress() {
+        let state = app().await;
+        let server_id = "mock".to_string();
+        state
+            .servers
+            .get(&server_id)
+            .unwrap()
+            .downloads
+            .insert(0, item(0, "Bot"));
+        let (dcc_send, _) =
+            dcc::DccSend::from_str("\u{1}DCC SEND file0.mkv 2130706433 5000 10000\u{1}").unwrap();
+        let (progress, receiver) = watch::channel(dcc::DownloadProgress::default());
+        // A bot sending 1000 bytes every half second, then dropping the connection
+        let transfer = async move {
+            for i in 1..=10 {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                progress.send_modify(|progress| {
+                    progress.transferred_bytes = i * 1000;
+                    progress.updated_at = Some(Instant::now());
+                });
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            Err(anyhow::anyhow!("Connection lost"))
+        };
+        let supervisor = {
+            let state = state.clone();
+            tokio::spawn(async move {
+                let options = state.download_options();
+                let server_id = "mock".to_string();
+                let mut observer = handler::DownloadObserver::new(&*state, &server_id, 0);
+                handler::supervise_transfer(&dcc_send, transfer, receiver, &options, &mut observer)
+                    .await
+            })
+        };
+
+        // Read between the updates, each read sees the progress of the one before
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        let mut seen = Vec::new();
+        let mut throughput = 0;
+        for _ in 0..11 {
+            let list = serde_json::to_value(downloads(State(state.clone())).await.0).unwrap();
+            let progress = &list[0]["status"]["Progress"];
+            seen.push(progress["transferred"].as_u64());
+            throughput = progress["bytes_per_sec"].as_u64().unwrap_or(0);
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+        let expected: Vec<_> = (0..=10).map(|i| (i > 0).then_some(i * 1000)).collect();
+        assert_eq!(seen, expected);
+        assert!(throughput > 0);
+
+        let (outcome, transferred) = supervisor.await.unwrap();
+        assert!(matches!(outcome, Ok(Err(_))));
+        assert_eq!(transferred, 10_000);
+    }
+
+    #[tokio::test]
+    async fn verify_repairs_only_when_asked() {
+        let state = app().await;
+        let folder = std::env::temp_dir().join(format!("irc-dl-verify-{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        std::fs::write(folder.join("file0.mkv"), "complete").unwrap();
+        std::fs::write(folder.join("file1.mkv"), "cut").unwrap();
+        let server = state.servers.get("mock").unwrap();
+        for id in 0..4 {
+            server.downloads.insert(id, item(id, "Bot"));
+            server.completed(&id, 8, folder.join(format!("file{}.mkv", id)));
+        }
+        drop(server);
+        let verify = |requeue, forget| {
+            verify_downloads(State(state.clone()), Query(VerifyQuery { requeue, forget }))
+        };
 
-        let capture = REX_SEARCH.captures(&input).unwrap();
-        itertools::assert_equal(
-            capture.iter().skip(1).flatten().map(|i| i.as_str()),
+        let report = verify(false, false).await.0;
+        let found: Vec<_> = report
+            .findings
+            .iter()
+            .map(|f| (f.id, f.inconsistency))
+            .collect();
+        assert_eq!(
+            found,
             [
-                "Something.Something-I-dont-really-know.2022.German.DTS.DL.720p.BluRay.x264-JJ.mkv",
-                "[AA]-DEMO|EU|S|DOESNOTEXIST",
-                "XDCC SEND 90",
-            ],
+                (1, Inconsistency::SizeMismatch),
+                (2, Inconsistency::MissingFile),
+                (3, Inconsistency::MissingFile)
+            ]
+        );
+        assert!(report.requeued.is_empty() && report.forgotten.is_empty());
+        assert_eq!(
+            state
+                .servers
+                .get("mock")
+                .unwrap()
+                .completed_downloads()
+                .len(),
+            4
+        );
+
+        let report = verify(true, false).await.0;
+        assert_eq!(report.requeued, [2, 3]);
+        let report = verify(false, true).await.0;
+        std::fs::remove_dir_all(&folder).unwrap();
+        assert_eq!(report.findings.len(), 1);
+        assert!(report.forgotten.is_empty());
+        let server = state.servers.get("mock").unwrap();
+        assert!(server.downloads.contains_key(&2));
+        assert!(server.downloads.contains_key(&3));
+    }
+
+    #[tokio::test]
+    async fn forgotten_when_asked() {
+        let state = app().await;
+        let server = state.servers.get("mock").unwrap();
+        server.downloads.insert(0, item(0, "Bot"));
+        server.completed(&0, 8, std::env::temp_dir().join("irc-dl-verify-gone.mkv"));
+        drop(server);
+
+        let query = VerifyQuery {
+            requeue: false,
+            forget: true,
+        };
+        let report = verify_downloads(State(state.clone()), Query(query)).await.0;
+        assert_eq!(report.forgotten, [0]);
+        assert!(state
+            .servers
+            .get("mock")
+            .unwrap()
+            .completed_downloads()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_transfers_retried() {
+        let state = app().await;
+        *state.retry_policy.lock().unwrap() = RetryPolicy {
+            max_retries: 1,
+            backoff: Duration::ZERO,
+        };
+        let server_id = "mock".to_string();
+        let server = state.servers.get(&server_id).unwrap();
+        for id in 0..2 {
+            server.request(item(id, "RetriedBot")).unwrap();
+        }
+        let status = |id| server.downloads.get(&id).unwrap().status.clone();
+
+        let lost = anyhow::anyhow!("Connection reset by peer");
+        handler::transfer_failed(&state, &server_id, 0, "RetriedBot", &lost);
+        assert!(matches!(status(0), DownloadStatus::Retrying(_)));
+        let until = Instant::now() + Duration::from_secs(5);
+        while !matches!(status(0), DownloadStatus::Requested) {
+            assert!(Instant::now() < until, "Not requested again");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let download = server.downloads.get(&0).unwrap().clone();
+        assert_eq!(download.retries, 1);
+        assert_eq!(download.notice.as_deref(), Some("Connection reset by peer"));
+        // No retries left
+        handler::transfer_failed(&state, &server_id, 0, "RetriedBot", &lost);
+        assert!(matches!(status(0), DownloadStatus::Failed(_)));
+
+        // Asking again would fail the same
+        let full = dcc::TransferError::new(FailureKind::Disk, anyhow::anyhow!("No space left"));
+        handler::transfer_failed(
+            &state,
+            &server_id,
+            1,
+            "RetriedBot",
+            &anyhow::Error::from(full),
+        );
+        assert!(matches!(status(1), DownloadStatus::Failed(_)));
+
+        // Removed while its transfer failed
+        server.downloads.remove(&1);
+        handler::transfer_failed(&state, &server_id, 1, "RetriedBot", &lost);
+    }
+
+    #[tokio::test]
+    async fn injection_rejected_on_every_endpoint() {
+        let state = app().await;
+        let injection = "x\r\nPRIVMSG NickServ :DROP";
+        let download = |nick: &str, command: &str, file_name: &str| DownloadRequest {
+            server: Some("mock".to_string()),
+            file_name: file_name.to_string(),
+            nick: nick.to_string(),
+            command: command.to_string(),
+            size: None,
+            digest: None,
+            alternatives: Vec::new(),
+            max_bytes_per_sec: None,
+        };
+        for request in [
+            download(injection, "xdcc send #1", "a.mkv"),
+            download("Bot", injection, "a.mkv"),
+            download("Bot", "xdcc send #1", injection),
+            download("Bot QUIT", "xdcc send #1", "a.mkv"),
+            download("Bot", "xdcc send #1", ".."),
+        ] {
+            let response = request_download(State(state.clone()), Json(request))
+                .await
+                .unwrap_err();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+        for query in [
+            SearchQuery {
+                query: injection.to_string(),
+                command_template: None,
+                include_raw: false,
+                append: false,
+            },
+            SearchQuery {
+                query: "x".to_string(),
+                command_template: Some(format!("!s {{}}{}", injection)),
+                include_raw: false,
+                append: false,
+            },
+        ] {
+            let response = search(State(state.clone()), Query(query))
+                .await
+                .unwrap_err();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+        let abort =
+            |filename: Option<&str>, nick: Option<&str>, command: Option<&str>| AbortQuery {
+                filename: filename.map(str::to_string),
+                nick: nick.map(str::to_string),
+                command: command.map(str::to_string),
+                all: true,
+            };
+        for query in [
+            abort(Some(injection), None, None),
+            abort(None, Some(injection), None),
+            abort(None, Some("Bot"), Some(injection)),
+        ] {
+            let response = abort_matching_downloads(State(state.clone()), Query(query))
+                .await
+                .unwrap_err();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+        assert!(state.servers.get("mock").unwrap().downloads.is_empty());
+
+        request_download(
+            State(state.clone()),
+            Json(download("[Bot]", "xdcc send #1", "a.mkv")),
+        )
+        .await
+        .unwrap();
+        assert_eq!(state.servers.get("mock").unwrap().downloads.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn present_files_not_requested() {
+        let state = app().await;
+        let folder = std::env::temp_dir().join(format!("irc-dl-skip-{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        std::fs::write(folder.join("a.mkv"), b"hello world").unwrap();
+        {
+            let mut options = state.download_options.write().unwrap();
+            options.download_folder = folder.clone();
+            options.existing_files = dcc::ExistingFiles::Skip;
+        }
+        let download = |size| DownloadRequest {
+            server: Some("mock".to_string()),
+            file_name: "a.mkv".to_string(),
+            nick: "Bot".to_string(),
+            command: "xdcc send #1".to_string(),
+            size,
+            digest: None,
+            alternatives: Vec::new(),
+            max_bytes_per_sec: None,
+        };
+
+        let Json(present) = request_download(State(state.clone()), Json(download(Some(11))))
+            .await
+            .unwrap();
+        assert!(present.already_present);
+        let server = state.servers.get("mock").unwrap();
+        assert!(server.downloads.is_empty());
+        assert!(server.completed_download(&present.id).is_some());
+        drop(server);
+
+        let Json(truncated) = request_download(State(state.clone()), Json(download(Some(12))))
+            .await
+            .unwrap();
+        assert!(!truncated.already_present);
+        assert!(state
+            .servers
+            .get("mock")
+            .unwrap()
+            .downloads
+            .contains_key(&truncated.id));
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    async fn content_encoding(router: &Router, uri: &str, accept: &str) -> Option<String> {
+        use tower::ServiceExt;
+        let request = axum::http::Request::builder()
+            .uri(uri)
+            .header(header::ACCEPT_ENCODING, accept)
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert!(response.status().is_success(), "{} failed", uri);
+        response
+            .headers()
+            .get(header::CONTENT_ENCODING)
+            .map(|encoding| encoding.to_str().unwrap().to_string())
+    }
+
+    #[tokio::test]
+    async fn compression_per_route() {
+        let static_files =
+            std::env::temp_dir().join(format!("irc-dl-static-{}", std::process::id()));
+        std::fs::create_dir_all(&static_files).unwrap();
+        std::fs::write(static_files.join("app.js"), "console.log('hi')").unwrap();
+        std::fs::write(static_files.join("app.js.br"), b"precompressed").unwrap();
+        let router = router(app().await, static_files.to_str().unwrap());
+
+        assert_eq!(
+            content_encoding(&router, "/api-docs/openapi.json", "gzip").await,
+            Some("gzip".to_string())
         );
-        assert!(capture.name("filename").is_some());
-        assert!(capture.name("nick").is_some());
-        assert!(capture.name("command").is_some());
+        assert_eq!(
+            content_encoding(&router, "/api-docs/openapi.json", "br").await,
+            Some("br".to_string())
+        );
+        assert_eq!(content_encoding(&router, "/events", "gzip, br").await, None);
+        assert_eq!(
+            content_encoding(&router, "/app.js", "br").await,
+            Some("br".to_string())
+        );
+        assert_eq!(content_encoding(&router, "/app.js", "identity").await, None);
+        std::fs::remove_dir_all(&static_files).unwrap();
+    }
+
+    #[tokio::test]
+    async fn wait_for_download_states() {
+        let state = app().await;
+        let wait = |id: DownloadId, timeout: u64, until: WaitUntil| {
+            wait_for_download(
+                State(state.clone()),
+                Path(id),
+                Query(WaitQuery {
+                    timeout: Some(timeout),
+                    until: Some(until),
+                }),
+            )
+        };
+        assert_eq!(
+            wait(7, 0, WaitUntil::Terminal).await.err(),
+            Some(StatusCode::NOT_FOUND)
+        );
+        {
+            let server = state.servers.get("mock").unwrap();
+            server.request(item(0, "Bot")).unwrap();
+            server.request(item(1, "Bot")).unwrap();
+            server.abort_download(&0, CancelReason::UserRequest);
+        }
+
+        // Ended before the wait started
+        let response = wait(0, 30, WaitUntil::Progress).await.unwrap();
+        assert!(!response.timed_out);
+        assert!(response.download.status.is_terminal());
+
+        let response = wait(1, 0, WaitUntil::Terminal).await.unwrap();
+        assert!(response.timed_out);
+
+        let waiting = tokio::spawn(wait(1, 30, WaitUntil::Terminal));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        state
+            .servers
+            .get("mock")
+            .unwrap()
+            .completed(&1, 0, PathBuf::new());
+        let response = waiting.await.unwrap().unwrap();
+        assert!(response.completed);
+        assert!(!response.timed_out);
+    }
+
+    #[tokio::test]
+    async fn event_clients_capped() {
+        use tower::ServiceExt;
+        let state = app_with_event_clients(1).await;
+        let router = router(state.clone(), "frontend/dist");
+        let events = || {
+            axum::http::Request::builder()
+                .uri("/events")
+                .body(axum::body::Body::empty())
+                .unwrap()
+        };
+
+        let first = router.clone().oneshot(events()).await.unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+        let second = router.clone().oneshot(events()).await.unwrap();
+        assert_eq!(second.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(second.headers()[header::RETRY_AFTER], EVENT_RETRY_AFTER);
+        assert_eq!(state.event_clients.load(Ordering::Relaxed), 1);
+
+        drop(first);
+        assert_eq!(state.event_clients.load(Ordering::Relaxed), 0);
+        let third = router.oneshot(events()).await.unwrap();
+        assert_eq!(third.status(), StatusCode::OK);
     }
 
     #[test]
-    fn search_result2() {
-        let input = "\u{3}03(\u{3} 0x \u{3}03[\u{3}001.7G\u{3}03]\u{2} I-cant-believe-this.S01E07.1080p.HEVC.x265-noooaa.mkv \u{2}) (\u{3} /msg IDONOTCAREWHATYOURNAMEIS xdcc send #13384 \u{3}03) (\u{3} Used:\u{3}03 1/10 \u{3}Avg: \u{3}991034.62MB/s )".strip_formatting();
-        eprintln!("{}", input);
+    fn zero_cleanup_interval_refused() {
+        let file = std::env::temp_dir().join(format!("irc-dl-load-{}.toml", std::process::id()));
+        let config = |interval_secs: u64| {
+            format!(
+                "download_folder = \"/tmp\"\nport = 0\nservers = []\n\
+                [cleanup]\ninterval_secs = {}\n",
+                interval_secs
+            )
+        };
+        std::fs::write(&file, config(60)).unwrap();
+        assert!(Configuration::load(&file).is_ok());
+        std::fs::write(&file, config(0)).unwrap();
+        let err = Configuration::load(&file).err().unwrap();
+        std::fs::remove_file(&file).unwrap();
+        assert!(err.to_string().contains("interval_secs"));
+    }
 
-        let capture = REX_SEARCH.captures(&input).unwrap();
-        itertools::assert_equal(
-            capture.iter().skip(1).flatten().map(|i| i.as_str()),
-            [
-                "I-cant-believe-this.S01E07.1080p.HEVC.x265-noooaa.mkv",
-                "IDONOTCAREWHATYOURNAMEIS",
-                "xdcc send #13384",
-            ],
+    #[test]
+    fn empty_event_buffer_refused() {
+        let file = std::env::temp_dir().join(format!("irc-dl-buffer-{}.toml", std::process::id()));
+        let config = |event_buffer: usize| {
+            format!(
+                "download_folder = \"/tmp\"\nport = 0\nevent_buffer = {}\nservers = []\n",
+                event_buffer
+            )
+        };
+        std::fs::write(&file, config(1)).unwrap();
+        assert!(Configuration::load(&file).is_ok());
+        std::fs::write(&file, config(0)).unwrap();
+        let err = Configuration::load(&file).err().unwrap();
+        std::fs::remove_file(&file).unwrap();
+        assert!(err.to_string().contains("event_buffer"));
+    }
+
+    #[tokio::test]
+    async fn reload_applies_live_settings() {
+        let mut state = app().await;
+        let file = std::env::temp_dir().join(format!("irc-dl-reload-{}.toml", std::process::id()));
+        Arc::get_mut(&mut state).unwrap().config_file = file.clone();
+        let config = |keep_parts: bool, max_sockets: usize, nickname: &str| {
+            format!(
+                "download_folder = \"/tmp\"\nport = 0\nkeep_aborted_parts = {}\n\
+                max_sockets = {}\n[[servers]]\nchannels = []\n[servers.config]\n\
+                server = \"mock\"\nnickname = \"{}\"\nuse_mock_connection = true\n",
+                keep_parts, max_sockets, nickname
+            )
+        };
+        std::fs::write(&file, config(true, 4, "me")).unwrap();
+        reload_config(State(state.clone())).await.unwrap();
+
+        std::fs::write(
+            &file,
+            config(false, 8, "other").replace(
+                "channels = []",
+                "channels = [{ name = \"#search\", search = true }]",
+            ),
+        )
+        .unwrap();
+        let reload = reload_config(State(state.clone())).await.unwrap().0;
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(
+            reload.applied,
+            ["keep_aborted_parts", "servers.mock.channels"]
         );
-        assert!(capture.name("filename").is_some());
-        assert!(capture.name("nick").is_some());
-        assert!(capture.name("command").is_some());
+        assert_eq!(reload.reconnecting, ["servers.mock.config"]);
+        assert_eq!(reload.requires_restart, ["max_sockets"]);
+        assert!(!state.keep_aborted_parts.load(Ordering::Relaxed));
+        assert_eq!(state.servers.get("mock").unwrap().channels.len(), 1);
+
+        std::fs::write(&file, "port = \"not a number\"").unwrap();
+        let response = reload_config(State(state.clone())).await.unwrap_err();
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn runtime_servers_kept_on_reload() {
+        let mut state = app().await;
+        let file = std::env::temp_dir().join(format!("irc-dl-runtime-{}.toml", std::process::id()));
+        Arc::get_mut(&mut state).unwrap().config_file = file.clone();
+        std::fs::write(
+            &file,
+            "download_folder = \"/tmp\"\nport = 0\n[[servers]]\nchannels = []\n\
+            [servers.config]\nserver = \"mock\"\nnickname = \"me\"\nuse_mock_connection = true\n",
+        )
+        .unwrap();
+        reload_config(State(state.clone())).await.unwrap();
+        add_server(
+            State(state.clone()),
+            Query(PersistQuery { persist: false }),
+            Json(serde_json::json!({
+                "label": "other",
+                "channels": [],
+                "config": {"server": "mock", "nickname": "me", "use_mock_connection": true}
+            })),
+        )
+        .await
+        .unwrap();
+
+        let reload = reload_config(State(state.clone())).await.unwrap().0;
+        std::fs::remove_file(&file).unwrap();
+        assert!(reload.reconnecting.is_empty());
+        let configuration = state.configuration.lock().unwrap();
+        assert!(servers_by_id(&configuration).contains_key("other"));
     }
-}
 
-trait IrcCase {
-    type Other: ?Sized;
+    #[tokio::test]
+    async fn reload_connects_and_disconnects_servers() {
+        let mut state = app().await;
+        let file = std::env::temp_dir().join(format!("irc-dl-servers-{}.toml", std::process::id()));
+        Arc::get_mut(&mut state).unwrap().config_file = file.clone();
+        let server = |label: &str| {
+            format!(
+                "[[servers]]\n{}channels = []\n[servers.config]\nserver = \"mock\"\n\
+                nickname = \"me\"\nuse_mock_connection = true\n",
+                label
+            )
+        };
+        std::fs::write(
+            &file,
+            format!(
+                "download_folder = \"/tmp\"\nport = 0\n{}{}",
+                server(""),
+                server("label = \"other\"\n")
+            ),
+        )
+        .unwrap();
+        reload_config(State(state.clone())).await.unwrap();
+        assert_eq!(state.configured_servers(), ["mock", "other"]);
 
-    fn eq_ignore_irc_case(&self, other: &Self::Other) -> bool;
-}
+        std::fs::write(
+            &file,
+            format!(
+                "download_folder = \"/tmp\"\nport = 0\n{}",
+                server("label = \"other\"\n")
+            ),
+        )
+        .unwrap();
+        let reload = reload_config(State(state.clone())).await.unwrap().0;
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(reload.reconnecting, ["servers.mock"]);
+        assert_eq!(state.configured_servers(), ["other"]);
+        assert!(!state.servers.contains_key("mock"));
+    }
+
+    #[tokio::test]
+    async fn search_done_when_ended_or_quiet() {
+        let state = app().await;
+        let mut server = state.servers.get_mut("mock").unwrap();
+        server.search_idle = Duration::from_millis(500);
+        server.search_timeout = Duration::from_secs(5);
+        let mut server_search = ServerSearch::new(&server);
+        assert!(server_search.pending_until().is_some());
+        server_search.last_activity = Instant::now() - Duration::from_millis(500);
+        // Bots get longer for their first result
+        assert!(server_search.pending_until().is_some());
+        server_search.results = 1;
+        assert!(server_search.pending_until().is_none());
+        assert!(!server_search.timed_out());
+
+        server_search.last_activity = Instant::now();
+        server_search
+            .bots
+            .extend(["a".to_string(), "b".to_string()]);
+        assert!(server_search.pending_until().is_some());
+        // Another bot may still be sending
+        server_search.ended.insert("a".to_string());
+        assert!(server_search.pending_until().is_some());
+        server_search.ended.insert("b".to_string());
+        assert!(server_search.pending_until().is_none());
+
+        // Results kept coming until the time was up
+        server_search.ended.clear();
+        server_search.started = Instant::now() - Duration::from_secs(5);
+        assert!(server_search.pending_until().is_none());
+        assert!(server_search.timed_out());
+    }
+
+    #[tokio::test]
+    async fn stats_report_event_queue() {
+        let state = app().await;
+        let mut slow = state.events.subscribe();
+        for n in 0..3 {
+            state.publish("irc-message", &n);
+        }
+        let Json(dto) = stats(State(state.clone())).await;
+        assert_eq!(dto.queued_events, 3);
+        assert_eq!(dto.event_buffer, default_event_buffer());
+
+        slow.recv().await.unwrap();
+        let Json(dto) = stats(State(state)).await;
+        assert_eq!(dto.queued_events, 2);
+    }
+
+    #[tokio::test]
+    async fn server_state_changes_published() {
+        let state = app().await;
+        let mut events = state.events.subscribe();
+        let server_id = "mock".to_string();
+
+        state.set_server_state(&server_id, ConnectionState::Connected);
+        // Unchanged, nothing to tell
+        state.set_server_state(&server_id, ConnectionState::Connected);
+        state.set_server_state(&server_id, ConnectionState::Disconnected);
 
-impl IrcCase for [u8] {
-    type Other = [u8];
+        for expected in ["connected", "disconnected"] {
+            let event = events.try_recv().unwrap();
+            assert_eq!(event.name, "server-status");
+            assert_eq!(
+                &*event.data,
+                format!(r#"{{"server":"mock","state":"{}"}}"#, expected)
+            );
+        }
+        assert!(events.try_recv().is_err());
+        let Json(servers) = servers(State(state)).await;
+        assert_eq!(servers[0].state, ConnectionState::Disconnected);
+        assert!(!servers[0].connected);
+        assert_eq!(servers[0].connected_secs, None);
+    }
 
-    fn eq_ignore_irc_case(&self, other: &Self::Other) -> bool {
-        self.len() == other.len()
-            && std::iter::zip(self, other).all(|(a, b)| {
-                a.eq_ignore_ascii_case(b)
-                    || matches!(
-                        (a, b),
-                        (b'{', b'[')
-                            | (b'[', b'{')
-                            | (b'}', b']')
-                            | (b']', b'}')
-                            | (b'\\', b'|')
-                            | (b'|', b'\\')
-                    )
+    #[tokio::test]
+    async fn servers_added_and_removed() {
+        let state = app().await;
+        let config = || {
+            serde_json::json!({
+                "label": "other",
+                "channels": [],
+                "config": {"server": "mock", "nickname": "me", "use_mock_connection": true}
             })
+        };
+        let (status, Json(id)) = add_server(
+            State(state.clone()),
+            Query(PersistQuery { persist: false }),
+            Json(config()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(id, "other");
+        assert!(state.servers.contains_key("other"));
+        let conflict = add_server(
+            State(state.clone()),
+            Query(PersistQuery { persist: false }),
+            Json(config()),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(conflict.status(), StatusCode::CONFLICT);
+        let Json(servers) = servers(State(state.clone())).await;
+        assert_eq!(servers.len(), 2);
+
+        remove_server(
+            State(state.clone()),
+            Path("other".to_string()),
+            Query(PersistQuery { persist: false }),
+        )
+        .await
+        .unwrap();
+        assert!(!state.servers.contains_key("other"));
+        assert_eq!(state.configured_servers(), ["mock"]);
+        assert_eq!(
+            remove_server(
+                State(state),
+                Path("other".to_string()),
+                Query(PersistQuery { persist: false }),
+            )
+            .await,
+            Err(StatusCode::NOT_FOUND)
+        );
     }
-}
 
-impl IrcCase for String {
-    type Other = str;
-    fn eq_ignore_irc_case(&self, other: &Self::Other) -> bool {
-        self.as_bytes().eq_ignore_irc_case(other.as_bytes())
+    #[tokio::test]
+    async fn servers_listed_with_connection() {
+        let state = app().await;
+        state.set_server_state(&"mock".to_string(), ConnectionState::Connected);
+        let server = state.servers.get("mock").unwrap();
+        server.request(item(0, "Bot")).unwrap();
+        server.completed(&0, 1, PathBuf::from("a.mkv"));
+        server.request(item(1, "Bot")).unwrap();
+        drop(server);
+
+        let Json(servers) = servers(State(state)).await;
+        assert_eq!(servers[0].nick.as_deref(), Some("me"));
+        assert!(servers[0].channels.is_empty());
+        assert_eq!(servers[0].connected_secs, Some(0));
+        assert_eq!(servers[0].active_downloads, 1);
     }
-}
 
-impl IrcCase for str {
-    type Other = str;
-    fn eq_ignore_irc_case(&self, other: &Self::Other) -> bool {
-        self.as_bytes().eq_ignore_irc_case(other.as_bytes())
+    #[test]
+    fn appended_results_deduplicated() {
+        let result = |nick: &str, command: &str| SearchResult {
+            server: "mock".to_string(),
+            file_name: "a.mkv".to_string(),
+            nick: nick.to_string(),
+            command: command.to_string(),
+            raw: None,
+        };
+        let mut search = Search::default();
+        assert!(search.add_result(result("Bot", "xdcc send #1")));
+        assert!(search.add_result(result("Bot", "xdcc send #2")));
+        assert!(!search.add_result(result("BOT", "XDCC SEND #1")));
+        assert!(search.add_result(result("Other", "xdcc send #1")));
+        assert_eq!(search.results.len(), 3);
+    }
+
+    #[test]
+    fn openapi_covers_all_routes() {
+        let spec = ApiDoc::openapi();
+        let route = Regex::new(r#"\.route\(\s*"([^"]+)""#).unwrap();
+        let param = Regex::new(r":(\w+)").unwrap();
+        let routes: Vec<_> = route
+            .captures_iter(include_str!("main.rs"))
+            .map(|c| param.replace_all(&c[1], "{$1}").into_owned())
+            .collect();
+        assert!(!routes.is_empty());
+        for route in routes {
+            assert!(
+                spec.paths.paths.contains_key(&route),
+                "Route {} is missing in the OpenAPI description",
+                route
+            );
+        }
     }
 }
@@ -0,0 +1,308 @@
+use crate::server::ServerId;
+use crate::{DownloadId, DownloadItem, DownloadSource, DownloadStatus};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// What a download was doing when it was saved.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SavedStatus {
+    Requested,
+    Queued,
+    Transferring,
+    /// Stays paused until resumed
+    Paused,
+}
+
+/// The part of an outstanding download that survives a restart. Transfers cannot, restored
+/// downloads are requested again and continue their partial file.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SavedDownload {
+    pub id: DownloadId,
+    pub server: ServerId,
+    pub file_name: String,
+    pub nick: String,
+    pub request_command: String,
+    pub status: SavedStatus,
+    #[serde(default)]
+    pub alternatives: Vec<DownloadSource>,
+    pub max_bytes_per_sec: Option<u64>,
+}
+
+impl SavedDownload {
+    /// The download to save, unless it is over or could not be requested again.
+    pub fn of(item: &DownloadItem) -> Option<Self> {
+        let status = match item.status {
+            _ if item.status.is_terminal() || item.request_command.is_empty() => return None,
+            DownloadStatus::Queued => SavedStatus::Queued,
+            DownloadStatus::Paused => SavedStatus::Paused,
+            DownloadStatus::Waiting | DownloadStatus::Connecting | DownloadStatus::Progress(_) => {
+                SavedStatus::Transferring
+            }
+            _ => SavedStatus::Requested,
+        };
+        Some(Self {
+            id: item.id,
+            server: item.server.clone(),
+            file_name: item.file_name.clone(),
+            nick: item.nick.clone(),
+            request_command: item.request_command.clone(),
+            status,
+            alternatives: item.alternatives.clone(),
+            max_bytes_per_sec: item.max_bytes_per_sec,
+        })
+    }
+
+    /// The download queued, to be requested once we are registered with the server. Paused ones
+    /// wait to be resumed.
+    pub fn restore(self) -> DownloadItem {
+        let (status, notice) = match self.status {
+            SavedStatus::Paused => (DownloadStatus::Paused, None),
+            SavedStatus::Transferring => (
+                DownloadStatus::Queued,
+                Some("Transfer interrupted by a restart".to_string()),
+            ),
+            SavedStatus::Requested | SavedStatus::Queued => (DownloadStatus::Queued, None),
+        };
+        DownloadItem {
+            status,
+            notice,
+            alternatives: self.alternatives,
+            max_bytes_per_sec: self.max_bytes_per_sec,
+            ..DownloadItem::new(
+                self.id,
+                self.server,
+                self.file_name,
+                self.nick,
+                self.request_command,
+            )
+        }
+    }
+}
+
+/// Outstanding downloads of all servers, kept in a JSON file across restarts.
+pub struct QueueStore {
+    path: PathBuf,
+    downloads: Mutex<HashMap<ServerId, Vec<SavedDownload>>>,
+    dirty: AtomicBool,
+}
+
+impl QueueStore {
+    /// Loads the downloads, starting empty if there is no file yet.
+    pub fn load(path: PathBuf) -> anyhow::Result<Self> {
+        let downloads = match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Self {
+            path,
+            downloads: Mutex::new(downloads),
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    /// The saved downloads of a server, queued.
+    pub fn downloads(&self, server: &str) -> Vec<DownloadItem> {
+        let downloads = self.downloads.lock().unwrap();
+        downloads
+            .get(server)
+            .into_iter()
+            .flatten()
+            .cloned()
+            .map(SavedDownload::restore)
+            .collect()
+    }
+
+    /// The highest id of the saved downloads, new ones have to start above.
+    pub fn max_id(&self) -> Option<DownloadId> {
+        let downloads = self.downloads.lock().unwrap();
+        downloads.values().flatten().map(|d| d.id).max()
+    }
+
+    /// File names of the saved downloads of all servers.
+    pub fn file_names(&self) -> HashSet<String> {
+        let downloads = self.downloads.lock().unwrap();
+        downloads
+            .values()
+            .flatten()
+            .map(|d| d.file_name.clone())
+            .collect()
+    }
+
+    /// Pauses the saved downloads `pause` picks, they are not requested again once restored.
+    /// Returns the file names of those paused.
+    pub fn pause_where(&self, mut pause: impl FnMut(&SavedDownload) -> bool) -> Vec<String> {
+        let mut paused = Vec::new();
+        let mut downloads = self.downloads.lock().unwrap();
+        for download in downloads.values_mut().flatten() {
+            if download.status != SavedStatus::Paused && pause(download) {
+                download.status = SavedStatus::Paused;
+                paused.push(download.file_name.clone());
+            }
+        }
+        if !paused.is_empty() {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        paused
+    }
+
+    /// Saves the transfers `restart` picks as requested, they start over rather than continue.
+    /// Returns those.
+    pub fn restart_interrupted(
+        &self,
+        mut restart: impl FnMut(&SavedDownload) -> bool,
+    ) -> Vec<SavedDownload> {
+        let mut restarted = Vec::new();
+        let mut downloads = self.downloads.lock().unwrap();
+        for download in downloads.values_mut().flatten() {
+            if download.status == SavedStatus::Transferring && restart(download) {
+                download.status = SavedStatus::Requested;
+                restarted.push(download.clone());
+            }
+        }
+        if !restarted.is_empty() {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        restarted
+    }
+
+    /// Replaces the downloads of a server. Those of servers not connected are kept.
+    pub fn update<'a>(&self, server: &str, items: impl IntoIterator<Item = &'a DownloadItem>) {
+        let mut saved: Vec<_> = items.into_iter().filter_map(SavedDownload::of).collect();
+        saved.sort_by_key(|d| d.id);
+        let mut downloads = self.downloads.lock().unwrap();
+        if downloads
+            .get(server)
+            .map_or(saved.is_empty(), |known| *known == saved)
+        {
+            return;
+        }
+        if saved.is_empty() {
+            downloads.remove(server);
+        } else {
+            downloads.insert(server.to_string(), saved);
+        }
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let content = serde_json::to_vec_pretty(&*self.downloads.lock().unwrap())?;
+        let temp = self.path.with_extension("tmp");
+        let result = (|| {
+            let mut file = std::fs::File::create(&temp)?;
+            file.write_all(&content)?;
+            file.sync_all()?;
+            std::fs::rename(&temp, &self.path)
+        })();
+        if result.is_err() {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        Ok(result?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(id: DownloadId, status: DownloadStatus) -> DownloadItem {
+        DownloadItem {
+            status,
+            ..DownloadItem::new(
+                id,
+                "irc.example.org".to_string(),
+                format!("file{}.mkv", id),
+                "Bot".to_string(),
+                format!("xdcc send #{}", id),
+            )
+        }
+    }
+
+    #[test]
+    fn queue_survives_restart() {
+        let path = std::env::temp_dir().join(format!("irc-dl-queue-{}.json", std::process::id()));
+        let store = QueueStore::load(path.clone()).unwrap();
+        store.update(
+            "irc.example.org",
+            &[
+                item(3, DownloadStatus::Requested),
+                item(1, DownloadStatus::Queued),
+                item(2, DownloadStatus::Failed("gone".to_string())),
+            ],
+        );
+        store.update("other.example.org", &[item(7, DownloadStatus::Connecting)]);
+        store.save().unwrap();
+
+        let store = QueueStore::load(path.clone()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let restored = store.downloads("irc.example.org");
+        assert_eq!(restored.iter().map(|d| d.id).collect::<Vec<_>>(), [1, 3]);
+        assert!(restored
+            .iter()
+            .all(|d| matches!(d.status, DownloadStatus::Queued)));
+        assert_eq!(restored[1].request_command, "xdcc send #3");
+        assert!(restored[1].notice.is_none());
+        let interrupted = store.downloads("other.example.org");
+        assert!(interrupted[0].notice.is_some());
+        assert_eq!(store.max_id(), Some(7));
+    }
+
+    #[test]
+    fn paused_stays_paused() {
+        let saved = SavedDownload::of(&item(1, DownloadStatus::Paused)).unwrap();
+        assert_eq!(saved.status, SavedStatus::Paused);
+        assert!(matches!(saved.restore().status, DownloadStatus::Paused));
+    }
+
+    #[test]
+    fn paused_before_restoring() {
+        let store =
+            QueueStore::load(std::env::temp_dir().join("irc-dl-queue-paused.json")).unwrap();
+        store.update(
+            "a",
+            &[
+                item(1, DownloadStatus::Connecting),
+                item(2, DownloadStatus::Queued),
+                item(3, DownloadStatus::Paused),
+            ],
+        );
+        store.dirty.store(false, Ordering::Relaxed);
+        assert_eq!(store.file_names().len(), 3);
+
+        let paused = store.pause_where(|d| d.file_name != "file2.mkv");
+        assert_eq!(paused, ["file1.mkv"]);
+        assert!(store.dirty.load(Ordering::Relaxed));
+        let restored = store.downloads("a");
+        assert!(matches!(restored[0].status, DownloadStatus::Paused));
+        assert!(matches!(restored[1].status, DownloadStatus::Queued));
+        assert!(store.pause_where(|_| false).is_empty());
+    }
+
+    #[test]
+    fn saved_only_when_changed() {
+        let store =
+            QueueStore::load(std::env::temp_dir().join("irc-dl-queue-unchanged.json")).unwrap();
+        store.update("a", &[]);
+        assert!(!store.dirty.load(Ordering::Relaxed));
+        store.update("a", &[item(1, DownloadStatus::Requested)]);
+        assert!(store.dirty.swap(false, Ordering::Relaxed));
+        store.update(
+            "a",
+            &[item(
+                1,
+                DownloadStatus::Delayed(tokio::time::Instant::now()),
+            )],
+        );
+        assert!(!store.dirty.load(Ordering::Relaxed));
+        store.update("a", &[]);
+        assert!(store.dirty.load(Ordering::Relaxed));
+    }
+}
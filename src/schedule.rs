@@ -0,0 +1,79 @@
+use chrono::{Datelike, NaiveDateTime, NaiveTime, Weekday};
+use serde::{Deserialize, Serialize};
+
+/// A daily time window in which no downloads are started.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct QuietHours {
+    #[serde(with = "hh_mm")]
+    pub start: NaiveTime,
+    #[serde(with = "hh_mm")]
+    pub end: NaiveTime,
+    /// Days on which the window starts, every day if empty
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+}
+
+impl QuietHours {
+    pub fn contains(&self, now: NaiveDateTime) -> bool {
+        let time = now.time();
+        let today = now.weekday();
+        let applies = |day: Weekday| self.days.is_empty() || self.days.contains(&day);
+        if self.start <= self.end {
+            applies(today) && self.start <= time && time < self.end
+        } else {
+            // Spans midnight, the early morning belongs to the window of the day before
+            (applies(today) && time >= self.start) || (applies(today.pred()) && time < self.end)
+        }
+    }
+}
+
+pub fn is_quiet(quiet_hours: &[QuietHours], now: NaiveDateTime) -> bool {
+    quiet_hours.iter().any(|q| q.contains(now))
+}
+
+mod hh_mm {
+    use chrono::NaiveTime;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(time: &NaiveTime, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&time.format("%H:%M").to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveTime, D::Error> {
+        let time = String::deserialize(deserializer)?;
+        NaiveTime::parse_from_str(&time, "%H:%M").map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        // 2023-05-01 is a monday
+        NaiveDate::from_ymd_opt(2023, 5, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn quiet_hours_over_midnight() {
+        let quiet: QuietHours =
+            toml::from_str("start = \"22:00\"\nend = \"06:30\"\ndays = [\"Fri\"]").unwrap();
+        assert!(!quiet.contains(at(5, 21, 59)));
+        assert!(quiet.contains(at(5, 22, 0)));
+        assert!(quiet.contains(at(6, 6, 29)));
+        assert!(!quiet.contains(at(6, 6, 30)));
+        assert!(!quiet.contains(at(6, 23, 0)));
+    }
+
+    #[test]
+    fn quiet_hours_every_day() {
+        let quiet: QuietHours = toml::from_str("start = \"09:00\"\nend = \"17:00\"").unwrap();
+        assert!(quiet.contains(at(1, 12, 0)));
+        assert!(quiet.contains(at(7, 9, 0)));
+        assert!(!quiet.contains(at(7, 17, 0)));
+    }
+}
@@ -1,71 +1,1066 @@
-use crate::{DownloadId, DownloadItem, DownloadStatus, IrcCase};
+use crate::dcc::{AckWidth, DccSend};
+use crate::{
+    check_irc_text, parse_search_line, same_file_name, CancelReason, DownloadId, DownloadItem,
+    DownloadSource, DownloadStatus, IrcCase, QueuePosition, SearchResult, DEFAULT_MAX_NICK_LEN,
+};
+use anyhow::{bail, Context};
 use dashmap::DashMap;
 use irc::client::{data::Config, Client, ClientStream};
+use irc::proto::Command;
+use lazy_static::lazy_static;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{oneshot, watch, Notify, OwnedSemaphorePermit};
 use tokio::time::{Duration, Instant};
+use utoipa::ToSchema;
+
+lazy_static! {
+    static ref REX_MAX_TRANSFERS: Regex = Regex::new(
+        r"(?i)(?:max(?:imum)?\W+(?P<a>\d+)\s+transfers?|(?P<b>\d+)\s+transfers?\s+(?:per\s+user|at\s+a\s+time))"
+    )
+    .expect("Valid regex");
+    static ref REX_MAX_QUEUED: Regex = Regex::new(
+        r"(?i)(?:max(?:imum)?\W+(?P<a>\d+)\s+(?:queued|in\s+queue)|(?P<b>\d+)\s+queued\s+(?:per\s+user|packs?))"
+    )
+    .expect("Valid regex");
+    static ref REX_ALREADY_SENDING: Regex = Regex::new(
+        r"(?i)(?:already\s+(?:have|has|got)\s+(?:a\s+|\d+\s+)?transfers?|already\s+(?:sending|receiving|requested)|transfer\s+(?:is\s+)?(?:already\s+)?(?:in\s+progress|running))"
+    )
+    .expect("Valid regex");
+    /// Notices of bots telling our place in their queue
+    static ref REX_QUEUE_POSITIONS: Vec<Regex> = vec![
+        Regex::new(
+            r"(?i)\bposition\s*:?\s*#?(?P<position>\d+)(?:\s*(?:of|/)\s*(?P<length>\d+))?"
+        )
+        .expect("Valid regex"),
+        Regex::new(
+            r"(?i)\b(?:queued|you\s+are)\s+(?:at\s+)?#(?P<position>\d+)(?:\s*(?:of|/)\s*(?P<length>\d+))?"
+        )
+        .expect("Valid regex"),
+        Regex::new(
+            r"(?i)\bqueue\s+(?:slot|spot|place)\s*:?\s*#?(?P<position>\d+)(?:\s*(?:of|/)\s*(?P<length>\d+))?"
+        )
+        .expect("Valid regex"),
+    ];
+    static ref REX_QUEUE_ETA: Regex = Regex::new(
+        r"(?i)\b(?:estimated(?:\s+wait(?:ing)?)?(?:\s+time)?|eta|wait(?:ing)?\s+time)\s*(?:of|is|:)?\s*(?P<eta>\d+(?::\d{2}){1,2}|(?:\d+\s*[hms][a-z]*[\s,]*)+)"
+    )
+    .expect("Valid regex");
+    static ref REX_DURATION_PART: Regex =
+        Regex::new(r"(?i)(?P<value>\d+)\s*(?P<unit>[hms])").expect("Valid regex");
+    static ref REX_REQUEST_DENIED: Regex = Regex::new(
+        r"(?i)(?:request|send)\s+(?:was\s+|is\s+)?(?:denied|refused|rejected)|(?:denied|refused|rejected)\s+(?:your\s+)?request"
+    )
+    .expect("Valid regex");
+    static ref REX_NO_SUCH_PACK: Regex = Regex::new(
+        r"(?i)(?:invalid\s+pack(?:\s+(?:number|#))?|no\s+such\s+pack|pack\s+(?:number\s+)?#?(?P<pack>\d+)\s+(?:does\s+not|doesn'?t)\s+exist|pack\s+not\s+found)"
+    )
+    .expect("Valid regex");
+    static ref REX_SLOTS_FULL: Regex = Regex::new(
+        r"(?i)^[\W_]*(?:sorry,?\s+)?(?:all\s+(?:\d+\s+)?(?:slots|sends)\s+(?:are\s+)?(?:full|taken|in\s+use|busy)|no\s+(?:free|open|available)\s+slots|(?:the\s+)?queue\s+(?:of\s+size\s+\d+\s+)?is\s+full)"
+    )
+    .expect("Valid regex");
+    static ref REX_PACK_NUMBER: Regex = Regex::new(r"#(?P<pack>\d+)").expect("Valid regex");
+    static ref REX_CHANNEL_REQUIRED: Regex = Regex::new(
+        r"(?i)(?:must|need\s+to|have\s+to)\s+(?:be\s+)?(?:on|in|join(?:ed)?)\s+(?:channel\s+)?(?P<channel>[#&][^\s,!]*[^\s,!.])"
+    )
+    .expect("Valid regex");
+    /// Notices of networks refusing messages of new or unregistered connections
+    static ref BUILTIN_RESTRICTION_PATTERNS: Vec<Regex> = vec![
+        Regex::new(
+            r"(?i)must\s+(?:be\s+)?(?:connected|online)\s+(?:for\s+)?(?:at\s+least\s+|more\s+than\s+)?(?P<secs>\d+)\s+sec"
+        )
+        .expect("Valid regex"),
+        Regex::new(
+            r"(?i)\+R\s+users\s+only|only\s+(?:registered|identified)\s+users|must\s+(?:identify|be\s+identified|log\s+in)\s+.*\s+(?:message|msg)"
+        )
+        .expect("Valid regex"),
+    ];
+}
+
+/// Numerics by which networks refuse to pass on our messages for now: Undernet's "connected too
+/// briefly", and the "registered nicks only" of various ircds.
+const BUILTIN_RESTRICTION_NUMERICS: &[&str] = &["531", "477", "486"];
+
+const DEFAULT_JOIN_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Time after which we try again, when a restriction does not tell how long it lasts.
+const RESTRICTION_RETRY: Duration = Duration::from_secs(60);
+
+const DEFAULT_OFFER_DEDUPE: Duration = Duration::from_secs(5);
+
+/// Time a bot is left alone after saying it has no free slots, doubled while it keeps saying so
+const DEFAULT_SLOTS_FULL_BACKOFF: Duration = Duration::from_secs(60);
+const MAX_SLOTS_FULL_BACKOFF: Duration = Duration::from_secs(1800);
+
+/// Requests sent to a bot at once, many bots refuse more or even ban for flooding
+const DEFAULT_MAX_REQUESTS_PER_BOT: usize = 1;
+
+/// A search is done once no results came in for this long
+const DEFAULT_SEARCH_IDLE: Duration = Duration::from_millis(1000);
+/// Longest a search waits for results, even if bots keep sending
+const DEFAULT_SEARCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Completed downloads kept, to show what finished and for those waiting on them.
+const RECENTLY_COMPLETED: usize = 200;
+
+/// Round trip times kept per server.
+const LATENCY_SAMPLES: usize = 10;
+
+/// Connections younger than this are considered less stable.
+const SETTLING_TIME: Duration = Duration::from_secs(300);
+
+/// How healthy a connection looks, lower is better. Combines the average round trip time, how
+/// recently we connected and whether the server currently refuses our messages. Only used to
+/// order servers, never to rule one out.
+pub fn health_score(latency: Option<Duration>, connected_for: Duration, restricted: bool) -> f64 {
+    // Unmeasured connections rank like a slow one, not like a broken one
+    let latency = latency.unwrap_or(Duration::from_secs(1)).as_secs_f64() * 1000.0;
+    let settling = if connected_for < SETTLING_TIME {
+        2000.0
+    } else {
+        0.0
+    };
+    let restriction = if restricted { 10000.0 } else { 0.0 };
+    latency + settling + restriction
+}
 
 pub type ServerId = String;
 
+pub const DEFAULT_SEARCH_TEMPLATE: &str = "!s {}";
+const MAX_SEARCH_TEMPLATE: usize = 64;
+
+/// Checks a search command template given by a user. It must contain the `{}` placeholder and
+/// must not be able to smuggle further IRC commands in.
+pub fn validate_search_template(template: &str) -> anyhow::Result<()> {
+    if !template.contains("{}") {
+        bail!("Search template lacks the {{}} placeholder");
+    }
+    if template.len() > MAX_SEARCH_TEMPLATE {
+        bail!("Search template longer than {} bytes", MAX_SEARCH_TEMPLATE);
+    }
+    if template.contains(['\r', '\n', '\0']) {
+        bail!("Search template contains line breaks");
+    }
+    Ok(())
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Channel {
     pub name: String,
     pub search: bool,
+    /// Bots answering searches of this channel split results over two notices
+    #[serde(default)]
+    pub multiline_results: bool,
+    /// Message to send after joining, for channels whose bots only answer users who greeted.
+    /// `/me ` at the start sends it as action.
+    pub on_join: Option<String>,
+    /// Seconds to wait after joining before sending `on_join`
+    #[serde(default)]
+    pub on_join_delay_secs: u64,
+    /// Key of a channel with mode +k
+    pub key: Option<String>,
 }
 
+/// Notices of the same sender arriving within this window may form one search result.
+const MULTILINE_WINDOW: Duration = Duration::from_secs(2);
+/// Senders whose last notice is kept at most, for a flood of notices not to pile up
+const MAX_BUFFERED_NOTICES: usize = 64;
+
 #[derive(Serialize, Deserialize)]
 pub struct ServerConfig {
     pub config: Config,
+    /// Name the connection is known by in the API, instead of the server URL. Lets several
+    /// profiles connect to the same network.
+    pub label: Option<String>,
     pub channels: Vec<Channel>,
+    /// Requests sent to a single bot at once, further ones are queued locally
+    #[serde(alias = "max_concurrent_per_nick")]
+    pub max_requests_per_bot: Option<usize>,
+    /// Requests sent to all bots of the server at once, unlimited if not set
+    #[serde(alias = "max_concurrent_per_server")]
+    pub max_requests_per_server: Option<usize>,
+    /// Username (ident) to register with, overrides the one in `config`
+    pub ident: Option<String>,
+    /// Realname to register with, overrides the one in `config`
+    pub realname: Option<String>,
+    /// Register with a random ident and realname, unless they are configured
+    #[serde(default)]
+    pub randomize_ident: bool,
+    /// Channels we may join on our own when a bot requires us to be there
+    #[serde(default)]
+    pub auto_join: Vec<String>,
+    /// Join any channel a bot requires us to be in, not only those in `auto_join`
+    #[serde(default)]
+    pub auto_join_any: bool,
+    /// Download files offered without being requested, instead of ignoring such offers
+    #[serde(default)]
+    pub accept_unsolicited: bool,
+    /// Keys of channels with mode +k that are not configured as `channels`
+    #[serde(default)]
+    pub channel_keys: HashMap<String, String>,
+    /// Width of DCC acknowledgements by bot nick, for bots expecting other than the global
+    /// `ack_width`
+    #[serde(default)]
+    pub ack_widths: HashMap<String, AckWidth>,
+    /// Seconds between joins, so joining many channels does not get us kicked for flooding
+    pub join_interval_secs: Option<u64>,
+    /// Seconds a bot may be missing before its downloads are marked absent, to ride out
+    /// netsplits. Without it, a single ERR_NOSUCHNICK is enough.
+    pub sender_absent_grace_secs: Option<u64>,
+    /// Search channels a single search is sent to at most, chosen by how productive they were
+    pub max_search_channels: Option<usize>,
+    /// Milliseconds without results after which a search is done, 1000 by default
+    pub search_idle_ms: Option<u64>,
+    /// Milliseconds a search collects results at most, 10000 by default
+    pub search_timeout_ms: Option<u64>,
+    /// Further numerics meaning the server does not let our messages through yet
+    #[serde(default)]
+    pub restriction_numerics: Vec<String>,
+    /// Further notice patterns (regex) meaning the same, a `secs` group gives the time required
+    /// to be connected
+    #[serde(default)]
+    pub restriction_patterns: Vec<String>,
+    /// Seconds between attempts to get the configured nick back while we are on an alternate one
+    pub nick_recovery_secs: Option<u64>,
+    /// Local address for DCC transfers from this server, instead of the global one
+    pub dcc_source_address: Option<Ipv4Addr>,
+    /// Seconds in which a repeated DCC SEND offer is ignored, 5 by default, 0 to accept all
+    pub offer_dedupe_secs: Option<u64>,
+    /// What becomes of the downloads of this server when we reconnect to it
+    #[serde(default)]
+    pub on_reconnect: ReconnectDownloads,
+    /// Seconds a bot is left alone after saying all its slots are full, doubled while it keeps
+    /// saying so, 60 by default
+    pub slots_full_backoff_secs: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReconnectDownloads {
+    /// Keep them, requesting those again that did not start yet
+    #[default]
+    Preserve,
+    /// Start over without them, aborting running transfers
+    Drop,
+}
+
+impl ServerConfig {
+    /// The label, or without one the server URL.
+    pub fn id(&self) -> Option<ServerId> {
+        self.label.clone().or_else(|| self.config.server.clone())
+    }
+
+    /// The restriction patterns compiled, failing on invalid ones.
+    pub fn restriction_patterns(&self) -> anyhow::Result<Vec<Regex>> {
+        self.restriction_patterns
+            .iter()
+            .map(|pattern| Regex::new(pattern))
+            .collect::<Result<Vec<_>, _>>()
+            .context("Invalid restriction pattern")
+    }
+}
+
+/// Settings of a [`ServerConfig`] that only take effect when connecting, all others can be
+/// changed with [`ServerConnection::apply_settings`].
+pub const RECONNECT_SETTINGS: &[&str] =
+    &["config", "label", "ident", "realname", "randomize_ident"];
+
+/// Refuses configurations with several entries for the same [`ServerId`], as only one of them
+/// would stay connected.
+pub fn check_unique_ids(servers: &[ServerConfig]) -> anyhow::Result<()> {
+    let mut seen = HashSet::new();
+    for (index, server) in servers.iter().enumerate() {
+        if server.config.server.is_none() {
+            bail!("Server URL missing in entry {}", index + 1);
+        }
+        let id = server.id().expect("Server URL present");
+        if !seen.insert(id.clone()) {
+            bail!(
+                "Server {} is configured more than once (entry {}), give the entries distinct \
+                 labels to connect to it several times",
+                id,
+                index + 1
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Where the connection to a server stands.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConnectionState {
+    /// Connected, but not yet registered with the server
+    Connecting,
+    /// Registered, joining the channels
+    Connected,
+    /// In all configured channels
+    JoinedChannels,
+    Disconnected,
+    /// Disconnected, trying to connect again
+    Reconnecting,
+}
+
+/// The server does not let our messages through for now.
+#[derive(Serialize, Clone, Debug, ToSchema)]
+pub struct Restriction {
+    /// Numeric or notice announcing it
+    pub reason: String,
+    /// How the time of the next attempt was chosen
+    pub expiry: String,
+    #[serde(skip)]
+    pub until: Instant,
+}
+
+/// A configured channel and whether we are in it.
+#[derive(Serialize, Clone, Debug, ToSchema)]
+pub struct ChannelStatus {
+    pub name: String,
+    pub search: bool,
+    pub joined: bool,
+}
+
+/// How productive searching a channel was so far.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ChannelStats {
+    pub searches: u64,
+    /// Results received while the channel was among the searched ones
+    pub hits: u64,
+    #[serde(skip)]
+    pub last_searched: Option<Instant>,
+}
+
+impl ChannelStats {
+    fn hit_rate(&self) -> f64 {
+        self.hits as f64 / self.searches.max(1) as f64
+    }
+}
+
+/// Our place in the queue of the bot and its length, if the notice tells.
+fn queue_position(notice: &str) -> Option<(usize, Option<usize>)> {
+    REX_QUEUE_POSITIONS.iter().find_map(|rex| {
+        let captures = rex.captures(notice)?;
+        let position = captures["position"].parse().ok()?;
+        let length = captures
+            .name("length")
+            .and_then(|length| length.as_str().parse().ok());
+        Some((position, length))
+    })
+}
+
+/// Seconds the bot expects us to wait, from e.g. "estimated wait 1h 12m" or "ETA: 01:12:00".
+fn queue_eta(notice: &str) -> Option<u64> {
+    let eta = REX_QUEUE_ETA.captures(notice)?.name("eta")?.as_str();
+    if eta.contains(':') {
+        return eta
+            .split(':')
+            .try_fold(0, |secs, part| Some(secs * 60 + part.parse::<u64>().ok()?));
+    }
+    REX_DURATION_PART
+        .captures_iter(eta)
+        .try_fold(0, |secs, part| {
+            let value: u64 = part["value"].parse().ok()?;
+            let unit = match part["unit"].to_ascii_lowercase().as_str() {
+                "h" => 3600,
+                "m" => 60,
+                _ => 1,
+            };
+            Some(secs + value * unit)
+        })
+}
+
+fn random_word(len: usize) -> String {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    let mut hasher = RandomState::new().build_hasher();
+    (0..len)
+        .map(|i| {
+            hasher.write_usize(i);
+            (b'a' + (hasher.finish() % 26) as u8) as char
+        })
+        .collect()
+}
+
+/// Limits a bot announced about itself.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BotLimits {
+    pub max_transfers: Option<usize>,
+    pub max_queued: Option<usize>,
+}
+
+impl BotLimits {
+    pub fn from_notice(notice: &str) -> Option<Self> {
+        let number = |rex: &Regex| {
+            rex.captures(notice)
+                .and_then(|c| c.name("a").or_else(|| c.name("b")))
+                .and_then(|n| n.as_str().parse().ok())
+        };
+        let limits = Self {
+            max_transfers: number(&REX_MAX_TRANSFERS),
+            max_queued: number(&REX_MAX_QUEUED),
+        };
+        (limits != Self::default()).then_some(limits)
+    }
+
+    fn merge(&mut self, other: Self) {
+        self.max_transfers = other.max_transfers.or(self.max_transfers);
+        self.max_queued = other.max_queued.or(self.max_queued);
+    }
+
+    /// How many requests the bot accepts from us at once, running and queued.
+    fn capacity(&self) -> Option<usize> {
+        match (self.max_transfers, self.max_queued) {
+            (Some(transfers), queued) => Some(transfers + queued.unwrap_or(0)),
+            (None, Some(queued)) => Some(1 + queued),
+            (None, None) => None,
+        }
+    }
 }
 
 pub struct ServerConnection {
+    pub id: ServerId,
     pub client: Client,
     pub channels: Vec<Channel>,
     pub downloads: DashMap<DownloadId, DownloadItem>,
+    /// Downloads removed by the user and when, restorable until they expire
+    pub trash: DashMap<DownloadId, (DownloadItem, Instant)>,
     pub connected_at: Instant,
+    state: Mutex<ConnectionState>,
+    pub bot_limits: DashMap<String, BotLimits>,
+    /// Bots that said all their slots are full, until when we leave them alone and how often
+    /// they said so in a row
+    bot_backoff: DashMap<String, (Instant, u32)>,
+    slots_full_backoff: Duration,
+    pub max_requests_per_bot: Option<usize>,
+    pub max_requests_per_server: Option<usize>,
+    /// Within quiet hours requests are queued instead of sent
+    pub quiet: AtomicBool,
+    /// With free space in the download folder below the reserve requests are queued as well
+    pub disk_low: AtomicBool,
+    pub auto_join: Vec<String>,
+    auto_join_any: bool,
+    accept_unsolicited: bool,
+    channel_keys: HashMap<String, String>,
+    ack_widths: HashMap<String, AckWidth>,
+    join_interval: Duration,
+    /// Earliest time of the next join
+    next_join: Mutex<Instant>,
+    pub joined_channels: DashMap<String, ()>,
+    /// Downloads to request again once we joined the channel
+    pub awaiting_join: DashMap<String, Vec<DownloadId>>,
+    /// Last unparsable notice per sender, might be the first half of a search result
+    notice_buffer: Mutex<HashMap<String, (String, Instant)>>,
+    sender_absent_grace: Option<Duration>,
+    /// Nicks we sent ISON for, in order, as the replies do not tell which nicks were asked for.
+    /// Lookups want the answer, otherwise it is the check after a grace period.
+    presence_checks: Mutex<VecDeque<(String, Option<oneshot::Sender<bool>>)>>,
+    /// NICKLEN the server announced, 0 until it did
+    nick_len: AtomicUsize,
+    max_search_channels: Option<usize>,
+    pub search_idle: Duration,
+    pub search_timeout: Duration,
+    pub channel_stats: DashMap<String, ChannelStats>,
+    /// Channels the latest search went to, they share the credit for its results
+    searched_channels: Mutex<Vec<String>>,
+    restriction_numerics: Vec<String>,
+    restriction_patterns: Vec<Regex>,
+    pub restriction: Mutex<Option<Restriction>>,
+    /// Nick as the server knows us, which may be an alternate one
+    nick: Mutex<String>,
+    primary_nick: String,
+    /// Password and NickServ commands to ghost whoever holds the primary nick
+    ghost: Option<(String, Vec<String>)>,
+    /// Notified when NickServ answers or whoever holds the primary nick changes it
+    pub nick_released: Arc<Notify>,
+    pub nick_recovery: Option<Duration>,
+    pub dcc_source_address: Option<Ipv4Addr>,
+    pub on_reconnect: ReconnectDownloads,
+    /// DCC SEND offers seen lately, by file name, address and token
+    recent_offers: Mutex<HashMap<(String, SocketAddr, Option<usize>), Instant>>,
+    offer_dedupe: Duration,
+    /// Bumped whenever the status of a download changes
+    download_updates: watch::Sender<()>,
+    /// Downloads completed lately, until a finished download stays in `downloads`
+    recently_completed: Mutex<VecDeque<DownloadItem>>,
+    /// Token and time of the PING awaiting its PONG
+    latency_ping: Mutex<Option<(String, Instant)>>,
+    latencies: Mutex<VecDeque<Duration>>,
+    /// Counts the connection against the limit of open sockets, if there is one
+    pub socket_permit: Option<OwnedSemaphorePermit>,
 }
 
 impl ServerConnection {
     pub async fn new(config: ServerConfig) -> anyhow::Result<(Self, ServerId, ClientStream)> {
         let server = config.config.server.clone().expect("Server URL missing");
-        let mut client = Client::from_config(config.config).await?;
+        let id = config.id().expect("Server URL present");
+        let mut irc_config = config.config;
+        if config.randomize_ident {
+            irc_config.username = Some(random_word(8));
+            irc_config.realname = Some(random_word(6));
+        }
+        if let Some(ident) = config.ident {
+            irc_config.username = Some(ident);
+        }
+        if let Some(realname) = config.realname {
+            irc_config.realname = Some(realname);
+        }
+        let restriction_patterns = config.restriction_patterns()?;
+        let primary_nick = irc_config.nickname()?.to_string();
+        let ghost = match (irc_config.should_ghost, &irc_config.nick_password) {
+            (true, Some(password)) => Some((
+                password.clone(),
+                irc_config
+                    .ghost_sequence
+                    .clone()
+                    .unwrap_or_else(|| vec!["GHOST".to_string()]),
+            )),
+            _ => None,
+        };
+        let mut client = Client::from_config(irc_config)
+            .await
+            .with_context(|| format!("Could not connect to {}", server))?;
         client.identify()?;
         let stream = client.stream()?;
         Ok((
             Self {
+                id: id.clone(),
                 client,
                 channels: config.channels,
                 downloads: DashMap::new(),
+                trash: DashMap::new(),
                 connected_at: Instant::now(),
+                bot_limits: DashMap::new(),
+                bot_backoff: DashMap::new(),
+                slots_full_backoff: config
+                    .slots_full_backoff_secs
+                    .map_or(DEFAULT_SLOTS_FULL_BACKOFF, Duration::from_secs),
+                max_requests_per_bot: config.max_requests_per_bot,
+                max_requests_per_server: config.max_requests_per_server,
+                quiet: AtomicBool::new(false),
+                disk_low: AtomicBool::new(false),
+                auto_join: config.auto_join,
+                auto_join_any: config.auto_join_any,
+                accept_unsolicited: config.accept_unsolicited,
+                channel_keys: config.channel_keys,
+                ack_widths: config.ack_widths,
+                join_interval: config
+                    .join_interval_secs
+                    .map_or(DEFAULT_JOIN_INTERVAL, Duration::from_secs),
+                next_join: Mutex::new(Instant::now()),
+                joined_channels: DashMap::new(),
+                awaiting_join: DashMap::new(),
+                notice_buffer: Mutex::new(HashMap::new()),
+                sender_absent_grace: config.sender_absent_grace_secs.map(Duration::from_secs),
+                presence_checks: Mutex::new(VecDeque::new()),
+                nick_len: AtomicUsize::new(0),
+                max_search_channels: config.max_search_channels,
+                search_idle: config
+                    .search_idle_ms
+                    .map_or(DEFAULT_SEARCH_IDLE, Duration::from_millis),
+                search_timeout: config
+                    .search_timeout_ms
+                    .map_or(DEFAULT_SEARCH_TIMEOUT, Duration::from_millis),
+                channel_stats: DashMap::new(),
+                searched_channels: Mutex::new(Vec::new()),
+                restriction_numerics: config.restriction_numerics,
+                restriction_patterns,
+                restriction: Mutex::new(None),
+                nick: Mutex::new(primary_nick.clone()),
+                primary_nick,
+                ghost,
+                nick_released: Arc::new(Notify::new()),
+                nick_recovery: config.nick_recovery_secs.map(Duration::from_secs),
+                dcc_source_address: config.dcc_source_address,
+                on_reconnect: config.on_reconnect,
+                recent_offers: Mutex::new(HashMap::new()),
+                offer_dedupe: config
+                    .offer_dedupe_secs
+                    .map_or(DEFAULT_OFFER_DEDUPE, Duration::from_secs),
+                state: Mutex::new(ConnectionState::Connecting),
+                download_updates: watch::channel(()).0,
+                recently_completed: Mutex::new(VecDeque::new()),
+                latency_ping: Mutex::new(None),
+                latencies: Mutex::new(VecDeque::new()),
+                socket_permit: None,
             },
-            server,
+            id,
             stream,
         ))
     }
 
+    /// Takes over the settings of a changed configuration that do not need a new connection,
+    /// see [`RECONNECT_SETTINGS`].
+    pub fn apply_settings(&mut self, config: ServerConfig) -> anyhow::Result<()> {
+        self.restriction_patterns = config.restriction_patterns()?;
+        self.channels = config.channels;
+        self.max_requests_per_bot = config.max_requests_per_bot;
+        self.max_requests_per_server = config.max_requests_per_server;
+        self.auto_join = config.auto_join;
+        self.auto_join_any = config.auto_join_any;
+        self.accept_unsolicited = config.accept_unsolicited;
+        self.channel_keys = config.channel_keys;
+        self.ack_widths = config.ack_widths;
+        self.join_interval = config
+            .join_interval_secs
+            .map_or(DEFAULT_JOIN_INTERVAL, Duration::from_secs);
+        self.sender_absent_grace = config.sender_absent_grace_secs.map(Duration::from_secs);
+        self.max_search_channels = config.max_search_channels;
+        self.search_idle = config
+            .search_idle_ms
+            .map_or(DEFAULT_SEARCH_IDLE, Duration::from_millis);
+        self.search_timeout = config
+            .search_timeout_ms
+            .map_or(DEFAULT_SEARCH_TIMEOUT, Duration::from_millis);
+        self.restriction_numerics = config.restriction_numerics;
+        self.nick_recovery = config.nick_recovery_secs.map(Duration::from_secs);
+        self.dcc_source_address = config.dcc_source_address;
+        self.on_reconnect = config.on_reconnect;
+        self.offer_dedupe = config
+            .offer_dedupe_secs
+            .map_or(DEFAULT_OFFER_DEDUPE, Duration::from_secs);
+        self.slots_full_backoff = config
+            .slots_full_backoff_secs
+            .map_or(DEFAULT_SLOTS_FULL_BACKOFF, Duration::from_secs);
+        Ok(())
+    }
+
+    /// Sends a PRIVMSG, refusing text that would break out of it into further IRC commands.
+    pub fn send_privmsg(&self, target: &str, message: &str) -> anyhow::Result<()> {
+        check_irc_text("Target", target)?;
+        check_irc_text("Message", message)?;
+        self.client.send_privmsg(target, message)?;
+        Ok(())
+    }
+
+    pub fn nick(&self) -> String {
+        self.nick.lock().unwrap().clone()
+    }
+
+    /// Takes note of the nick we registered with, from RPL_WELCOME.
+    pub fn registered_as(&self, nick: &str) {
+        *self.nick.lock().unwrap() = nick.to_string();
+    }
+
+    /// Follows NICK messages, updating our nick if it is ours that changed.
+    pub fn nick_changed(&self, old: &str, new: &str) {
+        let mut nick = self.nick.lock().unwrap();
+        if nick.eq_ignore_ascii_case(old) {
+            log::info!("Now known as {} on {}", new, self.id);
+            *nick = new.to_string();
+        } else if old.eq_ignore_ascii_case(&self.primary_nick) {
+            self.nick_released.notify_waiters();
+        }
+    }
+
+    /// Takes note of NickServ answering, after a ghost the primary nick may be free.
+    pub fn handle_services_notice(&self, nick: &str) {
+        if nick.eq_ignore_ascii_case("NickServ") {
+            self.nick_released.notify_waiters();
+        }
+    }
+
+    pub fn has_primary_nick(&self) -> bool {
+        self.nick
+            .lock()
+            .unwrap()
+            .eq_ignore_ascii_case(&self.primary_nick)
+    }
+
+    pub fn primary_nick(&self) -> &str {
+        &self.primary_nick
+    }
+
+    /// Asks NickServ to ghost whoever holds the primary nick. Returns false without NickServ
+    /// credentials. Either way the nick should only be claimed once nobody uses it, as a refused
+    /// NICK makes the client move on to the next alternate nick, see [`Self::nick_released`].
+    pub fn ghost_primary_nick(&self) -> anyhow::Result<bool> {
+        let Some((password, sequence)) = &self.ghost else {
+            return Ok(false);
+        };
+        for command in sequence {
+            self.send_privmsg(
+                "NickServ",
+                &format!("{} {} {}", command, self.primary_nick, password),
+            )?;
+        }
+        Ok(true)
+    }
+
+    pub fn claim_primary_nick(&self) -> anyhow::Result<()> {
+        self.client.send(Command::NICK(self.primary_nick.clone()))?;
+        Ok(())
+    }
+
+    /// Sends a PING to measure the round trip time, answered by [`Self::pong`].
+    pub fn ping(&self) -> anyhow::Result<()> {
+        let token = format!("latency-{}", self.connected_at.elapsed().as_millis());
+        self.client.send(Command::PING(token.clone(), None))?;
+        *self.latency_ping.lock().unwrap() = Some((token, Instant::now()));
+        Ok(())
+    }
+
+    pub fn pong(&self, token: &str) {
+        let mut latency_ping = self.latency_ping.lock().unwrap();
+        let sent_at = match latency_ping.take() {
+            Some((sent, sent_at)) if sent == token => sent_at,
+            other => {
+                *latency_ping = other;
+                return;
+            }
+        };
+        let mut latencies = self.latencies.lock().unwrap();
+        if latencies.len() == LATENCY_SAMPLES {
+            latencies.pop_front();
+        }
+        latencies.push_back(sent_at.elapsed());
+    }
+
+    /// The configured channels, with whether we joined them.
+    pub fn channel_statuses(&self) -> Vec<ChannelStatus> {
+        self.channels
+            .iter()
+            .map(|channel| ChannelStatus {
+                name: channel.name.clone(),
+                search: channel.search,
+                joined: self.is_joined(&channel.name),
+            })
+            .collect()
+    }
+
+    /// Downloads that did not end yet.
+    pub fn active_downloads(&self) -> usize {
+        self.downloads
+            .iter()
+            .filter(|d| !d.status.is_terminal())
+            .count()
+    }
+
+    /// Latest round trip times, oldest first.
+    pub fn latencies(&self) -> Vec<Duration> {
+        self.latencies.lock().unwrap().iter().copied().collect()
+    }
+
+    pub fn health(&self) -> f64 {
+        let latencies = self.latencies();
+        let average = (!latencies.is_empty())
+            .then(|| latencies.iter().sum::<Duration>() / latencies.len() as u32);
+        health_score(
+            average,
+            self.connected_at.elapsed(),
+            self.restriction.lock().unwrap().is_some(),
+        )
+    }
+
+    /// Takes note of the limits in RPL_ISUPPORT.
+    pub fn update_isupport(&self, params: &[String]) {
+        for param in params {
+            if let Some(nick_len) = param
+                .strip_prefix("NICKLEN=")
+                .and_then(|len| len.parse().ok())
+            {
+                self.nick_len.store(nick_len, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn max_nick_len(&self) -> usize {
+        match self.nick_len.load(Ordering::Relaxed) {
+            0 => DEFAULT_MAX_NICK_LEN,
+            nick_len => nick_len,
+        }
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        *self.state.lock().unwrap()
+    }
+
+    /// Moves the connection to another state. Returns whether that changed it.
+    pub fn set_state(&self, state: ConnectionState) -> bool {
+        let previous = std::mem::replace(&mut *self.state.lock().unwrap(), state);
+        if previous != state {
+            log::info!("{} is {:?}", self.id, state);
+        }
+        previous != state
+    }
+
+    /// Whether we are in all configured channels.
+    pub fn joined_all_channels(&self) -> bool {
+        self.channels.iter().all(|channel| {
+            self.joined_channels
+                .iter()
+                .any(|joined| joined.key().eq_ignore_irc_case(&channel.name))
+        })
+    }
+
     pub fn join_channels(&self) -> anyhow::Result<()> {
         for channel in self.channels.iter() {
-            self.client.send_join(&channel.name)?;
+            self.join(&channel.name)?;
         }
         Ok(())
     }
 
-    pub fn search(&self, query: &str) -> anyhow::Result<()> {
-        for channel in self.channels.iter().filter(|c| c.search) {
-            self.client
-                .send_privmsg(&channel.name, format!("!s {}", query))?;
+    fn channel_key(&self, channel: &str) -> Option<String> {
+        let configured = self
+            .channels
+            .iter()
+            .find(|c| c.name.eq_ignore_irc_case(channel))
+            .and_then(|c| c.key.clone());
+        configured.or_else(|| {
+            self.channel_keys
+                .iter()
+                .find(|(name, _)| name.eq_ignore_irc_case(channel))
+                .map(|(_, key)| key.clone())
+        })
+    }
+
+    /// Width of the DCC acknowledgements a bot expects, if configured for it.
+    pub fn ack_width(&self, nick: &str) -> Option<AckWidth> {
+        self.ack_widths
+            .iter()
+            .find(|(bot, _)| bot.eq_ignore_irc_case(nick))
+            .map(|(_, width)| *width)
+    }
+
+    /// Joins a channel, with its key if we know it. Joins are spaced by the join interval, later
+    /// ones are sent in the background.
+    pub fn join(&self, channel: &str) -> anyhow::Result<()> {
+        check_irc_text("Channel", channel)?;
+        let join = Command::JOIN(channel.to_string(), self.channel_key(channel), None);
+        let now = Instant::now();
+        let at = {
+            let mut next_join = self.next_join.lock().unwrap();
+            let at = (*next_join).max(now);
+            *next_join = at + self.join_interval;
+            at
+        };
+        if at <= now {
+            self.client.send(join)?;
+            return Ok(());
+        }
+        let sender = self.client.sender();
+        tokio::spawn(async move {
+            tokio::time::sleep_until(at).await;
+            if let Err(err) = sender.send(join) {
+                log::warn!("Could not join: {}", err);
+            }
+        });
+        Ok(())
+    }
+
+    /// Sends the search to all search channels. `template` replaces the default `!s {}`, with
+    /// `{}` being replaced by the query.
+    pub fn search(&self, query: &str, template: Option<&str>) -> anyhow::Result<()> {
+        let template = template.unwrap_or(DEFAULT_SEARCH_TEMPLATE);
+        validate_search_template(template)?;
+        let channels = self.search_channels();
+        for channel in &channels {
+            self.send_privmsg(channel, &template.replacen("{}", query, 1))?;
+            let mut stats = self.channel_stats.entry(channel.clone()).or_default();
+            stats.searches += 1;
+            stats.last_searched = Some(Instant::now());
         }
+        *self.searched_channels.lock().unwrap() = channels;
         Ok(())
     }
 
-    pub fn mark_downloads_delayed(&mut self) -> Instant {
-        let until = self.connected_at + Duration::from_secs(70);
+    /// The channels the next search goes to. With a cap, all but one are the ones with the most
+    /// results per search. The last one rotates through the others, so channels that were never
+    /// or not recently searched still get their turn.
+    fn search_channels(&self) -> Vec<String> {
+        let mut channels: Vec<_> = self
+            .channels
+            .iter()
+            .filter(|c| c.search)
+            .map(|c| {
+                let stats = self.channel_stats.get(&c.name).map(|s| s.clone());
+                (c.name.clone(), stats.unwrap_or_default())
+            })
+            .collect();
+        let max = match self.max_search_channels {
+            Some(max) if max < channels.len() => max.max(1),
+            _ => return channels.into_iter().map(|(name, _)| name).collect(),
+        };
+        channels.sort_by(|(_, a), (_, b)| {
+            b.hit_rate()
+                .total_cmp(&a.hit_rate())
+                .then(a.last_searched.cmp(&b.last_searched))
+        });
+        let mut selected: Vec<_> = channels.drain(..max - 1).map(|(name, _)| name).collect();
+        if let Some((name, _)) = channels
+            .into_iter()
+            .min_by_key(|(_, stats)| stats.last_searched)
+        {
+            selected.push(name);
+        }
+        selected
+    }
+
+    /// Whether results sent to `target` may be split over two notices. That is up to the channel
+    /// if sent there, or else to the channels the latest search went to.
+    fn multiline_results(&self, target: &str) -> bool {
+        if let Some(channel) = self
+            .channels
+            .iter()
+            .find(|c| c.name.eq_ignore_irc_case(target))
+        {
+            return channel.multiline_results;
+        }
+        let searched = self.searched_channels.lock().unwrap();
+        self.channels
+            .iter()
+            .any(|c| c.multiline_results && searched.iter().any(|s| s.eq_ignore_irc_case(&c.name)))
+    }
+
+    /// Parses a search result from a notice to `target`. If its channel is configured for
+    /// multi-line results, a notice is also tried joined to the previous one of the same sender.
+    pub fn search_result(
+        &self,
+        target: &str,
+        sender: Option<&str>,
+        notice: &str,
+    ) -> Option<SearchResult> {
+        let multiline = self.multiline_results(target);
+        let mut buffer = self.notice_buffer.lock().unwrap();
+        buffer.retain(|_, (_, at)| at.elapsed() < MULTILINE_WINDOW);
+        let joined = match (multiline, sender) {
+            (true, Some(sender)) => match buffer.remove(sender) {
+                Some((previous, at)) if at.elapsed() < MULTILINE_WINDOW => {
+                    Some(format!("{} {}", previous, notice))
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+        let line = match parse_search_line(notice) {
+            Some(found) => found,
+            None => match joined.as_deref().and_then(parse_search_line) {
+                Some(found) => found,
+                None => {
+                    if let (true, Some(sender)) = (multiline, sender) {
+                        if buffer.len() >= MAX_BUFFERED_NOTICES {
+                            let oldest = buffer
+                                .iter()
+                                .min_by_key(|(_, (_, at))| *at)
+                                .map(|(sender, _)| sender.clone());
+                            if let Some(oldest) = oldest {
+                                buffer.remove(&oldest);
+                            }
+                        }
+                        buffer.insert(sender.to_string(), (notice.to_string(), Instant::now()));
+                    }
+                    return None;
+                }
+            },
+        };
+        log::debug!("Search result in {} format", line.pattern);
+        for channel in self.searched_channels.lock().unwrap().iter() {
+            if let Some(mut stats) = self.channel_stats.get_mut(channel) {
+                stats.hits += 1;
+            }
+        }
+        Some(SearchResult {
+            server: self.id.clone(),
+            file_name: line.file_name.to_string(),
+            nick: line.nick.to_string(),
+            command: line.command.to_string(),
+            raw: Some(line.line.to_string()),
+        })
+    }
+
+    /// Whether the numeric reply means the server does not let our messages through yet.
+    pub fn is_restriction_numeric(&self, numeric: &str) -> bool {
+        BUILTIN_RESTRICTION_NUMERICS.contains(&numeric)
+            || self.restriction_numerics.iter().any(|n| n == numeric)
+    }
+
+    /// Checks a numeric reply or a notice for the server refusing our messages for now. If so,
+    /// the restriction is recorded, requested downloads are delayed and the time to request them
+    /// again is returned, unless a retry is pending already. That is then put off if need be.
+    pub fn detect_restriction(&self, numeric: Option<&str>, text: &str) -> Option<Instant> {
+        let by_numeric = numeric.filter(|numeric| self.is_restriction_numeric(numeric));
+        let captures = BUILTIN_RESTRICTION_PATTERNS
+            .iter()
+            .chain(&self.restriction_patterns)
+            .find_map(|pattern| pattern.captures(text));
+        if by_numeric.is_none() && captures.is_none() {
+            return None;
+        }
+        let required = captures
+            .and_then(|c| c.name("secs"))
+            .and_then(|secs| secs.as_str().parse().ok())
+            .map(Duration::from_secs);
+        // From now rather than from when we connected, a restriction noticed late is still on
+        let (until, expiry) = match required {
+            Some(required) => (
+                Instant::now() + required + Duration::from_secs(5),
+                format!("Waiting the {}s required", required.as_secs()),
+            ),
+            None => (
+                Instant::now() + RESTRICTION_RETRY,
+                format!(
+                    "No duration given, trying again after {}s",
+                    RESTRICTION_RETRY.as_secs()
+                ),
+            ),
+        };
+        let reason = match by_numeric {
+            Some(numeric) => format!("{} {}", numeric, text),
+            None => text.to_string(),
+        };
+        log::info!("{} restricts messages: {}", self.id, reason);
         for mut item in self.downloads.iter_mut() {
             if matches!(item.status, DownloadStatus::Requested) {
                 item.status = DownloadStatus::Delayed(until);
             }
         }
-        until
+        self.download_updated();
+        let mut restriction = self.restriction.lock().unwrap();
+        let pending = restriction.as_ref().map(|r| r.until);
+        *restriction = Some(Restriction {
+            reason,
+            expiry,
+            until: pending.map_or(until, |pending| pending.max(until)),
+        });
+        match pending {
+            Some(_) => None,
+            None => Some(until),
+        }
+    }
+
+    /// Requests the downloads delayed by a restriction again, those whose time came. If the
+    /// restriction was put off meanwhile, nothing is requested and the new time is returned.
+    pub fn retry_restricted(&self) -> Option<Instant> {
+        let mut restriction = self.restriction.lock().unwrap();
+        if let Some(until) = restriction
+            .as_ref()
+            .map(|r| r.until)
+            .filter(|until| *until > Instant::now())
+        {
+            return Some(until);
+        }
+        *restriction = None;
+        drop(restriction);
+        let now = Instant::now();
+        let due: Vec<_> = self
+            .downloads
+            .iter()
+            .filter(|d| matches!(d.status, DownloadStatus::Delayed(at) if at <= now))
+            .map(|d| d.id)
+            .collect();
+        log::info!("Retrying {} downloads of {}", due.len(), self.id);
+        for id in due {
+            if let Err(err) = self.retry_delayed(&id) {
+                log::warn!("Could not retry download {}: {}", id, err);
+            }
+        }
+        None
     }
 
     pub fn handle_sender_gone(&mut self, nick: &str) {
@@ -74,25 +1069,2193 @@ impl ServerConnection {
                 item.status = DownloadStatus::SenderAbsent;
             }
         }
+        self.download_updated();
     }
 
-    pub fn abort_download(&self, id: &DownloadId) {
-        let download = self.downloads.remove(id);
-        if let Some((
-            _,
-            DownloadItem {
-                file_name,
-                status: DownloadStatus::Progress(progress),
-                ..
-            },
-        )) = download
+    /// Handles ERR_NOSUCHNICK for a bot. Without a grace period its downloads are marked absent
+    /// right away, otherwise the grace period is returned, after which
+    /// [`Self::check_sender_presence`] is due.
+    pub fn sender_missing(&mut self, nick: &str) -> Option<Duration> {
+        match self.sender_absent_grace {
+            Some(grace) => {
+                log::info!("{} is missing, checking again in {:?}", nick, grace);
+                Some(grace)
+            }
+            None => {
+                self.handle_sender_gone(nick);
+                None
+            }
+        }
+    }
+
+    /// Asks the server whether a missing bot is back.
+    pub fn check_sender_presence(&self, nick: &str) -> anyhow::Result<()> {
+        self.send_ison(nick, None)
+    }
+
+    /// Asks the server whether a nick is online, answered once RPL_ISON arrives.
+    pub fn lookup_nick(&self, nick: &str) -> anyhow::Result<oneshot::Receiver<bool>> {
+        let (sender, receiver) = oneshot::channel();
+        self.send_ison(nick, Some(sender))?;
+        Ok(receiver)
+    }
+
+    fn send_ison(&self, nick: &str, answer: Option<oneshot::Sender<bool>>) -> anyhow::Result<()> {
+        check_irc_text("Nick", nick)?;
+        self.presence_checks
+            .lock()
+            .unwrap()
+            .push_back((nick.to_string(), answer));
+        self.client.send(Command::ISON(vec![nick.to_string()]))?;
+        Ok(())
+    }
+
+    /// Handles RPL_ISON, answering the oldest presence check. Downloads of a bot that is still
+    /// missing after its grace period are marked absent, those of a returned one are left as they
+    /// are.
+    pub fn presence_reply(&mut self, present: &str) {
+        let Some((nick, answer)) = self.presence_checks.lock().unwrap().pop_front() else {
+            return;
+        };
+        let online = present
+            .split_whitespace()
+            .any(|present| present.eq_ignore_irc_case(&nick));
+        match answer {
+            Some(answer) => {
+                // Nobody waiting anymore is fine
+                answer.send(online).ok();
+            }
+            None if online => log::info!("{} is back", nick),
+            None => self.handle_sender_gone(&nick),
+        }
+    }
+
+    pub fn update_bot_limits(&self, nick: &str, limits: BotLimits) {
+        log::info!("{} announced limits {:?}", nick, limits);
+        let mut entry = self.bot_limits.entry(nick.to_string()).or_default();
+        entry.merge(limits);
+    }
+
+    fn bot_capacity(&self, nick: &str) -> usize {
+        let announced = self
+            .bot_limits
+            .iter()
+            .find(|l| l.key().eq_ignore_irc_case(nick))
+            .and_then(|l| l.capacity());
+        let configured = self
+            .max_requests_per_bot
+            .unwrap_or(DEFAULT_MAX_REQUESTS_PER_BOT);
+        announced.map_or(configured, |announced| announced.min(configured))
+    }
+
+    /// Downloads requested and not ended yet, of the bot or of all bots.
+    fn outstanding_requests(&self, nick: Option<&str>) -> usize {
+        self.downloads
+            .iter()
+            .filter(|d| {
+                nick.map_or(true, |nick| d.nick.eq_ignore_irc_case(nick))
+                    && matches!(
+                        d.status,
+                        DownloadStatus::Requested
+                            | DownloadStatus::RemoteQueued { .. }
+                            | DownloadStatus::Delayed(_)
+                            | DownloadStatus::Retrying(_)
+                            | DownloadStatus::Waiting
+                            | DownloadStatus::Connecting
+                            | DownloadStatus::Progress(_)
+                    )
+            })
+            .count()
+    }
+
+    /// Whether another request to the bot would exceed its limits or those of the server.
+    fn at_capacity(&self, nick: &str) -> bool {
+        self.outstanding_requests(Some(nick)) >= self.bot_capacity(nick)
+            || self
+                .max_requests_per_server
+                .map_or(false, |limit| self.outstanding_requests(None) >= limit)
+    }
+
+    /// Sends the request for a new download, unless it would exceed the limits of the bot or the
+    /// server, we are in quiet hours, short of disk space or disconnected. In that case it is
+    /// queued until another download ends or we are connected again. Returns whether the request
+    /// was sent, a download whose request could not be sent fails.
+    pub fn request(&self, mut item: DownloadItem) -> anyhow::Result<bool> {
+        if item.request_command.is_empty() {
+            item.status = DownloadStatus::Failed("Offered unsolicited, cannot be requested".into());
+            self.downloads.insert(item.id, item);
+            self.download_updated();
+            return Ok(false);
+        }
+        if self.at_capacity(&item.nick) || self.holding_requests() {
+            log::info!("Queueing {} of {}", item.file_name, item.nick);
+            item.status = DownloadStatus::Queued;
+            self.downloads.insert(item.id, item);
+            self.download_updated();
+            return Ok(false);
+        }
+        let sent = self.send_privmsg(&item.nick, &item.request_command);
+        item.status = match &sent {
+            Ok(()) => DownloadStatus::Requested,
+            Err(err) => DownloadStatus::Failed(format!("Could not request: {}", err)),
+        };
+        self.downloads.insert(item.id, item);
+        self.download_updated();
+        sent.map(|()| true)
+    }
+
+    /// Whether requests are held back for all bots.
+    fn holding_requests(&self) -> bool {
+        self.quiet.load(Ordering::Relaxed)
+            || self.disk_low.load(Ordering::Relaxed)
+            || matches!(
+                self.state(),
+                ConnectionState::Disconnected | ConnectionState::Reconnecting
+            )
+    }
+
+    fn is_joined(&self, channel: &str) -> bool {
+        self.joined_channels
+            .iter()
+            .any(|c| c.key().eq_ignore_irc_case(channel))
+    }
+
+    /// Handles a bot refusing a request because we are not in its channel. If we may, we join the
+    /// channel and request again once joined. Otherwise the download fails naming the channel.
+    pub fn handle_channel_required(&self, nick: &str, notice: &str) -> anyhow::Result<bool> {
+        let Some(captures) = REX_CHANNEL_REQUIRED.captures(notice) else {
+            return Ok(false);
+        };
+        let channel = &captures["channel"];
+        let latest = self
+            .downloads
+            .iter()
+            .filter(|d| {
+                d.nick.eq_ignore_irc_case(nick) && matches!(d.status, DownloadStatus::Requested)
+            })
+            .map(|d| d.id)
+            .max();
+        let Some(mut item) = latest.and_then(|id| self.downloads.get_mut(&id)) else {
+            return Ok(true);
+        };
+        item.notice = Some(notice.to_string());
+        let may_join =
+            self.auto_join_any || self.auto_join.iter().any(|c| c.eq_ignore_irc_case(channel));
+        if may_join && !self.is_joined(channel) {
+            log::info!(
+                "Joining {} to request {} from {}",
+                channel,
+                item.file_name,
+                nick
+            );
+            self.awaiting_join
+                .entry(channel.to_string())
+                .or_default()
+                .push(item.id);
+            drop(item);
+            self.join(channel)?;
+        } else {
+            item.status = DownloadStatus::Failed(format!("requires presence in {}", channel));
+            drop(item);
+            self.download_updated();
+        }
+        Ok(true)
+    }
+
+    /// Takes note of us having joined a channel. If the channel wants a greeting, the time after
+    /// which [`Self::greet`] is due is returned. Otherwise downloads waiting for the channel are
+    /// requested again right away.
+    pub fn channel_joined(&self, channel: &str) -> Option<Duration> {
+        self.joined_channels.insert(channel.to_string(), ());
+        if let Some(channel) = self
+            .channels
+            .iter()
+            .find(|c| c.on_join.is_some() && c.name.eq_ignore_irc_case(channel))
+        {
+            return Some(Duration::from_secs(channel.on_join_delay_secs));
+        }
+        self.request_awaiting(channel);
+        None
+    }
+
+    /// Sends the greeting a channel wants after joining, then requests the downloads waiting for
+    /// the channel again.
+    pub fn greet(&self, channel: &str) -> anyhow::Result<()> {
+        let greeting = self
+            .channels
+            .iter()
+            .find(|c| c.name.eq_ignore_irc_case(channel))
+            .and_then(|c| c.on_join.as_deref());
+        match greeting.map(|greeting| (greeting, greeting.strip_prefix("/me "))) {
+            Some((_, Some(action))) => {
+                check_irc_text("Greeting", action)?;
+                self.client.send_action(channel, action)?;
+            }
+            Some((greeting, None)) => self.send_privmsg(channel, greeting)?,
+            None => {}
+        }
+        self.request_awaiting(channel);
+        Ok(())
+    }
+
+    fn request_awaiting(&self, channel: &str) {
+        let waiting: Vec<_> = self
+            .awaiting_join
+            .iter()
+            .filter(|w| w.key().eq_ignore_irc_case(channel))
+            .map(|w| w.key().clone())
+            .collect();
+        for (_, ids) in waiting
+            .iter()
+            .filter_map(|key| self.awaiting_join.remove(key))
+        {
+            for id in ids {
+                let Some(item) = self.downloads.get(&id) else {
+                    continue;
+                };
+                if matches!(item.status, DownloadStatus::Requested) {
+                    log::info!(
+                        "Requesting {} again after joining {}",
+                        item.file_name,
+                        channel
+                    );
+                    if let Err(err) = self.send_privmsg(&item.nick, &item.request_command) {
+                        log::warn!("Could not request {} again: {}", item.file_name, err);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether a DCC SEND offer is new rather than a repetition of a recent one. Checking also
+    /// records it, so of two identical offers arriving at once only the first is accepted.
+    pub fn is_new_offer(&self, offer: &DccSend) -> bool {
+        let mut recent_offers = self.recent_offers.lock().unwrap();
+        recent_offers.retain(|_, seen| seen.elapsed() < self.offer_dedupe);
+        let key = (offer.file_name.clone(), offer.address, offer.id);
+        if recent_offers.contains_key(&key) {
+            log::info!("Ignoring repeated offer of {}", offer.file_name);
+            return false;
+        }
+        if !self.offer_dedupe.is_zero() {
+            recent_offers.insert(key, Instant::now());
+        }
+        true
+    }
+
+    /// Finds the download an offer of the bot belongs to. An offer repeating the token of an
+    /// earlier one continues its download. Otherwise it is the oldest download waiting for the
+    /// offered file. An offer of some other file is not taken for any download of the bot.
+    pub fn offered_download(&self, nick: &str, offer: &DccSend) -> Option<DownloadId> {
+        let from_bot = |d: &DownloadItem| d.nick.eq_ignore_irc_case(nick);
+        if let Some(token) = offer.id {
+            let resumed = self
+                .downloads
+                .iter()
+                .find(|d| from_bot(d) && d.dcc_token == Some(token) && !d.status.is_terminal());
+            if let Some(download) = resumed {
+                return Some(download.id);
+            }
+        }
+        let waiting: Vec<_> = self
+            .downloads
+            .iter()
+            .filter(|d| {
+                from_bot(d)
+                    && matches!(
+                        d.status,
+                        DownloadStatus::Requested
+                            | DownloadStatus::RemoteQueued { .. }
+                            | DownloadStatus::Delayed(_)
+                            | DownloadStatus::Retrying(_)
+                            | DownloadStatus::SenderAbsent
+                            | DownloadStatus::Queued
+                    )
+            })
+            .map(|d| {
+                let exact = d.file_name == offer.file_name;
+                (
+                    d.id,
+                    exact,
+                    exact || same_file_name(&d.file_name, &offer.file_name),
+                )
+            })
+            .collect();
+        // The exact name first, then the oldest request of a name rewritten by the bot
+        let oldest = |matches: fn(&(DownloadId, bool, bool)) -> bool| {
+            waiting.iter().filter(|d| matches(d)).map(|d| d.0).min()
+        };
+        oldest(|d| d.1).or_else(|| oldest(|d| d.2))
+    }
+
+    /// Takes an offer no download is waiting for as a new download, if unsolicited offers are
+    /// accepted. Returns the id it got from `next_id`.
+    pub fn unsolicited_download(
+        &self,
+        nick: &str,
+        offer: &DccSend,
+        next_id: impl FnOnce() -> DownloadId,
+    ) -> Option<DownloadId> {
+        if !self.accept_unsolicited {
+            return None;
+        }
+        log::info!(
+            "Accepting unsolicited offer of {} from {}",
+            offer.file_name,
+            nick
+        );
+        let id = next_id();
+        // Without a command, there is nothing to request again
+        let item = DownloadItem::new(
+            id,
+            self.id.clone(),
+            offer.file_name.clone(),
+            nick.to_string(),
+            String::new(),
+        );
+        self.downloads.insert(id, item);
+        self.download_updated();
+        Some(id)
+    }
+
+    /// The notice without mentions of our nick, whose digits could be taken for a position.
+    fn without_own_nick(&self, notice: &str) -> String {
+        let own = self.nick();
+        notice
+            .split_whitespace()
+            .filter(|word| {
+                !word
+                    .trim_matches(|c| matches!(c, ':' | ',' | '!' | '.' | '@'))
+                    .eq_ignore_irc_case(&own)
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Handles a bot telling our place in its queue, for the download named in the notice or
+    /// else the latest one requested from the bot.
+    pub fn handle_queue_position(&self, nick: &str, notice: &str) -> bool {
+        let text = self.without_own_nick(notice);
+        let Some((position, length)) = queue_position(&text) else {
+            return false;
+        };
+        let mut requested: Vec<_> = self
+            .downloads
+            .iter()
+            .filter(|d| {
+                d.nick.eq_ignore_irc_case(nick)
+                    && matches!(
+                        d.status,
+                        DownloadStatus::Requested | DownloadStatus::RemoteQueued { .. }
+                    )
+            })
+            .map(|d| (d.id, notice.contains(&d.file_name)))
+            .collect();
+        requested.sort_unstable();
+        let id = requested
+            .iter()
+            .find(|(_, named)| *named)
+            .or(requested.last())
+            .map(|(id, _)| *id);
+        let Some(mut item) = id.and_then(|id| self.downloads.get_mut(&id)) else {
+            return true;
+        };
+        log::info!("{} is at position {} of {}", item.file_name, position, nick);
+        let queue = match &mut item.queue {
+            Some(queue) => {
+                queue.update(position, length);
+                queue
+            }
+            None => item.queue.insert(QueuePosition::new(position, length)),
+        };
+        let eta_secs = queue_eta(&text).or(queue.eta_secs);
+        item.status = DownloadStatus::RemoteQueued {
+            position,
+            total: length,
+            eta_secs,
+        };
+        item.notice = Some(notice.to_string());
+        drop(item);
+        self.download_updated();
+        true
+    }
+
+    /// Handles a bot refusing a request because it is already sending to us. The latest request
+    /// is queued again until the running transfer ends. Without one we know of, the bot is left
+    /// alone for a while as if its slots were full. Returns the download and until when it was
+    /// delayed then.
+    pub fn handle_already_sending(
+        &self,
+        nick: &str,
+        notice: &str,
+    ) -> Option<(DownloadId, Instant)> {
+        if !REX_ALREADY_SENDING.is_match(notice) {
+            return None;
+        }
+        let transferring = self.downloads.iter().any(|d| {
+            d.nick.eq_ignore_irc_case(nick)
+                && matches!(
+                    d.status,
+                    DownloadStatus::Waiting
+                        | DownloadStatus::Connecting
+                        | DownloadStatus::Progress(_)
+                )
+        });
+        let latest = self
+            .downloads
+            .iter()
+            .filter(|d| {
+                d.nick.eq_ignore_irc_case(nick) && matches!(d.status, DownloadStatus::Requested)
+            })
+            .map(|d| d.id)
+            .max()?;
+        log::info!("{} is busy sending to us: {}", nick, notice);
+        let until = (!transferring).then(|| self.back_off(nick));
+        let mut item = self.downloads.get_mut(&latest)?;
+        item.status = match until {
+            Some(until) => DownloadStatus::Delayed(until),
+            None => DownloadStatus::Queued,
+        };
+        item.notice = Some(notice.to_string());
+        drop(item);
+        self.download_updated();
+        until.map(|until| (latest, until))
+    }
+
+    /// Handles a bot refusing a request because all its slots are full. The bot is left alone
+    /// for a while, the latest request is sent to the next of its alternatives that is not, or
+    /// else delayed until the bot may have a free slot. Returns the download and until when it
+    /// was delayed.
+    pub fn handle_slots_full(&self, nick: &str, notice: &str) -> Option<(DownloadId, Instant)> {
+        // Bots queueing us say so as well, we wait in the queue then. Being busy with us is
+        // handled by `handle_already_sending`.
+        if !REX_SLOTS_FULL.is_match(notice)
+            || queue_position(&self.without_own_nick(notice)).is_some()
+            || REX_ALREADY_SENDING.is_match(notice)
         {
-            log::info!("Aborted download of {}", file_name);
-            progress.abort_handle.abort();
+            return None;
+        }
+        let latest = self
+            .downloads
+            .iter()
+            .filter(|d| {
+                d.nick.eq_ignore_irc_case(nick) && matches!(d.status, DownloadStatus::Requested)
+            })
+            .map(|d| d.id)
+            .max()?;
+        let until = self.back_off(nick);
+        log::info!("{} has no free slots until {:?}: {}", nick, until, notice);
+        let (_, mut item) = self.downloads.remove(&latest)?;
+        item.notice = Some(notice.to_string());
+        if item.alternatives.is_empty() && REX_REQUEST_DENIED.is_match(notice) {
+            // Nobody else to ask, and this bot does not want to be asked again
+            log::info!("{} denied {}: {}", nick, item.file_name, notice);
+            item.status = DownloadStatus::Failed(notice.to_string());
+            self.downloads.insert(latest, item);
+            self.download_updated();
+            return None;
+        }
+        if let Some(until) = self.pick_source(&mut item) {
+            item.status = DownloadStatus::Delayed(until);
+            self.downloads.insert(latest, item);
+            self.download_updated();
+            return Some((latest, until));
+        }
+        if let Err(err) = self.request(item) {
+            log::warn!("Could not request {} elsewhere: {}", latest, err);
+        }
+        None
+    }
+
+    /// Leaves the bot alone for longer each time it has no free slots. Returns until when.
+    fn back_off(&self, nick: &str) -> Instant {
+        let key = self
+            .bot_backoff
+            .iter()
+            .find(|b| b.key().eq_ignore_irc_case(nick))
+            .map_or_else(|| nick.to_string(), |b| b.key().clone());
+        let mut entry = self.bot_backoff.entry(key).or_insert((Instant::now(), 0));
+        let strikes = entry.1.saturating_add(1);
+        let delay = self
+            .slots_full_backoff
+            .saturating_mul(2u32.saturating_pow(strikes - 1))
+            .min(MAX_SLOTS_FULL_BACKOFF);
+        *entry = (Instant::now() + delay, strikes);
+        entry.0
+    }
+
+    /// Until when the bot is left alone, if it is.
+    fn backoff_until(&self, nick: &str) -> Option<Instant> {
+        self.bot_backoff
+            .iter()
+            .find(|b| b.key().eq_ignore_irc_case(nick))
+            .map(|b| b.value().0)
+            .filter(|until| *until > Instant::now())
+    }
+
+    /// Switches the download to the first of its alternatives that is not backing off, if its
+    /// bot is. Returns until when the bot backs off if all of them do.
+    pub fn pick_source(&self, item: &mut DownloadItem) -> Option<Instant> {
+        let until = self.backoff_until(&item.nick)?;
+        let Some(index) = item
+            .alternatives
+            .iter()
+            .position(|source| self.backoff_until(&source.nick).is_none())
+        else {
+            return Some(until);
+        };
+        let source = item.alternatives.remove(index);
+        log::info!(
+            "{} is busy, requesting {} from {}",
+            item.nick,
+            item.file_name,
+            source.nick
+        );
+        item.alternatives.push(DownloadSource {
+            nick: std::mem::replace(&mut item.nick, source.nick),
+            command: std::mem::replace(&mut item.request_command, source.command),
+        });
+        item.queue = None;
+        None
+    }
+
+    /// Handles a bot telling us it does not have the pack we asked for. Fails the requested
+    /// download of the bot with the pack number the notice names, or else the latest one.
+    pub fn handle_no_such_pack(&self, nick: &str, notice: &str) -> bool {
+        let Some(captures) = REX_NO_SUCH_PACK.captures(notice) else {
+            return false;
+        };
+        let pack = captures
+            .name("pack")
+            .or_else(|| {
+                REX_PACK_NUMBER
+                    .captures(notice)
+                    .and_then(|c| c.name("pack"))
+            })
+            .map(|pack| pack.as_str().to_string());
+        let requested: Vec<_> = self
+            .downloads
+            .iter()
+            .filter(|d| {
+                d.nick.eq_ignore_irc_case(nick) && matches!(d.status, DownloadStatus::Requested)
+            })
+            .map(|d| (d.id, d.request_command.clone()))
+            .collect();
+        let matching = pack.and_then(|pack| {
+            requested
+                .iter()
+                .filter(|(_, command)| {
+                    command
+                        .rsplit(|c: char| !c.is_ascii_digit())
+                        .next()
+                        .map_or(false, |number| number == pack)
+                })
+                .map(|(id, _)| *id)
+                .max()
+        });
+        let Some(id) = matching.or_else(|| requested.iter().map(|(id, _)| *id).max()) else {
+            return true;
+        };
+        if let Some(mut item) = self.downloads.get_mut(&id) {
+            log::info!(
+                "{} does not have {}: {}",
+                nick,
+                item.request_command,
+                notice
+            );
+            item.status = DownloadStatus::Failed("no such pack".to_string());
+            item.notice = Some(notice.to_string());
+        }
+        self.download_updated();
+        if let Err(err) = self.dispatch_queued(nick) {
+            log::warn!("Could not request next download of {}: {}", nick, err);
+        }
+        true
+    }
+
+    /// Requests the next queued download of the bot, or if it has none the limits allow, the next
+    /// one of another bot. Returns whether a request was sent.
+    pub fn dispatch_queued(&self, nick: &str) -> anyhow::Result<bool> {
+        if self.holding_requests() {
+            return Ok(false);
+        }
+        let next = self
+            .queue_order()
+            .into_iter()
+            .filter(|(_, queued)| queued.eq_ignore_irc_case(nick))
+            .chain(self.queue_order())
+            .find(|(_, queued)| !self.at_capacity(queued))
+            .map(|(id, _)| id);
+        match next.and_then(|id| self.downloads.remove(&id)) {
+            Some((_, item)) => self.request(item),
+            None => Ok(false),
+        }
+    }
+
+    /// The queued downloads and their bots, in the order they are requested.
+    fn queue_order(&self) -> Vec<(DownloadId, String)> {
+        let mut queued: Vec<_> = self
+            .downloads
+            .iter()
+            .filter(|d| matches!(d.status, DownloadStatus::Queued))
+            .map(|d| (d.id, d.nick.clone()))
+            .collect();
+        queued.sort_unstable();
+        queued
+    }
+
+    /// The downloads, those queued with their place in the queue of their bot.
+    pub fn download_list(&self) -> Vec<DownloadItem> {
+        let mut positions: HashMap<DownloadId, usize> = HashMap::new();
+        let queued = self.queue_order();
+        for (index, (id, nick)) in queued.iter().enumerate() {
+            let ahead = queued[..index]
+                .iter()
+                .filter(|(_, other)| other.eq_ignore_irc_case(nick))
+                .count();
+            positions.insert(*id, ahead + 1);
+        }
+        self.downloads
+            .iter()
+            .map(|d| DownloadItem {
+                position: positions.get(&d.id).copied(),
+                ..d.clone()
+            })
+            .collect()
+    }
+
+    /// Requests as many queued downloads as the limits allow.
+    pub fn dispatch_all_queued(&self) -> anyhow::Result<()> {
+        let mut nicks: Vec<_> = self
+            .downloads
+            .iter()
+            .filter(|d| matches!(d.status, DownloadStatus::Queued))
+            .map(|d| d.nick.clone())
+            .collect();
+        nicks.dedup_by(|a, b| a.eq_ignore_irc_case(b));
+        for nick in nicks {
+            while self.dispatch_queued(&nick)? {}
+        }
+        Ok(())
+    }
+
+    /// Cancels a download, keeping it with the reason as status. The next queued download of the
+    /// bot may then be requested.
+    pub fn abort_download(&self, id: &DownloadId, reason: CancelReason) {
+        let Some(mut item) = self.downloads.get_mut(id) else {
+            return;
+        };
+        if item.status.is_terminal() {
+            return;
+        }
+        if let DownloadStatus::Progress(progress) = &item.status {
+            progress.cancellation.cancel(reason);
+        }
+        log::info!("Aborted download of {}: {:?}", item.file_name, reason);
+        item.status = DownloadStatus::Aborted { reason };
+        let nick = item.nick.clone();
+        drop(item);
+        self.download_updated();
+        if let Err(err) = self.dispatch_queued(&nick) {
+            log::warn!("Could not request next download of {}: {}", nick, err);
+        }
+    }
+
+    /// Stops a running transfer, the partial file is kept to continue from. The transfer marks
+    /// the download paused once it stopped. Returns whether it was transferring and can be
+    /// requested again, which downloads offered unsolicited cannot.
+    pub fn pause_download(&self, id: &DownloadId) -> bool {
+        let Some(item) = self.downloads.get(id) else {
+            return false;
+        };
+        let DownloadStatus::Progress(progress) = &item.status else {
+            return false;
+        };
+        if item.request_command.is_empty() {
+            return false;
+        }
+        log::info!("Pausing download of {}", item.file_name);
+        progress.cancellation.cancel(CancelReason::Pause);
+        true
+    }
+
+    /// Requests a paused download again, or queues it. The bot is asked to resume the transfer
+    /// where the partial file ends. Returns whether it was paused and can be requested.
+    pub fn resume_download(&self, id: &DownloadId) -> anyhow::Result<bool> {
+        let Some((_, mut item)) = self.downloads.remove_if(id, |_, item| {
+            matches!(item.status, DownloadStatus::Paused) && !item.request_command.is_empty()
+        }) else {
+            return Ok(false);
+        };
+        log::info!("Resuming download of {}", item.file_name);
+        item.queue = None;
+        item.dcc_token = None;
+        self.request(item)?;
+        Ok(true)
+    }
+
+    /// Aborts a download and moves it to the trash, from where it can be restored for a while.
+    /// Downloads that ended, completed ones included, and queued ones are dropped right away.
+    /// Returns whether there was such a download.
+    pub fn remove_download(&self, id: &DownloadId) -> bool {
+        let ended = self
+            .downloads
+            .get(id)
+            .map(|d| d.status.is_terminal() || matches!(d.status, DownloadStatus::Queued));
+        let removed = match ended {
+            // Nothing left to abort or restore, it just goes. Queued ones were not requested yet.
+            Some(true) => self.downloads.remove(id).is_some(),
+            Some(false) => {
+                self.abort_download(id, CancelReason::UserRequest);
+                match self.downloads.remove(id) {
+                    Some((id, item)) => {
+                        self.trash.insert(id, (item, Instant::now()));
+                        true
+                    }
+                    None => false,
+                }
+            }
+            None => {
+                let mut recently_completed = self.recently_completed.lock().unwrap();
+                let before = recently_completed.len();
+                recently_completed.retain(|item| item.id != *id);
+                recently_completed.len() != before
+            }
+        };
+        if removed {
+            self.download_updated();
+        }
+        removed
+    }
+
+    /// Takes a download out of the trash and requests it again, or queues it. Returns whether it
+    /// was in the trash.
+    pub fn restore_download(&self, id: &DownloadId) -> anyhow::Result<bool> {
+        let Some((_, (item, _))) = self.trash.remove(id) else {
+            return Ok(false);
+        };
+        log::info!("Restoring download of {}", item.file_name);
+        self.request(item)?;
+        Ok(true)
+    }
+
+    /// Requests a download again that failed, was aborted or whose bot was absent, or queues it.
+    /// Returns whether there was such a download.
+    pub fn retry_download(&self, id: &DownloadId) -> anyhow::Result<bool> {
+        let Some((_, mut item)) = self
+            .downloads
+            .remove_if(id, |_, item| item.status.is_retryable())
+        else {
+            return Ok(false);
+        };
+        log::info!("Retrying download of {}", item.file_name);
+        item.notice = None;
+        item.queue = None;
+        item.dcc_token = None;
+        item.retries = 0;
+        self.request(item)?;
+        Ok(true)
+    }
+
+    /// Requests a delayed or failed download again once it is due, unless it was removed or
+    /// aborted meanwhile.
+    pub fn retry_delayed(&self, id: &DownloadId) -> anyhow::Result<bool> {
+        let Some((_, mut item)) = self.downloads.remove_if(id, |_, item| {
+            matches!(
+                item.status,
+                DownloadStatus::Delayed(_) | DownloadStatus::Retrying(_)
+            )
+        }) else {
+            return Ok(false);
+        };
+        log::info!("Requesting {} again", item.file_name);
+        item.queue = None;
+        item.dcc_token = None;
+        self.pick_source(&mut item);
+        self.request(item)?;
+        Ok(true)
+    }
+
+    /// Takes the downloads out of the trash that were removed `window` or longer ago.
+    pub fn expire_trash(&self, window: Duration) -> Vec<DownloadItem> {
+        let expired: Vec<_> = self
+            .trash
+            .iter()
+            .filter(|entry| entry.value().1.elapsed() >= window)
+            .map(|entry| *entry.key())
+            .collect();
+        expired
+            .iter()
+            .filter_map(|id| self.trash.remove(id))
+            .map(|(_, (item, _))| item)
+            .collect()
+    }
+
+    /// Takes over the downloads of the previous connection to this server, as `on_reconnect`
+    /// says. Preserved downloads that were not transferring yet are queued, to be requested
+    /// again once we are registered. Returns how many downloads were taken over.
+    pub fn adopt_downloads(&self, previous: Vec<DownloadItem>) -> usize {
+        if self.on_reconnect == ReconnectDownloads::Drop {
+            for item in &previous {
+                if let DownloadStatus::Progress(progress) = &item.status {
+                    progress.cancellation.cancel(CancelReason::Shutdown);
+                }
+            }
+            log::info!("Dropped {} downloads of {}", previous.len(), self.id);
+            return 0;
+        }
+        let count = previous.len();
+        for mut item in previous {
+            // Transfers do not depend on the IRC connection, they go on
+            if !item.status.is_terminal()
+                && !matches!(
+                    item.status,
+                    DownloadStatus::Waiting
+                        | DownloadStatus::Connecting
+                        | DownloadStatus::Progress(_)
+                )
+            {
+                item.status = DownloadStatus::Queued;
+                item.queue = None;
+            }
+            self.downloads.insert(item.id, item);
+        }
+        self.download_updated();
+        count
+    }
+
+    /// Takes over from the previous connection to this server what outlives it: the downloads
+    /// as `on_reconnect` says, the trash and the completed downloads, what we learned about
+    /// bots and channels, and its socket. Returns how many downloads were taken over.
+    pub fn take_over(&mut self, previous: ServerConnection) -> usize {
+        self.trash = previous.trash;
+        *self.recently_completed.get_mut().unwrap() =
+            previous.recently_completed.into_inner().unwrap();
+        self.channel_stats = previous.channel_stats;
+        self.bot_limits = previous.bot_limits;
+        self.bot_backoff = previous.bot_backoff;
+        self.quiet = previous.quiet;
+        self.disk_low = previous.disk_low;
+        self.socket_permit = previous.socket_permit;
+        self.adopt_downloads(
+            previous
+                .downloads
+                .into_iter()
+                .map(|(_, item)| item)
+                .collect(),
+        )
+    }
+
+    /// Ids of the downloads matching all of the given criteria. Nicks are compared ignoring IRC
+    /// case.
+    pub fn find_downloads(
+        &self,
+        file_name: Option<&str>,
+        nick: Option<&str>,
+        command: Option<&str>,
+    ) -> Vec<DownloadId> {
+        let mut ids: Vec<_> = self
+            .downloads
+            .iter()
+            .filter(|d| {
+                file_name.map_or(true, |file_name| d.file_name == file_name)
+                    && nick.map_or(true, |nick| d.nick.eq_ignore_irc_case(nick))
+                    && command.map_or(true, |command| {
+                        d.request_command
+                            .trim()
+                            .eq_ignore_ascii_case(command.trim())
+                    })
+            })
+            .map(|d| d.id)
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Moves a download to the completed ones, with the file of `bytes` at `path`.
+    pub fn completed(&self, id: &DownloadId, bytes: u64, path: PathBuf) {
+        if let Some((_, mut item)) = self.downloads.remove(id) {
+            self.bot_backoff
+                .retain(|nick, _| !nick.eq_ignore_irc_case(&item.nick));
+            // The file may have been renamed to not replace another
+            if let Some(file_name) = path.file_name().and_then(|name| name.to_str()) {
+                item.file_name = file_name.to_string();
+            }
+            item.status = DownloadStatus::Completed {
+                finished_at: chrono::Utc::now(),
+                bytes,
+                path,
+            };
+            let mut recently_completed = self.recently_completed.lock().unwrap();
+            if recently_completed.len() == RECENTLY_COMPLETED {
+                recently_completed.pop_front();
+            }
+            recently_completed.push_back(item);
+        }
+        self.download_updated();
+    }
+
+    /// The downloads completed lately, oldest first.
+    pub fn completed_downloads(&self) -> Vec<DownloadItem> {
+        self.recently_completed
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Forgets the completed downloads. Returns how many there were.
+    pub fn clear_completed(&self) -> usize {
+        let cleared = std::mem::take(&mut *self.recently_completed.lock().unwrap()).len();
+        self.download_updated();
+        cleared
+    }
+
+    /// Forgets a completed download. Returns whether there was one.
+    pub fn forget_completed(&self, id: &DownloadId) -> bool {
+        let mut recently_completed = self.recently_completed.lock().unwrap();
+        let count = recently_completed.len();
+        recently_completed.retain(|item| item.id != *id);
+        let forgotten = recently_completed.len() != count;
+        drop(recently_completed);
+        if forgotten {
+            self.download_updated();
+        }
+        forgotten
+    }
+
+    /// Requests a completed download again, whose file got lost. Returns whether there was one
+    /// that could be.
+    pub fn request_again(&self, id: &DownloadId) -> anyhow::Result<bool> {
+        let item = {
+            let mut recently_completed = self.recently_completed.lock().unwrap();
+            // Offered without being asked, there is no command to ask with
+            let Some(index) = recently_completed
+                .iter()
+                .position(|item| item.id == *id && !item.request_command.is_empty())
+            else {
+                return Ok(false);
+            };
+            recently_completed.remove(index)
+        };
+        let Some(mut item) = item else {
+            return Ok(false);
+        };
+        log::info!("Requesting {} again, its file is gone", item.file_name);
+        item.notice = None;
+        item.queue = None;
+        item.dcc_token = None;
+        item.digest = None;
+        item.retries = 0;
+        self.request(item)?;
+        Ok(true)
+    }
+
+    /// A download completed lately, it is no longer in `downloads`.
+    pub fn completed_download(&self, id: &DownloadId) -> Option<DownloadItem> {
+        self.recently_completed
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|item| item.id == *id)
+            .cloned()
+    }
+
+    /// A download that ended, whether it is still listed, completed or removed by the user.
+    pub fn ended_download(&self, id: &DownloadId) -> Option<DownloadItem> {
+        let listed = self.downloads.get(id).map(|d| d.clone());
+        let trashed = || self.trash.get(id).map(|entry| entry.value().0.clone());
+        listed
+            .or_else(|| self.completed_download(id))
+            .or_else(trashed)
+            .filter(|d| d.status.is_terminal())
+    }
+
+    /// Notifies those waiting for downloads to change, after changing the status of one.
+    pub fn download_updated(&self) {
+        self.download_updates.send_replace(());
+    }
+
+    /// Changes after subscribing, i.e. the status is to be checked after this.
+    pub fn subscribe_download_updates(&self) -> watch::Receiver<()> {
+        self.download_updates.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::{FileDigest, HashAlgorithm};
+    use crate::{Cancellation, DownloadProgress};
+    use futures_util::FutureExt;
+
+    async fn mock_connection(config: &str) -> ServerConnection {
+        let config: ServerConfig =
+            toml::from_str(&format!(
+            "{}\n{}\n[config]\nserver = \"mock\"\nnickname = \"me\"\nuse_mock_connection = true\n",
+            if config.contains("channels") { "" } else { "channels = []" },
+            config
+        ))
+            .unwrap();
+        let (connection, _, stream) = ServerConnection::new(config).await.unwrap();
+        // The stream owns the queue of outgoing messages, sending fails once it is dropped
+        tokio::spawn(async move {
+            let _stream = stream;
+            std::future::pending::<()>().await
+        });
+        connection
+    }
+
+    fn item(id: DownloadId, nick: &str) -> DownloadItem {
+        DownloadItem {
+            id,
+            server: "mock".to_string(),
+            file_name: format!("file{}.mkv", id),
+            nick: nick.to_string(),
+            status: DownloadStatus::Requested,
+            request_command: format!("xdcc send #{}", id),
+            notice: None,
+            digest: None,
+            queue: None,
+            position: None,
+            advertised_address: None,
+            dcc_token: None,
+            retries: 0,
+            alternatives: Vec::new(),
+            max_bytes_per_sec: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn join_channel_required_by_bot() {
+        let server = mock_connection("auto_join = [\"#Main\"]").await;
+        server.request(item(0, "Bot")).unwrap();
+        server.request(item(1, "Other")).unwrap();
+
+        assert!(server
+            .handle_channel_required("Bot", "You must join #main to request from me.")
+            .unwrap());
+        assert!(server.awaiting_join.contains_key("#main"));
+        assert!(matches!(
+            server.downloads.get(&0).unwrap().status,
+            DownloadStatus::Requested
+        ));
+
+        assert!(server
+            .handle_channel_required("Other", "you must be on #elite")
+            .unwrap());
+        assert!(matches!(
+            &server.downloads.get(&1).unwrap().status,
+            DownloadStatus::Failed(reason) if reason == "requires presence in #elite"
+        ));
+
+        server.channel_joined("#MAIN");
+        assert!(server.awaiting_join.is_empty());
+        assert!(matches!(
+            server.downloads.get(&0).unwrap().status,
+            DownloadStatus::Requested
+        ));
+    }
+
+    #[tokio::test]
+    async fn search_result_over_two_notices() {
+        let server = mock_connection(
+            "channels = [\
+                { name = \"#search\", search = true, multiline_results = true },\
+                { name = \"#chat\", search = false }]",
+        )
+        .await;
+        server.search("foo", None).unwrap();
+        assert!(server
+            .search_result("me", Some("Bot"), "#12 1.2G Some.File.S01E01.mkv")
+            .is_none());
+        let result = server
+            .search_result("me", Some("Bot"), "- /msg Bot xdcc send #12")
+            .unwrap();
+        assert_eq!(result.file_name, "Some.File.S01E01.mkv");
+        assert_eq!(result.command, "xdcc send #12");
+        assert_eq!(
+            result.raw.as_deref(),
+            Some("#12 1.2G Some.File.S01E01.mkv - /msg Bot xdcc send #12")
+        );
+
+        // Single line results are unaffected
+        assert!(server
+            .search_result("me", Some("Bot"), "Other.mkv - /msg Bot xdcc send #13")
+            .is_some());
+
+        // Not in a channel without multi-line results
+        server.search_result("#chat", Some("Bot"), "#12 1.2G Some.File.S01E01.mkv");
+        assert!(server
+            .search_result("#chat", Some("Bot"), "- /msg Bot xdcc send #12")
+            .is_none());
+
+        // Many senders don't pile up
+        for i in 0..MAX_BUFFERED_NOTICES + 10 {
+            server.search_result("me", Some(&format!("Bot{}", i)), "#12 1.2G Some.File.mkv");
+        }
+        assert_eq!(
+            server.notice_buffer.lock().unwrap().len(),
+            MAX_BUFFERED_NOTICES
+        );
+    }
+
+    #[tokio::test]
+    async fn search_channels_capped() {
+        let server = mock_connection(
+            "max_search_channels = 2\nchannels = [\
+                { name = \"#a\", search = true },\
+                { name = \"#b\", search = true },\
+                { name = \"#c\", search = true },\
+                { name = \"#chat\", search = false }]",
+        )
+        .await;
+        server.search("foo", None).unwrap();
+        assert_eq!(*server.searched_channels.lock().unwrap(), ["#a", "#b"]);
+        server
+            .search_result("me", Some("Bot"), "Other.mkv - /msg Bot xdcc send #13")
+            .unwrap();
+
+        // #c was not searched yet and gets the rotating slot
+        server.search("foo", None).unwrap();
+        let searched = server.searched_channels.lock().unwrap().clone();
+        assert_eq!(searched.len(), 2);
+        assert_eq!(searched[1], "#c");
+        assert_eq!(server.channel_stats.get("#a").unwrap().hits, 1);
+        assert_eq!(server.channel_stats.get("#c").unwrap().searches, 1);
+    }
+
+    #[tokio::test]
+    async fn restrictions_of_different_networks() {
+        // Undernet
+        let server = mock_connection("").await;
+        server.request(item(0, "Bot")).unwrap();
+        assert!(server
+            .detect_restriction(Some("531"), "You cannot send messages to users yet")
+            .is_some());
+        assert!(matches!(
+            server.downloads.get(&0).unwrap().status,
+            DownloadStatus::Delayed(_)
+        ));
+        assert!(server
+            .restriction
+            .lock()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .reason
+            .starts_with("531"));
+
+        // Notice naming the time required
+        let server = mock_connection("").await;
+        let until = server
+            .detect_restriction(
+                None,
+                "You must be connected for 60 seconds before messaging users",
+            )
+            .unwrap();
+        assert!(until > Instant::now() + Duration::from_secs(64));
+
+        // Registered users only, configured pattern
+        let server = mock_connection("restriction_patterns = [\"(?i)new users must wait\"]").await;
+        assert!(server
+            .detect_restriction(Some("486"), "You must log in with services to message")
+            .is_some());
+        // Recorded, but the retry is pending already
+        assert!(server
+            .detect_restriction(None, "New users must wait a bit")
+            .is_none());
+        assert_eq!(
+            server.restriction.lock().unwrap().as_ref().unwrap().reason,
+            "New users must wait a bit"
+        );
+        assert!(server
+            .detect_restriction(Some("401"), "No such nick/channel")
+            .is_none());
+        assert!(server
+            .detect_restriction(None, "Welcome to the network")
+            .is_none());
+
+        let server = mock_connection("restriction_numerics = [\"999\"]").await;
+        assert!(server.is_restriction_numeric("531"));
+        assert!(server.is_restriction_numeric("999"));
+        assert!(!server.is_restriction_numeric("401"));
+    }
+
+    #[tokio::test]
+    async fn only_delayed_downloads_retried_after_restriction() {
+        let server = mock_connection("").await;
+        server.request(item(0, "Bot")).unwrap();
+        server.request(item(1, "Other")).unwrap();
+        server.request(item(2, "Third")).unwrap();
+        server.downloads.get_mut(&1).unwrap().status = DownloadStatus::Connecting;
+        server.downloads.get_mut(&2).unwrap().status = DownloadStatus::Failed("gone".to_string());
+        let until = server
+            .detect_restriction(Some("531"), "You cannot send messages to users yet")
+            .unwrap();
+        let status = |id| server.downloads.get(&id).unwrap().status.clone();
+        assert!(matches!(status(0), DownloadStatus::Delayed(at) if at == until));
+        assert!(matches!(status(1), DownloadStatus::Connecting));
+
+        // Put off by a later one, without a second retry
+        let later = Instant::now() + Duration::from_secs(600);
+        server.restriction.lock().unwrap().as_mut().unwrap().until = later;
+        assert_eq!(server.retry_restricted(), Some(later));
+        assert!(matches!(status(0), DownloadStatus::Delayed(_)));
+
+        server.restriction.lock().unwrap().as_mut().unwrap().until = Instant::now();
+        server.downloads.get_mut(&0).unwrap().status = DownloadStatus::Delayed(Instant::now());
+        // Waiting for the retry policy, not for the restriction
+        server.downloads.get_mut(&1).unwrap().status = DownloadStatus::Retrying(Instant::now());
+        assert_eq!(server.retry_restricted(), None);
+        assert!(matches!(status(0), DownloadStatus::Requested));
+        assert!(matches!(status(1), DownloadStatus::Retrying(_)));
+        assert!(matches!(status(2), DownloadStatus::Failed(_)));
+        assert!(server.restriction.lock().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn unsolicited_offers() {
+        let offer = DccSend::from_str("\u{1}DCC SEND spam.exe 2130706433 1234 100\u{1}")
+            .unwrap()
+            .0;
+        let server = mock_connection("").await;
+        assert_eq!(server.offered_download("Troll", &offer), None);
+        assert_eq!(server.unsolicited_download("Troll", &offer, || 7), None);
+        assert!(server.downloads.is_empty());
+
+        let server = mock_connection("accept_unsolicited = true").await;
+        assert_eq!(server.unsolicited_download("Bot", &offer, || 7), Some(7));
+        let download = server.downloads.get(&7).unwrap().clone();
+        assert_eq!(
+            (download.nick.as_str(), download.file_name.as_str()),
+            ("Bot", "spam.exe")
+        );
+        // Nothing to ask the bot for when retrying
+        server.downloads.remove(&7);
+        assert!(!server.request(download).unwrap());
+        assert!(matches!(
+            server.downloads.get(&7).unwrap().status,
+            DownloadStatus::Failed(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn pause_and_resume() {
+        let server = mock_connection("").await;
+        server.request(item(0, "Bot")).unwrap();
+        assert!(!server.pause_download(&0));
+        let (cancellation, _registration) = Cancellation::new_pair();
+        server.downloads.get_mut(&0).unwrap().status = DownloadStatus::Progress(DownloadProgress {
+            transferred: Default::default(),
+            file_size: None,
+            bytes_per_sec: Default::default(),
+            eta_seconds: Default::default(),
+            cancellation: cancellation.clone(),
+        });
+        assert!(server.pause_download(&0));
+        assert_eq!(cancellation.reason(), Some(CancelReason::Pause));
+        assert!(!server.resume_download(&0).unwrap());
+
+        // As the transfer does once stopped
+        server.downloads.get_mut(&0).unwrap().status = DownloadStatus::Paused;
+        server.request(item(1, "Bot")).unwrap();
+        assert!(matches!(
+            server.downloads.get(&1).unwrap().status,
+            DownloadStatus::Requested
+        ));
+        assert!(server.resume_download(&0).unwrap());
+        assert!(matches!(
+            server.downloads.get(&0).unwrap().status,
+            DownloadStatus::Queued
+        ));
+
+        // Offered unsolicited, there is no command to request it again with
+        let mut unsolicited = item(2, "Bot");
+        unsolicited.request_command = String::new();
+        unsolicited.status = DownloadStatus::Paused;
+        server.downloads.insert(2, unsolicited);
+        assert!(!server.resume_download(&2).unwrap());
+        assert!(matches!(
+            server.downloads.get(&2).unwrap().status,
+            DownloadStatus::Paused
+        ));
+    }
+
+    #[tokio::test]
+    async fn join_any_required_channel_with_key() {
+        let server = mock_connection(
+            "auto_join_any = true\njoin_interval_secs = 5\n[channel_keys]\n\"#Gated\" = \"secret\"",
+        )
+        .await;
+        server.request(item(0, "Bot")).unwrap();
+        assert!(server
+            .handle_channel_required("Bot", "You need to be in #gated to get packs")
+            .unwrap());
+        assert!(server.awaiting_join.contains_key("#gated"));
+        assert_eq!(server.channel_key("#GATED").as_deref(), Some("secret"));
+        // The next join waits for the interval
+        assert!(*server.next_join.lock().unwrap() > Instant::now() + Duration::from_secs(4));
+    }
+
+    #[tokio::test]
+    async fn ack_width_per_bot() {
+        let server = mock_connection("[ack_widths]\n\"Big|Bot\" = \"bits64\"").await;
+        assert_eq!(server.ack_width("big\\bot"), Some(AckWidth::Bits64));
+        assert_eq!(server.ack_width("Other"), None);
+    }
+
+    #[tokio::test]
+    async fn greet_after_join() {
+        let server = mock_connection(
+            "auto_join = [\"#Main\"]\nchannels = [\
+                { name = \"#main\", search = true, on_join = \"/me waves\", on_join_delay_secs = 3 }]",
+        )
+        .await;
+        server.request(item(0, "Bot")).unwrap();
+        server
+            .handle_channel_required("Bot", "You must join #main to request from me.")
+            .unwrap();
+
+        assert_eq!(server.channel_joined("#main"), Some(Duration::from_secs(3)));
+        // Requests wait for the greeting
+        assert!(server.awaiting_join.contains_key("#main"));
+        server.greet("#main").unwrap();
+        assert!(server.awaiting_join.is_empty());
+    }
+
+    #[test]
+    fn random_ident() {
+        let word = random_word(8);
+        assert_eq!(word.len(), 8);
+        assert!(word.chars().all(|c| c.is_ascii_lowercase()));
+    }
+
+    #[tokio::test]
+    async fn overlapping_requests_to_one_bot() {
+        let server = mock_connection("max_requests_per_bot = 1").await;
+        server.request(item(0, "Bot")).unwrap();
+        server.request(item(1, "bot")).unwrap();
+        assert!(matches!(
+            server.downloads.get(&0).unwrap().status,
+            DownloadStatus::Requested
+        ));
+        assert!(matches!(
+            server.downloads.get(&1).unwrap().status,
+            DownloadStatus::Queued
+        ));
+
+        server.completed(&0, 0, PathBuf::new());
+        server.dispatch_queued("BOT").unwrap();
+        assert!(matches!(
+            server.downloads.get(&1).unwrap().status,
+            DownloadStatus::Requested
+        ));
+    }
+
+    #[tokio::test]
+    async fn abort_keeps_reason() {
+        let server = mock_connection("max_requests_per_bot = 1").await;
+        server.request(item(0, "Bot")).unwrap();
+        server.request(item(1, "Bot")).unwrap();
+        let (cancellation, _) = Cancellation::new_pair();
+        server.downloads.get_mut(&0).unwrap().status = DownloadStatus::Progress(DownloadProgress {
+            transferred: Default::default(),
+            file_size: None,
+            bytes_per_sec: Default::default(),
+            eta_seconds: Default::default(),
+            cancellation: cancellation.clone(),
+        });
+
+        server.abort_download(&0, CancelReason::Stall);
+        assert_eq!(cancellation.reason(), Some(CancelReason::Stall));
+        assert!(matches!(
+            server.downloads.get(&0).unwrap().status,
+            DownloadStatus::Aborted {
+                reason: CancelReason::Stall
+            }
+        ));
+        // The queued download takes the slot and can be aborted as well
+        assert!(matches!(
+            server.downloads.get(&1).unwrap().status,
+            DownloadStatus::Requested
+        ));
+        server.abort_download(&1, CancelReason::UserRequest);
+        assert!(matches!(
+            server.downloads.get(&1).unwrap().status,
+            DownloadStatus::Aborted {
+                reason: CancelReason::UserRequest
+            }
+        ));
+        // A later reason does not replace the first
+        server.abort_download(&0, CancelReason::Shutdown);
+        cancellation.cancel(CancelReason::Shutdown);
+        assert_eq!(cancellation.reason(), Some(CancelReason::Stall));
+    }
+
+    #[tokio::test]
+    async fn requests_limited_per_server() {
+        let server = mock_connection("max_requests_per_server = 2").await;
+        for (id, nick) in [
+            (0, "Bot"),
+            (1, "Bot"),
+            (2, "Other"),
+            (3, "Third"),
+            (4, "Bot"),
+        ] {
+            server.request(item(id, nick)).unwrap();
+        }
+        let status = |id| server.downloads.get(&id).unwrap().status.clone();
+        assert!(matches!(status(0), DownloadStatus::Requested));
+        assert!(matches!(status(2), DownloadStatus::Requested));
+        let mut positions: Vec<_> = server
+            .download_list()
+            .into_iter()
+            .filter_map(|d| Some((d.id, d.position?)))
+            .collect();
+        positions.sort_unstable();
+        assert_eq!(positions, [(1, 1), (3, 1), (4, 2)]);
+
+        // The slot goes to the next download of the same bot
+        server.completed(&0, 0, PathBuf::new());
+        server.dispatch_queued("Bot").unwrap();
+        assert!(matches!(status(1), DownloadStatus::Requested));
+        // Or if it has none, to the next of another bot
+        server.completed(&1, 0, PathBuf::new());
+        server.dispatch_queued("Bot").unwrap();
+        assert!(matches!(status(3), DownloadStatus::Queued));
+        assert!(matches!(status(4), DownloadStatus::Requested));
+        server.abort_download(&2, CancelReason::UserRequest);
+        assert!(matches!(status(3), DownloadStatus::Requested));
+    }
+
+    #[tokio::test]
+    async fn queued_download_dropped() {
+        let server = mock_connection("").await;
+        server.request(item(0, "Bot")).unwrap();
+        server.request(item(1, "Bot")).unwrap();
+        assert!(server.remove_download(&1));
+        assert!(!server.downloads.contains_key(&1));
+        assert!(server.trash.is_empty());
+        assert!(matches!(
+            server.downloads.get(&0).unwrap().status,
+            DownloadStatus::Requested
+        ));
+    }
+
+    #[test]
+    fn duplicate_servers_refused() {
+        let server = |url: &str| -> ServerConfig {
+            toml::from_str(&format!(
+                "channels = []\n[config]\nserver = \"{}\"\nnickname = \"me\"\n",
+                url
+            ))
+            .unwrap()
+        };
+        check_unique_ids(&[server("irc.a.net"), server("irc.b.net")]).unwrap();
+        let labelled = |url: &str, label: &str| ServerConfig {
+            label: Some(label.to_string()),
+            ..server(url)
+        };
+        check_unique_ids(&[server("irc.a.net"), labelled("irc.a.net", "second")]).unwrap();
+        assert!(
+            check_unique_ids(&[labelled("irc.a.net", "b"), labelled("irc.b.net", "b")]).is_err()
+        );
+        let err = check_unique_ids(&[
+            server("irc.a.net"),
+            server("irc.b.net"),
+            server("irc.a.net"),
+        ])
+        .unwrap_err();
+        assert!(err.to_string().contains("irc.a.net"));
+        assert!(err.to_string().contains("entry 3"));
+    }
+
+    #[test]
+    fn health_scores() {
+        let settled = SETTLING_TIME * 2;
+        let fast = health_score(Some(Duration::from_millis(50)), settled, false);
+        let slow = health_score(Some(Duration::from_millis(800)), settled, false);
+        let unmeasured = health_score(None, settled, false);
+        let fresh = health_score(Some(Duration::from_millis(50)), Duration::ZERO, false);
+        let restricted = health_score(Some(Duration::from_millis(50)), settled, true);
+        assert!(fast < slow);
+        assert!(slow < unmeasured);
+        assert!(unmeasured < fresh);
+        assert!(fresh < restricted);
+    }
+
+    #[tokio::test]
+    async fn latency_from_matching_pong() {
+        let server = mock_connection("").await;
+        server.ping().unwrap();
+        server.pong("something else");
+        assert!(server.latencies().is_empty());
+        let token = server.latency_ping.lock().unwrap().clone().unwrap().0;
+        server.pong(&token);
+        server.pong(&token);
+        assert_eq!(server.latencies().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn follows_own_nick() {
+        let server = mock_connection("nick_recovery_secs = 60").await;
+        assert_eq!(server.nick_recovery, Some(Duration::from_secs(60)));
+        server.registered_as("me_");
+        assert!(!server.has_primary_nick());
+        server.nick_changed("someone", "me");
+        assert_eq!(server.nick(), "me_");
+        // Without NickServ credentials there is nobody to ghost
+        assert!(!server.ghost_primary_nick().unwrap());
+        server.nick_changed("ME_", "Me");
+        assert!(server.has_primary_nick());
+    }
+
+    #[tokio::test]
+    async fn primary_nick_released() {
+        let server = mock_connection("").await;
+        server.registered_as("me_");
+        let released = server.nick_released.clone();
+        let releases = |by: &dyn Fn()| {
+            let notified = released.notified();
+            by();
+            notified.now_or_never().is_some()
+        };
+        assert!(!releases(&|| server.handle_services_notice("SomeBot")));
+        assert!(!releases(&|| server.nick_changed("someone", "other")));
+        assert!(releases(&|| server.handle_services_notice("NickServ")));
+        // The holder of our nick moving on
+        assert!(releases(&|| server.nick_changed("Me", "me_ghost")));
+        assert_eq!(server.nick(), "me_");
+    }
+
+    #[tokio::test]
+    async fn repeated_offers_ignored() {
+        let server = mock_connection("").await;
+        let offer = |line: &str| DccSend::from_str(line).unwrap().0;
+        let first = offer("\u{1}DCC SEND file.mkv 1226420238 0 1000 7\u{1}");
+        assert!(server.is_new_offer(&first));
+        assert!(!server.is_new_offer(&first));
+        let other_token = offer("\u{1}DCC SEND file.mkv 1226420238 0 1000 8\u{1}");
+        assert!(server.is_new_offer(&other_token));
+
+        let server = mock_connection("offer_dedupe_secs = 0").await;
+        assert!(server.is_new_offer(&first));
+        assert!(server.is_new_offer(&first));
+    }
+
+    #[tokio::test]
+    async fn queue_position_and_eta() {
+        let server = mock_connection("max_requests_per_bot = 2").await;
+        server.request(item(0, "Bot")).unwrap();
+        server.request(item(1, "Bot")).unwrap();
+        assert!(server.handle_queue_position(
+            "Bot",
+            "Added you to the main queue for pack 1 (\"file0.mkv\") in position 5."
+        ));
+        let queue = server.downloads.get(&0).unwrap().queue.clone().unwrap();
+        assert_eq!((queue.position, queue.eta_secs), (5, None));
+        assert!(matches!(
+            server.downloads.get(&0).unwrap().status,
+            DownloadStatus::RemoteQueued {
+                position: 5,
+                total: None,
+                eta_secs: None
+            }
+        ));
+        // Without a file name, the latest request is meant
+        server.handle_queue_position("Bot", "You are in position 2 of 7 in the main queue");
+        assert_eq!(
+            server
+                .downloads
+                .get(&1)
+                .unwrap()
+                .queue
+                .as_ref()
+                .unwrap()
+                .length,
+            Some(7)
+        );
+
+        // Three positions in 30 minutes, two to go
+        server
+            .downloads
+            .get_mut(&0)
+            .unwrap()
+            .queue
+            .as_mut()
+            .unwrap()
+            .first_seen -= Duration::from_secs(1800);
+        server.handle_queue_position("Bot", "file0.mkv: position 2");
+        let queue = server.downloads.get(&0).unwrap().queue.clone().unwrap();
+        assert_eq!(queue.position, 2);
+        assert_eq!(queue.eta_secs, Some(1200));
+
+        assert!(!server.handle_queue_position("Bot", "Sending you pack #1"));
+    }
+
+    #[tokio::test]
+    async fn queue_notices_parsed() {
+        assert_eq!(
+            queue_position("You have been queued for pack 13, position 4 of 10"),
+            Some((4, Some(10)))
+        );
+        assert_eq!(queue_position("Queue position: 2/3"), Some((2, Some(3))));
+        assert_eq!(queue_position("You are #7 in the queue"), Some((7, None)));
+        assert_eq!(queue_eta("position 4 of 10, estimated wait 12m"), Some(720));
+        assert_eq!(queue_eta("ETA: 1h 5m 3s"), Some(3903));
+        assert_eq!(queue_eta("Estimated time 01:30:00"), Some(5400));
+        assert_eq!(queue_eta("Sending you pack #1"), None);
+
+        let server = mock_connection("").await;
+        server.registered_as("Position1");
+        server.request(item(0, "Bot")).unwrap();
+        assert!(server.handle_queue_position("BOT", "position1: you are #2 of 4, ETA 30 min"));
+        assert!(matches!(
+            server.downloads.get(&0).unwrap().status,
+            DownloadStatus::RemoteQueued {
+                position: 2,
+                total: Some(4),
+                eta_secs: Some(1800)
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn denied_request_fails() {
+        let server = mock_connection("").await;
+        server.request(item(0, "Bot")).unwrap();
+        assert!(server
+            .handle_slots_full("Bot", "All slots full, request denied")
+            .is_none());
+        assert!(matches!(
+            &server.downloads.get(&0).unwrap().status,
+            DownloadStatus::Failed(reason) if reason == "All slots full, request denied"
+        ));
+    }
+
+    #[tokio::test]
+    async fn restore_from_trash() {
+        let server = mock_connection("").await;
+        server.request(item(0, "Bot")).unwrap();
+        assert!(server.remove_download(&0));
+        assert!(server.downloads.is_empty());
+        assert!(server.expire_trash(Duration::from_secs(600)).is_empty());
+
+        assert!(server.restore_download(&0).unwrap());
+        assert!(matches!(
+            server.downloads.get(&0).unwrap().status,
+            DownloadStatus::Requested
+        ));
+        assert!(server.trash.is_empty());
+        assert!(!server.restore_download(&0).unwrap());
+    }
+
+    #[tokio::test]
+    async fn failed_download_retried() {
+        let server = mock_connection("").await;
+        server.request(item(0, "Bot")).unwrap();
+        assert!(!server.retry_download(&0).unwrap());
+
+        server.downloads.get_mut(&0).unwrap().status = DownloadStatus::Failed("gone".to_string());
+        assert!(server.retry_download(&0).unwrap());
+        assert!(matches!(
+            server.downloads.get(&0).unwrap().status,
+            DownloadStatus::Requested
+        ));
+        assert!(!server.retry_download(&1).unwrap());
+    }
+
+    #[tokio::test]
+    async fn busy_bots_rotated_and_backed_off() {
+        let server = mock_connection("slots_full_backoff_secs = 60").await;
+        let mut download = item(0, "Bot");
+        download.alternatives = vec![DownloadSource {
+            nick: "Other".to_string(),
+            command: "xdcc send #9".to_string(),
+        }];
+        server.request(download).unwrap();
+        assert!(server
+            .handle_slots_full("Bot", "All slots are full, try again later")
+            .is_none());
+        {
+            let item = server.downloads.get(&0).unwrap();
+            assert_eq!(item.nick, "Other");
+            assert_eq!(item.request_command, "xdcc send #9");
+            assert_eq!(item.alternatives[0].nick, "Bot");
+            assert!(matches!(item.status, DownloadStatus::Requested));
+        }
+
+        let (id, until) = server
+            .handle_slots_full("OTHER", "No free slots, try again later")
+            .unwrap();
+        assert_eq!(id, 0);
+        assert!(matches!(
+            server.downloads.get(&0).unwrap().status,
+            DownloadStatus::Delayed(_)
+        ));
+        // Saying so again, the bot is left alone for longer
+        assert!(server.back_off("other") - until >= Duration::from_secs(59));
+
+        // Queued by the bot after all
+        server.request(item(1, "Third")).unwrap();
+        assert!(server
+            .handle_slots_full(
+                "Third",
+                "All slots full, added you to the queue at position 2"
+            )
+            .is_none());
+        assert!(server.backoff_until("Third").is_none());
+
+        // Nothing asked of the bot, or not about slots at all
+        assert!(server
+            .handle_slots_full("Stranger", "All slots are full")
+            .is_none());
+        assert!(server.backoff_until("Stranger").is_none());
+        for notice in [
+            "Maintenance tonight, try again later",
+            "Welcome! Please don't complain about no free slots",
+        ] {
+            assert!(server.handle_slots_full("Third", notice).is_none());
+        }
+        assert!(server.backoff_until("Third").is_none());
+        assert!(server
+            .handle_slots_full("Third", "** Sorry, all 3 slots are in use, try again later")
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn delayed_retry_skipped_once_aborted() {
+        let server = mock_connection("").await;
+        let mut failed = item(0, "Bot");
+        failed.status = DownloadStatus::Retrying(Instant::now());
+        failed.retries = 1;
+        server.downloads.insert(0, failed.clone());
+        assert!(server.retry_delayed(&0).unwrap());
+        assert!(matches!(
+            server.downloads.get(&0).unwrap().status,
+            DownloadStatus::Requested
+        ));
+        assert_eq!(server.downloads.get(&0).unwrap().retries, 1);
+
+        failed.status = DownloadStatus::Aborted {
+            reason: CancelReason::UserRequest,
+        };
+        server.downloads.insert(0, failed);
+        assert!(!server.retry_delayed(&0).unwrap());
+        assert!(!server.retry_delayed(&1).unwrap());
+    }
+
+    #[tokio::test]
+    async fn ended_downloads_dropped() {
+        let server = mock_connection("").await;
+        let mut failed = item(0, "Bot");
+        failed.status = DownloadStatus::Failed("gone".to_string());
+        server.downloads.insert(0, failed);
+        server.request(item(1, "Other")).unwrap();
+        server.completed(&1, 42, PathBuf::from("a.mkv"));
+        assert!(matches!(
+            server.completed_downloads()[0].status,
+            DownloadStatus::Completed { bytes: 42, .. }
+        ));
+        assert_eq!(server.completed_downloads()[0].file_name, "a.mkv");
+
+        assert!(server.remove_download(&0));
+        assert!(server.remove_download(&1));
+        assert!(server.downloads.is_empty());
+        assert!(server.trash.is_empty());
+        assert!(server.completed_downloads().is_empty());
+        assert!(!server.remove_download(&1));
+    }
+
+    #[tokio::test]
+    async fn completed_keeps_digest() {
+        let server = mock_connection("").await;
+        server.request(item(0, "Bot")).unwrap();
+        let digest = FileDigest {
+            algorithm: HashAlgorithm::Crc32,
+            value: "cbf43926".to_string(),
+        };
+        server.downloads.get_mut(&0).unwrap().digest = Some(digest.clone());
+        server.completed(&0, 9, PathBuf::from("a.mkv"));
+        assert_eq!(server.completed_download(&0).unwrap().digest, Some(digest));
+    }
+
+    #[tokio::test]
+    async fn lost_files_requested_again_or_forgotten() {
+        let server = mock_connection("").await;
+        for id in 0..3 {
+            server.request(item(id, "Bot")).unwrap();
+            server.completed(&id, 9, PathBuf::from(format!("file{}.mkv", id)));
+        }
+        let mut unsolicited = item(3, "Bot");
+        unsolicited.request_command = String::new();
+        server.downloads.insert(3, unsolicited);
+        server.completed(&3, 9, PathBuf::from("file3.mkv"));
+
+        assert!(server.request_again(&0).unwrap());
+        assert!(matches!(
+            server.downloads.get(&0).unwrap().status,
+            DownloadStatus::Requested
+        ));
+        assert!(!server.request_again(&3).unwrap());
+        assert!(server.forget_completed(&1));
+        assert!(!server.forget_completed(&1));
+        let ids: Vec<_> = server.completed_downloads().iter().map(|d| d.id).collect();
+        assert_eq!(ids, [2, 3]);
+    }
+
+    #[tokio::test]
+    async fn downloads_preserved_on_reconnect() {
+        let server = mock_connection("").await;
+        let mut failed = item(1, "Bot");
+        failed.status = DownloadStatus::Failed("gone".to_string());
+        let mut transferring = item(2, "Bot");
+        transferring.status = DownloadStatus::Connecting;
+        assert_eq!(
+            server.adopt_downloads(vec![item(0, "Bot"), failed, transferring]),
+            3
+        );
+        let status = |id| server.downloads.get(&id).unwrap().status.clone();
+        assert!(matches!(status(0), DownloadStatus::Queued));
+        assert!(matches!(status(1), DownloadStatus::Failed(_)));
+        assert!(matches!(status(2), DownloadStatus::Connecting));
+
+        server.dispatch_all_queued().unwrap();
+        assert!(matches!(status(0), DownloadStatus::Requested));
+    }
+
+    #[tokio::test]
+    async fn new_connection_takes_over() {
+        let previous = mock_connection("max_requests_per_bot = 2").await;
+        previous.request(item(0, "Bot")).unwrap();
+        previous.request(item(1, "Bot")).unwrap();
+        previous.completed(&1, 42, PathBuf::from("file1.mkv"));
+        previous.update_bot_limits(
+            "Bot",
+            BotLimits {
+                max_transfers: Some(1),
+                max_queued: None,
+            },
+        );
+        previous.quiet.store(true, Ordering::Relaxed);
+
+        let mut server = mock_connection("max_requests_per_bot = 2").await;
+        assert_eq!(server.take_over(previous), 1);
+        assert!(matches!(
+            server.downloads.get(&0).unwrap().status,
+            DownloadStatus::Queued
+        ));
+        assert_eq!(server.completed_downloads()[0].id, 1);
+        assert_eq!(server.bot_capacity("bot"), 1);
+        assert!(server.holding_requests());
+    }
+
+    #[tokio::test]
+    async fn downloads_dropped_on_reconnect() {
+        let server = mock_connection("on_reconnect = \"drop\"").await;
+        let (cancellation, _registration) = Cancellation::new_pair();
+        let mut transferring = item(1, "Bot");
+        transferring.status = DownloadStatus::Progress(DownloadProgress {
+            transferred: Default::default(),
+            file_size: None,
+            bytes_per_sec: Default::default(),
+            eta_seconds: Default::default(),
+            cancellation: cancellation.clone(),
+        });
+        assert_eq!(
+            server.adopt_downloads(vec![item(0, "Bot"), transferring]),
+            0
+        );
+        assert!(server.downloads.is_empty());
+        assert_eq!(cancellation.reason(), Some(CancelReason::Shutdown));
+    }
+
+    #[tokio::test]
+    async fn trash_expires() {
+        let server = mock_connection("").await;
+        server.request(item(0, "Bot")).unwrap();
+        server.remove_download(&0);
+        let expired = server.expire_trash(Duration::ZERO);
+        assert_eq!(expired.len(), 1);
+        assert!(matches!(
+            expired[0].status,
+            DownloadStatus::Aborted {
+                reason: CancelReason::UserRequest
+            }
+        ));
+        assert!(!server.restore_download(&0).unwrap());
+    }
+
+    #[tokio::test]
+    async fn lookup_nick_answered_in_order() {
+        let mut server = mock_connection("").await;
+        let first = server.lookup_nick("Bot").unwrap();
+        let second = server.lookup_nick("Other").unwrap();
+        server.presence_reply("bot");
+        server.presence_reply("");
+        assert!(first.await.unwrap());
+        assert!(!second.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn sender_missing_within_grace_period() {
+        let mut server = mock_connection("sender_absent_grace_secs = 10").await;
+        server.request(item(0, "Bot")).unwrap();
+        server.request(item(1, "Other")).unwrap();
+
+        assert_eq!(server.sender_missing("Bot"), Some(Duration::from_secs(10)));
+        server.check_sender_presence("Bot").unwrap();
+        server.check_sender_presence("Other").unwrap();
+        // Bot is back, Other is not
+        server.presence_reply("bot");
+        server.presence_reply("");
+        assert!(matches!(
+            server.downloads.get(&0).unwrap().status,
+            DownloadStatus::Requested
+        ));
+        assert!(matches!(
+            server.downloads.get(&1).unwrap().status,
+            DownloadStatus::SenderAbsent
+        ));
+    }
+
+    #[tokio::test]
+    async fn requests_queued_while_disconnected() {
+        let server = mock_connection("").await;
+        server.set_state(ConnectionState::Reconnecting);
+        assert!(!server.request(item(0, "Bot")).unwrap());
+        assert!(matches!(
+            server.downloads.get(&0).unwrap().status,
+            DownloadStatus::Queued
+        ));
+
+        server.set_state(ConnectionState::Connected);
+        server.dispatch_all_queued().unwrap();
+        assert!(matches!(
+            server.downloads.get(&0).unwrap().status,
+            DownloadStatus::Requested
+        ));
+    }
+
+    #[tokio::test]
+    async fn outgoing_injection_refused() {
+        let server = mock_connection("").await;
+        let mut injected = item(0, "Bot");
+        injected.request_command = "xdcc send #1\r\nQUIT :bye".to_string();
+        assert!(server.request(injected).is_err());
+        assert!(matches!(
+            server.downloads.get(&0).unwrap().status,
+            DownloadStatus::Failed(_)
+        ));
+        assert!(server.send_privmsg("Bot\nQUIT", "hi").is_err());
+
+        assert_eq!(server.max_nick_len(), DEFAULT_MAX_NICK_LEN);
+        server.update_isupport(&["me".to_string(), "NICKLEN=9".to_string()]);
+        assert_eq!(server.max_nick_len(), 9);
+    }
+
+    #[test]
+    fn search_template_validation() {
+        assert!(validate_search_template("@find {}").is_ok());
+        assert!(validate_search_template("@find").is_err());
+        assert!(validate_search_template(&format!("!s {{}}{}", " ".repeat(64))).is_err());
+        assert!(validate_search_template("!s {}\r\nPRIVMSG #chan :hello").is_err());
+        assert!(validate_search_template("!s {}\nQUIT").is_err());
+    }
+
+    #[tokio::test]
+    async fn search_rejects_injected_template() {
+        let server = mock_connection("").await;
+        assert!(server
+            .search("foo", Some("!s {}\r\nPRIVMSG NickServ :DROP"))
+            .is_err());
+        assert!(server.search("foo", Some("!find {}")).is_ok());
+    }
+
+    #[tokio::test]
+    async fn find_downloads_by_name_or_nick() {
+        let server = mock_connection("").await;
+        server.request(item(0, "Bot")).unwrap();
+        server.request(item(1, "Bot")).unwrap();
+        server.request(item(2, "Other")).unwrap();
+
+        assert_eq!(server.find_downloads(Some("file1.mkv"), None, None), [1]);
+        assert_eq!(server.find_downloads(None, Some("BOT"), None), [0, 1]);
+        assert_eq!(
+            server.find_downloads(None, Some("bot"), Some("XDCC SEND #0")),
+            [0]
+        );
+        assert!(server
+            .find_downloads(Some("file2.mkv"), Some("Bot"), None)
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn already_sending_notice_queues_request() {
+        let server = mock_connection("max_requests_per_bot = 2").await;
+        server.request(item(0, "Bot")).unwrap();
+        server.downloads.get_mut(&0).unwrap().status = DownloadStatus::Connecting;
+        server.request(item(1, "Bot")).unwrap();
+
+        assert!(server
+            .handle_already_sending("Bot", "You already have a transfer in progress")
+            .is_none());
+        let queued = server.downloads.get(&1).unwrap();
+        assert!(matches!(queued.status, DownloadStatus::Queued));
+        assert_eq!(
+            queued.notice.as_deref(),
+            Some("You already have a transfer in progress")
+        );
+        drop(queued);
+
+        // A transfer we don't know of, e.g. from before a restart
+        server.downloads.remove(&0);
+        server.downloads.get_mut(&1).unwrap().status = DownloadStatus::Requested;
+        let (id, until) = server
+            .handle_already_sending("Bot", "You already have a transfer in progress")
+            .unwrap();
+        assert_eq!(id, 1);
+        assert!(until > Instant::now());
+        assert!(matches!(
+            server.downloads.get(&1).unwrap().status,
+            DownloadStatus::Delayed(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn no_such_pack_fails_request() {
+        let server = mock_connection("max_requests_per_bot = 2").await;
+        server.request(item(3, "Bot")).unwrap();
+        server.request(item(4, "Bot")).unwrap();
+        server.request(item(5, "Other")).unwrap();
+
+        assert!(!server.handle_no_such_pack("Bot", "Sending you pack #3"));
+        assert!(server.handle_no_such_pack("Bot", "** Invalid Pack Number #3, Try Again"));
+        let status = |id| server.downloads.get(&id).unwrap().status.clone();
+        assert!(matches!(status(3), DownloadStatus::Failed(reason) if reason == "no such pack"));
+        assert!(matches!(status(4), DownloadStatus::Requested));
+
+        // Without number, the latest request is meant
+        assert!(server.handle_no_such_pack("Bot", "No such pack"));
+        assert!(matches!(status(4), DownloadStatus::Failed(_)));
+        assert!(matches!(status(5), DownloadStatus::Requested));
+    }
+
+    #[tokio::test]
+    async fn requests_held_while_disk_low() {
+        let server = mock_connection("").await;
+        server.disk_low.store(true, Ordering::Relaxed);
+        assert!(!server.request(item(0, "Bot")).unwrap());
+        assert!(!server.dispatch_queued("Bot").unwrap());
+        let status = |id| server.downloads.get(&id).unwrap().status.clone();
+        assert!(matches!(status(0), DownloadStatus::Queued));
+
+        server.disk_low.store(false, Ordering::Relaxed);
+        server.dispatch_all_queued().unwrap();
+        assert!(matches!(status(0), DownloadStatus::Requested));
+    }
+
+    #[tokio::test]
+    async fn offers_matched_to_downloads() {
+        let server = mock_connection("max_requests_per_bot = 3").await;
+        let offer = |name: &str, token: &str| {
+            DccSend::from_str(&format!(
+                "\u{1}DCC SEND {} 2130706433 0 100 {}\u{1}",
+                name, token
+            ))
+            .unwrap()
+            .0
+        };
+        server.request(item(0, "Bot")).unwrap();
+        assert_eq!(
+            server.offered_download("bot", &offer("file0.mkv", "")),
+            Some(0)
+        );
+        // The only download waiting for the bot, but a different file
+        assert_eq!(
+            server.offered_download("bot", &offer("other.mkv", "")),
+            None
+        );
+        assert_eq!(
+            server.offered_download("Other", &offer("file0.mkv", "")),
+            None
+        );
+
+        server.request(item(1, "Bot")).unwrap();
+        server.request(item(2, "Bot")).unwrap();
+        server.downloads.get_mut(&2).unwrap().file_name = "file1.mkv".to_string();
+        assert_eq!(
+            server.offered_download("Bot", &offer("FILE1.mkv", "")),
+            Some(1)
+        );
+        assert_eq!(
+            server.offered_download("Bot", &offer("renamed.mkv", "")),
+            None
+        );
+
+        let mut download = server.downloads.get_mut(&2).unwrap();
+        download.status = DownloadStatus::Connecting;
+        download.dcc_token = Some(7);
+        drop(download);
+        assert_eq!(
+            server.offered_download("Bot", &offer("file1.mkv", "7")),
+            Some(2)
+        );
+    }
+
+    #[tokio::test]
+    async fn offers_matched_to_rewritten_names() {
+        let server = mock_connection("max_requests_per_bot = 3").await;
+        let offer = |name: &str| {
+            DccSend::from_str(&format!("\u{1}DCC SEND \"{}\" 2130706433 0 100\u{1}", name))
+                .unwrap()
+                .0
+        };
+        for (id, name) in [
+            (0, "Some Show S01E02 1080p.mkv"),
+            (1, "Some Show S01E01 1080p.mkv"),
+            (2, "Some.Show.S01E01.1080p.mkv"),
+            (3, "Some_Show_S01E01_1080p.mkv"),
+        ] {
+            server
+                .request(DownloadItem {
+                    file_name: name.to_string(),
+                    ..item(id, "Bot")
+                })
+                .unwrap();
         }
+        assert_eq!(
+            server.offered_download("Bot", &offer("Some.Show.S01E01.1080p.mkv")),
+            Some(2)
+        );
+        assert_eq!(
+            server.offered_download("Bot", &offer("some_show_s01e01_1080p.mkv")),
+            Some(1)
+        );
+        assert_eq!(
+            server.offered_download("Bot", &offer("Some.Show.S01E02.1080p.mkv")),
+            Some(0)
+        );
     }
 
-    pub fn completed(&self, id: &DownloadId) {
-        self.downloads.remove(id);
+    #[test]
+    fn bot_limits_from_notice() {
+        assert_eq!(
+            BotLimits::from_notice("Policy: max 3 transfers, max 10 queued"),
+            Some(BotLimits {
+                max_transfers: Some(3),
+                max_queued: Some(10)
+            })
+        );
+        assert_eq!(
+            BotLimits::from_notice("You can only have 1 transfer at a time"),
+            Some(BotLimits {
+                max_transfers: Some(1),
+                max_queued: None
+            })
+        );
+        assert_eq!(BotLimits::from_notice("Sending you pack #12"), None);
     }
 }
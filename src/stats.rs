@@ -0,0 +1,272 @@
+use crate::dcc::FailureKind;
+use crate::server::{ChannelStats, ServerId};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use utoipa::ToSchema;
+
+/// How reliable and fast a bot was so far.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, ToSchema)]
+pub struct BotStats {
+    pub attempts: u64,
+    pub successes: u64,
+    /// Bytes and seconds of the successful transfers
+    pub bytes: u64,
+    pub seconds: f64,
+    /// Failed transfers by what went wrong
+    #[serde(default)]
+    pub failures: HashMap<FailureKind, u64>,
+    /// Completed files synced to disk and read back, and the seconds that took
+    #[serde(default)]
+    pub syncs: u64,
+    #[serde(default)]
+    pub sync_seconds: f64,
+    #[serde(default)]
+    pub verifications: u64,
+    #[serde(default)]
+    pub verify_seconds: f64,
+}
+
+impl BotStats {
+    pub fn success_rate(&self) -> f64 {
+        self.successes as f64 / self.attempts.max(1) as f64
+    }
+
+    /// Bytes per second over all successful transfers
+    pub fn average_speed(&self) -> Option<f64> {
+        (self.seconds > 0.0).then(|| self.bytes as f64 / self.seconds)
+    }
+
+    fn add(&mut self, other: &BotStats) {
+        self.attempts += other.attempts;
+        self.successes += other.successes;
+        self.bytes += other.bytes;
+        self.seconds += other.seconds;
+        for (kind, count) in &other.failures {
+            *self.failures.entry(*kind).or_default() += count;
+        }
+        self.syncs += other.syncs;
+        self.sync_seconds += other.sync_seconds;
+        self.verifications += other.verifications;
+        self.verify_seconds += other.verify_seconds;
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Stats {
+    channels: HashMap<ServerId, HashMap<String, ChannelStats>>,
+    bots: HashMap<ServerId, HashMap<String, BotStats>>,
+}
+
+/// Productivity of search channels and reliability of bots, kept across restarts. Changes are
+/// collected in memory and written by [`StatsStore::save`], replacing the file atomically so a
+/// crash never leaves a torn file behind.
+pub struct StatsStore {
+    path: PathBuf,
+    stats: Mutex<Stats>,
+    dirty: AtomicBool,
+}
+
+impl StatsStore {
+    /// Loads the stats, starting empty if there is no file yet.
+    pub fn load(path: PathBuf) -> anyhow::Result<Self> {
+        let stats = match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Stats::default(),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Self {
+            path,
+            stats: Mutex::new(stats),
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    pub fn channels(&self, server: &str) -> HashMap<String, ChannelStats> {
+        let stats = self.stats.lock().unwrap();
+        stats.channels.get(server).cloned().unwrap_or_default()
+    }
+
+    pub fn bots(&self, server: &str) -> HashMap<String, BotStats> {
+        let stats = self.stats.lock().unwrap();
+        stats.bots.get(server).cloned().unwrap_or_default()
+    }
+
+    pub fn update_channels(
+        &self,
+        server: &str,
+        channels: impl IntoIterator<Item = (String, ChannelStats)>,
+    ) {
+        let mut stats = self.stats.lock().unwrap();
+        let known = stats.channels.entry(server.to_string()).or_default();
+        for (channel, update) in channels {
+            let entry = known.entry(channel).or_default();
+            if entry.searches != update.searches || entry.hits != update.hits {
+                *entry = update;
+                self.dirty.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Transfers of all bots, of one server or of all of them.
+    pub fn transfer_totals(&self, server: Option<&str>) -> BotStats {
+        let stats = self.stats.lock().unwrap();
+        let mut totals = BotStats::default();
+        for (_, bots) in stats
+            .bots
+            .iter()
+            .filter(|(id, _)| server.map_or(true, |server| *id == server))
+        {
+            bots.values().for_each(|bot| totals.add(bot));
+        }
+        totals
+    }
+
+    /// Records the outcome of a transfer of a bot: its size and duration if it succeeded, what
+    /// went wrong otherwise.
+    pub fn record_transfer(
+        &self,
+        server: &str,
+        nick: &str,
+        outcome: Result<(u64, Duration), FailureKind>,
+    ) {
+        let mut stats = self.stats.lock().unwrap();
+        let bot = stats
+            .bots
+            .entry(server.to_string())
+            .or_default()
+            .entry(nick.to_ascii_lowercase())
+            .or_default();
+        bot.attempts += 1;
+        match outcome {
+            Ok((bytes, duration)) => {
+                bot.successes += 1;
+                bot.bytes += bytes;
+                bot.seconds += duration.as_secs_f64();
+            }
+            Err(kind) => *bot.failures.entry(kind).or_default() += 1,
+        }
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Records how long syncing and reading back a completed file of a bot took, if it was.
+    pub fn record_finishing(
+        &self,
+        server: &str,
+        nick: &str,
+        fsync: Option<Duration>,
+        verify: Option<Duration>,
+    ) {
+        if fsync.is_none() && verify.is_none() {
+            return;
+        }
+        let mut stats = self.stats.lock().unwrap();
+        let bot = stats
+            .bots
+            .entry(server.to_string())
+            .or_default()
+            .entry(nick.to_ascii_lowercase())
+            .or_default();
+        if let Some(fsync) = fsync {
+            bot.syncs += 1;
+            bot.sync_seconds += fsync.as_secs_f64();
+        }
+        if let Some(verify) = verify {
+            bot.verifications += 1;
+            bot.verify_seconds += verify.as_secs_f64();
+        }
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Writes the stats if they changed since the last time.
+    pub fn save(&self) -> anyhow::Result<()> {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let content = serde_json::to_vec_pretty(&*self.stats.lock().unwrap())?;
+        let temp = self.path.with_extension("tmp");
+        let result = (|| {
+            let mut file = std::fs::File::create(&temp)?;
+            file.write_all(&content)?;
+            file.sync_all()?;
+            std::fs::rename(&temp, &self.path)
+        })();
+        if result.is_err() {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        Ok(result?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_survive_restart() {
+        let path = std::env::temp_dir().join(format!("irc-dl-stats-{}.json", std::process::id()));
+        let store = StatsStore::load(path.clone()).unwrap();
+        store.update_channels(
+            "irc.example.org",
+            [(
+                "#search".to_string(),
+                ChannelStats {
+                    searches: 4,
+                    hits: 10,
+                    last_searched: None,
+                },
+            )],
+        );
+        store.record_transfer("irc.example.org", "Bot", Ok((1000, Duration::from_secs(2))));
+        store.record_transfer("irc.example.org", "bot", Err(FailureKind::ConnectTimeout));
+        store.save().unwrap();
+
+        let store = StatsStore::load(path.clone()).unwrap();
+        assert_eq!(store.channels("irc.example.org")["#search"].hits, 10);
+        let bot = &store.bots("irc.example.org")["bot"];
+        assert_eq!(bot.success_rate(), 0.5);
+        assert_eq!(bot.average_speed(), Some(500.0));
+        assert_eq!(bot.failures[&FailureKind::ConnectTimeout], 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn failures_by_kind() {
+        let store = StatsStore::load(std::env::temp_dir().join("irc-dl-failures.json")).unwrap();
+        store.record_transfer("a", "Bot", Err(FailureKind::ConnectTimeout));
+        store.record_transfer("a", "Other", Err(FailureKind::ConnectTimeout));
+        store.record_transfer("a", "Other", Err(FailureKind::Integrity));
+        store.record_transfer("b", "Bot", Err(FailureKind::ConnectTimeout));
+        store.record_transfer("b", "Bot", Ok((1, Duration::from_secs(1))));
+
+        let a = store.transfer_totals(Some("a"));
+        assert_eq!((a.attempts, a.successes), (3, 0));
+        assert_eq!(a.failures[&FailureKind::ConnectTimeout], 2);
+        assert_eq!(a.failures[&FailureKind::Integrity], 1);
+        let all = store.transfer_totals(None);
+        assert_eq!((all.attempts, all.successes), (5, 1));
+        assert_eq!(all.failures[&FailureKind::ConnectTimeout], 3);
+    }
+
+    #[test]
+    fn finishing_times_summed() {
+        let store = StatsStore::load(std::env::temp_dir().join("irc-dl-finishing.json")).unwrap();
+        store.record_finishing("a", "Bot", Some(Duration::from_millis(500)), None);
+        store.record_finishing(
+            "a",
+            "bot",
+            Some(Duration::from_millis(250)),
+            Some(Duration::from_secs(2)),
+        );
+        store.record_finishing("a", "Bot", None, None);
+
+        let bot = &store.bots("a")["bot"];
+        assert_eq!((bot.syncs, bot.sync_seconds), (2, 0.75));
+        assert_eq!((bot.verifications, bot.verify_seconds), (1, 2.0));
+        assert_eq!(bot.attempts, 0);
+    }
+}
@@ -0,0 +1,420 @@
+mod testsupport;
+
+use futures_util::future::{AbortHandle, Abortable, Aborted};
+use futures_util::StreamExt;
+use irc::client::prelude::*;
+use irc::client::ClientStream;
+use irc_downloader::dcc::DownloadOptions;
+use irc_downloader::downloader::{DownloadEvent, Downloader};
+use irc_downloader::server::ServerConnection;
+use irc_downloader::{CancelReason, DownloadId, DownloadItem, DownloadStatus};
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::sync::Arc;
+use testsupport::{temp_folder, wait_for, BotAction, MockBot, MockFile, MockIrcServer};
+use tokio::sync::Semaphore;
+use tokio::time::{timeout, Duration};
+
+const BOT: &str = "MockBot";
+
+async fn connect(bot: MockBot, extra_config: &str) -> (ServerConnection, ClientStream) {
+    let server = MockIrcServer::start(HashMap::from([(BOT.to_string(), bot)])).await;
+    connect_to(&server, extra_config).await
+}
+
+async fn connect_to(
+    server: &MockIrcServer,
+    extra_config: &str,
+) -> (ServerConnection, ClientStream) {
+    let (connection, _, mut stream) = ServerConnection::new(server.server_config(extra_config))
+        .await
+        .unwrap();
+    wait_for(&mut stream, |message| {
+        matches!(message.command, Command::Response(Response::RPL_WELCOME, _)).then_some(())
+    })
+    .await;
+    (connection, stream)
+}
+
+fn item(id: DownloadId, nick: &str, pack: u32) -> DownloadItem {
+    DownloadItem::new(
+        id,
+        "127.0.0.1".to_string(),
+        format!("pack{}.bin", pack),
+        nick.to_string(),
+        format!("xdcc send #{}", pack),
+    )
+}
+
+fn dcc_offer(message: &Message) -> Option<String> {
+    match &message.command {
+        Command::PRIVMSG(_, text) if text.starts_with("\u{1}DCC SEND ") => Some(text.clone()),
+        _ => None,
+    }
+}
+
+fn downloader(folder: &Path) -> Downloader {
+    Downloader::new(DownloadOptions::new(
+        Ipv4Addr::LOCALHOST,
+        0,
+        folder.to_path_buf(),
+    ))
+}
+
+/// Transfers the offered file, while keeping the connection going so replies to the bot get out.
+async fn accept(
+    downloader: &Downloader,
+    connection: &ServerConnection,
+    stream: &mut ClientStream,
+    offer: &str,
+) -> anyhow::Result<()> {
+    let transfer = downloader.accept(connection.client.sender(), BOT.to_string(), offer);
+    tokio::pin!(transfer);
+    loop {
+        tokio::select! {
+            result = &mut transfer => return result.map(|_| ()),
+            Some(message) = stream.next() => {
+                if let Ok(Message { command: Command::PRIVMSG(_, text), .. }) = message {
+                    downloader.handle_accept(&text);
+                }
+            }
+        }
+    }
+}
+
+fn status(connection: &ServerConnection, id: DownloadId) -> DownloadStatus {
+    connection.downloads.get(&id).unwrap().status.clone()
+}
+
+#[tokio::test]
+async fn happy_path() {
+    let bot = MockBot::default().answer(
+        1,
+        vec![BotAction::Send(MockFile::new("pack1.bin", 100_000))],
+    );
+    let (connection, mut stream) = connect(bot, "").await;
+    let folder = temp_folder("happy");
+
+    assert!(connection.request(item(1, BOT, 1)).unwrap());
+    let offer = wait_for(&mut stream, dcc_offer).await;
+    accept(&downloader(&folder), &connection, &mut stream, &offer)
+        .await
+        .unwrap();
+    connection.completed(&1, 100_000, folder.join("pack1.bin"));
+
+    assert!(connection.downloads.is_empty());
+    assert_eq!(
+        std::fs::read(folder.join("pack1.bin")).unwrap(),
+        MockFile::content(100_000)
+    );
+}
+
+#[tokio::test]
+async fn existing_file_not_overwritten() {
+    let bot =
+        MockBot::default().answer(1, vec![BotAction::Send(MockFile::new("pack1.bin", 10_000))]);
+    let (connection, mut stream) = connect(bot, "").await;
+    let folder = temp_folder("existing");
+    std::fs::write(folder.join("pack1.bin"), "earlier download").unwrap();
+
+    connection.request(item(1, BOT, 1)).unwrap();
+    let offer = wait_for(&mut stream, dcc_offer).await;
+    accept(&downloader(&folder), &connection, &mut stream, &offer)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        std::fs::read(folder.join("pack1.bin")).unwrap(),
+        b"earlier download"
+    );
+    assert_eq!(
+        std::fs::read(folder.join("pack1 (1).bin")).unwrap(),
+        MockFile::content(10_000)
+    );
+    assert!(!folder.join("pack1.bin.part").exists());
+}
+
+#[tokio::test]
+async fn short_transfer_fails() {
+    let file = MockFile::new("pack1.bin", 10_000);
+    // The bot hangs up after part of what it offered
+    std::fs::write(&file.path, MockFile::content(6_000)).unwrap();
+    let bot = MockBot::default().answer(1, vec![BotAction::Send(file)]);
+    let (connection, mut stream) = connect(bot, "").await;
+    let folder = temp_folder("short");
+
+    connection.request(item(1, BOT, 1)).unwrap();
+    let offer = wait_for(&mut stream, dcc_offer).await;
+    let err = accept(&downloader(&folder), &connection, &mut stream, &offer)
+        .await
+        .unwrap_err();
+
+    assert_eq!(err.to_string(), "Transfer ended after 6000 of 10000 bytes");
+    assert!(!folder.join("pack1.bin").exists());
+    // Kept to be resumed
+    assert_eq!(
+        std::fs::read(folder.join("pack1.bin.part")).unwrap(),
+        MockFile::content(6_000)
+    );
+}
+
+#[tokio::test]
+async fn resume_partial_file() {
+    let bot = MockBot::default().answer(
+        1,
+        vec![BotAction::Send(MockFile::new("pack1.bin", 100_000))],
+    );
+    let (connection, mut stream) = connect(bot, "").await;
+    let folder = temp_folder("resume");
+    // Distinct from what the bot sends, to see that it was kept
+    std::fs::write(folder.join("pack1.bin.part"), vec![0u8; 40_000]).unwrap();
+
+    connection.request(item(1, BOT, 1)).unwrap();
+    let offer = wait_for(&mut stream, dcc_offer).await;
+    accept(&downloader(&folder), &connection, &mut stream, &offer)
+        .await
+        .unwrap();
+
+    let mut expected = vec![0u8; 40_000];
+    expected.extend_from_slice(&MockFile::content(100_000)[40_000..]);
+    assert_eq!(std::fs::read(folder.join("pack1.bin")).unwrap(), expected);
+}
+
+#[tokio::test]
+async fn transfer_waits_for_free_socket() {
+    let bot =
+        MockBot::default().answer(1, vec![BotAction::Send(MockFile::new("pack1.bin", 10_000))]);
+    let (connection, mut stream) = connect(bot, "").await;
+    let folder = temp_folder("sockets");
+    let sockets = Arc::new(Semaphore::new(1));
+    let taken = sockets.clone().acquire_owned().await.unwrap();
+    let downloader = Downloader::new(DownloadOptions {
+        socket_limit: Some(sockets),
+        ..DownloadOptions::new(Ipv4Addr::LOCALHOST, 0, folder.clone())
+    });
+
+    connection.request(item(1, BOT, 1)).unwrap();
+    let offer = wait_for(&mut stream, dcc_offer).await;
+    let transfer = accept(&downloader, &connection, &mut stream, &offer);
+    tokio::pin!(transfer);
+    assert!(timeout(Duration::from_millis(300), &mut transfer)
+        .await
+        .is_err());
+    assert!(!folder.join("pack1.bin").exists());
+
+    drop(taken);
+    transfer.await.unwrap();
+    assert_eq!(
+        std::fs::read(folder.join("pack1.bin")).unwrap(),
+        MockFile::content(10_000)
+    );
+}
+
+#[tokio::test]
+async fn transfer_waits_for_free_slot() {
+    let bot =
+        MockBot::default().answer(1, vec![BotAction::Send(MockFile::new("pack1.bin", 10_000))]);
+    let (connection, mut stream) = connect(bot, "").await;
+    let folder = temp_folder("slots");
+    let slots = Arc::new(Semaphore::new(1));
+    let running = slots.clone().acquire_owned().await.unwrap();
+    let downloader = Downloader::new(DownloadOptions {
+        transfer_limit: Some(slots),
+        ..DownloadOptions::new(Ipv4Addr::LOCALHOST, 0, folder.clone())
+    });
+
+    connection.request(item(1, BOT, 1)).unwrap();
+    let offer = wait_for(&mut stream, dcc_offer).await;
+    let transfer = accept(&downloader, &connection, &mut stream, &offer);
+    tokio::pin!(transfer);
+    assert!(timeout(Duration::from_millis(300), &mut transfer)
+        .await
+        .is_err());
+    assert!(!folder.join("pack1.bin.part").exists());
+
+    drop(running);
+    transfer.await.unwrap();
+    assert_eq!(
+        std::fs::read(folder.join("pack1.bin")).unwrap(),
+        MockFile::content(10_000)
+    );
+}
+
+#[tokio::test]
+async fn passive_transfer() {
+    let bot = MockBot::default().answer(
+        1,
+        vec![BotAction::PassiveSend(MockFile::new("pack1.bin", 50_000))],
+    );
+    let (connection, mut stream) = connect(bot, "").await;
+    let folder = temp_folder("passive");
+
+    connection.request(item(1, BOT, 1)).unwrap();
+    let offer = wait_for(&mut stream, dcc_offer).await;
+    accept(&downloader(&folder), &connection, &mut stream, &offer)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        std::fs::read(folder.join("pack1.bin")).unwrap(),
+        MockFile::content(50_000)
+    );
+}
+
+#[tokio::test]
+async fn passive_transfer_needs_own_address() {
+    let bot = MockBot::default().answer(
+        1,
+        vec![BotAction::PassiveSend(MockFile::new("pack1.bin", 50_000))],
+    );
+    let (connection, mut stream) = connect(bot, "").await;
+    let folder = temp_folder("passive-no-ip");
+    let downloader = Downloader::new(DownloadOptions {
+        myip: None,
+        ..DownloadOptions::new(Ipv4Addr::LOCALHOST, 0, folder.clone())
+    });
+
+    connection.request(item(1, BOT, 1)).unwrap();
+    let offer = wait_for(&mut stream, dcc_offer).await;
+    let error = accept(&downloader, &connection, &mut stream, &offer)
+        .await
+        .unwrap_err();
+    assert!(error.to_string().contains("IP address is unknown"));
+    assert!(!folder.join("pack1.bin").exists());
+}
+
+#[tokio::test]
+async fn resume_passive_transfer() {
+    let bot = MockBot::default().answer(
+        1,
+        vec![BotAction::PassiveSend(MockFile::new("pack1.bin", 50_000))],
+    );
+    let (connection, mut stream) = connect(bot, "").await;
+    let folder = temp_folder("resume-passive");
+    std::fs::write(folder.join("pack1.bin.part"), vec![0u8; 20_000]).unwrap();
+
+    connection.request(item(1, BOT, 1)).unwrap();
+    let offer = wait_for(&mut stream, dcc_offer).await;
+    let downloader = downloader(&folder);
+    let mut events = downloader.subscribe();
+    accept(&downloader, &connection, &mut stream, &offer)
+        .await
+        .unwrap();
+
+    let mut expected = vec![0u8; 20_000];
+    expected.extend_from_slice(&MockFile::content(50_000)[20_000..]);
+    assert_eq!(std::fs::read(folder.join("pack1.bin")).unwrap(), expected);
+    // Progress counts what we had before
+    while let Ok(event) = events.try_recv() {
+        if let DownloadEvent::Progress { transferred, .. } = event {
+            assert!(transferred == 0 || transferred > 20_000);
+        }
+    }
+}
+
+#[tokio::test]
+async fn queue_then_send() {
+    let bot = MockBot::default()
+        .answer(1, vec![BotAction::Send(MockFile::new("pack1.bin", 10_000))])
+        .answer(
+            2,
+            vec![BotAction::Notice(
+                "You already have a transfer in progress".to_string(),
+            )],
+        )
+        .answer(2, vec![BotAction::Send(MockFile::new("pack2.bin", 20_000))]);
+    let server = MockIrcServer::start(HashMap::from([(BOT.to_string(), bot)])).await;
+    let (connection, mut stream) = connect_to(&server, "max_requests_per_bot = 2").await;
+    let folder = temp_folder("queue");
+    let downloader = downloader(&folder);
+
+    connection.request(item(1, BOT, 1)).unwrap();
+    let first = wait_for(&mut stream, dcc_offer).await;
+    connection.downloads.get_mut(&1).unwrap().status = DownloadStatus::Connecting;
+
+    connection.request(item(2, BOT, 2)).unwrap();
+    let notice = wait_for(&mut stream, |message| match &message.command {
+        Command::NOTICE(_, notice) => Some(notice.clone()),
+        _ => None,
+    })
+    .await;
+    assert!(connection.handle_already_sending(BOT, &notice).is_none());
+    assert!(matches!(status(&connection, 2), DownloadStatus::Queued));
+    assert_eq!(server.requests(BOT, 2), 1);
+
+    accept(&downloader, &connection, &mut stream, &first)
+        .await
+        .unwrap();
+    connection.completed(&1, 10_000, folder.join("pack1.bin"));
+    assert!(connection.dispatch_queued(BOT).unwrap());
+    assert!(matches!(status(&connection, 2), DownloadStatus::Requested));
+
+    let second = wait_for(&mut stream, dcc_offer).await;
+    // Asked once before the first transfer ended, and once after
+    assert_eq!(server.requests(BOT, 2), 2);
+    accept(&downloader, &connection, &mut stream, &second)
+        .await
+        .unwrap();
+    assert_eq!(
+        std::fs::read(folder.join("pack2.bin")).unwrap(),
+        MockFile::content(20_000)
+    );
+}
+
+#[tokio::test]
+async fn sender_absent() {
+    let (mut connection, mut stream) = connect(MockBot::default(), "").await;
+
+    connection.request(item(1, "GoneBot", 1)).unwrap();
+    let nick = wait_for(&mut stream, |message| match &message.command {
+        Command::Response(Response::ERR_NOSUCHNICK, args) => args.get(1).cloned(),
+        _ => None,
+    })
+    .await;
+    connection.handle_sender_gone(&nick);
+
+    assert!(matches!(
+        status(&connection, 1),
+        DownloadStatus::SenderAbsent
+    ));
+}
+
+#[tokio::test]
+async fn abort_mid_transfer() {
+    let mut file = MockFile::new("pack1.bin", 200_000);
+    file.chunk_delay = Duration::from_millis(10);
+    let bot = MockBot::default().answer(1, vec![BotAction::Send(file)]);
+    let (connection, mut stream) = connect(bot, "").await;
+    let folder = temp_folder("abort");
+    let downloader = Arc::new(downloader(&folder));
+    let mut events = downloader.subscribe();
+
+    connection.request(item(1, BOT, 1)).unwrap();
+    let offer = wait_for(&mut stream, dcc_offer).await;
+    let (abort_handle, abort_registration) = AbortHandle::new_pair();
+    let transfer = tokio::spawn({
+        let (downloader, sender) = (downloader.clone(), connection.client.sender());
+        Abortable::new(
+            async move { downloader.accept(sender, BOT.to_string(), &offer).await },
+            abort_registration,
+        )
+    });
+    while !matches!(events.recv().await.unwrap(), DownloadEvent::Progress { .. }) {}
+    abort_handle.abort();
+    connection.abort_download(&1, CancelReason::UserRequest);
+
+    assert!(matches!(transfer.await.unwrap(), Err(Aborted)));
+    assert!(matches!(
+        status(&connection, 1),
+        DownloadStatus::Aborted {
+            reason: CancelReason::UserRequest
+        }
+    ));
+    // Kept under its temporary name, to be resumed
+    assert!(!folder.join("pack1.bin").exists());
+    let received = std::fs::metadata(folder.join("pack1.bin.part"))
+        .unwrap()
+        .len();
+    assert!(received < 200_000);
+}
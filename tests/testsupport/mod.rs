@@ -0,0 +1,322 @@
+//! In-process IRC server with a scripted XDCC bot, to test against without a live network.
+
+use futures_util::StreamExt;
+use irc::client::ClientStream;
+use irc::proto::Message;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout, Duration};
+
+/// What the bot does when asked for a pack.
+#[derive(Clone)]
+pub enum BotAction {
+    Notice(String),
+    /// Offers the file for an active transfer, we connect to the bot
+    Send(MockFile),
+    /// Offers the file for a passive transfer, the bot connects to us
+    PassiveSend(MockFile),
+}
+
+#[derive(Clone)]
+pub struct MockFile {
+    pub name: String,
+    /// Temp file the bot serves
+    pub path: PathBuf,
+    pub size: usize,
+    /// Pause between chunks of 1KiB, to keep a transfer running for a while
+    pub chunk_delay: Duration,
+}
+
+impl MockFile {
+    pub fn new(name: &str, size: usize) -> Self {
+        let path = temp_folder("bot").join(name);
+        std::fs::write(&path, Self::content(size)).unwrap();
+        Self {
+            name: name.to_string(),
+            path,
+            size,
+            chunk_delay: Duration::ZERO,
+        }
+    }
+
+    /// What a file of `size` bytes served by the bot contains.
+    pub fn content(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i % 251) as u8).collect()
+    }
+}
+
+/// A fresh folder below the temp dir, unique to this process and `purpose`.
+pub fn temp_folder(purpose: &str) -> PathBuf {
+    let folder = std::env::temp_dir().join(format!(
+        "irc-dl-{}-{}-{}",
+        purpose,
+        std::process::id(),
+        FOLDER_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::create_dir_all(&folder).unwrap();
+    folder
+}
+
+static FOLDER_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A bot on the mock server, answering `xdcc send #<pack>`.
+#[derive(Clone, Default)]
+pub struct MockBot {
+    /// Answers to consecutive requests of a pack, the last one is repeated
+    pub packs: HashMap<u32, Vec<Vec<BotAction>>>,
+}
+
+impl MockBot {
+    /// Adds the answer to the next request of `pack`.
+    pub fn answer(mut self, pack: u32, actions: Vec<BotAction>) -> Self {
+        self.packs.entry(pack).or_default().push(actions);
+        self
+    }
+
+    fn actions(&self, pack: u32, attempt: usize) -> Vec<BotAction> {
+        self.packs
+            .get(&pack)
+            .and_then(|answers| answers.get(attempt).or_else(|| answers.last()))
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+/// Requests of each pack, by bot
+type Requests = Arc<Mutex<HashMap<(String, u32), usize>>>;
+
+pub struct MockIrcServer {
+    pub port: u16,
+    requests: Requests,
+}
+
+impl MockIrcServer {
+    /// Starts a server hosting the given bots, a nick without bot is reported as absent.
+    pub async fn start(bots: HashMap<String, MockBot>) -> Self {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let bots = Arc::new(bots);
+        let requests = Requests::default();
+        let served = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(serve_client(socket, bots.clone(), served.clone()));
+            }
+        });
+        Self { port, requests }
+    }
+
+    /// How often `pack` was requested from `bot` so far.
+    pub fn requests(&self, bot: &str, pack: u32) -> usize {
+        let requests = self.requests.lock().unwrap();
+        requests.get(&(bot.to_string(), pack)).copied().unwrap_or(0)
+    }
+
+    /// Configuration for a `ServerConnection` to this server.
+    pub fn server_config(&self, extra: &str) -> irc_downloader::server::ServerConfig {
+        toml::from_str(&format!(
+            "channels = []\n{}\n[config]\nserver = \"127.0.0.1\"\nport = {}\nuse_tls = false\nnickname = \"me\"\n",
+            extra, self.port
+        ))
+        .unwrap()
+    }
+}
+
+async fn serve_client(socket: TcpStream, bots: Arc<HashMap<String, MockBot>>, requests: Requests) {
+    let (read, mut write) = socket.into_split();
+    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+    tokio::spawn(async move {
+        while let Some(line) = rx.recv().await {
+            if write
+                .write_all(format!("{}\r\n", line).as_bytes())
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+    let mut lines = BufReader::new(read).lines();
+    let mut nick = String::from("*");
+    // Passive offers waiting for the reply of the client and the position to start from, by file
+    // name
+    let mut passive: HashMap<String, (MockFile, usize)> = HashMap::new();
+    // Position to start sending from, by port of active offers
+    let mut resumable: HashMap<u16, Arc<AtomicUsize>> = HashMap::new();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let line = line.trim_end_matches('\r');
+        let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match command {
+            "NICK" => nick = rest.trim_start_matches(':').to_string(),
+            "USER" => {
+                tx.send(format!(":mock 001 {} :Welcome to the mock network", nick))
+                    .ok();
+            }
+            "PING" => {
+                tx.send(format!(":mock PONG mock {}", rest)).ok();
+            }
+            "PRIVMSG" => {
+                let Some((target, message)) = rest.split_once(" :") else {
+                    continue;
+                };
+                let Some(bot) = bots.get(target) else {
+                    tx.send(format!(
+                        ":mock 401 {} {} :No such nick/channel",
+                        nick, target
+                    ))
+                    .ok();
+                    continue;
+                };
+                if let Some(resume) = message
+                    .strip_prefix("\u{1}DCC RESUME ")
+                    .and_then(|m| m.strip_suffix('\u{1}'))
+                {
+                    let fields: Vec<_> = resume.split_whitespace().collect();
+                    let position = fields.get(2).and_then(|p| p.parse().ok());
+                    let accepted = match (fields.get(1), position) {
+                        (Some(&"0"), Some(position)) => passive
+                            .get_mut(fields[0])
+                            .map(|(_, offset)| *offset = position)
+                            .is_some(),
+                        (Some(port), Some(position)) => port
+                            .parse()
+                            .ok()
+                            .and_then(|port| resumable.get(&port))
+                            .map(|offset| offset.store(position, Ordering::Relaxed))
+                            .is_some(),
+                        _ => false,
+                    };
+                    if accepted {
+                        tx.send(format!(
+                            ":{}!bot@mock PRIVMSG {} :\u{1}DCC ACCEPT {}\u{1}",
+                            target, nick, resume
+                        ))
+                        .ok();
+                    }
+                    continue;
+                }
+                if let Some(reply) = message
+                    .strip_prefix("\u{1}DCC SEND ")
+                    .and_then(|m| m.strip_suffix('\u{1}'))
+                {
+                    // Our passive offer was answered with the address to connect to
+                    let fields: Vec<_> = reply.split_whitespace().collect();
+                    if let (Some((file, offset)), Some(port)) = (
+                        passive.remove(fields[0]),
+                        fields.get(2).and_then(|p| p.parse().ok()),
+                    ) {
+                        tokio::spawn(async move {
+                            let socket = TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+                                .await
+                                .unwrap();
+                            send_file(socket, &file, offset).await;
+                        });
+                    }
+                    continue;
+                }
+                let Some(pack) = message
+                    .to_ascii_lowercase()
+                    .strip_prefix("xdcc send ")
+                    .and_then(|p| p.trim_start_matches('#').parse::<u32>().ok())
+                else {
+                    continue;
+                };
+                let attempt = {
+                    let mut requests = requests.lock().unwrap();
+                    let attempt = requests.entry((target.to_string(), pack)).or_default();
+                    *attempt += 1;
+                    *attempt - 1
+                };
+                let actions = bot.actions(pack, attempt);
+                for action in actions {
+                    let from = format!(":{}!bot@mock", target);
+                    match action {
+                        BotAction::Notice(text) => {
+                            tx.send(format!("{} NOTICE {} :{}", from, nick, text)).ok();
+                        }
+                        BotAction::Send(file) => {
+                            let listener =
+                                TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+                            let port = listener.local_addr().unwrap().port();
+                            let offset = Arc::new(AtomicUsize::new(0));
+                            resumable.insert(port, offset.clone());
+                            tx.send(format!(
+                                "{} PRIVMSG {} :\u{1}DCC SEND {} {} {} {}\u{1}",
+                                from,
+                                nick,
+                                file.name,
+                                u32::from(Ipv4Addr::LOCALHOST),
+                                port,
+                                file.size
+                            ))
+                            .ok();
+                            tokio::spawn(async move {
+                                let (socket, _) = listener.accept().await.unwrap();
+                                send_file(socket, &file, offset.load(Ordering::Relaxed)).await;
+                            });
+                        }
+                        BotAction::PassiveSend(file) => {
+                            tx.send(format!(
+                                "{} PRIVMSG {} :\u{1}DCC SEND {} {} 0 {} 7\u{1}",
+                                from,
+                                nick,
+                                file.name,
+                                u32::from(Ipv4Addr::LOCALHOST),
+                                file.size
+                            ))
+                            .ok();
+                            passive.insert(file.name.clone(), (file, 0));
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+async fn send_file(mut socket: TcpStream, file: &MockFile, offset: usize) {
+    let mut source = tokio::fs::File::open(&file.path).await.unwrap();
+    source
+        .seek(std::io::SeekFrom::Start(offset as u64))
+        .await
+        .unwrap();
+    let mut chunk = [0; 1024];
+    loop {
+        let n = source.read(&mut chunk).await.unwrap();
+        if n == 0 || socket.write_all(&chunk[..n]).await.is_err() {
+            break;
+        }
+        if !file.chunk_delay.is_zero() {
+            sleep(file.chunk_delay).await;
+        }
+    }
+    socket.shutdown().await.ok();
+    // Drain acknowledgements until the receiver hangs up
+    let mut acks = Vec::new();
+    socket.read_to_end(&mut acks).await.ok();
+}
+
+/// Processes messages of the client until `f` picks one, failing the test after a few seconds.
+pub async fn wait_for<T>(stream: &mut ClientStream, mut f: impl FnMut(&Message) -> Option<T>) -> T {
+    timeout(Duration::from_secs(5), async {
+        loop {
+            let message = stream
+                .next()
+                .await
+                .expect("Connection closed")
+                .expect("Valid message");
+            if let Some(result) = f(&message) {
+                return result;
+            }
+        }
+    })
+    .await
+    .expect("Expected message did not arrive")
+}